@@ -51,7 +51,8 @@ pub fn main() -> Result<()> {
             }
             cmds::Ledger::DumpDb(cmds::LedgerDumpDb(args)) => {
                 let chain_ctx = ctx.take_chain_or_exit();
-                node::dump_db(chain_ctx.config.ledger, args);
+                node::dump_db(chain_ctx.config.ledger, args)
+                    .wrap_err("Failed to dump the DB")?;
             }
             cmds::Ledger::RollBack(_) => {
                 let chain_ctx = ctx.take_chain_or_exit();
@@ -3211,6 +3211,7 @@ pub mod args {
             Err(_) => config::get_default_namada_folder(),
         }),
     );
+    pub const BINARY_DUMP: ArgFlag = flag("binary");
     pub const BLOCK_HEIGHT: Arg<BlockHeight> = arg("block-height");
     pub const BLOCK_HEIGHT_OPT: ArgOpt<BlockHeight> = arg_opt("height");
     pub const BLOCK_HEIGHT_FROM_OPT: ArgOpt<BlockHeight> =
@@ -3415,6 +3416,7 @@ pub mod args {
     pub const SIGNING_KEYS: ArgMulti<WalletPublicKey, GlobStar> =
         arg_multi("signing-keys");
     pub const SIGNATURES: ArgMulti<PathBuf, GlobStar> = arg_multi("signatures");
+    pub const SINCE_DUMP: ArgOpt<PathBuf> = arg_opt("since-dump");
     pub const SOURCE: Arg<WalletAddress> = arg("source");
     pub const SOURCE_OPT: ArgOpt<WalletAddress> = SOURCE.opt();
     pub const SOURCE_VALIDATOR: Arg<WalletAddress> = arg("source-validator");
@@ -3644,6 +3646,8 @@ pub mod args {
         pub block_height: Option<BlockHeight>,
         pub out_file_path: PathBuf,
         pub historic: bool,
+        pub since_dump: Option<PathBuf>,
+        pub binary: bool,
     }
 
     impl Args for LedgerDumpDb {
@@ -3653,11 +3657,15 @@ pub mod args {
                 .parse(matches)
                 .unwrap_or_else(|| PathBuf::from("db_dump".to_string()));
             let historic = HISTORIC.parse(matches);
+            let since_dump = SINCE_DUMP.parse(matches);
+            let binary = BINARY_DUMP.parse(matches);
 
             Self {
                 block_height,
                 out_file_path,
                 historic,
+                since_dump,
+                binary,
             }
         }
 
@@ -3674,6 +3682,15 @@ pub mod args {
             .arg(HISTORIC.def().help(wrap!(
                 "If provided, dump also the diff of the last height"
             )))
+            .arg(SINCE_DUMP.def().help(wrap!(
+                "Path of a previous dump or delta. If provided, only the \
+                 changes of the subspace since its height are dumped into a \
+                 \"{out_file_path}_{from}_{to}.delta\" file."
+            )))
+            .arg(BINARY_DUMP.def().help(wrap!(
+                "If provided, write the dump in a compact binary format that \
+                 can be restored, instead of TOML."
+            )))
         }
     }
 
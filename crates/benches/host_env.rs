@@ -1,6 +1,7 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use namada_apps_lib::account::AccountPublicKeysMap;
 use namada_apps_lib::collections::{HashMap, HashSet};
+use namada_apps_lib::hash::Hash;
 use namada_apps_lib::storage::DB;
 use namada_apps_lib::token::{Amount, Transfer};
 use namada_apps_lib::tx::Authorization;
@@ -321,6 +322,56 @@ fn storage_write(c: &mut Criterion) {
     group.finish();
 }
 
+fn replay_protection_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("replay_protection_lookup");
+    let mut shell = BenchShell::default();
+
+    // Commit some replay protection entries
+    let present_hash = Hash::sha256(b"present");
+    let mut batch = namada_node::storage::PersistentDB::batch();
+    for hash in (0..10_000_u32)
+        .map(|i| Hash::sha256(i.to_le_bytes()))
+        .chain([present_hash])
+    {
+        // The key of a replay protection entry is the tx hash
+        let key = storage::Key::parse(hash.to_string()).unwrap();
+        shell
+            .state
+            .db_mut()
+            .write_replay_protection_entry(&mut batch, &key)
+            .unwrap();
+    }
+    shell.state.db().exec_batch(batch).unwrap();
+    shell.state.db().flush(true).unwrap();
+
+    // The common case of a tx that's not been applied yet
+    let missing_hash = Hash::sha256(b"missing");
+    group.bench_function("missing", |b| {
+        b.iter(|| {
+            assert!(
+                !shell
+                    .state
+                    .db()
+                    .has_replay_protection_entry(&missing_hash)
+                    .unwrap()
+            )
+        })
+    });
+    group.bench_function("present", |b| {
+        b.iter(|| {
+            assert!(
+                shell
+                    .state
+                    .db()
+                    .has_replay_protection_entry(&present_hash)
+                    .unwrap()
+            )
+        })
+    });
+
+    group.finish();
+}
+
 criterion_group!(
     host_env,
     tx_section_signature_validation,
@@ -330,5 +381,6 @@ criterion_group!(
     storage_read,
     write_log_write,
     storage_write,
+    replay_protection_lookup,
 );
 criterion_main!(host_env);
@@ -115,6 +115,12 @@ pub const STATE_CF: &str = "state";
 pub const BLOCK_CF: &str = "block";
 /// Replay protection column family name
 pub const REPLAY_PROTECTION_CF: &str = "replay_protection";
+/// Operator annotations column family name. This column family only holds
+/// node-local metadata, so it's not part of [`DbColFam`].
+pub const ANNOTATIONS_CF: &str = "annotations";
+/// Block archive column family name. This column family only exists in the
+/// DBs with the archive enabled, so it's not part of [`DbColFam`].
+pub const ARCHIVE_CF: &str = "archive";
 
 impl DbColFam {
     /// Get the name of the column family
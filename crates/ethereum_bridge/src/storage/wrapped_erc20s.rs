@@ -52,6 +52,16 @@ pub fn has_erc20_segment(key: &storage::Key) -> bool {
     )
 }
 
+/// Returns true if the given key has a NUT segment
+pub fn has_nut_segment(key: &storage::Key) -> bool {
+    matches!(
+        key.segments.get(1),
+        Some(DbKeySeg::AddressSeg(Address::Internal(
+            InternalAddress::Nut(_addr)
+        )))
+    )
+}
+
 impl TryFrom<(&Address, &storage::Key)> for Key {
     type Error = eyre::Error;
 
@@ -13,7 +13,9 @@ use namada_tx::BatchedTxRef;
 use namada_vp::native_vp::{self, Ctx, NativeVp, StorageReader, VpEvaluator};
 
 use crate::storage;
-use crate::storage::escrow_key;
+use crate::storage::bridge_pool::BRIDGE_POOL_ADDRESS;
+use crate::storage::{escrow_key, wrapped_erc20s};
+use crate::vp::nut_vp::check_nut_balance_change;
 
 /// Generic error that may be returned by the validity predicate
 #[derive(thiserror::Error, Debug)]
@@ -65,7 +67,7 @@ where
             // NB: normally, we only escrow NAM under the Ethereum bridge
             // address in the context of a Bridge pool transfer
             let bridge_pool_is_verifier =
-                verifiers.contains(&storage::bridge_pool::BRIDGE_POOL_ADDRESS);
+                verifiers.contains(&BRIDGE_POOL_ADDRESS);
 
             bridge_pool_is_verifier.ok_or_else(|| {
                 native_vp::Error::new_const(
@@ -82,6 +84,46 @@ where
             .into())
         }
     }
+
+    /// Check the NUT balances changed by a tx.
+    ///
+    /// NUTs can't be transferred like regular wrapped ERC20s. The only
+    /// permitted flow is sending them back to Ethereum, i.e. moving them
+    /// into the Bridge pool escrow, while triggering the Bridge pool VP. The
+    /// balance changes are checked as in the NUT VP.
+    fn check_nut_transfer(
+        &self,
+        nut_keys: &[(&Key, &Address)],
+        verifiers: &BTreeSet<Address>,
+    ) -> Result<(), Error> {
+        verifiers.contains(&BRIDGE_POOL_ADDRESS).ok_or_else(|| {
+            let error: Error = native_vp::Error::new_const(
+                "NUT transfers must send the tokens to the Bridge pool",
+            )
+            .into();
+            tracing::debug!("{error}");
+            error
+        })?;
+
+        for &(key, owner) in nut_keys {
+            if owner == &crate::ADDRESS {
+                let error = native_vp::Error::new_const(
+                    "NUTs cannot be held by the Ethereum bridge account",
+                )
+                .into();
+                tracing::debug!("{error}");
+                return Err(error);
+            }
+
+            let pre: Amount =
+                (&self.ctx).read_pre_value(key)?.unwrap_or_default();
+            let post: Amount =
+                (&self.ctx).read_post_value(key)?.unwrap_or_default();
+            check_nut_balance_change(key, owner, pre, post)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<'view, 'ctx: 'view, S, CA, EVAL, TokenKeys> NativeVp<'view>
@@ -98,7 +140,8 @@ where
     /// account.
     ///
     /// We only permit increasing the escrowed balance of NAM under the Ethereum
-    /// bridge address, when writing to storage from wasm transactions.
+    /// bridge address, when writing to storage from wasm transactions. NUT
+    /// balances may only be moved into the Bridge pool escrow.
     ///
     /// Some other changes to the storage subspace of this account are expected
     /// to happen natively i.e. bypassing this validity predicate. For example,
@@ -116,6 +159,18 @@ where
             "Ethereum Bridge VP triggered",
         );
 
+        let nut_keys: Vec<_> = keys_changed
+            .iter()
+            .filter(|&key| wrapped_erc20s::has_nut_segment(key))
+            .filter_map(|key| {
+                TokenKeys::is_any_token_balance_key(key)
+                    .map(|[_nut, owner]| (key, owner))
+            })
+            .collect();
+        if !nut_keys.is_empty() {
+            self.check_nut_transfer(&nut_keys, verifiers)?;
+        }
+
         validate_changed_keys::<TokenKeys>(
             &self.ctx.state.in_mem().native_token,
             keys_changed,
@@ -204,11 +259,9 @@ mod tests {
     use rand::Rng;
 
     use super::*;
-    use crate::storage::bridge_pool::BRIDGE_POOL_ADDRESS;
     use crate::storage::parameters::{
         Contracts, EthereumBridgeParams, UpgradeableContract,
     };
-    use crate::storage::wrapped_erc20s;
 
     const ARBITRARY_OWNER_A_ADDRESS: &str =
         "tnam1qqwuj7aart6ackjfkk7486jwm2ufr4t7cq4535u4";
@@ -541,4 +594,134 @@ mod tests {
         let res = vp.validate_tx(&batched_tx, &keys_changed, &verifiers);
         assert!(res.is_err());
     }
+
+    /// Run the VP on a transfer of NUTs from [`ARBITRARY_OWNER_A_ADDRESS`]
+    /// to the given destination, along with an escrow of NAM in the
+    /// Ethereum bridge account if `escrow_nam` is set.
+    fn check_nut_transfer(
+        dst: Address,
+        verifiers: BTreeSet<Address>,
+        escrow_nam: bool,
+    ) -> Result<(), Error> {
+        let mut state = setup_storage();
+        let nut = wrapped_erc20s::nut(
+            &ethereum_events::testing::DAI_ERC20_ETH_ADDRESS,
+        );
+        let src_key = balance_key(
+            &nut,
+            &Address::decode(ARBITRARY_OWNER_A_ADDRESS).expect("Test failed"),
+        );
+        let dst_key = balance_key(&nut, &dst);
+        state
+            .write(&src_key, Amount::from(ARBITRARY_OWNER_A_INITIAL_BALANCE))
+            .expect("Test failed");
+        state.commit_block().expect("Test failed");
+
+        // move the NUTs
+        state
+            .write_log_mut()
+            .write(
+                &src_key,
+                Amount::from(ARBITRARY_OWNER_A_INITIAL_BALANCE - ESCROW_AMOUNT)
+                    .serialize_to_vec(),
+            )
+            .expect("Test failed");
+        state
+            .write_log_mut()
+            .write(&dst_key, Amount::from(ESCROW_AMOUNT).serialize_to_vec())
+            .expect("Test failed");
+        let mut keys_changed = BTreeSet::from([src_key, dst_key]);
+
+        if escrow_nam {
+            let account_key = balance_key(
+                &nam(),
+                &Address::decode(ARBITRARY_OWNER_A_ADDRESS)
+                    .expect("Test failed"),
+            );
+            state
+                .write_log_mut()
+                .write(
+                    &account_key,
+                    Amount::from(
+                        ARBITRARY_OWNER_A_INITIAL_BALANCE - ESCROW_AMOUNT,
+                    )
+                    .serialize_to_vec(),
+                )
+                .expect("Test failed");
+            let escrow_key = balance_key(&nam(), &crate::ADDRESS);
+            state
+                .write_log_mut()
+                .write(
+                    &escrow_key,
+                    Amount::from(
+                        BRIDGE_POOL_ESCROW_INITIAL_BALANCE + ESCROW_AMOUNT,
+                    )
+                    .serialize_to_vec(),
+                )
+                .expect("Test failed");
+            keys_changed.extend([account_key, escrow_key]);
+        }
+
+        // set up the VP
+        let mut tx = Tx::from_type(TxType::Raw);
+        tx.push_default_inner_tx();
+        let gas_meter = RefCell::new(VpGasMeter::new_from_tx_meter(
+            &TxGasMeter::new(u64::MAX),
+        ));
+        let batched_tx = tx.batch_ref_first_tx().unwrap();
+        let vp = EthBridge::new(setup_ctx(
+            batched_tx.tx,
+            batched_tx.cmt,
+            &state,
+            &gas_meter,
+            &keys_changed,
+            &verifiers,
+        ));
+
+        vp.validate_tx(&batched_tx, &keys_changed, &verifiers)
+    }
+
+    /// Test that regular NUT transfers are rejected.
+    #[test]
+    fn test_nut_transfer_rejected() {
+        let res = check_nut_transfer(
+            established_address_1(),
+            BTreeSet::from([BRIDGE_POOL_ADDRESS]),
+            true,
+        );
+        assert!(res.is_err());
+
+        // nor can NUTs be moved into the Ethereum bridge account
+        let res = check_nut_transfer(
+            crate::ADDRESS,
+            BTreeSet::from([BRIDGE_POOL_ADDRESS]),
+            true,
+        );
+        assert!(res.is_err());
+    }
+
+    /// Test that NUTs can be sent back to Ethereum over the Bridge pool.
+    #[test]
+    fn test_nut_transfer_to_bridge_pool_accepted() {
+        let res = check_nut_transfer(
+            BRIDGE_POOL_ADDRESS,
+            BTreeSet::from([BRIDGE_POOL_ADDRESS]),
+            true,
+        );
+        assert!(res.is_ok());
+
+        // the Bridge pool VP must be triggered
+        let res =
+            check_nut_transfer(BRIDGE_POOL_ADDRESS, BTreeSet::new(), true);
+        assert!(res.is_err());
+
+        // the keys changed under our account are still validated, so the
+        // VP can't be triggered by the NUTs alone
+        let res = check_nut_transfer(
+            BRIDGE_POOL_ADDRESS,
+            BTreeSet::from([BRIDGE_POOL_ADDRESS]),
+            false,
+        );
+        assert!(res.is_err());
+    }
 }
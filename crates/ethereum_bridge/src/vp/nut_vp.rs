@@ -84,48 +84,57 @@ where
                 .map_err(Error)?
                 .unwrap_or_default();
 
-            match token_owner {
-                // the NUT balance of the bridge pool should increase
-                Address::Internal(InternalAddress::EthBridgePool) => {
-                    if post < pre {
-                        tracing::debug!(
-                            %changed_key,
-                            pre_amount = ?pre,
-                            post_amount = ?post,
-                            "Bridge pool balance should have increased"
-                        );
-                        return Err(native_vp::Error::new_alloc(format!(
-                            "Bridge pool balance should have increased. The \
-                             previous balance was {pre:?}, the post balance \
-                             is {post:?}.",
-                        ))
-                        .into());
-                    }
-                }
-                // arbitrary addresses should have their balance decrease
-                _addr => {
-                    if post > pre {
-                        tracing::debug!(
-                            %changed_key,
-                            pre_amount = ?pre,
-                            post_amount = ?post,
-                            "Balance should have decreased"
-                        );
-                        return Err(native_vp::Error::new_alloc(format!(
-                            "Balance should have decreased. The previous \
-                             balance was {pre:?}, the post balance is \
-                             {post:?}."
-                        ))
-                        .into());
-                    }
-                }
-            }
+            check_nut_balance_change(changed_key, token_owner, pre, post)?;
         }
 
         Ok(())
     }
 }
 
+/// Check the change of the NUT balance of the given owner. The NUT balance of
+/// the Bridge pool may only increase, while that of any other owner may only
+/// decrease.
+pub(super) fn check_nut_balance_change(
+    changed_key: &Key,
+    token_owner: &Address,
+    pre: Amount,
+    post: Amount,
+) -> Result<(), native_vp::Error> {
+    match token_owner {
+        // the NUT balance of the bridge pool should increase
+        Address::Internal(InternalAddress::EthBridgePool) => {
+            if post < pre {
+                tracing::debug!(
+                    %changed_key,
+                    pre_amount = ?pre,
+                    post_amount = ?post,
+                    "Bridge pool balance should have increased"
+                );
+                return Err(native_vp::Error::new_alloc(format!(
+                    "Bridge pool balance should have increased. The previous \
+                     balance was {pre:?}, the post balance is {post:?}.",
+                )));
+            }
+        }
+        // arbitrary addresses should have their balance decrease
+        _addr => {
+            if post > pre {
+                tracing::debug!(
+                    %changed_key,
+                    pre_amount = ?pre,
+                    post_amount = ?post,
+                    "Balance should have decreased"
+                );
+                return Err(native_vp::Error::new_alloc(format!(
+                    "Balance should have decreased. The previous balance was \
+                     {pre:?}, the post balance is {post:?}."
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
 impl<'ctx, S, CA, EVAL, TokenKeys> NonUsableTokens<'ctx, S, CA, EVAL, TokenKeys>
 where
     S: 'static + StateRead,
@@ -1,3 +1,4 @@
+use borsh::BorshDeserialize;
 use namada_sdk::address::Address;
 use namada_sdk::hash::Hash;
 use namada_sdk::key::common;
@@ -5,7 +6,7 @@ use namada_sdk::masp::TxId;
 use namada_sdk::token::transaction::Transaction;
 pub use namada_sdk::token::{DenominatedAmount, Transfer};
 use namada_sdk::tx::data::GasLimit;
-use namada_sdk::tx::{Authorization, Tx, TxError, TX_TRANSFER_WASM};
+use namada_sdk::tx::{Authorization, Section, Tx, TxError, TX_TRANSFER_WASM};
 
 use super::{attach_fee, attach_fee_signature, GlobalArgs};
 use crate::transaction;
@@ -38,6 +39,41 @@ impl TransferBuilder {
         Self(tx)
     }
 
+    /// Attach a MASP transaction to the transfer, e.g. to shield or unshield
+    /// the tokens of a transfer built with [`TransferBuilder::transfer`]. The
+    /// MASP transaction is added as a section of the tx and the transfer data
+    /// is updated to reference it by hash, so that the protocol can locate
+    /// it.
+    ///
+    /// A transfer carries a single MASP transaction: the unshielding of the
+    /// fees, if paid from the shielded pool, must be built into the same MASP
+    /// transaction as the transfer. Attaching another MASP transaction
+    /// replaces the previous one.
+    pub fn masp_transaction(mut self, transaction: Transaction) -> Self {
+        let cmt = self
+            .0
+            .first_commitments()
+            .expect("The transfer should have commitments")
+            .to_owned();
+        let mut transfer = self
+            .0
+            .data(&cmt)
+            .and_then(|data| Transfer::try_from_slice(&data).ok())
+            .expect("The transfer data should be valid");
+
+        // Drop the previous MASP transaction and the data referencing it
+        self.0.sections.retain(|section| match section {
+            Section::MaspTx(_) => false,
+            _ => section.get_hash() != cmt.data_hash,
+        });
+        let (tx, shielded_section_hash) =
+            self.0.add_masp_tx_section(transaction);
+        transfer.shielded_section_hash = Some(shielded_section_hash);
+        tx.add_data(transfer);
+
+        self
+    }
+
     /// Get the bytes to sign for the given transaction
     pub fn get_sign_bytes(&self) -> Vec<Hash> {
         transaction::get_sign_bytes(&self.0)
@@ -94,3 +130,66 @@ impl TransferBuilder {
         self.0.validate_tx()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use namada_sdk::chain::ChainId;
+    use namada_sdk::masp_primitives::consensus::{BlockHeight, BranchId};
+    use namada_sdk::masp_primitives::transaction::{
+        Authorized, TransactionData, TxVersion,
+    };
+
+    use super::*;
+
+    fn masp_tx(lock_time: u32) -> Transaction {
+        TransactionData::<Authorized>::from_parts(
+            TxVersion::MASPv5,
+            BranchId::MASP,
+            lock_time,
+            BlockHeight::from_u32(0),
+            None,
+            None,
+        )
+        .freeze()
+        .unwrap()
+    }
+
+    /// Test attaching a MASP transaction to a transfer
+    #[test]
+    fn test_masp_transaction() {
+        let args = GlobalArgs {
+            expiration: None,
+            code_hash: Hash::zero(),
+            chain_id: ChainId("test-chain".to_string()),
+        };
+        let first = masp_tx(0);
+        let second = masp_tx(1);
+
+        // Attaching another MASP transaction replaces the first one
+        let tx = TransferBuilder::transfer(Transfer::default(), args)
+            .masp_transaction(first.clone())
+            .masp_transaction(second.clone())
+            .payload();
+        let masp_sections: Vec<_> = tx
+            .sections
+            .iter()
+            .filter(|section| matches!(section, Section::MaspTx(_)))
+            .collect();
+        assert_eq!(masp_sections.len(), 1);
+        assert!(tx.get_masp_section(&first.txid().into()).is_none());
+        assert!(tx.get_masp_section(&second.txid().into()).is_some());
+
+        // The transfer data references the MASP section
+        let cmt = tx.first_commitments().unwrap();
+        let transfer =
+            Transfer::try_from_slice(&tx.data(cmt).unwrap()).unwrap();
+        assert_eq!(transfer.shielded_section_hash, Some(second.txid().into()));
+        assert_eq!(
+            tx.sections.iter().filter(|s| s.data().is_some()).count(),
+            1
+        );
+
+        // The MASP section is part of the signed commitments
+        assert!(tx.sechashes().contains(&masp_sections[0].get_hash()));
+    }
+}
@@ -35,6 +35,7 @@ use ics23_specs::ibc_leaf_spec;
 use namada_core::address::{Address, InternalAddress};
 use namada_core::borsh::{BorshDeserialize, BorshSerialize, BorshSerializeExt};
 use namada_core::bytes::ByteBuf;
+use namada_core::collections::HashSet;
 use namada_core::eth_bridge_pool::{is_pending_transfer_key, PendingTransfer};
 use namada_core::hash::{Hash, StorageHasher};
 use namada_core::keccak::KeccakHash;
@@ -217,6 +218,25 @@ impl Store {
             Self::CommitData => StoreRef::CommitData,
         }
     }
+
+    /// Recompute the root of the tree from this backing store and check it
+    /// matches the given root. The commit only data has no backing store to
+    /// recompute its root from, so it's always considered valid.
+    pub fn validate_root<H: StorageHasher + Default>(self, root: Hash) -> bool {
+        match self {
+            Self::Base(store)
+            | Self::Account(store)
+            | Self::PoS(store)
+            | Self::NoDiff(store) => {
+                Smt::<H>::new(root.into(), store).validate()
+            }
+            Self::Ibc(store) => Amt::<H>::new(root.into(), store).validate(),
+            Self::BridgePool(store) => {
+                BridgePoolTree::new(root.into(), store).validate()
+            }
+            Self::CommitData => true,
+        }
+    }
 }
 
 /// Pointer to backing storage of merkle tree
@@ -251,7 +271,9 @@ impl<'a> StoreRef<'a> {
         }
     }
 
-    /// Borsh Seriliaze the backing stores of our Merkle tree.
+    /// Borsh Seriliaze the backing stores of our Merkle tree. The stores
+    /// don't depend on the tree's hasher, which only derives their keys and
+    /// roots.
     pub fn encode(&self) -> Vec<u8> {
         match self {
             Self::Base(store) => store.serialize_to_vec(),
@@ -366,7 +388,9 @@ impl StoreType {
         Some(addr.to_db_key().into())
     }
 
-    /// Decode the backing store from bytes and tag its type correctly
+    /// Decode the backing store from bytes and tag its type correctly. The
+    /// store can be restored into a tree of any hasher, see
+    /// [`MerkleTree::new`].
     pub fn decode_store<T: AsRef<[u8]>>(
         &self,
         bytes: T,
@@ -851,11 +875,14 @@ pub struct MerkleTreeStoresRead {
     bridge_pool: (KeccakHash, BridgePoolStore),
     no_diff: (Hash, SmtStore),
     commit: Hash,
+    /// The store types whose root has been set
+    roots_set: HashSet<StoreType>,
 }
 
 impl MerkleTreeStoresRead {
     /// Set the root of the given store type
     pub fn set_root(&mut self, store_type: &StoreType, root: Hash) {
+        self.roots_set.insert(*store_type);
         match store_type {
             StoreType::Base => self.base.0 = root,
             StoreType::Account => self.account.0 = root,
@@ -893,6 +920,12 @@ impl MerkleTreeStoresRead {
         }
     }
 
+    /// Check if the root of the given store type has been set. The root of an
+    /// empty tree is zero, so it can't tell a missing root apart.
+    pub fn has_root(&self, store_type: &StoreType) -> bool {
+        self.roots_set.contains(store_type)
+    }
+
     /// Read the merkle root of the requested type
     pub fn get_root(&self, store_type: StoreType) -> Hash {
         match store_type {
@@ -724,7 +724,7 @@ impl ShieldedUtils for BenchShieldedUtils {
         // Fill the supplied context with the deserialized object
         *ctx = ShieldedContext {
             utils: ctx.utils.clone(),
-            ..ShieldedContext::deserialize(&mut &bytes[..])?
+            ..ShieldedContext::deserialize_versioned(&bytes)?
         };
         Ok(())
     }
@@ -752,8 +752,8 @@ impl ShieldedUtils for BenchShieldedUtils {
                 .write(true)
                 .create_new(true)
                 .open(tmp_path.clone())?;
-            let mut bytes = Vec::new();
-            ctx.serialize(&mut bytes)
+            let bytes = ctx
+                .serialize_versioned()
                 .expect("cannot serialize shielded context");
             ctx_file.write_all(&bytes[..])?;
         }
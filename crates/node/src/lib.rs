@@ -340,13 +340,46 @@ pub fn dump_db(
         block_height,
         out_file_path,
         historic,
+        since_dump,
+        binary,
     }: args::LedgerDumpDb,
-) {
+) -> Result<(), shell::Error> {
     let chain_id = config.chain_id;
     let db_path = config.shell.db_dir(&chain_id);
 
     let db = storage::PersistentDB::open(db_path, None);
-    db.dump_block(out_file_path, historic, block_height);
+    match since_dump {
+        Some(since_dump) => {
+            let delta_path = db
+                .dump_delta(
+                    &since_dump,
+                    out_file_path,
+                    block_height,
+                    storage::DEFAULT_DUMP_BUFFER_SIZE,
+                )
+                .map_err(|e| {
+                    shell::Error::Storage(namada_sdk::state::StorageError::new(
+                        e,
+                    ))
+                })?;
+            tracing::info!("Done writing to {}", delta_path.to_string_lossy());
+        }
+        None => {
+            let format = if binary {
+                storage::DumpFormat::Binary
+            } else {
+                storage::DumpFormat::Toml
+            };
+            db.dump_block(
+                out_file_path,
+                historic,
+                block_height,
+                format,
+                storage::DEFAULT_DUMP_BUFFER_SIZE,
+            )
+        }
+    }
+    Ok(())
 }
 
 #[cfg(feature = "migrations")]
@@ -407,6 +440,8 @@ pub fn update_db_keys(config: config::Ledger, updates: PathBuf, dry_run: bool) {
     let batch = db.apply_migration_to_batch(updates.changes).unwrap();
     if !dry_run {
         tracing::info!("Persisting DB changes...");
+        db.mark_migration_in_progress()
+            .expect("Failed to mark the migration as in progress");
         db.exec_batch(batch).expect("Failed to execute write batch");
         db.flush(true).expect("Failed to flush data to disk");
 
@@ -414,6 +449,8 @@ pub fn update_db_keys(config: config::Ledger, updates: PathBuf, dry_run: bool) {
         // hash
         tendermint_node::reset_state(cometbft_path)
             .expect("Failed to reset CometBFT state");
+        db.clear_migration_marker()
+            .expect("Failed to clear the migration marker");
     }
 }
 
@@ -137,8 +137,9 @@ where
                 )
         };
 
-        // Tracks the accepted transactions
+        // Tracks the accepted transactions and their gas
         self.state.in_mem_mut().block.results = BlockResults::default();
+        self.state.in_mem_mut().block.gas = 0;
         let mut changed_keys = BTreeSet::new();
 
         // Execute wrapper and protocol transactions
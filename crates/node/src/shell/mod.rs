@@ -171,6 +171,7 @@ pub fn rollback(config: config::Ledger) -> Result<()> {
     tracing::info!("Rollback Namada state");
 
     db.rollback(tendermint_block_height)
+        .map(|_stats| ())
         .map_err(|e| Error::Storage(namada_sdk::state::StorageError::new(e)))
 }
 
@@ -1993,6 +1994,7 @@ mod test_utils {
 #[cfg(test)]
 mod shell_tests {
     use eth_bridge::storage::eth_bridge_queries::is_bridge_comptime_enabled;
+    use namada_replay_protection as replay_protection;
     use namada_sdk::address;
     use namada_sdk::storage::Epoch;
     use namada_sdk::token::read_denom;
@@ -2002,7 +2004,7 @@ mod shell_tests {
     use namada_vote_ext::{
         bridge_pool_roots, ethereum_events, ethereum_tx_data_variants,
     };
-    use {namada_replay_protection as replay_protection, wallet};
+    use wallet;
 
     use super::*;
     use crate::shell::token::DenominatedAmount;
@@ -10,7 +10,12 @@ use arse_merkle_tree::traits::Hasher;
 use arse_merkle_tree::H256;
 use blake2b_rs::{Blake2b, Blake2bBuilder};
 use namada_sdk::state::{FullAccessState, StorageHasher};
-pub use rocksdb::{open, DbSnapshot, RocksDBUpdateVisitor, SnapshotMetadata};
+pub use rocksdb::{
+    open, open_with_encryption, open_with_options, DbHealth, DbSnapshot,
+    DbStatus, DumpFormat, DurabilityMode, OpenOptions, ReplayProtectionBloom,
+    RocksDBUpdateVisitor, SnapshotMetadata, SubspaceCipher,
+    DEFAULT_DUMP_BUFFER_SIZE,
+};
 
 #[derive(Default)]
 pub struct PersistentStorageHasher(Blake2bHasher);
@@ -607,6 +612,8 @@ mod tests {
             Some(5),
             is_key_diff_storable,
         );
+        // The blocks between the epochs' first blocks are skipped
+        state.db_mut().set_allow_non_contiguous_blocks(true);
         let new_epoch_start = BlockHeight(1);
         let signed_root_key = bridge_pool::get_signed_root_key();
         // the first nonce isn't written for a test skipping pruning
@@ -806,7 +813,9 @@ mod tests {
             .map(|i| (prefix.push(i).unwrap(), *i));
         itertools::assert_equal(iter, expected.clone());
 
-        // Commit genesis state
+        // Commit the next block
+        state.in_mem_mut().block.height =
+            state.in_mem().block.height.next_height();
         state.commit_block().unwrap();
 
         // And check again
@@ -19,6 +19,15 @@
 //!     - `commit_only_data_commitment`
 //!     - `update_epoch_blocks_delay`
 //!   - `conversion_state`: MASP conversion state
+//!   - `diffs_retained_from`: the lowest height whose diffs are retained, only
+//!     written once the diffs have been pruned
+//!   - `hash`: sha256 hashes of the critical top-level keys' values of the same
+//!     name, only written when the integrity checks are enabled
+//!     - `height`
+//!     - `next_epoch_min_start_height`
+//!     - `next_epoch_min_start_time`
+//!     - `update_epoch_blocks_delay`
+//!     - `conversion_state`
 //! - `subspace`: accounts sub-spaces
 //!   - `{address}/{dyn}`: any byte data associated with accounts
 //! - `diffs`: diffs in account subspaces' key-vals modified with `persist_diff
@@ -38,24 +47,36 @@
 //!       - `store`: the tree's store
 //!     - `time`: block time
 //!     - `epoch`: block epoch
+//!     - `gas`: total gas used by the block's txs
 //!     - `address_gen`: established address generator
 //!     - `header`: block's header
 //! - `replay_protection`: hashes of processed tx for replay protection purposes
 //!     - `current/{hash}`: a hash included in the current block
 //!     - `{hash}`: a hash included in previous blocks
+//!
+//! The values of the `subspace`, `diffs` and `rollback` column families may
+//! be encrypted at rest, see [`open_with_encryption`].
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::ThreadId;
+use std::time::{Duration, Instant};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use borsh_ext::BorshSerializeExt;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
 use data_encoding::HEXLOWER;
 use itertools::Either;
 use namada_replay_protection as replay_protection;
+use namada_sdk::address::EstablishedAddressGen;
 use namada_sdk::arith::checked;
 use namada_sdk::collections::{HashMap, HashSet};
 use namada_sdk::eth_bridge::storage::bridge_pool;
@@ -70,29 +91,65 @@ use namada_sdk::state::{
     DbResult as Result, MerkleTreeStoresRead, PatternIterator, PrefixIterator,
     StoreType, DB,
 };
+use namada_sdk::storage::conversion_state::ConversionState;
+use namada_sdk::storage::types::CommitOnlyData;
 use namada_sdk::storage::{
-    BlockHeight, DbColFam, Epoch, Header, Key, KeySeg, BLOCK_CF, DIFFS_CF,
-    REPLAY_PROTECTION_CF, ROLLBACK_CF, STATE_CF, SUBSPACE_CF,
+    BlockHeight, BlockResults, DbColFam, Epoch, Epochs, EthEventsQueue, Header,
+    Key, KeySeg, ANNOTATIONS_CF, ARCHIVE_CF, BLOCK_CF, DIFFS_CF,
+    KEY_SEGMENT_SEPARATOR, REPLAY_PROTECTION_CF, ROLLBACK_CF, STATE_CF,
+    SUBSPACE_CF,
 };
-use namada_sdk::{decode, encode, ethereum_events};
+use namada_sdk::time::DateTimeUtc;
+use namada_sdk::{decode, encode, ethereum_events, ethereum_structs};
+use rand::RngCore;
 use rayon::prelude::*;
 use regex::Regex;
 use rocksdb::{
-    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle,
-    DBCompressionType, Direction, FlushOptions, IteratorMode, Options,
-    ReadOptions, WriteBatch,
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor,
+    CompactionDecision, DBCompactionStyle, DBCompressionType, Direction,
+    FlushOptions, IteratorMode, Options, ReadOptions, WriteBatch, WriteOptions,
 };
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 
 use crate::config::utils::num_of_threads;
+use crate::shell::is_key_diff_storable;
 use crate::storage;
 
-// TODO the DB schema will probably need some kind of versioning
+/// The version of the DB layout. It must be bumped whenever the layout
+/// changes, e.g. when a column family is added.
+///
+/// - 1: added the [`ANNOTATIONS_CF`]
+/// - 2: added the gas of each block to the [`BLOCK_CF`]
+const DB_SCHEMA_VERSION: u64 = 2;
+
+/// The key of the DB schema version in the [`ANNOTATIONS_CF`]. This key is
+/// reserved and cannot be set as an operator annotation.
+const DB_SCHEMA_VERSION_KEY: &str = "db_schema_version";
+
+/// The key of the check value of the subspace encryption key in the
+/// [`ANNOTATIONS_CF`]. This key is reserved and cannot be set as an operator
+/// annotation.
+const SUBSPACE_ENCRYPTION_CHECK_KEY: &str = "subspace_encryption_check";
+
+/// The plaintext of the check value of the subspace encryption key
+const SUBSPACE_ENCRYPTION_CHECK: &[u8] = b"namada subspace encryption";
+
+/// The length of the nonce prefixed to the encrypted values
+const SUBSPACE_NONCE_LEN: usize = 24;
+
+/// The interval between the attempts at opening a locked DB, see
+/// [`OpenOptions::lock_timeout`]
+const DB_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
 /// Env. var to set a number of Rayon global worker threads
 const ENV_VAR_ROCKSDB_COMPACTION_THREADS: &str =
     "NAMADA_ROCKSDB_COMPACTION_THREADS";
 
+/// Env. var to enable integrity checks of the critical state values
+const ENV_VAR_ROCKSDB_STATE_INTEGRITY_CHECKS: &str =
+    "NAMADA_ROCKSDB_STATE_INTEGRITY_CHECKS";
+
 const BLOCK_HEIGHT_KEY: &str = "height";
 const NEXT_EPOCH_MIN_START_HEIGHT_KEY: &str = "next_epoch_min_start_height";
 const NEXT_EPOCH_MIN_START_TIME_KEY: &str = "next_epoch_min_start_time";
@@ -101,21 +158,44 @@ const COMMIT_ONLY_DATA_KEY: &str = "commit_only_data_commitment";
 const CONVERSION_STATE_KEY: &str = "conversion_state";
 const ETHEREUM_HEIGHT_KEY: &str = "ethereum_height";
 const ETH_EVENTS_QUEUE_KEY: &str = "eth_events_queue";
+const DIFFS_RETAINED_FROM_KEY: &str = "diffs_retained_from";
+const PINNED_HEIGHTS_KEY: &str = "pinned_heights";
+const SNAPSHOT_IMPORT_CHUNK_KEY: &str = "snapshot_import_chunk";
+const SNAPSHOT_IMPORT_IN_PROGRESS_KEY: &str = "snapshot_import_in_progress";
+const MIGRATION_IN_PROGRESS_KEY: &str = "migration_in_progress";
 const RESULTS_KEY_PREFIX: &str = "results";
 const PRED_KEY_PREFIX: &str = "pred";
+const STATE_HASH_KEY_PREFIX: &str = "hash";
+
+/// The state keys whose values are verified against their stored hash when
+/// the integrity checks are enabled
+const INTEGRITY_CHECKED_STATE_KEYS: [&str; 5] = [
+    BLOCK_HEIGHT_KEY,
+    NEXT_EPOCH_MIN_START_HEIGHT_KEY,
+    NEXT_EPOCH_MIN_START_TIME_KEY,
+    UPDATE_EPOCH_BLOCKS_DELAY_KEY,
+    CONVERSION_STATE_KEY,
+];
 
 const MERKLE_TREE_ROOT_KEY_SEGMENT: &str = "root";
 const MERKLE_TREE_STORE_KEY_SEGMENT: &str = "store";
 const BLOCK_HEADER_KEY_SEGMENT: &str = "header";
 const BLOCK_TIME_KEY_SEGMENT: &str = "time";
 const EPOCH_KEY_SEGMENT: &str = "epoch";
+const BLOCK_GAS_KEY_SEGMENT: &str = "gas";
 const PRED_EPOCHS_KEY_SEGMENT: &str = "pred_epochs";
 const ADDRESS_GEN_KEY_SEGMENT: &str = "address_gen";
+const ARCHIVE_BLOCK_KEY_SEGMENT: &str = "block";
+const ARCHIVE_CHANGES_KEY_SEGMENT: &str = "changes";
 
 const OLD_DIFF_PREFIX: &str = "old";
 const NEW_DIFF_PREFIX: &str = "new";
 const MAX_CHUNK_SIZE: usize = 10_000_000;
 
+/// The number of heights whose diffs are read together when looking up the
+/// last change of a key, see [`RocksDB::read_subspace_val_and_height`]
+const LAST_CHANGE_LOOKUP_BATCH: usize = 256;
+
 /// RocksDB handle
 #[derive(Debug)]
 pub struct RocksDB {
@@ -123,11 +203,848 @@ pub struct RocksDB {
     inner: rocksdb::DB,
     /// Indicates if read only
     read_only: bool,
+    /// Indicates if the critical state values are stored with and verified
+    /// against their hash
+    state_integrity_checks: bool,
+    /// Indicates if a block may be written at any height rather than only at
+    /// the height following the last committed block
+    allow_non_contiguous_blocks: bool,
+    /// Indicates if the blocks are written to the [`ARCHIVE_CF`], see
+    /// [`OpenOptions::archive`]
+    archive: bool,
+    /// Indicates if the overwrites of the merkle tree stores written per
+    /// epoch are checked, see [`RocksDB::set_check_merkle_store_overwrites`]
+    check_merkle_store_overwrites: bool,
+    /// The overwrites of the merkle tree stores found by the check
+    merkle_store_overwrites: Mutex<Vec<MerkleStoreOverwrite>>,
+    /// The consumer of the changes of the committed blocks, if any, see
+    /// [`RocksDB::set_change_sink`]
+    change_sink: Option<Box<dyn ChangeSink>>,
+    /// How durable the writes of the batches are
+    durability: DurabilityMode,
+    /// When the memtables were last flushed in the
+    /// [`DurabilityMode::Periodic`] mode
+    last_flush: Mutex<Instant>,
+    /// The last polled status of the DB's background work
+    bg_status: Mutex<BackgroundStatus>,
+    /// Counters of the DB operations
+    metrics: MetricsCounters,
+    /// The cipher of the subspace values, if they're encrypted at rest
+    subspace_cipher: Option<SubspaceCipher>,
+    /// Serializes the writes of the batches, see
+    /// [`RocksDB::write_exclusively`]
+    write_lock: Mutex<()>,
+    /// The thread building a batch in [`RocksDB::write_exclusively`] while
+    /// holding the `write_lock`, to refuse its re-entrant writes
+    write_lock_owner: Mutex<Option<ThreadId>>,
+    /// The height of the last committed block, shared with the compaction
+    /// filter of the [`ROLLBACK_CF`], see [`rollback_compaction_filter`]
+    rollback_tip: Arc<AtomicU64>,
+}
+
+/// The cipher of the values of the subspace and of its diffs at rest. The
+/// values are encrypted with XChaCha20 under a random nonce that is prefixed
+/// to the stored bytes.
+#[derive(Clone)]
+pub struct SubspaceCipher {
+    key: [u8; 32],
+}
+
+impl SubspaceCipher {
+    /// Create a cipher from a 32 bytes key
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    /// Encrypt a value under a fresh random nonce
+    fn encrypt(&self, value: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; SUBSPACE_NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let mut encrypted = [&nonce[..], value].concat();
+        XChaCha20::new(&self.key.into(), &nonce.into())
+            .apply_keystream(&mut encrypted[SUBSPACE_NONCE_LEN..]);
+        encrypted
+    }
+
+    /// Decrypt a value prefixed with its nonce
+    fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
+        if encrypted.len() < SUBSPACE_NONCE_LEN {
+            return Err(Error::DBError(
+                "An encrypted subspace value is shorter than its nonce"
+                    .to_string(),
+            ));
+        }
+        let (nonce, value) = encrypted.split_at(SUBSPACE_NONCE_LEN);
+        let nonce: [u8; SUBSPACE_NONCE_LEN] =
+            nonce.try_into().expect("The nonce length is checked above");
+        let mut value = value.to_vec();
+        XChaCha20::new(&self.key.into(), &nonce.into())
+            .apply_keystream(&mut value);
+        Ok(value)
+    }
+}
+
+impl std::fmt::Debug for SubspaceCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubspaceCipher").finish_non_exhaustive()
+    }
+}
+
+/// Counters of the DB operations. They are only incremented when the
+/// `storage-metrics` feature is enabled.
+#[derive(Debug, Default)]
+struct MetricsCounters {
+    subspace_reads: AtomicU64,
+    subspace_writes: AtomicU64,
+    diff_writes: AtomicU64,
+    rp_writes: AtomicU64,
+}
+
+/// A snapshot of the counters of the DB operations, see
+/// [`RocksDB::metrics_snapshot`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DbMetrics {
+    /// The number of subspace values read
+    pub subspace_reads: u64,
+    /// The number of subspace values written to a batch
+    pub subspace_writes: u64,
+    /// The number of subspace diffs written to a batch
+    pub diff_writes: u64,
+    /// The number of replay protection entries written to a batch
+    pub rp_writes: u64,
+}
+
+/// The values of the last block found in the DB, see
+/// [`RocksDB::diagnose_last_block`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LastBlockDiagnosis {
+    /// The height of the last committed block, if any
+    pub height: Option<BlockHeight>,
+    /// The keys of the last block's values that are present
+    pub present: Vec<String>,
+    /// The keys of the last block's values that are missing
+    pub missing: Vec<String>,
+}
+
+impl LastBlockDiagnosis {
+    /// Check if the last block has only been partially written
+    pub fn is_torn(&self) -> bool {
+        self.height.is_some() && !self.missing.is_empty()
+    }
+}
+
+/// A summary of the readiness of the DB, e.g. for the liveness and readiness
+/// probes of an orchestrator, see [`RocksDB::health`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DbHealth {
+    /// The overall status of the DB. A [`DbStatus::Degraded`] DB should be
+    /// reported as not ready by a readiness probe.
+    pub status: DbStatus,
+    /// The column families that should have been opened but are missing
+    pub missing_column_families: Vec<String>,
+    /// The height of the last committed block, if any
+    pub last_height: Option<BlockHeight>,
+    /// The error of a failed read of the DB's state, if any
+    pub read_error: Option<String>,
+    /// The description of the last background error reported by the DB, if
+    /// any
+    pub bg_error: Option<String>,
+    /// Whether a snapshot import has been started and not completed, see
+    /// [`RocksDB::import_snapshot`]
+    pub snapshot_import_in_progress: bool,
+    /// Whether an offline migration has been started and not completed, see
+    /// [`RocksDB::mark_migration_in_progress`]
+    pub migration_in_progress: bool,
+}
+
+/// The overall status of the DB in a [`DbHealth`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DbStatus {
+    /// The DB can serve the node
+    Healthy,
+    /// The DB is missing some of its column families, its state can't be
+    /// read, a background error has been reported or a snapshot import or a
+    /// migration is in progress
+    Degraded,
+}
+
+/// The status of RocksDB's background work (flushes and compactions), polled
+/// from the DB properties
+#[derive(Debug, Default)]
+struct BackgroundStatus {
+    /// The number of background errors reported by the DB
+    errors: u64,
+    /// Whether the writes were stalled by the last poll
+    write_stalled: bool,
+    /// The description of the last background error
+    last_error: Option<String>,
+}
+
+/// Marks the current thread as the owner of the write lock of a
+/// [`RocksDB::write_exclusively`], until dropped, however its `build` ends
+struct WriteLockOwner<'a>(&'a Mutex<Option<ThreadId>>);
+
+impl<'a> WriteLockOwner<'a> {
+    fn set(owner: &'a Mutex<Option<ThreadId>>) -> Self {
+        *owner.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(std::thread::current().id());
+        Self(owner)
+    }
+}
+
+impl Drop for WriteLockOwner<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
 }
 
 /// DB Handle for batch writes.
 #[derive(Default)]
-pub struct RocksDBWriteBatch(WriteBatch);
+pub struct RocksDBWriteBatch {
+    /// The RocksDB batch
+    inner: WriteBatch,
+    /// The net size change in bytes of the values written to and deleted
+    /// from the batch
+    size_diff: i64,
+    /// The height of the block written in the batch, if any
+    block_height: Option<BlockHeight>,
+    /// The subspace keys whose diffs are persisted in the batch, with
+    /// whether they have an old and a new value. Only tracked when a
+    /// [`ChangeSink`] is registered.
+    changed_keys: BTreeMap<Key, (bool, bool)>,
+}
+
+impl RocksDBWriteBatch {
+    /// Get the net size change in bytes of the values written or deleted
+    /// with `batch_write_subspace_val`, `batch_delete_subspace_val` and
+    /// `overwrite_entry` since the batch was created.
+    pub fn size_diff(&self) -> i64 {
+        self.size_diff
+    }
+}
+
+/// A write batch for the bulk operations (e.g. migrations, imports or
+/// restores) that are too large to be written in a single batch. It's
+/// written in chunks of about `max_ops` operations, each written once full
+/// and before the next operation is added to the batch, and the last one
+/// with [`ChunkedWriteBatch::finish`].
+///
+/// The chunks are written atomically one by one, but not all together, so a
+/// failure or a crash in the middle of the operation leaves only a part of it
+/// written. It must therefore only be used for the operations that can be
+/// restarted from their source, and never for the block commits.
+pub struct ChunkedWriteBatch<'db> {
+    db: &'db RocksDB,
+    batch: RocksDBWriteBatch,
+    max_ops: usize,
+    /// The number of chunks written so far
+    written_chunks: usize,
+    /// The net size change in bytes of the chunks written so far
+    size_diff: i64,
+}
+
+impl ChunkedWriteBatch<'_> {
+    /// Get the batch to which the next operation is added. The current chunk
+    /// is written first if it already has `max_ops` operations. The chunk
+    /// isn't split within an operation, so it can exceed `max_ops` by the
+    /// number of writes of a single operation (e.g. a subspace write with its
+    /// diffs).
+    pub fn batch(&mut self) -> Result<&mut RocksDBWriteBatch> {
+        if self.batch.inner.len() >= self.max_ops {
+            self.write_chunk()?;
+        }
+        Ok(&mut self.batch)
+    }
+
+    /// Get the number of chunks written so far
+    pub fn written_chunks(&self) -> usize {
+        self.written_chunks
+    }
+
+    /// Write the last chunk, if not empty. Returns the total number of
+    /// written chunks and the net size change in bytes of all of them.
+    pub fn finish(mut self) -> Result<(usize, i64)> {
+        if !self.batch.inner.is_empty() {
+            self.write_chunk()?;
+        }
+        Ok((self.written_chunks, self.size_diff))
+    }
+
+    fn write_chunk(&mut self) -> Result<()> {
+        let chunk = std::mem::take(&mut self.batch);
+        let size_diff = chunk.size_diff();
+        self.db.exec_batch(chunk)?;
+        checked!(self.written_chunks += 1)?;
+        checked!(self.size_diff += size_diff)?;
+        Ok(())
+    }
+}
+
+/// An iterator of the decoded results of the committed blocks, in ascending
+/// order of their heights, see [`RocksDB::iter_block_results`].
+pub struct BlockResultsIter<'a> {
+    iter: PersistentPrefixIterator<'a>,
+    skip_corrupt: bool,
+    corrupt_heights: Vec<BlockHeight>,
+}
+
+impl BlockResultsIter<'_> {
+    /// The heights of the results that couldn't be decoded and have been
+    /// skipped so far
+    pub fn corrupt_heights(&self) -> &[BlockHeight] {
+        &self.corrupt_heights
+    }
+}
+
+impl Iterator for BlockResultsIter<'_> {
+    type Item = Result<(BlockHeight, BlockResults)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, val, _gas) = self.iter.next()?;
+            let height = match u64::parse(key) {
+                Ok(height) => BlockHeight(height),
+                Err(e) => return Some(Err(Error::KeyError(e))),
+            };
+            match decode(val) {
+                Ok(results) => return Some(Ok((height, results))),
+                Err(e) if self.skip_corrupt => {
+                    tracing::warn!(
+                        %height,
+                        "Skipping the corrupt results of a block: {e}"
+                    );
+                    self.corrupt_heights.push(height);
+                }
+                Err(e) => return Some(Err(Error::CodingError(e))),
+            }
+        }
+    }
+}
+
+/// A bloom filter of the replay protection hashes, as exported by
+/// [`RocksDB::replay_protection_bloom`]. A light client can use it for a
+/// cheap check of whether a tx has been applied: a tx that's not in the
+/// filter definitely hasn't been, while one that is may have been and must be
+/// checked against the full set.
+#[derive(Debug, Clone, PartialEq, BorshSerialize, BorshDeserialize)]
+pub struct ReplayProtectionBloom {
+    /// The number of hashes added to the filter
+    pub num_items: u64,
+    /// The number of bits set in the filter for each hash
+    pub num_hashes: u32,
+    /// The bits of the filter
+    pub bits: Vec<u8>,
+}
+
+impl ReplayProtectionBloom {
+    /// Make an empty filter sized for the given number of hashes and target
+    /// false-positive rate, which must be strictly between 0 and 1
+    pub fn new(num_items: u64, fp_rate: f64) -> Result<Self> {
+        if !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(Error::DBError(format!(
+                "The false-positive rate of a bloom filter must be strictly \
+                 between 0 and 1, got {fp_rate}"
+            )));
+        }
+        // The optimal sizing for `n` items at the false-positive rate `p`:
+        // `m = -n * ln(p) / ln(2)^2` bits and `k = m / n * ln(2)` hashes
+        let n = num_items.max(1) as f64;
+        let ln_2 = std::f64::consts::LN_2;
+        let num_bits = (-n * fp_rate.ln() / (ln_2 * ln_2)).ceil().max(8.0);
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let (num_bytes, num_hashes) = (
+            (num_bits / 8.0).ceil() as usize,
+            (num_bits / n * ln_2).round().max(1.0) as u32,
+        );
+        Ok(Self {
+            num_items,
+            num_hashes,
+            bits: vec![0; num_bytes],
+        })
+    }
+
+    /// Add a tx hash to the filter
+    pub fn insert(&mut self, hash: &Hash) {
+        for (byte, mask) in self.bit_positions(hash) {
+            self.bits[byte] |= mask;
+        }
+    }
+
+    /// Check whether the filter may contain the given tx hash. A `false` is
+    /// definite, while a `true` can be a false positive.
+    pub fn may_contain(&self, hash: &Hash) -> bool {
+        self.bit_positions(hash)
+            .all(|(byte, mask)| self.bits[byte] & mask != 0)
+    }
+
+    /// The byte index and bit mask of each bit of the given hash, derived by
+    /// double hashing from the hash bytes, which are already uniformly
+    /// distributed
+    fn bit_positions(&self, hash: &Hash) -> impl Iterator<Item = (usize, u8)> {
+        let word = |bytes: &[u8]| {
+            u64::from_le_bytes(
+                bytes.try_into().expect("A hash has more than 16 bytes"),
+            )
+        };
+        let (h1, h2) = (word(&hash.0[..8]), word(&hash.0[8..16]));
+        let num_bits = (self.bits.len() as u64).wrapping_mul(8);
+        (0..u64::from(self.num_hashes)).filter_map(move |i| {
+            let bit =
+                h1.wrapping_add(i.wrapping_mul(h2)).checked_rem(num_bits)?;
+            let byte = usize::try_from(bit / 8).ok()?;
+            let mask = 1_u8.rotate_left(u32::try_from(bit % 8).ok()?);
+            Some((byte, mask))
+        })
+    }
+}
+
+/// The counts of the changes made by [`RocksDB::rollback`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RollbackStats {
+    /// The subspace keys whose value was restored to the one of the previous
+    /// height
+    pub subspace_keys_restored: u64,
+    /// The subspace keys created at the rolled back height, which were
+    /// deleted
+    pub subspace_keys_deleted: u64,
+    /// The diffs keys of the rolled back height that were deleted
+    pub diff_keys_deleted: u64,
+    /// The block keys of the rolled back height that were deleted, including
+    /// its results
+    pub block_keys_deleted: u64,
+    /// The current replay protection entries, i.e. the ones of the rolled
+    /// back height, that were removed
+    pub replay_protection_entries_removed: u64,
+}
+
+/// How much history is retained in the DB when pruning it with
+/// [`RocksDB::prune_to_policy`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// The number of the most recent blocks whose diffs are kept
+    pub keep_diffs_blocks: u64,
+    /// The number of the most recent epochs whose merkle tree stores are
+    /// kept
+    pub keep_merkle_epochs: u64,
+    /// The number of the most recent blocks whose header is kept. The
+    /// headers are lightweight, so they may be kept for longer than the
+    /// diffs, e.g. to serve light clients.
+    pub keep_headers_blocks: u64,
+}
+
+/// The number of entries deleted by [`RocksDB::prune_to_policy`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneReport {
+    /// The number of deleted diffs
+    pub diffs: u64,
+    /// The number of deleted non-persisted diffs kept for rollback
+    pub rollback_entries: u64,
+    /// The number of deleted merkle tree stores
+    pub merkle_stores: u64,
+    /// The number of deleted block headers
+    pub headers: u64,
+}
+
+/// The result of [`RocksDB::prune_and_compact`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceReport {
+    /// The number of entries deleted by the pruning
+    pub pruned: PruneReport,
+    /// The total size in bytes of the SST files of the pruned column
+    /// families before the pruning
+    pub before: u64,
+    /// The total size in bytes of the SST files of the pruned column
+    /// families after the compaction
+    pub after: u64,
+}
+
+impl SpaceReport {
+    /// The number of bytes reclaimed by the pruning
+    pub fn reclaimed(&self) -> u64 {
+        self.before.saturating_sub(self.after)
+    }
+}
+
+/// A merkle tree store written per epoch that was written again within its
+/// epoch, found by the check enabled with
+/// [`RocksDB::set_check_merkle_store_overwrites`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MerkleStoreOverwrite {
+    /// The type of the overwritten store
+    pub store_type: StoreType,
+    /// The epoch of the overwritten store
+    pub epoch: Epoch,
+    /// The height of the block that overwrote the store
+    pub height: BlockHeight,
+}
+
+/// A block read back from the archive with [`RocksDB::replay_block`]
+#[derive(Debug, Clone)]
+pub struct ArchivedBlock {
+    /// Header of the block
+    pub header: Option<Header>,
+    /// Time of the block
+    pub time: DateTimeUtc,
+    /// Epoch of the block
+    pub epoch: Epoch,
+    /// Results of applying transactions
+    pub results: BlockResults,
+    /// The subspace keys changed in the block with their new value, or
+    /// `None` if they were deleted. The values are the decrypted ones.
+    pub changes: BTreeMap<Key, Option<Vec<u8>>>,
+}
+
+/// The subspace values repaired by [`RocksDB::repair_orphaned_diffs`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The keys whose subspace value was missing or different from the new
+    /// value recorded in their diff, and has been filled in from it
+    pub filled: Vec<String>,
+    /// The keys whose subspace value was still present although their diff
+    /// recorded a deletion, and has been removed
+    pub removed: Vec<String>,
+}
+
+/// A diff that is inconsistent with the rest of the DB, found by
+/// [`RocksDB::validate_diffs_at`]. The values are the decrypted ones and
+/// `None` stands for a key without value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffInconsistency {
+    /// The subspace value of a key that wasn't changed after the height
+    /// doesn't match the new value recorded in its diff, or is present
+    /// although its diff recorded a deletion
+    Subspace {
+        key: String,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+    /// The old value recorded in the diff of a key doesn't match its value at
+    /// the last height it was changed at before
+    OldValue {
+        key: String,
+        expected: Option<Vec<u8>>,
+        actual: Option<Vec<u8>>,
+    },
+}
+
+/// How a subspace key changed in a block, see [`RocksDB::changed_keys`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The key had no value before the block
+    Created,
+    /// The value of the key was overwritten in the block
+    Updated,
+    /// The key was deleted in the block
+    Deleted,
+}
+
+impl ChangeKind {
+    /// The kind of the change of a key whose diffs at a height have an old
+    /// and/or a new value
+    fn from_diffs(has_old: bool, has_new: bool) -> Self {
+        match (has_old, has_new) {
+            (true, true) => ChangeKind::Updated,
+            (true, false) => ChangeKind::Deleted,
+            (false, _) => ChangeKind::Created,
+        }
+    }
+}
+
+/// A consumer of the subspace changes of the committed blocks, e.g. to push
+/// them to a change-data-capture pipeline without scanning the diffs, see
+/// [`RocksDB::set_change_sink`]
+pub trait ChangeSink: std::fmt::Debug + Send + Sync {
+    /// Called once the batch with the block at the given height has been
+    /// written, with the keys changed in the batch sorted by key, as
+    /// [`RocksDB::changed_keys`] lists them. It's called while the writes of
+    /// the DB are locked, so it should hand the changes off quickly.
+    fn on_block(&self, height: BlockHeight, changes: Vec<(Key, ChangeKind)>);
+}
+
+/// A key whose entry differs between two DBs, found by
+/// [`RocksDB::diff_against`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyDivergence {
+    /// The key is only in this DB
+    OnlyInSelf(String),
+    /// The key is only in the other DB
+    OnlyInOther(String),
+    /// The key's value differs between the DBs
+    ValueMismatch(String),
+}
+
+/// The maximum number of discrepancies listed by [`RocksDB::verify_dump`]
+const MAX_DUMP_DISCREPANCIES: usize = 100;
+
+/// The default size of the buffer of the dump files, large enough to avoid
+/// excessive syscalls when dumping a large DB
+pub const DEFAULT_DUMP_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The number of entries after which the dumps flush their buffer, such that
+/// a dump that fails midway leaves a partial file of whole lines
+const DUMP_FLUSH_INTERVAL: usize = 100_000;
+
+/// The magic bytes at the start of the binary dumps
+const BINARY_DUMP_MAGIC: &[u8; 8] = b"NAMDBDMP";
+
+/// The column families of the records of the binary dumps, identified by
+/// their index
+const BINARY_DUMP_CFS: [&str; 4] =
+    [SUBSPACE_CF, DIFFS_CF, BLOCK_CF, REPLAY_PROTECTION_CF];
+
+/// The format of the dumps written by [`RocksDB::dump_block`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// A TOML file of the hex encoded values
+    #[default]
+    Toml,
+    /// A compact file of length-prefixed raw records that can be restored
+    /// with [`RocksDB::restore_from_dump`].
+    ///
+    /// The file starts with a header of the magic bytes `NAMDBDMP`, the DB
+    /// schema version and the height of the dump, both as `u64` little
+    /// endian. It's followed by the records, each made of the id of its
+    /// column family as a byte (0 for the subspace, 1 for the diffs, 2 for
+    /// the block data and 3 for the replay protection), then the key and the
+    /// value, each prefixed with its length as a `u32` little endian.
+    Binary,
+}
+
+impl DumpFormat {
+    /// The extension of the dump files in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Toml => "toml",
+            Self::Binary => "bin",
+        }
+    }
+
+    /// Encode an entry of the given column family as a record of the dump
+    fn encode_record(&self, cf: &str, key: &str, value: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Toml => {
+                let val = HEXLOWER.encode(value);
+                format!("\"{key}\" = \"{val}\"\n").into_bytes()
+            }
+            Self::Binary => {
+                let cf_id = BINARY_DUMP_CFS
+                    .iter()
+                    .position(|dump_cf| *dump_cf == cf)
+                    .and_then(|id| u8::try_from(id).ok())
+                    .expect("The column family should be dumpable");
+                let len = |bytes: &[u8]| {
+                    u32::try_from(bytes.len())
+                        .expect("The dumped entry should fit a record")
+                        .to_le_bytes()
+                };
+                let mut record = vec![cf_id];
+                record.extend_from_slice(&len(key.as_bytes()));
+                record.extend_from_slice(key.as_bytes());
+                record.extend_from_slice(&len(value));
+                record.extend_from_slice(value);
+                record
+            }
+        }
+    }
+}
+
+/// A reader of the records of a binary dump, see [`DumpFormat::Binary`]
+struct BinaryDumpReader {
+    reader: BufReader<File>,
+    /// The height of the dump, read from its header
+    height: BlockHeight,
+}
+
+impl BinaryDumpReader {
+    /// Open a binary dump and read its header
+    fn open(dump_path: &Path) -> Result<Self> {
+        let file = File::open(dump_path).map_err(|e| {
+            Error::DBError(format!(
+                "Unable to open the dump {}: {e}",
+                dump_path.to_string_lossy()
+            ))
+        })?;
+        let mut reader = BufReader::new(file);
+        let mut magic = [0_u8; 8];
+        read_dump_bytes(&mut reader, &mut magic)?;
+        if &magic != BINARY_DUMP_MAGIC {
+            return Err(Error::DBError(format!(
+                "The dump {} is not a binary dump",
+                dump_path.to_string_lossy()
+            )));
+        }
+        let schema_version = read_dump_u64(&mut reader)?;
+        if schema_version != DB_SCHEMA_VERSION {
+            return Err(Error::DBError(format!(
+                "The dump's schema version {schema_version} doesn't match the \
+                 DB schema version {DB_SCHEMA_VERSION}"
+            )));
+        }
+        let height = BlockHeight(read_dump_u64(&mut reader)?);
+        Ok(Self { reader, height })
+    }
+
+    /// Read the next record, if any, as its column family, key and value
+    fn read_record(
+        &mut self,
+    ) -> Result<Option<(&'static str, String, Vec<u8>)>> {
+        let mut cf_id = [0_u8; 1];
+        match self.reader.read_exact(&mut cf_id) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => {
+                return Err(Error::DBError(format!(
+                    "Unable to read the dump: {e}"
+                )));
+            }
+        }
+        let cf = BINARY_DUMP_CFS
+            .get(usize::from(cf_id[0]))
+            .copied()
+            .ok_or_else(|| {
+                Error::DBError(format!(
+                    "Unknown column family {} in the dump",
+                    cf_id[0]
+                ))
+            })?;
+        let key = String::from_utf8(read_dump_chunk(&mut self.reader)?)
+            .map_err(|e| {
+                Error::DBError(format!("Malformed key in the dump: {e}"))
+            })?;
+        let value = read_dump_chunk(&mut self.reader)?;
+        Ok(Some((cf, key, value)))
+    }
+}
+
+impl Iterator for BinaryDumpReader {
+    type Item = Result<(&'static str, String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Fill the buffer with the next bytes of a binary dump
+fn read_dump_bytes(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| Error::DBError(format!("Unable to read the dump: {e}")))
+}
+
+/// Read the next `u64` of a binary dump
+fn read_dump_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0_u8; 8];
+    read_dump_bytes(reader, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read the next length-prefixed chunk of a binary dump
+fn read_dump_chunk(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0_u8; 4];
+    read_dump_bytes(reader, &mut len)?;
+    let len = usize::try_from(u32::from_le_bytes(len)).map_err(|e| {
+        Error::DBError(format!("Malformed record in the dump: {e}"))
+    })?;
+    let mut chunk = vec![0_u8; len];
+    read_dump_bytes(reader, &mut chunk)?;
+    Ok(chunk)
+}
+
+/// A discrepancy between a dump and the DB found by
+/// [`RocksDB::verify_dump`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpDiscrepancy {
+    /// The key's value in the dump differs from the value in the DB
+    Mismatch(String),
+    /// The key is in the DB but missing from the dump
+    Missing(String),
+    /// The key is in the dump but not in the DB
+    Extra(String),
+}
+
+/// The result of [`RocksDB::verify_dump`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DumpVerification {
+    /// The height of the dump, recorded in its file name
+    pub height: BlockHeight,
+    /// The number of keys whose value matches the DB
+    pub matching: u64,
+    /// The number of keys whose value differs from the DB
+    pub mismatched: u64,
+    /// The number of keys of the DB missing from the dump
+    pub missing: u64,
+    /// The number of keys of the dump that are not in the DB
+    pub extra: u64,
+    /// The first discrepancies found, up to 100
+    pub discrepancies: Vec<DumpDiscrepancy>,
+}
+
+impl DumpVerification {
+    /// Check if the dump faithfully represents the DB
+    pub fn is_faithful(&self) -> bool {
+        self.mismatched == 0 && self.missing == 0 && self.extra == 0
+    }
+
+    fn record(&mut self, discrepancy: DumpDiscrepancy) -> Result<()> {
+        match &discrepancy {
+            DumpDiscrepancy::Mismatch(_) => checked!(self.mismatched += 1)?,
+            DumpDiscrepancy::Missing(_) => checked!(self.missing += 1)?,
+            DumpDiscrepancy::Extra(_) => checked!(self.extra += 1)?,
+        }
+        if self.discrepancies.len() < MAX_DUMP_DISCREPANCIES {
+            self.discrepancies.push(discrepancy);
+        }
+        Ok(())
+    }
+}
+
+/// Read the height of a dump or of a delta, recorded at the end of its file
+/// name
+fn dump_height(dump_path: &Path) -> Result<BlockHeight> {
+    dump_path
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .and_then(|stem| stem.rsplit_once('_'))
+        .and_then(|(_, height)| height.parse::<u64>().ok())
+        .map(BlockHeight)
+        .ok_or_else(|| {
+            Error::DBError(format!(
+                "The height of the dump {} is not recorded in its name",
+                dump_path.to_string_lossy()
+            ))
+        })
+}
+
+/// Read the entries of a TOML dump written by [`RocksDB::dump_block`]
+fn read_toml_dump(
+    dump_path: &Path,
+    dumped: &mut HashMap<String, Vec<u8>>,
+) -> Result<()> {
+    let file = File::open(dump_path).map_err(|e| {
+        Error::DBError(format!(
+            "Unable to open the dump {}: {e}",
+            dump_path.to_string_lossy()
+        ))
+    })?;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| {
+            Error::DBError(format!("Unable to read the dump: {e}"))
+        })?;
+        let Some((key, val)) = line
+            .strip_prefix('"')
+            .and_then(|line| line.rsplit_once("\" = \""))
+            .and_then(|(key, val)| Some((key, val.strip_suffix('"')?)))
+        else {
+            return Err(Error::DBError(format!("Malformed dump line: {line}")));
+        };
+        let val = HEXLOWER.decode(val.as_bytes()).map_err(|e| {
+            Error::DBError(format!("Malformed dump value of {key}: {e}"))
+        })?;
+        dumped.insert(key.to_owned(), val);
+    }
+    Ok(())
+}
 
 /// Open RocksDB for the DB
 pub fn open(
@@ -135,6 +1052,158 @@ pub fn open(
     read_only: bool,
     cache: Option<&rocksdb::Cache>,
 ) -> Result<RocksDB> {
+    open_with_encryption(path, read_only, cache, None)
+}
+
+/// Open RocksDB for the DB, with the values of the subspace and of its diffs
+/// encrypted at rest if a cipher is given.
+///
+/// The encryption is only enabled for new DBs and the DB then cannot be
+/// opened without the same key. The values are decrypted when they're read,
+/// so the merkle tree, which is built from the values written by the
+/// transactions, commits to the plaintext values and its root hashes are the
+/// same as without the encryption. The keys are not encrypted and neither are
+/// the values copied into snapshots, which stay encrypted.
+pub fn open_with_encryption(
+    path: impl AsRef<Path>,
+    read_only: bool,
+    cache: Option<&rocksdb::Cache>,
+    subspace_cipher: Option<SubspaceCipher>,
+) -> Result<RocksDB> {
+    let options = OpenOptions {
+        read_only,
+        ..OpenOptions::default()
+    };
+    open_with_options(path, options, cache, subspace_cipher)
+}
+
+/// The options of opening the DB, see [`open_with_options`]
+#[derive(Debug, Clone)]
+pub struct OpenOptions {
+    /// Open the DB in read-only mode
+    pub read_only: bool,
+    /// Flush the memtables of all the column families together. Enabled by
+    /// default.
+    ///
+    /// Disabling it lowers the latency of the flushes, but a crash may then
+    /// leave the column families at different points, e.g. with a block
+    /// whose subspace writes have not been persisted. This is unsafe for a
+    /// node taking part in consensus, so it can only be disabled for a
+    /// read-only DB, e.g. a disposable query replica.
+    pub atomic_flush: bool,
+    /// How long to keep retrying to open the DB while its lock is held by
+    /// another process, e.g. a node that is still shutting down. When not
+    /// set, a locked DB fails to open right away with [`Error::DbLocked`].
+    /// A read-only DB doesn't take the lock.
+    pub lock_timeout: Option<Duration>,
+    /// Archive every committed block for replay, see
+    /// [`RocksDB::replay_block`]. Disabled by default.
+    ///
+    /// The archive keeps the header, time, epoch and results of the blocks
+    /// along with the new values of the subspace keys changed in them, both
+    /// persisted or not, independently of the pruning of the diffs. Its size
+    /// therefore grows with the whole history of the chain, by about the
+    /// size of the new diffs of every block, unless it's pruned with
+    /// [`RocksDB::prune_archive`]. The archived blocks stay readable when a
+    /// DB is reopened without the archive, but no new block is archived.
+    pub archive: bool,
+    /// How durable the writes of the block commits and of the other batches
+    /// are, see [`DurabilityMode`]. Defaults to
+    /// [`DurabilityMode::WalDefault`].
+    pub durability: DurabilityMode,
+}
+
+/// How durable the writes of the batches are, trading the throughput of the
+/// block commits against how much may be lost by a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityMode {
+    /// Write to the WAL and sync it to disk with every batch. A committed
+    /// block survives a crash of the process and of the machine, e.g. a
+    /// power loss, at the cost of a disk sync per commit.
+    Sync,
+    /// Write to the WAL without syncing it. A committed block survives a
+    /// crash of the process, but the WAL writes that the OS hasn't synced
+    /// yet are lost by a crash of the machine, after which the DB recovers
+    /// to the last synced write.
+    #[default]
+    WalDefault,
+    /// Write without the WAL and flush the memtables to disk at most `every`
+    /// interval, once a batch is written. This gives the highest
+    /// throughput, but a crash of the process or of the machine loses all
+    /// the writes since the last completed flush. Thanks to the atomic
+    /// flush, the DB then restarts from a consistent earlier block and the
+    /// lost blocks have to be applied again, e.g. replayed by CometBFT from
+    /// its own block store on the handshake. A clean shutdown flushes the
+    /// memtables, so nothing is lost.
+    Periodic { every: Duration },
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            atomic_flush: true,
+            lock_timeout: None,
+            archive: false,
+            durability: DurabilityMode::default(),
+        }
+    }
+}
+
+/// Open RocksDB for the DB with the given options, and with the values of
+/// the subspace and of its diffs encrypted at rest if a cipher is given (see
+/// [`open_with_encryption`]).
+pub fn open_with_options(
+    path: impl AsRef<Path>,
+    options: OpenOptions,
+    cache: Option<&rocksdb::Cache>,
+    subspace_cipher: Option<SubspaceCipher>,
+) -> Result<RocksDB> {
+    let start = Instant::now();
+    loop {
+        match open_once(
+            path.as_ref(),
+            options.clone(),
+            cache,
+            subspace_cipher.clone(),
+        ) {
+            Err(Error::DbLocked { path })
+                if options
+                    .lock_timeout
+                    .is_some_and(|timeout| start.elapsed() < timeout) =>
+            {
+                tracing::info!(
+                    "The DB at {path} is locked by another process, retrying \
+                     to open it"
+                );
+                std::thread::sleep(DB_LOCK_RETRY_INTERVAL);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// A single attempt at opening the DB, see [`open_with_options`]
+fn open_once(
+    path: &Path,
+    options: OpenOptions,
+    cache: Option<&rocksdb::Cache>,
+    subspace_cipher: Option<SubspaceCipher>,
+) -> Result<RocksDB> {
+    let OpenOptions {
+        read_only,
+        atomic_flush,
+        lock_timeout: _,
+        archive,
+        durability,
+    } = options;
+    if !atomic_flush && !read_only {
+        return Err(Error::DBError(
+            "The atomic flush can only be disabled for a read-only DB"
+                .to_string(),
+        ));
+    }
+
     let logical_cores = num_cpus::get();
     let compaction_threads = i32::try_from(num_of_threads(
         ENV_VAR_ROCKSDB_COMPACTION_THREADS,
@@ -161,18 +1230,22 @@ pub fn open(
 
     db_opts.create_missing_column_families(true);
     db_opts.create_if_missing(true);
-    db_opts.set_atomic_flush(true);
+    db_opts.set_atomic_flush(atomic_flush);
 
     let mut cfs = Vec::new();
-    let mut table_opts = BlockBasedOptions::default();
-    table_opts.set_block_size(16 * 1024);
-    table_opts.set_cache_index_and_filter_blocks(true);
-    table_opts.set_pin_l0_filter_and_index_blocks_in_cache(true);
-    if let Some(cache) = cache {
-        table_opts.set_block_cache(cache);
-    }
-    // latest format versions https://github.com/facebook/rocksdb/blob/d1c510baecc1aef758f91f786c4fbee3bc847a63/include/rocksdb/table.h#L394
-    table_opts.set_format_version(5);
+    let make_table_opts = || {
+        let mut table_opts = BlockBasedOptions::default();
+        table_opts.set_block_size(16 * 1024);
+        table_opts.set_cache_index_and_filter_blocks(true);
+        table_opts.set_pin_l0_filter_and_index_blocks_in_cache(true);
+        if let Some(cache) = cache {
+            table_opts.set_block_cache(cache);
+        }
+        // latest format versions https://github.com/facebook/rocksdb/blob/d1c510baecc1aef758f91f786c4fbee3bc847a63/include/rocksdb/table.h#L394
+        table_opts.set_format_version(5);
+        table_opts
+    };
+    let table_opts = make_table_opts();
 
     // for subspace (read/update-intensive)
     let mut subspace_cf_opts = Options::default();
@@ -198,6 +1271,11 @@ pub fn open(
     rollback_cf_opts.set_compression_options(0, 0, 0, 1024 * 1024);
     rollback_cf_opts.set_compaction_style(DBCompactionStyle::Level);
     rollback_cf_opts.set_block_based_table_factory(&table_opts);
+    let rollback_tip = Arc::new(AtomicU64::new(0));
+    rollback_cf_opts.set_compaction_filter(
+        "rollback_expiry",
+        rollback_compaction_filter(rollback_tip.clone()),
+    );
     cfs.push(ColumnFamilyDescriptor::new(ROLLBACK_CF, rollback_cf_opts));
 
     // for the ledger state (update-intensive)
@@ -223,44 +1301,368 @@ pub fn open(
     replay_protection_cf_opts.set_level_compaction_dynamic_level_bytes(true);
     // Prioritize minimizing read amplification
     replay_protection_cf_opts.set_compaction_style(DBCompactionStyle::Level);
-    replay_protection_cf_opts.set_block_based_table_factory(&table_opts);
+    // Most lookups are for txs that are not yet applied, so a bloom filter
+    // lets them skip reading the tables. This only applies to the tables
+    // written from now on.
+    let mut replay_protection_table_opts = make_table_opts();
+    replay_protection_table_opts.set_bloom_filter(10.0, false);
+    replay_protection_cf_opts
+        .set_block_based_table_factory(&replay_protection_table_opts);
     cfs.push(ColumnFamilyDescriptor::new(
         REPLAY_PROTECTION_CF,
         replay_protection_cf_opts,
     ));
-    Ok(if read_only {
+
+    // for operator annotations (rarely accessed). DBs created before this
+    // column family existed get it on open, as missing column families are
+    // created. This is not possible in a read-only DB, so there we only open
+    // the annotations if they've been created already.
+    let open_annotations = !read_only
+        || rocksdb::DB::list_cf(&db_opts, &path)
+            .unwrap_or_default()
+            .iter()
+            .any(|cf| cf == ANNOTATIONS_CF);
+    if open_annotations {
+        let mut annotations_cf_opts = Options::default();
+        annotations_cf_opts.set_block_based_table_factory(&table_opts);
+        cfs.push(ColumnFamilyDescriptor::new(
+            ANNOTATIONS_CF,
+            annotations_cf_opts,
+        ));
+    }
+
+    // for the block archive (insert-intensive). Like the annotations, it's
+    // opened whenever it exists, as all the column families of a DB must be
+    // opened, but it's only created when the archive is enabled.
+    let archive = archive && !read_only;
+    let open_archive = archive
+        || rocksdb::DB::list_cf(&db_opts, &path)
+            .unwrap_or_default()
+            .iter()
+            .any(|cf| cf == ARCHIVE_CF);
+    if open_archive {
+        let mut archive_cf_opts = Options::default();
+        archive_cf_opts.set_compression_type(DBCompressionType::Zstd);
+        archive_cf_opts.set_compression_options(0, 0, 0, 1024 * 1024);
+        archive_cf_opts.set_compaction_style(DBCompactionStyle::Universal);
+        archive_cf_opts.set_block_based_table_factory(&table_opts);
+        cfs.push(ColumnFamilyDescriptor::new(ARCHIVE_CF, archive_cf_opts));
+    }
+
+    let state_integrity_checks =
+        match std::env::var(ENV_VAR_ROCKSDB_STATE_INTEGRITY_CHECKS) {
+            Ok(val) => val.to_ascii_lowercase().trim() == "true",
+            _ => false,
+        };
+
+    let db = if read_only {
         RocksDB {
             inner: rocksdb::DB::open_cf_descriptors_read_only(
                 &db_opts, path, cfs, false,
             )
-            .map_err(|e| Error::DBError(e.into_string()))?,
+            .map_err(|e| open_error(path, e.into_string()))?,
             read_only: true,
+            state_integrity_checks,
+            allow_non_contiguous_blocks: false,
+            archive,
+            check_merkle_store_overwrites: false,
+            merkle_store_overwrites: Default::default(),
+            change_sink: None,
+            durability,
+            last_flush: Mutex::new(Instant::now()),
+            bg_status: Default::default(),
+            metrics: Default::default(),
+            subspace_cipher,
+            write_lock: Default::default(),
+            write_lock_owner: Default::default(),
+            rollback_tip,
         }
     } else {
-        RocksDB {
+        let db = RocksDB {
             inner: rocksdb::DB::open_cf_descriptors(&db_opts, path, cfs)
-                .map_err(|e| Error::DBError(e.into_string()))?,
+                .map_err(|e| open_error(path, e.into_string()))?,
             read_only: false,
-        }
-    })
+            state_integrity_checks,
+            allow_non_contiguous_blocks: false,
+            archive,
+            check_merkle_store_overwrites: false,
+            merkle_store_overwrites: Default::default(),
+            change_sink: None,
+            durability,
+            last_flush: Mutex::new(Instant::now()),
+            bg_status: Default::default(),
+            metrics: Default::default(),
+            subspace_cipher,
+            write_lock: Default::default(),
+            write_lock_owner: Default::default(),
+            rollback_tip,
+        };
+        db.upgrade_schema_version()?;
+        db
+    };
+    db.check_subspace_encryption()?;
+    if let Some(height) = db.last_committed_height()? {
+        db.rollback_tip.store(height.0, Ordering::Relaxed);
+    }
+    Ok(db)
 }
 
-impl Drop for RocksDB {
-    fn drop(&mut self) {
-        if !self.read_only {
-            self.flush(true).expect("flush failed");
+/// Map the error message of RocksDB failing to open the DB at the given path.
+/// A failure to acquire the lock of the DB is told apart, as its IO error is
+/// otherwise easily mistaken for a corruption of the DB.
+fn open_error(path: &Path, error: String) -> Error {
+    // The lock is held by another process:
+    //   "IO error: While lock file: <path>/LOCK: Resource temporarily
+    //    unavailable"
+    // The lock is held by the current process:
+    //   "IO error: lock hold by current process, acquire time <time>
+    //    acquiring thread <id>: <path>/LOCK: No locks available"
+    if error.contains("While lock file")
+        || error.contains("lock hold by current process")
+    {
+        Error::DbLocked {
+            path: path.display().to_string(),
         }
+    } else {
+        Error::DBError(error)
     }
 }
 
-impl RocksDB {
-    fn get_column_family(&self, cf_name: &str) -> Result<&ColumnFamily> {
-        self.inner
-            .cf_handle(cf_name)
-            .ok_or(Error::DBError("No {cf_name} column family".to_string()))
-    }
+/// Split a `{key}={val}` entry of a snapshot, where the value is base64
+/// encoded. The key may itself contain a `=`, so the separator is the last
+/// `=` before a valid base64 value, whose only `=` are its (at most two)
+/// trailing padding characters.
+fn split_snapshot_entry(entry: &str) -> Option<(&str, &str)> {
+    let is_base64_value = |val: &str| {
+        let unpadded = val.trim_end_matches('=');
+        !unpadded.is_empty()
+            && !unpadded.contains('=')
+            && !val.ends_with("===")
+            && val.len() % 4 == 0
+    };
+    entry
+        .match_indices('=')
+        .rev()
+        .filter_map(|(idx, _)| {
+            let (key, val) = entry.split_at(idx);
+            Some((key, val.strip_prefix('=')?))
+        })
+        .find(|(_, val)| is_base64_value(val))
+        // An empty value has no base64 characters to tell it apart
+        .or_else(|| entry.rsplit_once('=').filter(|(_, val)| val.is_empty()))
+}
 
-    fn read_value<T>(
+/// Map an error of a batch write to an [`Error`], telling apart the full disk
+/// and the transient errors from the other errors, that may be fatal.
+///
+/// After a failed write to its storage (e.g. of the WAL), RocksDB sets a
+/// background error and rejects all the later writes until the DB is
+/// reopened, so no IO error is transient: only the statuses of a write that
+/// was rejected before anything was written may be retried.
+fn write_error(error: String) -> Error {
+    // RocksDB formats the errors as `<status>: <message>`, where the message
+    // of an IO error is the description of the OS error
+    const STORAGE_FULL: [&str; 2] =
+        ["No space left on device", "Disk quota exceeded"];
+    const TRANSIENT_STATUSES: [&str; 3] = [
+        "Resource busy:",
+        "Operation timed out:",
+        "Operation failed. Try again.:",
+    ];
+    let is_io_error = error.starts_with("IO error:");
+    if is_io_error && STORAGE_FULL.iter().any(|msg| error.contains(msg)) {
+        Error::StorageFull { error }
+    } else if TRANSIENT_STATUSES
+        .iter()
+        .any(|status| error.starts_with(status))
+    {
+        Error::IoTransient { error }
+    } else {
+        Error::DBError(error)
+    }
+}
+
+/// The compaction filter of the [`ROLLBACK_CF`], which drops the entries
+/// more than one block below the height of the last committed block.
+///
+/// The non-persisted diffs are only needed to roll back the last block and
+/// [`DB::prune_non_persisted_diffs`] remains the primary mechanism deleting
+/// them. The filter is only a safety net, such that stale entries don't
+/// accumulate if a prune is missed. The height is shared through an atomic
+/// that is updated as the blocks are committed, and the entries are kept as
+/// long as no block has been committed.
+fn rollback_compaction_filter(
+    tip: Arc<AtomicU64>,
+) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision + Send + 'static {
+    move |_level, key, _value| {
+        let tip = tip.load(Ordering::Relaxed);
+        // The entries are keyed by `{height}/old/{key}` or `{height}/new/{key}`
+        let height = std::str::from_utf8(key)
+            .ok()
+            .and_then(|key| key.split(KEY_SEGMENT_SEPARATOR).next())
+            .and_then(|height| u64::parse(height.to_owned()).ok());
+        match height {
+            Some(height) if height.saturating_add(1) < tip => {
+                CompactionDecision::Remove
+            }
+            _ => CompactionDecision::Keep,
+        }
+    }
+}
+
+impl Drop for RocksDB {
+    fn drop(&mut self) {
+        if !self.read_only {
+            self.flush(true).expect("flush failed");
+        }
+    }
+}
+
+impl RocksDB {
+    fn get_column_family(&self, cf_name: &str) -> Result<&ColumnFamily> {
+        self.inner.cf_handle(cf_name).ok_or_else(|| {
+            Error::MissingColumnFamily {
+                name: cf_name.to_string(),
+            }
+        })
+    }
+
+    /// Record the current [`DB_SCHEMA_VERSION`], if the DB was written with
+    /// an older layout.
+    fn upgrade_schema_version(&self) -> Result<()> {
+        let annotations_cf = self.get_column_family(ANNOTATIONS_CF)?;
+        let version: Option<u64> =
+            self.read_value(annotations_cf, DB_SCHEMA_VERSION_KEY)?;
+        if version.map_or(true, |version| version < DB_SCHEMA_VERSION) {
+            tracing::info!(
+                "Upgrading the DB schema version from {} to {}",
+                version.unwrap_or_default(),
+                DB_SCHEMA_VERSION
+            );
+            self.inner
+                .put_cf(
+                    annotations_cf,
+                    DB_SCHEMA_VERSION_KEY,
+                    encode(&DB_SCHEMA_VERSION),
+                )
+                .map_err(|e| Error::DBError(e.into_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Check that the subspace encryption key, if any, matches the one the
+    /// DB was created with. When a writable DB is opened with a key for the
+    /// first time, its check value is recorded.
+    fn check_subspace_encryption(&self) -> Result<()> {
+        let check = self.get_annotation(SUBSPACE_ENCRYPTION_CHECK_KEY)?;
+        match (&self.subspace_cipher, check) {
+            (Some(cipher), Some(check)) => {
+                if cipher.decrypt(&check)? != SUBSPACE_ENCRYPTION_CHECK {
+                    return Err(Error::DBError(
+                        "The subspace encryption key doesn't match the DB's"
+                            .to_string(),
+                    ));
+                }
+            }
+            (Some(cipher), None) => {
+                let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+                if iter_prefix_no_gas(self, subspace_cf, None, None)
+                    .next()
+                    .is_some()
+                {
+                    return Err(Error::DBError(
+                        "The encryption cannot be enabled for a DB with an \
+                         unencrypted subspace"
+                            .to_string(),
+                    ));
+                }
+                if !self.read_only {
+                    let annotations_cf =
+                        self.get_column_family(ANNOTATIONS_CF)?;
+                    self.inner
+                        .put_cf(
+                            annotations_cf,
+                            SUBSPACE_ENCRYPTION_CHECK_KEY,
+                            cipher.encrypt(SUBSPACE_ENCRYPTION_CHECK),
+                        )
+                        .map_err(|e| Error::DBError(e.into_string()))?;
+                }
+            }
+            (None, Some(_)) => {
+                return Err(Error::DBError(
+                    "The DB's subspace is encrypted, but no key was given"
+                        .to_string(),
+                ));
+            }
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Encrypt a subspace value, if the encryption is enabled
+    fn encrypt_subspace_val<'a>(&self, value: &'a [u8]) -> Cow<'a, [u8]> {
+        match &self.subspace_cipher {
+            Some(cipher) => Cow::Owned(cipher.encrypt(value)),
+            None => Cow::Borrowed(value),
+        }
+    }
+
+    /// Decrypt a stored subspace value, if the encryption is enabled
+    fn decrypt_subspace_val(
+        &self,
+        value: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        match (&self.subspace_cipher, value) {
+            (Some(cipher), Some(value)) => cipher.decrypt(&value).map(Some),
+            (_, value) => Ok(value),
+        }
+    }
+
+    /// The length of the plaintext of a stored subspace value
+    fn subspace_val_len(&self, stored: &[u8]) -> usize {
+        if self.subspace_cipher.is_some() {
+            stored.len().saturating_sub(SUBSPACE_NONCE_LEN)
+        } else {
+            stored.len()
+        }
+    }
+
+    /// Read the version of the DB layout. Returns `None` for DBs created
+    /// before the schema was versioned that have only been opened read-only.
+    pub fn schema_version(&self) -> Result<Option<u64>> {
+        match self.inner.cf_handle(ANNOTATIONS_CF) {
+            Some(annotations_cf) => {
+                self.read_value(annotations_cf, DB_SCHEMA_VERSION_KEY)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Set an operator annotation. Annotations are node-local metadata that
+    /// survive restarts, but are not part of the chain's state.
+    pub fn set_annotation(&self, key: &str, value: &[u8]) -> Result<()> {
+        if key == DB_SCHEMA_VERSION_KEY || key == SUBSPACE_ENCRYPTION_CHECK_KEY
+        {
+            return Err(Error::DBError(format!(
+                "The annotation key {key} is reserved"
+            )));
+        }
+        let annotations_cf = self.get_column_family(ANNOTATIONS_CF)?;
+        self.inner
+            .put_cf(annotations_cf, key, value)
+            .map_err(|e| Error::DBError(e.into_string()))
+    }
+
+    /// Read an operator annotation
+    pub fn get_annotation(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        // The annotations may be missing from an old DB opened read-only
+        match self.inner.cf_handle(ANNOTATIONS_CF) {
+            Some(annotations_cf) => self.read_value_bytes(annotations_cf, key),
+            None => Ok(None),
+        }
+    }
+
+    fn read_value<T>(
         &self,
         cf: &ColumnFamily,
         key: impl AsRef<str>,
@@ -283,6 +1685,278 @@ impl RocksDB {
             .map_err(|e| Error::DBError(e.into_string()))
     }
 
+    /// Read a value of a state key. When the integrity checks are enabled and
+    /// a hash has been stored for the key, the value is verified against it.
+    fn read_verified_value<T>(
+        &self,
+        cf: &ColumnFamily,
+        key: &str,
+    ) -> Result<Option<T>>
+    where
+        T: BorshDeserialize,
+    {
+        let Some(bytes) = self.read_value_bytes(cf, key)? else {
+            return Ok(None);
+        };
+        if self.state_integrity_checks
+            && INTEGRITY_CHECKED_STATE_KEYS.contains(&key)
+        {
+            let hash_key = format!("{STATE_HASH_KEY_PREFIX}/{key}");
+            if let Some(hash) = self.read_value_bytes(cf, hash_key)? {
+                if hash.as_slice() != Sha256::digest(&bytes).as_slice() {
+                    return Err(Error::CorruptValue {
+                        key: key.to_string(),
+                    });
+                }
+            }
+        }
+        decode(bytes).map(Some).map_err(Error::CodingError)
+    }
+
+    /// Keep the hash of a critical state value in sync with the value written
+    /// in the batch. When the integrity checks are disabled, any previously
+    /// written hash is removed instead so that it cannot become stale.
+    fn add_state_value_hash_to_batch(
+        &self,
+        cf: &ColumnFamily,
+        key: &str,
+        value: &[u8],
+        batch: &mut RocksDBWriteBatch,
+    ) {
+        if !INTEGRITY_CHECKED_STATE_KEYS.contains(&key) {
+            return;
+        }
+        let hash_key = format!("{STATE_HASH_KEY_PREFIX}/{key}");
+        if self.state_integrity_checks {
+            batch.inner.put_cf(cf, hash_key, Sha256::digest(value));
+        } else {
+            batch.inner.delete_cf(cf, hash_key);
+        }
+    }
+
+    /// Poll the DB properties for write stalls and background errors and
+    /// report any change through the logs. The Rust binding doesn't expose
+    /// RocksDB's event listeners, so this is polled after every batch write
+    /// instead, which is when a stall would be hit.
+    fn poll_background_status(&self) {
+        let read_property = |name: &str| {
+            self.inner
+                .property_int_value(name)
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        };
+        let errors = read_property("rocksdb.background-errors");
+        let write_stopped = read_property("rocksdb.is-write-stopped") != 0;
+        let delayed_write_rate =
+            read_property("rocksdb.actual-delayed-write-rate");
+        let write_stalled = write_stopped || delayed_write_rate != 0;
+
+        let mut status = self.bg_status.lock().unwrap();
+        if errors > status.errors {
+            let error = format!(
+                "RocksDB reported {} new background error(s), {errors} in \
+                 total",
+                checked!(errors - status.errors).unwrap_or_default()
+            );
+            tracing::error!("{error}");
+            status.errors = errors;
+            status.last_error = Some(error);
+        }
+        if write_stalled != status.write_stalled {
+            if write_stalled {
+                tracing::warn!(
+                    "RocksDB writes are stalled (stopped: {write_stopped}, \
+                     delayed write rate: {delayed_write_rate} B/s)"
+                );
+            } else {
+                tracing::info!("RocksDB writes are no longer stalled");
+            }
+            status.write_stalled = write_stalled;
+        }
+    }
+
+    /// Get the description of the last background error reported by the DB,
+    /// if any
+    pub fn last_bg_error(&self) -> Option<String> {
+        self.poll_background_status();
+        self.bg_status.lock().unwrap().last_error.clone()
+    }
+
+    /// Summarize the readiness of the DB in a single cheap call, e.g. for the
+    /// liveness and readiness probes of an orchestrator. Only a few state
+    /// values and the DB properties are read, and the failures are reported
+    /// in the summary rather than returned.
+    ///
+    /// A [`DbStatus::Degraded`] status should map to a non-ready probe
+    /// response. A fresh DB with no committed block is healthy.
+    pub fn health(&self) -> DbHealth {
+        let mut expected_cfs = DbColFam::all().to_vec();
+        if !self.read_only {
+            expected_cfs.push(ANNOTATIONS_CF);
+        }
+        if self.archive {
+            expected_cfs.push(ARCHIVE_CF);
+        }
+        let missing_column_families: Vec<String> = expected_cfs
+            .into_iter()
+            .filter(|cf| self.inner.cf_handle(cf).is_none())
+            .map(str::to_string)
+            .collect();
+
+        let mut read_error = None;
+        let last_height = self.last_committed_height().unwrap_or_else(|e| {
+            read_error = Some(e.to_string());
+            None
+        });
+        let mut has_marker = |key: &str| {
+            let Some(state_cf) = self.inner.cf_handle(STATE_CF) else {
+                return false;
+            };
+            self.read_value_bytes(state_cf, key)
+                .map(|marker| marker.is_some())
+                .unwrap_or_else(|e| {
+                    if read_error.is_none() {
+                        read_error = Some(e.to_string());
+                    }
+                    false
+                })
+        };
+        let snapshot_import_in_progress =
+            has_marker(SNAPSHOT_IMPORT_IN_PROGRESS_KEY);
+        let migration_in_progress = has_marker(MIGRATION_IN_PROGRESS_KEY);
+        // Poll the DB properties again rather than reporting the last poll,
+        // which only happens on writes and so never on a read-only DB
+        self.poll_background_status();
+        let bg_error = self.bg_status.lock().unwrap().last_error.clone();
+
+        let status = if missing_column_families.is_empty()
+            && read_error.is_none()
+            && bg_error.is_none()
+            && !snapshot_import_in_progress
+            && !migration_in_progress
+        {
+            DbStatus::Healthy
+        } else {
+            DbStatus::Degraded
+        };
+        DbHealth {
+            status,
+            missing_column_families,
+            last_height,
+            read_error,
+            bg_error,
+            snapshot_import_in_progress,
+            migration_in_progress,
+        }
+    }
+
+    /// Mark an offline migration as in progress, until it's cleared with
+    /// [`RocksDB::clear_migration_marker`].
+    ///
+    /// The changes of a migration are written in a single batch, but the
+    /// migration is only complete once the state of CometBFT has also been
+    /// reset to match the new app hash. If the process is interrupted in
+    /// between, the marker is left set and the DB is reported as degraded by
+    /// [`RocksDB::health`], rather than starting a node that can't agree with
+    /// CometBFT's state.
+    pub fn mark_migration_in_progress(&self) -> Result<()> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let mut batch = Self::batch();
+        batch.inner.put_cf(state_cf, MIGRATION_IN_PROGRESS_KEY, []);
+        self.exec_batch(batch)
+    }
+
+    /// Clear the marker of an offline migration set by
+    /// [`RocksDB::mark_migration_in_progress`], once it has completed
+    pub fn clear_migration_marker(&self) -> Result<()> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let mut batch = Self::batch();
+        batch.inner.delete_cf(state_cf, MIGRATION_IN_PROGRESS_KEY);
+        self.exec_batch(batch)
+    }
+
+    /// Get a snapshot of the counters of the DB operations. The counters are
+    /// only incremented when the `storage-metrics` feature is enabled,
+    /// otherwise they are always zero.
+    pub fn metrics_snapshot(&self) -> DbMetrics {
+        let MetricsCounters {
+            subspace_reads,
+            subspace_writes,
+            diff_writes,
+            rp_writes,
+        } = &self.metrics;
+        DbMetrics {
+            subspace_reads: subspace_reads.load(Ordering::Relaxed),
+            subspace_writes: subspace_writes.load(Ordering::Relaxed),
+            diff_writes: diff_writes.load(Ordering::Relaxed),
+            rp_writes: rp_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Enable or disable the integrity checks of the critical state values
+    pub fn set_state_integrity_checks(&mut self, enabled: bool) {
+        self.state_integrity_checks = enabled;
+    }
+
+    /// Allow or disallow writing a block at a height other than the one
+    /// following the last committed block. This is only meant for restoring
+    /// a state at an arbitrary height, e.g. from a snapshot, as blocks are
+    /// otherwise always committed in order.
+    pub fn set_allow_non_contiguous_blocks(&mut self, allowed: bool) {
+        self.allow_non_contiguous_blocks = allowed;
+    }
+
+    /// Enable or disable the check of the merkle tree stores written per
+    /// epoch being overwritten within their epoch. These stores must only be
+    /// written once per epoch, so an overwrite points to a bug in the gating
+    /// of the full commits that may mask an inconsistency of the tree. The
+    /// check reads the stores before overwriting them, so it's disabled by
+    /// default to keep it off the commit path. The overwrites found are
+    /// logged and reported by [`RocksDB::take_merkle_store_overwrites`].
+    pub fn set_check_merkle_store_overwrites(&mut self, enabled: bool) {
+        self.check_merkle_store_overwrites = enabled;
+    }
+
+    /// Register a consumer of the subspace changes of the committed blocks,
+    /// or unregister it with `None`. Once a batch with a block is written,
+    /// the sink is given the keys changed in the batch, so it never sees
+    /// uncommitted changes. Like [`RocksDB::changed_keys`], only the keys
+    /// whose diffs are persisted are given. Without a sink, the changes
+    /// aren't tracked.
+    pub fn set_change_sink(&mut self, sink: Option<Box<dyn ChangeSink>>) {
+        self.change_sink = sink;
+    }
+
+    /// Take the overwrites of the merkle tree stores found since the last
+    /// call, see [`RocksDB::set_check_merkle_store_overwrites`]
+    pub fn take_merkle_store_overwrites(&self) -> Vec<MerkleStoreOverwrite> {
+        std::mem::take(&mut self.merkle_store_overwrites.lock().unwrap())
+    }
+
+    /// Change the number of background compaction threads of an open DB,
+    /// overriding the value taken from
+    /// `ENV_VAR_ROCKSDB_COMPACTION_THREADS` when the DB was opened.
+    ///
+    /// Only the DB-wide `max_background_jobs` option is changed, which is
+    /// the option set by `increase_parallelism` on open. RocksDB grows its
+    /// thread pool as needed to match it. Other options set on open, such as
+    /// the column families' compaction style, cannot be changed on a live
+    /// DB. The number of threads must be positive.
+    pub fn set_compaction_threads(&self, threads: i32) -> Result<()> {
+        if threads < 1 {
+            return Err(Error::DBError(format!(
+                "Invalid number of compaction threads {threads}, it must be \
+                 at least 1"
+            )));
+        }
+        self.inner
+            .set_options(&[("max_background_jobs", &threads.to_string())])
+            .map_err(|e| Error::DBError(e.into_string()))?;
+        tracing::info!("Using {} compactions threads for RocksDB.", threads);
+        Ok(())
+    }
+
     fn add_state_value_to_batch<T>(
         &self,
         cf: &ColumnFamily,
@@ -298,13 +1972,15 @@ impl RocksDB {
             .get_cf(cf, key.as_ref())
             .map_err(|e| Error::DBError(e.into_string()))?
         {
-            batch.0.put_cf(
+            batch.inner.put_cf(
                 cf,
                 format!("{PRED_KEY_PREFIX}/{}", key.as_ref()),
                 current_value,
             );
         }
-        self.add_value_to_batch(cf, key, value, batch);
+        let bytes = encode(value);
+        self.add_state_value_hash_to_batch(cf, key.as_ref(), &bytes, batch);
+        self.add_value_bytes_to_batch(cf, key, bytes, batch);
         Ok(())
     }
 
@@ -327,7 +2003,7 @@ impl RocksDB {
         value: Vec<u8>,
         batch: &mut RocksDBWriteBatch,
     ) {
-        batch.0.put_cf(cf, key.as_ref(), value);
+        batch.inner.put_cf(cf, key.as_ref(), value);
     }
 
     /// Persist the diff of an account subspace key-val under the height where
@@ -341,6 +2017,8 @@ impl RocksDB {
         new_value: Option<&[u8]>,
         persist_diffs: bool,
     ) -> Result<()> {
+        #[cfg(feature = "storage-metrics")]
+        self.metrics.diff_writes.fetch_add(1, Ordering::Relaxed);
         let cf = if persist_diffs {
             self.get_column_family(DIFFS_CF)?
         } else {
@@ -349,21 +2027,39 @@ impl RocksDB {
         let (old_val_key, new_val_key) = old_and_new_diff_key(key, height)?;
 
         if let Some(old_value) = old_value {
-            batch.0.put_cf(cf, old_val_key, old_value);
+            batch.inner.put_cf(cf, old_val_key, old_value);
         }
 
         if let Some(new_value) = new_value {
-            batch.0.put_cf(cf, new_val_key, new_value);
+            batch.inner.put_cf(cf, new_val_key, new_value);
+        }
+
+        if persist_diffs && self.change_sink.is_some() {
+            let (has_old, has_new) =
+                batch.changed_keys.entry(key.clone()).or_default();
+            *has_old |= old_value.is_some();
+            *has_new |= new_value.is_some();
+        }
+
+        if self.archive {
+            let archive_cf = self.get_column_family(ARCHIVE_CF)?;
+            let archive_key =
+                format!("{}/{ARCHIVE_CHANGES_KEY_SEGMENT}/{key}", height.raw());
+            self.add_value_to_batch(archive_cf, archive_key, &new_value, batch);
         }
         Ok(())
     }
 
-    /// Dump last known block
+    /// Dump last known block in the given format. The output file is
+    /// written through a buffer of `buffer_size` bytes, see
+    /// [`DEFAULT_DUMP_BUFFER_SIZE`].
     pub fn dump_block(
         &self,
         out_file_path: std::path::PathBuf,
         historic: bool,
         height: Option<BlockHeight>,
+        format: DumpFormat,
+        buffer_size: usize,
     ) {
         // Find the last block height
         let state_cf = self
@@ -385,95 +2081,124 @@ impl RocksDB {
                     .map(|name| name.to_string_lossy().into_owned())
                     .unwrap_or_else(|| "dump_db".to_string())
             ))
-            .with_extension("toml");
+            .with_extension(format.extension());
 
-        let mut file = File::options()
+        let file = File::options()
             .append(true)
             .create_new(true)
             .open(&full_path)
             .expect("Cannot open the output file");
+        let mut file = BufWriter::with_capacity(buffer_size, file);
 
         println!("Will write to {} ...", full_path.to_string_lossy());
 
+        if format == DumpFormat::Binary {
+            let header = [
+                BINARY_DUMP_MAGIC.as_slice(),
+                DB_SCHEMA_VERSION.to_le_bytes().as_slice(),
+                height.0.to_le_bytes().as_slice(),
+            ]
+            .concat();
+            file.write_all(&header)
+                .expect("Unable to write to output file");
+        }
+
         if historic {
             // Dump the keys prepended with the selected block height (includes
             // subspace diff keys)
 
             // Diffs
-            let cf = self
-                .get_column_family(DIFFS_CF)
-                .expect("Diffs column family should exist");
             let prefix = height.raw();
-            self.dump_it(cf, Some(prefix.clone()), &mut file);
+            self.dump_it(DIFFS_CF, Some(prefix.clone()), format, &mut file);
 
             // Block
-            let cf = self
-                .get_column_family(BLOCK_CF)
-                .expect("Block column family should exist");
-            self.dump_it(cf, Some(prefix), &mut file);
+            self.dump_it(BLOCK_CF, Some(prefix), format, &mut file);
         }
 
         // subspace
         if height != last_height {
             // Restoring subspace at specified height
-            let restored_subspace = self
-                .iter_prefix(None)
-                .par_bridge()
-                .fold(
-                    || "".to_string(),
-                    |mut cur, (key, _value, _gas)| match self
-                        .read_subspace_val_with_height(
-                            &Key::from(key.to_db_key()),
-                            height,
-                            last_height,
-                        )
-                        .expect("Unable to find subspace key")
-                    {
-                        Some(value) => {
-                            let val = HEXLOWER.encode(&value);
-                            let new_line = format!("\"{key}\" = \"{val}\"\n");
-                            cur.push_str(new_line.as_str());
-                            cur
+            let subspace_cf = self
+                .get_column_family(SUBSPACE_CF)
+                .expect("Subspace column family should exist");
+            let restored_subspace =
+                iter_prefix_no_gas(self, subspace_cf, None, None)
+                    .par_bridge()
+                    .fold(Vec::new, |mut cur, (key, _value)| {
+                        if let Some(value) = self
+                            .read_subspace_val_with_height(
+                                &Key::from(key.to_db_key()),
+                                height,
+                                last_height,
+                            )
+                            .expect("Unable to find subspace key")
+                        {
+                            cur.extend(format.encode_record(
+                                SUBSPACE_CF,
+                                &key,
+                                &value,
+                            ));
                         }
-                        None => cur,
-                    },
-                )
-                .reduce(
-                    || "".to_string(),
-                    |mut a: String, b: String| {
-                        a.push_str(&b);
+                        cur
+                    })
+                    .reduce(Vec::new, |mut a, b| {
+                        a.extend(b);
                         a
-                    },
-                );
-            file.write_all(restored_subspace.as_bytes())
+                    });
+            file.write_all(&restored_subspace)
                 .expect("Unable to write to output file");
         } else {
             // Just dump the current subspace
-            let cf = self
-                .get_column_family(SUBSPACE_CF)
-                .expect("Subspace column family should exist");
-            self.dump_it(cf, None, &mut file);
+            self.dump_it(SUBSPACE_CF, None, format, &mut file);
         }
 
         // replay protection
         // Dump of replay protection keys is possible only at the last height
         if height == last_height {
-            let cf = self
-                .get_column_family(REPLAY_PROTECTION_CF)
-                .expect("Replay protection column family should exist");
-            self.dump_it(cf, None, &mut file);
+            self.dump_it(REPLAY_PROTECTION_CF, None, format, &mut file);
         }
 
+        file.flush().expect("Unable to write to output file");
         println!("Done writing to {}", full_path.to_string_lossy());
     }
 
-    /// Dump data
+    /// Dump the data of a column family in the given format. The values of
+    /// the subspace and of its diffs are decrypted.
     fn dump_it(
         &self,
-        cf: &ColumnFamily,
+        cf_name: &str,
         prefix: Option<String>,
-        file: &mut File,
+        format: DumpFormat,
+        buf: &mut BufWriter<File>,
     ) {
+        let cf = self
+            .get_column_family(cf_name)
+            .expect("The dumped column family should exist");
+        let is_subspace_val = cf_name == SUBSPACE_CF || cf_name == DIFFS_CF;
+        for (count, (key, raw_val)) in (1..).zip(self.dump_iter(cf, prefix)) {
+            let raw_val = if is_subspace_val {
+                self.decrypt_subspace_val(Some(raw_val))
+                    .expect("Unable to decrypt a subspace value")
+                    .unwrap_or_default()
+            } else {
+                raw_val
+            };
+            let bytes = format.encode_record(cf_name, &key, &raw_val);
+            buf.write_all(&bytes).expect("Unable to write to buffer");
+            if count % DUMP_FLUSH_INTERVAL == 0 {
+                buf.flush().expect("Unable to write to output file");
+            }
+        }
+        buf.flush().expect("Unable to write to output file");
+    }
+
+    /// Iterate the raw entries of a column family that are dumped, starting
+    /// with the given prefix if any
+    fn dump_iter(
+        &self,
+        cf: &ColumnFamily,
+        prefix: Option<String>,
+    ) -> PrefixIteratorNoGas<'_> {
         let read_opts = make_iter_read_opts(prefix.clone());
         let iter = if let Some(prefix) = prefix {
             self.inner.iterator_cf_opt(
@@ -485,96 +2210,717 @@ impl RocksDB {
             self.inner
                 .iterator_cf_opt(cf, read_opts, IteratorMode::Start)
         };
-
-        let mut buf = BufWriter::new(file);
-        for (key, raw_val, _gas) in PersistentPrefixIterator(
+        PrefixIteratorNoGas(
             PrefixIterator::new(iter, String::default()),
             // Empty string to prevent prefix stripping, the prefix is
             // already in the enclosed iterator
-        ) {
-            let val = HEXLOWER.encode(&raw_val);
-            let bytes = format!("\"{key}\" = \"{val}\"\n");
-            buf.write_all(bytes.as_bytes())
-                .expect("Unable to write to buffer");
-        }
-        buf.flush().expect("Unable to write to output file");
-    }
-
-    pub fn snapshot(&self) -> DbSnapshot<'_> {
-        DbSnapshot(self.inner.snapshot())
+        )
     }
 
-    /// Rollback to previous block. Given the inner working of tendermint
-    /// rollback and of the key structure of Namada, calling rollback more than
-    /// once without restarting the chain results in a single rollback.
-    pub fn rollback(
-        &mut self,
-        tendermint_block_height: BlockHeight,
-    ) -> Result<()> {
-        let last_block = self.read_last_block()?.ok_or(Error::DBError(
-            "Missing last block in storage".to_string(),
-        ))?;
-        tracing::info!(
-            "Namada last block height: {}, Tendermint last block height: {}",
-            last_block.height,
-            tendermint_block_height
-        );
-
-        // If the block height to which tendermint rolled back matches the
-        // Namada height, there's no need to rollback
-        if tendermint_block_height == last_block.height {
-            tracing::info!(
-                "Namada height already matches the rollback Tendermint \
-                 height, no need to rollback."
-            );
-            return Ok(());
+    /// Verify that a dump written by [`RocksDB::dump_block`] faithfully
+    /// represents the DB, before trusting it for a restore.
+    ///
+    /// Every value of the dump is compared against the live value of its key
+    /// at the dump's height, recorded in the file name, and every key that
+    /// the dump should contain is checked to be in it. The subspace values
+    /// of a dump below the last committed height are read with
+    /// [`RocksDB::read_subspace_val_with_height`]. A dump is historic if it
+    /// contains the block data of its height, in which case the diffs and
+    /// the block data are verified too. The dumps in both formats of
+    /// [`DumpFormat`] are supported, as told by their extension.
+    pub fn verify_dump(&self, dump_path: &Path) -> Result<DumpVerification> {
+        let height = dump_height(dump_path)?;
+        let last_height = self.last_committed_height()?.ok_or_else(|| {
+            Error::DBError("No block has been committed".to_string())
+        })?;
+        if height > last_height {
+            return Err(Error::DBError(format!(
+                "The dump's height {height} is above the last committed \
+                 height {last_height}"
+            )));
         }
 
-        let mut batch = RocksDB::batch();
-        let previous_height =
-            last_block.height.prev_height().expect("Must have a pred");
+        let mut dumped = HashMap::new();
+        if dump_path.extension().and_then(OsStr::to_str)
+            == Some(DumpFormat::Binary.extension())
+        {
+            for record in BinaryDumpReader::open(dump_path)? {
+                let (_cf, key, val) = record?;
+                dumped.insert(key, val);
+            }
+        } else {
+            read_toml_dump(dump_path, &mut dumped)?;
+        }
+        let prefix = height.raw();
+        let block_prefix = format!("{prefix}/");
+        let historic = dumped.keys().any(|key| key.starts_with(&block_prefix));
 
-        let state_cf = self.get_column_family(STATE_CF)?;
-        // Revert the non-height-prepended metadata storage keys which get
-        // updated with every block. Because of the way we save these
-        // three keys in storage we can only perform one rollback before
-        // restarting the chain
-        tracing::info!("Reverting non-height-prepended metadata keys");
-        batch
-            .0
-            .put_cf(state_cf, BLOCK_HEIGHT_KEY, encode(&previous_height));
-        for metadata_key in [
-            NEXT_EPOCH_MIN_START_HEIGHT_KEY,
-            NEXT_EPOCH_MIN_START_TIME_KEY,
-            COMMIT_ONLY_DATA_KEY,
-            UPDATE_EPOCH_BLOCKS_DELAY_KEY,
-        ] {
-            let previous_key = format!("{PRED_KEY_PREFIX}/{metadata_key}");
-            let previous_value = self
-                .read_value_bytes(state_cf, &previous_key)?
-                .ok_or(Error::UnknownKey { key: previous_key })?;
+        let mut verification = DumpVerification {
+            height,
+            ..Default::default()
+        };
+        let mut compare = |key: String, value: Vec<u8>| -> Result<()> {
+            match dumped.swap_remove(&key) {
+                Some(dumped_val) if dumped_val == value => {
+                    checked!(verification.matching += 1)?;
+                    Ok(())
+                }
+                Some(_) => verification.record(DumpDiscrepancy::Mismatch(key)),
+                None => verification.record(DumpDiscrepancy::Missing(key)),
+            }
+        };
 
-            self.add_value_bytes_to_batch(
-                state_cf,
-                metadata_key,
-                previous_value,
-                &mut batch,
-            );
-            // NOTE: we cannot restore the "pred/" keys themselves since we
-            // don't have their predecessors in storage, but there's no need to
-            // since we cannot do more than one rollback anyway because of
-            // CometBFT.
+        if historic {
+            let diffs_cf = self.get_column_family(DIFFS_CF)?;
+            for (key, raw_val) in self.dump_iter(diffs_cf, Some(prefix.clone()))
+            {
+                let value = self
+                    .decrypt_subspace_val(Some(raw_val))?
+                    .unwrap_or_default();
+                compare(key, value)?;
+            }
+            let block_cf = self.get_column_family(BLOCK_CF)?;
+            for (key, raw_val) in self.dump_iter(block_cf, Some(prefix)) {
+                compare(key, raw_val)?;
+            }
         }
 
-        // Revert conversion state if the epoch had been changed
-        if last_block.pred_epochs.get_epoch(previous_height)
-            != Some(last_block.epoch)
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        if height != last_height {
+            for (key, _val) in iter_prefix_no_gas(self, subspace_cf, None, None)
+            {
+                if let Some(value) = self.read_subspace_val_with_height(
+                    &Key::from(key.to_db_key()),
+                    height,
+                    last_height,
+                )? {
+                    compare(key, value)?;
+                }
+            }
+        } else {
+            for (key, raw_val) in self.dump_iter(subspace_cf, None) {
+                let value = self
+                    .decrypt_subspace_val(Some(raw_val))?
+                    .unwrap_or_default();
+                compare(key, value)?;
+            }
+            // Replay protection is only dumped at the last height
+            let reprot_cf = self.get_column_family(REPLAY_PROTECTION_CF)?;
+            for (key, raw_val) in self.dump_iter(reprot_cf, None) {
+                compare(key, raw_val)?;
+            }
+        }
+
+        let mut extra: Vec<_> = dumped.into_keys().collect();
+        extra.sort();
+        for key in extra {
+            verification.record(DumpDiscrepancy::Extra(key))?;
+        }
+        Ok(verification)
+    }
+
+    /// Restore the entries of a binary dump written by
+    /// [`RocksDB::dump_block`] with [`DumpFormat::Binary`], routing each
+    /// record to its column family. Returns the height of the dump.
+    ///
+    /// The dump is streamed and its records are written in batches, so it
+    /// doesn't need to fit in memory. The values of the subspace and of its
+    /// diffs are encrypted if the DB encrypts them at rest. Only binary dumps
+    /// can be restored, as the TOML dumps don't record the column families of
+    /// their entries.
+    ///
+    /// A dump doesn't include the [`STATE_CF`], so a restore only gives the
+    /// subspace, the replay protection entries and, for a historic dump, the
+    /// diffs and the block data of the dumped height. The chain state (last
+    /// block, conversion state, etc.) is not restored and the restored DB
+    /// cannot be used to start a node on its own.
+    pub fn restore_from_dump(&self, dump_path: &Path) -> Result<BlockHeight> {
+        let mut reader = BinaryDumpReader::open(dump_path)?;
+        let mut batch = Self::batch();
+        for (count, record) in (1..).zip(reader.by_ref()) {
+            let (cf_name, key, value) = record?;
+            let cf = self.get_column_family(cf_name)?;
+            if cf_name == SUBSPACE_CF || cf_name == DIFFS_CF {
+                batch.inner.put_cf(cf, key, self.encrypt_subspace_val(&value));
+            } else {
+                batch.inner.put_cf(cf, key, value);
+            }
+            if count % DUMP_FLUSH_INTERVAL == 0 {
+                self.exec_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.exec_batch(batch)?;
+        Ok(reader.height)
+    }
+
+    /// Dump the changes of the subspace since a previous dump, for an
+    /// incremental backup. The previous dump is either a full dump written by
+    /// [`RocksDB::dump_block`] or a delta written by this method, and the
+    /// changes are dumped up to the given height, defaulting to the last
+    /// committed height. Returns the path of the delta file, named
+    /// `{out_file_path}_{from}_{to}.delta`.
+    ///
+    /// The changed keys are found from the diffs of the heights in between,
+    /// which must not have been pruned. The keys written without their diffs
+    /// persisted, see [`is_key_diff_storable`], are not tracked by the diffs,
+    /// so all of them are dumped with their current value instead. Their
+    /// deletions are only known in the last block, from its non-persisted
+    /// diffs, and a delta cannot end before the last committed height if any
+    /// such key exists. The delta file holds a `[write]` table of the keys
+    /// set to a new value, hex encoded as in a full dump, and a `[delete]`
+    /// table of the keys that have been deleted.
+    ///
+    /// The subspace at a height `h_n` is reconstructed from a full dump at
+    /// height `h_0` and the chain of deltas from `h_0` to `h_1`, ..., from
+    /// `h_n-1` to `h_n`, by applying the deltas in order: the keys of the
+    /// `[write]` table are set to their value and the keys of the `[delete]`
+    /// table are removed.
+    ///
+    /// The delta file is written through a buffer of `buffer_size` bytes,
+    /// see [`DEFAULT_DUMP_BUFFER_SIZE`].
+    pub fn dump_delta(
+        &self,
+        since_dump: &Path,
+        out_file_path: PathBuf,
+        height: Option<BlockHeight>,
+        buffer_size: usize,
+    ) -> Result<PathBuf> {
+        let from = dump_height(since_dump)?;
+        let last_height = self.last_committed_height()?.ok_or_else(|| {
+            Error::DBError("No block has been committed".to_string())
+        })?;
+        let to = height.unwrap_or(last_height);
+        if from >= to || to > last_height {
+            return Err(Error::DBError(format!(
+                "Invalid delta from height {from} to height {to}, the last \
+                 committed height is {last_height}"
+            )));
+        }
+
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let mut changed = BTreeSet::new();
+        for height in (from.next_height().0..=to.0).map(BlockHeight) {
+            for is_old in [true, false] {
+                for (key, _val) in
+                    iter_diffs_prefix_no_gas(self, diffs_cf, height, is_old)
+                {
+                    changed.insert(key);
+                }
+            }
+        }
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let mut not_diffed = BTreeSet::new();
+        for (key, _val) in iter_prefix_no_gas(self, subspace_cf, None, None) {
+            let parsed = Key::parse(&key).map_err(Error::KeyError)?;
+            if !is_key_diff_storable(&parsed) {
+                not_diffed.insert(key);
+            }
+        }
+        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
+        for is_old in [true, false] {
+            for (key, _val) in
+                iter_diffs_prefix_no_gas(self, rollback_cf, last_height, is_old)
+            {
+                not_diffed.insert(key);
+            }
+        }
+        if to < last_height && !not_diffed.is_empty() {
+            return Err(Error::DBError(format!(
+                "Cannot dump a delta up to height {to}, the keys whose diffs \
+                 are not persisted are only known at the last committed \
+                 height {last_height}"
+            )));
+        }
+        changed.extend(not_diffed);
+
+        let full_path = out_file_path
+            .with_file_name(format!(
+                "{}_{from}_{to}",
+                out_file_path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "dump_db".to_string())
+            ))
+            .with_extension("delta");
+        let write_err = |e: std::io::Error| {
+            Error::DBError(format!(
+                "Unable to write the delta {}: {e}",
+                full_path.to_string_lossy()
+            ))
+        };
+        let file = File::options()
+            .append(true)
+            .create_new(true)
+            .open(&full_path)
+            .map_err(write_err)?;
+        let mut buf = BufWriter::with_capacity(buffer_size, file);
+
+        writeln!(buf, "# Changes of the subspace from height {from} to {to}")
+            .map_err(write_err)?;
+        writeln!(buf, "[write]").map_err(write_err)?;
+        let mut deleted = vec![];
+        for key in changed {
+            match self.read_subspace_val_with_height(
+                &Key::from(key.to_db_key()),
+                to,
+                last_height,
+            )? {
+                Some(value) => {
+                    let val = HEXLOWER.encode(&value);
+                    writeln!(buf, "\"{key}\" = \"{val}\"")
+                        .map_err(write_err)?;
+                }
+                None => deleted.push(key),
+            }
+        }
+        writeln!(buf, "[delete]").map_err(write_err)?;
+        for key in deleted {
+            writeln!(buf, "\"{key}\" = true").map_err(write_err)?;
+        }
+        buf.flush().map_err(write_err)?;
+
+        Ok(full_path)
+    }
+
+    pub fn snapshot(&self) -> DbSnapshot<'_> {
+        DbSnapshot(self.inner.snapshot())
+    }
+
+    /// The index of the next chunk to import of the snapshot at the given
+    /// height with the given chunks, i.e. the number of its chunks already
+    /// imported by [`RocksDB::import_snapshot`]. This is 0 once the import
+    /// has completed.
+    pub fn snapshot_import_progress(
+        &self,
+        height: BlockHeight,
+        chunks: &[Chunk],
+    ) -> Result<u64> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        Ok(self
+            .read_value::<u64>(
+                state_cf,
+                snapshot_import_chunk_key(height, chunks),
+            )?
+            .unwrap_or_default())
+    }
+
+    /// Import a snapshot at the given height streamed from the given reader,
+    /// e.g. a network socket, as written by [`DbSnapshot::write_to`] with the
+    /// given chunks. Returns the number of chunks imported.
+    ///
+    /// The reader must yield the snapshot from the start of the chunk given
+    /// by [`RocksDB::snapshot_import_progress`], such that an interrupted
+    /// import resumes from its last verified chunk. Each chunk is verified
+    /// against its hash as it arrives and is then written in a single batch
+    /// along with the progress of the import, which is keyed by the height
+    /// and the hash of the snapshot's chunks. Until the last chunk is
+    /// imported, the import is reported as in progress by
+    /// [`RocksDB::health`] and the import of another snapshot is refused, as
+    /// it would mix the entries of both snapshots. The progress is deleted
+    /// along with the last chunk.
+    pub fn import_snapshot(
+        &self,
+        reader: impl Read,
+        height: BlockHeight,
+        chunks: &[Chunk],
+    ) -> Result<u64> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let chunk_key = snapshot_import_chunk_key(height, chunks);
+        if let Some(in_progress) = self
+            .read_value::<String>(state_cf, SNAPSHOT_IMPORT_IN_PROGRESS_KEY)?
+        {
+            if in_progress != chunk_key {
+                return Err(Error::DBError(format!(
+                    "Another snapshot import is in progress: {in_progress}"
+                )));
+            }
+        }
+        let first_chunk = self.snapshot_import_progress(height, chunks)?;
+        let first_idx = usize::try_from(first_chunk)
+            .map_err(|e| Error::DBError(e.to_string()))?;
+        let remaining_chunks = chunks.get(first_idx..).ok_or_else(|| {
+            Error::DBError(format!(
+                "The snapshot import is at chunk {first_chunk}, but the \
+                 snapshot only has {} chunks",
+                chunks.len()
+            ))
+        })?;
+        let mut line_num = match first_idx.checked_sub(1) {
+            Some(prev_idx) => chunks[prev_idx].boundary,
+            None => 0,
+        };
+        let num_chunks = u64::try_from(chunks.len())
+            .map_err(|e| Error::DBError(e.to_string()))?;
+        let read_err = |e: std::io::Error| Error::DBError(e.to_string());
+
+        let mut reader = BufReader::new(reader);
+        let mut imported = 0_u64;
+        for (chunk_idx, chunk) in (first_chunk..).zip(remaining_chunks) {
+            let mut batch = Self::batch();
+            let mut hasher = Sha256::default();
+            while line_num < chunk.boundary {
+                let mut line = String::new();
+                if reader.read_line(&mut line).map_err(read_err)? == 0 {
+                    return Err(Error::DBError(format!(
+                        "The snapshot ended within the chunk {chunk_idx}"
+                    )));
+                }
+                hasher.update(line.as_bytes());
+                // The lines are formatted as `{cf_name}:{key}={val}`, where
+                // the value is base64 encoded
+                let entry = line
+                    .strip_suffix('\n')
+                    .and_then(|entry| entry.split_once(':'))
+                    .and_then(|(cf_name, entry)| {
+                        split_snapshot_entry(entry)
+                            .map(|(key, val)| (cf_name, key, val))
+                    });
+                let Some((cf_name, key, val)) = entry else {
+                    return Err(Error::DBError(format!(
+                        "Invalid line {line_num} of the snapshot"
+                    )));
+                };
+                let val = base64::decode(val).map_err(|e| {
+                    Error::DBError(format!(
+                        "Invalid value on line {line_num} of the snapshot: {e}"
+                    ))
+                })?;
+                batch.inner.put_cf(self.get_column_family(cf_name)?, key, val);
+                line_num = checked!(line_num + 1)?;
+            }
+            let hash: [u8; 32] = hasher.finalize().into();
+            if Hash(hash) != chunk.hash {
+                return Err(Error::DBError(format!(
+                    "The chunk {chunk_idx} of the snapshot doesn't match its \
+                     hash"
+                )));
+            }
+            let next_chunk = checked!(chunk_idx + 1)?;
+            if next_chunk < num_chunks {
+                self.add_value_to_batch(
+                    state_cf,
+                    &chunk_key,
+                    &next_chunk,
+                    &mut batch,
+                );
+                self.add_value_to_batch(
+                    state_cf,
+                    SNAPSHOT_IMPORT_IN_PROGRESS_KEY,
+                    &chunk_key,
+                    &mut batch,
+                );
+            } else {
+                batch.inner.delete_cf(state_cf, &chunk_key);
+                batch
+                    .inner
+                    .delete_cf(state_cf, SNAPSHOT_IMPORT_IN_PROGRESS_KEY);
+            }
+            self.exec_batch(batch)?;
+            imported = checked!(imported + 1)?;
+        }
+        Ok(imported)
+    }
+
+    /// Lend the raw RocksDB handle to the given closure, e.g. for a one-off
+    /// property query or a custom iterator while debugging a node.
+    ///
+    /// This is an advanced and unstable escape hatch, that may change along
+    /// with the storage layout. The handle is meant for read-only inspection:
+    /// any write through it bypasses the diffs and rollback bookkeeping (and
+    /// the encryption of the subspace values, if enabled) and may corrupt the
+    /// state.
+    pub fn with_raw<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&rocksdb::DB) -> R,
+    {
+        f(&self.inner)
+    }
+
+    /// Execute a batch without writing it to the write-ahead log (WAL), for
+    /// bulk imports (e.g. of a snapshot or of the genesis state) that can be
+    /// restarted from their source.
+    ///
+    /// The batch only lives in the memtables until they're flushed, so a
+    /// crash before the flush loses it and can leave a partially imported
+    /// state. The bulk import must therefore be followed by a
+    /// [`DB::flush`] waiting for its completion, before anything relies on
+    /// the imported data. This must never be used for the block commits, as
+    /// their writes must survive a crash.
+    pub fn exec_batch_no_wal(&self, batch: RocksDBWriteBatch) -> Result<()> {
+        let mut write_opts = WriteOptions::default();
+        write_opts.disable_wal(true);
+        let _write_lock = self.lock_writes()?;
+        self.write_batch(batch, &write_opts)
+    }
+
+    /// Start a write batch that's written in chunks of about `max_ops`
+    /// operations (at least 1), for a bulk operation too large for a single
+    /// batch. Unlike [`DB::exec_batch`], the operation isn't written
+    /// atomically, see [`ChunkedWriteBatch`].
+    pub fn chunked_batch(&self, max_ops: usize) -> ChunkedWriteBatch<'_> {
+        ChunkedWriteBatch {
+            db: self,
+            batch: Self::batch(),
+            max_ops: max_ops.max(1),
+            written_chunks: 0,
+            size_diff: 0,
+        }
+    }
+
+    /// Build a batch with `build` and write it, without any other batch
+    /// being written in between.
+    ///
+    /// The batch-building methods of the [`DB`] trait aren't exclusive, see
+    /// its docs, and the size difference of a batch is stale too if another
+    /// batch is written before it. Building the batch in `build` makes this
+    /// read-modify-write atomic with respect to the other writers, while the
+    /// readers stay concurrent.
+    ///
+    /// Only the batches built in `build` are protected: a batch built outside
+    /// of it and written with [`DB::exec_batch`] is still serialized with
+    /// the other writes, but the values it was built against may have changed
+    /// in between, so such writes stay racy.
+    ///
+    /// The write lock is not re-entrant. `build` must only add to the given
+    /// batch, as writing any batch from it, e.g. with [`DB::exec_batch`] or a
+    /// nested [`RocksDB::write_exclusively`], would deadlock. Such writes are
+    /// refused with an error instead.
+    pub fn write_exclusively<T>(
+        &self,
+        build: impl FnOnce(&mut RocksDBWriteBatch) -> Result<T>,
+    ) -> Result<T> {
+        let _write_lock = self.lock_writes()?;
+        let mut batch = Self::batch();
+        let result = {
+            let _owner = WriteLockOwner::set(&self.write_lock_owner);
+            build(&mut batch)?
+        };
+        self.write_batch(batch, &self.write_options())?;
+        Ok(result)
+    }
+
+    /// Acquire the write lock, see [`RocksDB::write_exclusively`]. Errors
+    /// instead of deadlocking if the current thread is building a batch in
+    /// [`RocksDB::write_exclusively`].
+    fn lock_writes(&self) -> Result<MutexGuard<'_, ()>> {
+        if *self
+            .write_lock_owner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            == Some(std::thread::current().id())
+        {
+            return Err(Error::DBError(
+                "Cannot write a batch while building another one in \
+                 `write_exclusively`"
+                    .to_string(),
+            ));
+        }
+        // The lock guards no data, so a panic of a previous writer, e.g. in
+        // a `build` of `write_exclusively`, leaves nothing inconsistent
+        Ok(self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner))
+    }
+
+    /// Write a batch built against a read of the subspace only if the read
+    /// values haven't changed since, for an optimistic concurrency. Each key
+    /// of `expected` must still have a value whose SHA-256 hash is the
+    /// expected one, or still be absent if `None` is expected. The values are
+    /// checked and the batch written under the write lock, see
+    /// [`RocksDB::write_exclusively`]. Returns whether the batch has been
+    /// written, which isn't the case on a conflict.
+    pub fn exec_batch_if_unchanged(
+        &self,
+        batch: RocksDBWriteBatch,
+        expected: &[(Key, Option<Hash>)],
+    ) -> Result<bool> {
+        let _write_lock = self.lock_writes()?;
+        for (key, expected_hash) in expected {
+            let hash = self.read_subspace_val(key)?.map(Hash::sha256);
+            if hash != *expected_hash {
+                tracing::debug!(
+                    "The value of the key {key} has changed, the batch is not \
+                     written"
+                );
+                return Ok(false);
+            }
+        }
+        self.write_batch(batch, &self.write_options())?;
+        Ok(true)
+    }
+
+    /// The options of the writes of the batches, per the durability mode
+    fn write_options(&self) -> WriteOptions {
+        let mut write_opts = WriteOptions::default();
+        match self.durability {
+            DurabilityMode::Sync => write_opts.set_sync(true),
+            DurabilityMode::WalDefault => {}
+            DurabilityMode::Periodic { .. } => write_opts.disable_wal(true),
+        }
+        write_opts
+    }
+
+    /// Start a flush of the memtables if the last one is older than the
+    /// interval of the [`DurabilityMode::Periodic`] mode
+    fn flush_periodically(&self) -> Result<()> {
+        let DurabilityMode::Periodic { every } = self.durability else {
+            return Ok(());
+        };
+        let mut last_flush = self.last_flush.lock().unwrap();
+        if last_flush.elapsed() >= every {
+            self.flush(false)?;
+            *last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Write a batch, with the write lock held by the caller
+    fn write_batch(
+        &self,
+        batch: RocksDBWriteBatch,
+        write_opts: &WriteOptions,
+    ) -> Result<()> {
+        let RocksDBWriteBatch {
+            inner: batch,
+            size_diff: _,
+            block_height,
+            changed_keys: changes,
+        } = batch;
+        let result = self
+            .inner
+            .write_opt(batch, write_opts)
+            .map_err(|e| write_error(e.into_string()));
+        self.poll_background_status();
+        // The non-persisted diffs of the block are only stale once it's
+        // written, see [`rollback_compaction_filter`]
+        if let (Ok(()), Some(height)) = (&result, block_height) {
+            self.rollback_tip.store(height.0, Ordering::Relaxed);
+        }
+        if let (Ok(()), Some(sink), Some(height)) =
+            (&result, &self.change_sink, block_height)
+        {
+            let changes = changes
+                .into_iter()
+                .map(|(key, (has_old, has_new))| {
+                    (key, ChangeKind::from_diffs(has_old, has_new))
+                })
+                .collect();
+            sink.on_block(height, changes);
+        }
+        result
+    }
+
+    /// Rollback to previous block. Given the inner working of tendermint
+    /// rollback and of the key structure of Namada, calling rollback more than
+    /// once without restarting the chain results in a single rollback.
+    ///
+    /// Returns the counts of the changes made, which are also logged.
+    pub fn rollback(
+        &mut self,
+        tendermint_block_height: BlockHeight,
+    ) -> Result<RollbackStats> {
+        let last_block = self.read_last_block()?.ok_or(Error::DBError(
+            "Missing last block in storage".to_string(),
+        ))?;
+        tracing::info!(
+            "Namada last block height: {}, Tendermint last block height: {}",
+            last_block.height,
+            tendermint_block_height
+        );
+
+        // If the block height to which tendermint rolled back matches the
+        // Namada height, there's no need to rollback
+        if tendermint_block_height == last_block.height {
+            tracing::info!(
+                "Namada height already matches the rollback Tendermint \
+                 height, no need to rollback."
+            );
+            return Ok(RollbackStats::default());
+        }
+
+        let mut stats = RollbackStats::default();
+        let mut batch = RocksDB::batch();
+        let previous_height =
+            last_block.height.prev_height().expect("Must have a pred");
+
+        // The previous state is restored from the diffs of the last height
+        let state_cf = self.get_column_family(STATE_CF)?;
+        if let Some(oldest_retained) =
+            self.read_value::<BlockHeight>(state_cf, DIFFS_RETAINED_FROM_KEY)?
+        {
+            if oldest_retained > last_block.height {
+                return Err(Error::RollbackBeyondRetention {
+                    target: previous_height,
+                    oldest_retained,
+                });
+            }
+        }
+
+        // Revert the non-height-prepended metadata storage keys which get
+        // updated with every block. Because of the way we save these
+        // three keys in storage we can only perform one rollback before
+        // restarting the chain
+        tracing::info!("Reverting non-height-prepended metadata keys");
+        let previous_height_bytes = encode(&previous_height);
+        self.add_state_value_hash_to_batch(
+            state_cf,
+            BLOCK_HEIGHT_KEY,
+            &previous_height_bytes,
+            &mut batch,
+        );
+        batch.inner.put_cf(state_cf, BLOCK_HEIGHT_KEY, previous_height_bytes);
+        for metadata_key in [
+            NEXT_EPOCH_MIN_START_HEIGHT_KEY,
+            NEXT_EPOCH_MIN_START_TIME_KEY,
+            COMMIT_ONLY_DATA_KEY,
+            UPDATE_EPOCH_BLOCKS_DELAY_KEY,
+        ] {
+            let previous_key = format!("{PRED_KEY_PREFIX}/{metadata_key}");
+            let previous_value = self
+                .read_value_bytes(state_cf, &previous_key)?
+                .ok_or(Error::UnknownKey { key: previous_key })?;
+
+            self.add_state_value_hash_to_batch(
+                state_cf,
+                metadata_key,
+                &previous_value,
+                &mut batch,
+            );
+            self.add_value_bytes_to_batch(
+                state_cf,
+                metadata_key,
+                previous_value,
+                &mut batch,
+            );
+            // NOTE: we cannot restore the "pred/" keys themselves since we
+            // don't have their predecessors in storage, but there's no need to
+            // since we cannot do more than one rollback anyway because of
+            // CometBFT.
+        }
+        // The commit-only data of the previous height can be queried, so its
+        // stale predecessor is deleted rather than left to be read as if it
+        // belonged to the height before
+        batch.inner.delete_cf(
+            state_cf,
+            format!("{PRED_KEY_PREFIX}/{COMMIT_ONLY_DATA_KEY}"),
+        );
+
+        // Revert conversion state if the epoch had been changed
+        if last_block.pred_epochs.get_epoch(previous_height)
+            != Some(last_block.epoch)
         {
             let previous_key =
                 format!("{PRED_KEY_PREFIX}/{CONVERSION_STATE_KEY}");
             let previous_value = self
                 .read_value_bytes(state_cf, &previous_key)?
                 .ok_or(Error::UnknownKey { key: previous_key })?;
+            self.add_state_value_hash_to_batch(
+                state_cf,
+                CONVERSION_STATE_KEY,
+                &previous_value,
+                &mut batch,
+            );
             self.add_value_bytes_to_batch(
                 state_cf,
                 CONVERSION_STATE_KEY,
@@ -586,25 +2932,35 @@ impl RocksDB {
         // Delete block results for the last block
         let block_cf = self.get_column_family(BLOCK_CF)?;
         tracing::info!("Removing last block results");
-        batch.0.delete_cf(
+        batch.inner.delete_cf(
             block_cf,
-            format!("{RESULTS_KEY_PREFIX}/{}", last_block.height),
+            format!("{RESULTS_KEY_PREFIX}/{}", last_block.height.raw()),
         );
+        stats.block_keys_deleted = 1;
 
         // Restore the state of replay protection to the last block
         let reprot_cf = self.get_column_family(REPLAY_PROTECTION_CF)?;
         tracing::info!("Restoring replay protection state");
         // Remove the "current" tx hashes
-        for (ref current_key, _, _) in self.iter_current_replay_protection() {
-            batch.0.delete_cf(reprot_cf, current_key);
+        let current_prefix = replay_protection::current_prefix();
+        for (ref current_key, _) in
+            iter_prefix_no_gas(self, reprot_cf, None, Some(&current_prefix))
+        {
+            batch.inner.delete_cf(reprot_cf, current_key);
+            stats.replay_protection_entries_removed =
+                stats.replay_protection_entries_removed.saturating_add(1);
         }
 
         // Execute next step in parallel
         let batch = Mutex::new(batch);
+        let restored = AtomicU64::new(0);
+        let deleted = AtomicU64::new(0);
 
         tracing::info!("Restoring previous height subspace diffs");
-        self.iter_prefix(None).par_bridge().try_for_each(
-            |(key, _value, _gas)| -> Result<()> {
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        iter_prefix_no_gas(self, subspace_cf, None, None)
+            .par_bridge()
+            .try_for_each(|(key, value)| -> Result<()> {
                 // Restore previous height diff if present, otherwise delete the
                 // subspace key
                 let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
@@ -613,40 +2969,47 @@ impl RocksDB {
                     previous_height,
                     last_block.height,
                 )? {
-                    Some(previous_value) => batch.lock().unwrap().0.put_cf(
-                        subspace_cf,
-                        &key,
-                        previous_value,
-                    ),
+                    Some(previous_value) => {
+                        // Only the changed values are counted as restored
+                        if self.decrypt_subspace_val(Some(value))?.as_ref()
+                            != Some(&previous_value)
+                        {
+                            restored.fetch_add(1, Ordering::Relaxed);
+                        }
+                        batch.lock().unwrap().inner.put_cf(
+                            subspace_cf,
+                            &key,
+                            self.encrypt_subspace_val(&previous_value),
+                        )
+                    }
                     None => {
-                        batch.lock().unwrap().0.delete_cf(subspace_cf, &key)
+                        deleted.fetch_add(1, Ordering::Relaxed);
+                        batch.lock().unwrap().inner.delete_cf(subspace_cf, &key)
                     }
                 }
 
                 Ok(())
-            },
-        )?;
+            })?;
 
         let mut batch = batch.into_inner().unwrap();
+        stats.subspace_keys_restored = restored.into_inner();
+        stats.subspace_keys_deleted = deleted.into_inner();
 
         let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
         let diffs_cf = self.get_column_family(DIFFS_CF)?;
         // Look for diffs in this block to find what has been deleted
-        let diff_new_key_prefix = Key {
-            segments: vec![
-                last_block.height.to_db_key(),
-                NEW_DIFF_PREFIX.to_string().to_db_key(),
-            ],
-        };
-        for (key_str, val, _) in
-            iter_diffs_prefix(self, diffs_cf, last_block.height, None, true)
+        for (key_str, val) in
+            iter_diffs_prefix_no_gas(self, diffs_cf, last_block.height, true)
         {
             let key = Key::parse(&key_str).unwrap();
-            let diff_new_key = diff_new_key_prefix.join(&key);
-            if self.read_subspace_val(&diff_new_key)?.is_none() {
+            let (_old_key, new_key) =
+                old_and_new_diff_key(&key, last_block.height)?;
+            if self.read_value_bytes(diffs_cf, new_key)?.is_none() {
                 // If there is no new value, it has been deleted in this
                 // block and we have to restore it
-                batch.0.put_cf(subspace_cf, key_str, val)
+                batch.inner.put_cf(subspace_cf, key_str, val);
+                stats.subspace_keys_restored =
+                    stats.subspace_keys_restored.saturating_add(1);
             }
         }
 
@@ -654,28 +3017,36 @@ impl RocksDB {
         let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
         // Iterate the old keys first and keep a set of keys that have old val
         let mut keys_with_old_value = HashSet::<String>::new();
-        for (key_str, val, _) in
-            iter_diffs_prefix(self, rollback_cf, last_block.height, None, true)
+        for (key_str, val) in
+            iter_diffs_prefix_no_gas(self, rollback_cf, last_block.height, true)
         {
             // If there is no new value, it has been deleted in this
             // block and we have to restore it
             keys_with_old_value.insert(key_str.clone());
-            batch.0.put_cf(subspace_cf, key_str, val)
+            batch.inner.put_cf(subspace_cf, key_str, val);
+            stats.subspace_keys_restored =
+                stats.subspace_keys_restored.saturating_add(1);
         }
         // Then the new keys
-        for (key_str, _val, _) in
-            iter_diffs_prefix(self, rollback_cf, last_block.height, None, false)
-        {
+        for (key_str, _val) in iter_diffs_prefix_no_gas(
+            self,
+            rollback_cf,
+            last_block.height,
+            false,
+        ) {
             if !keys_with_old_value.contains(&key_str) {
                 // If there was no old value it means that the key was newly
                 // written in the last block and we have to delete it
-                batch.0.delete_cf(subspace_cf, key_str)
+                batch.inner.delete_cf(subspace_cf, key_str);
+                stats.subspace_keys_deleted =
+                    stats.subspace_keys_deleted.saturating_add(1);
             }
         }
 
         tracing::info!("Deleting keys prepended with the last height");
-        let prefix = last_block.height.to_string();
-        let mut delete_keys = |cf: &ColumnFamily| {
+        let prefix = last_block.height.raw();
+        let mut delete_keys = |cf: &ColumnFamily| -> u64 {
+            let mut deleted = 0_u64;
             let read_opts = make_iter_read_opts(Some(prefix.clone()));
             let iter = self.inner.iterator_cf_opt(
                 cf,
@@ -685,19 +3056,41 @@ impl RocksDB {
             for (key, _value, _gas) in PersistentPrefixIterator(
                 // Empty prefix string to prevent stripping
                 PrefixIterator::new(iter, String::default()),
+                None,
             ) {
-                batch.0.delete_cf(cf, key);
+                batch.inner.delete_cf(cf, key);
+                deleted = deleted.saturating_add(1);
             }
+            deleted
         };
         // Delete any height-prepended key in subspace diffs
         let diffs_cf = self.get_column_family(DIFFS_CF)?;
-        delete_keys(diffs_cf);
+        stats.diff_keys_deleted = delete_keys(diffs_cf);
         // Delete any height-prepended key in the block
-        delete_keys(block_cf);
+        stats.block_keys_deleted = stats
+            .block_keys_deleted
+            .saturating_add(delete_keys(block_cf));
+        // Delete the archive of the block, if any
+        if let Some(archive_cf) = self.inner.cf_handle(ARCHIVE_CF) {
+            delete_keys(archive_cf);
+        }
 
         // Write the batch and persist changes to disk
         tracing::info!("Flushing restored state to disk");
-        self.exec_batch(batch)
+        self.exec_batch(batch)?;
+        self.rollback_tip
+            .store(previous_height.0, Ordering::Relaxed);
+        tracing::info!(
+            height = %previous_height,
+            subspace_keys_restored = stats.subspace_keys_restored,
+            subspace_keys_deleted = stats.subspace_keys_deleted,
+            diff_keys_deleted = stats.diff_keys_deleted,
+            block_keys_deleted = stats.block_keys_deleted,
+            replay_protection_entries_removed =
+                stats.replay_protection_entries_removed,
+            "Rolled back the last block"
+        );
+        Ok(stats)
     }
 
     #[inline]
@@ -717,1677 +3110,6731 @@ impl RocksDB {
             .unwrap()
     }
 
-    /// Read diffs of non-persisted key-vals that are only kept for rollback of
-    /// one block height.
-    #[cfg(test)]
-    pub fn read_rollback_val(
-        &self,
-        key: &Key,
-        height: BlockHeight,
-        is_old: bool,
-    ) -> Result<Option<Vec<u8>>> {
-        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
-        let key = if is_old {
-            old_and_new_diff_key(key, height)?.0
-        } else {
-            old_and_new_diff_key(key, height)?.1
-        };
+    /// Read only the height of the last committed block. Returns `None` if no
+    /// block has been committed yet.
+    pub fn last_committed_height(&self) -> Result<Option<BlockHeight>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        self.read_verified_value(state_cf, BLOCK_HEIGHT_KEY)
+    }
 
-        self.inner
-            .get_cf(rollback_cf, key)
-            .map_err(|e| Error::DBError(e.into_string()))
+    /// Read only the height of the last Ethereum block processed by the
+    /// bridge, without reading the rest of the last block's state. Returns
+    /// `None` if no block has been committed yet or if no Ethereum block has
+    /// been processed.
+    pub fn ethereum_height(
+        &self,
+    ) -> Result<Option<ethereum_structs::BlockHeight>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        Ok(self
+            .read_value::<Option<_>>(state_cf, ETHEREUM_HEIGHT_KEY)?
+            .flatten())
     }
-}
 
-/// Information about a particular snapshot
-/// owned by a node
-pub struct SnapshotMetadata {
-    /// The height at which the snapshot was taken
-    pub height: BlockHeight,
-    /// The name of the paths to the file and metadata
-    /// holding the snapshot minus extensions
-    pub path_stem: String,
-    /// Data about the chunks that the snapshot is
-    /// partitioned into
-    pub chunks: Vec<Chunk>,
-}
+    /// Read only the queue of the Ethereum events to be processed, without
+    /// reading the rest of the last block's state. Returns `None` if no block
+    /// has been committed yet.
+    pub fn eth_events_queue(&self) -> Result<Option<EthEventsQueue>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        self.read_value(state_cf, ETH_EVENTS_QUEUE_KEY)
+    }
 
-pub struct DbSnapshot<'a>(pub rocksdb::Snapshot<'a>);
+    /// Read only the conversion state of the last block, without reading the
+    /// rest of the last block's state, e.g. to display or audit the allowed
+    /// conversions. Returns `None` if no block has been committed yet.
+    pub fn conversion_state(&self) -> Result<Option<ConversionState>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        match self.read_verified_value(state_cf, CONVERSION_STATE_KEY) {
+            Err(e @ (Error::CodingError(_) | Error::CorruptValue { .. })) => {
+                match self.last_committed_height()? {
+                    Some(height) => {
+                        Err(Error::CorruptConversionState { height })
+                    }
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
 
-impl<'a> DbSnapshot<'a> {
-    /// Write a snapshot of the database out to file. The last line
-    /// of the file contains metadata about how to break the file into
-    /// chunks.
-    pub fn write_to_file(
+    /// Read the commit-only data commitment of the block at the given
+    /// height, e.g. to verify the components of its app hash. Returns `None`
+    /// if no block has been committed yet, or if the height is the one
+    /// before the first block.
+    ///
+    /// The commitment isn't stored per height. Only the value of the last
+    /// committed block is kept, along with that of its predecessor to roll
+    /// it back. Like the replay protection entries, which are only dumped at
+    /// the last height, it therefore can't be read for the older heights,
+    /// nor for the heights above the last block, which are an error. A
+    /// rollback deletes the predecessor, as it can't be restored, so after a
+    /// rollback and until the next block is committed, the height before the
+    /// last one is an error too.
+    pub fn commit_only_data_at(
         &self,
-        cfs: [(&'static str, &'a ColumnFamily); 6],
-        base_dir: PathBuf,
         height: BlockHeight,
-    ) -> std::io::Result<()> {
-        let [snap_file, metadata_file] = Self::paths(height, base_dir);
-        let file = File::create(snap_file)?;
-        let mut buf = BufWriter::new(file);
-        let mut chunker = Chunker::new(MAX_CHUNK_SIZE);
-        for (cf_name, cf) in cfs {
-            let read_opts = make_iter_read_opts(None);
-            let iter =
-                self.0.iterator_cf_opt(cf, read_opts, IteratorMode::Start);
-
-            for (key, raw_val, _gas) in PersistentPrefixIterator(
-                PrefixIterator::new(iter, String::default()),
-                // Empty string to prevent prefix stripping, the prefix is
-                // already in the enclosed iterator
-            ) {
-                let val = base64::encode(raw_val);
-                let bytes = format!("{cf_name}:{key}={val}\n");
-                chunker.add_line(&bytes);
-                buf.write_all(bytes.as_bytes())?;
+    ) -> Result<Option<CommitOnlyData>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let Some(last_height) = self.last_committed_height()? else {
+            return Ok(None);
+        };
+        if height == last_height {
+            self.read_value(state_cf, COMMIT_ONLY_DATA_KEY)
+        } else if Some(height) == last_height.prev_height() {
+            let pred: Option<CommitOnlyData> = self.read_value(
+                state_cf,
+                format!("{PRED_KEY_PREFIX}/{COMMIT_ONLY_DATA_KEY}"),
+            )?;
+            if pred.is_none() && last_height != BlockHeight::first() {
+                return Err(Error::DBError(format!(
+                    "The commit-only data of the height {height} is unknown \
+                     since the last rollback"
+                )));
             }
-            buf.flush()?;
+            Ok(pred)
+        } else {
+            Err(Error::DBError(format!(
+                "The commit-only data is only available at the last height \
+                 {last_height} and the one before, not at the height {height}"
+            )))
         }
-        buf.flush()?;
-        let chunks = chunker.finalize();
-        let metadata = base64::encode(chunks.serialize_to_vec());
-        std::fs::write(metadata_file, metadata.as_bytes())?;
-        Ok(())
     }
 
-    /// Remove snapshots older than the latest
-    pub fn cleanup(
-        latest_height: BlockHeight,
-        base_dir: &Path,
-    ) -> std::io::Result<()> {
-        for SnapshotMetadata {
-            height, path_stem, ..
-        } in Self::files(base_dir)?
+    /// Check which of the values read with the last block are present in the
+    /// DB. A block whose height is committed but that is missing some of
+    /// these values has only been partially written.
+    pub fn diagnose_last_block(&self) -> Result<LastBlockDiagnosis> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let mut diagnosis = LastBlockDiagnosis {
+            height: self.read_value(state_cf, BLOCK_HEIGHT_KEY)?,
+            present: vec![],
+            missing: vec![],
+        };
+        let Some(height) = diagnosis.height else {
+            return Ok(diagnosis);
+        };
+
+        let state_keys = [
+            NEXT_EPOCH_MIN_START_HEIGHT_KEY,
+            NEXT_EPOCH_MIN_START_TIME_KEY,
+            UPDATE_EPOCH_BLOCKS_DELAY_KEY,
+            COMMIT_ONLY_DATA_KEY,
+            CONVERSION_STATE_KEY,
+            ETHEREUM_HEIGHT_KEY,
+            ETH_EVENTS_QUEUE_KEY,
+        ]
+        .map(|key| (state_cf, key.to_string()));
+        let prefix = height.raw();
+        let block_keys = [
+            BLOCK_TIME_KEY_SEGMENT,
+            EPOCH_KEY_SEGMENT,
+            PRED_EPOCHS_KEY_SEGMENT,
+            ADDRESS_GEN_KEY_SEGMENT,
+        ]
+        .map(|segment| (block_cf, format!("{prefix}/{segment}")));
+        let results_key = (block_cf, format!("{RESULTS_KEY_PREFIX}/{prefix}"));
+
+        for (cf, key) in state_keys
+            .into_iter()
+            .chain([results_key])
+            .chain(block_keys)
         {
-            if height < latest_height {
-                let path = PathBuf::from(path_stem);
-                _ = std::fs::remove_file(&path.with_extension("snap"));
-                _ = std::fs::remove_file(path.with_extension("meta"));
+            if self.read_value_bytes(cf, &key)?.is_some() {
+                diagnosis.present.push(key);
+            } else {
+                diagnosis.missing.push(key);
             }
         }
-        Ok(())
+        Ok(diagnosis)
     }
 
-    /// List all snapshot files along with the block height at which
-    /// they were created and their chunks.
-    pub fn files(base_dir: &Path) -> std::io::Result<Vec<SnapshotMetadata>> {
-        let snap = OsStr::new("snap");
-        let meta = OsStr::new("meta");
-        let mut files =
-            HashMap::<BlockHeight, (Option<String>, Option<Vec<Chunk>>)>::new();
-        for entry in std::fs::read_dir(base_dir)? {
-            let entry = entry?;
-            let entry_path = entry.path();
-            let entry_ext = entry_path.extension();
-            if entry_path.is_file()
-                && (Some(snap) == entry_ext || Some(meta) == entry_ext)
-            {
-                if let Some(name) = entry.path().file_name() {
-                    // Extract the block height from the file name
-                    // (assuming the file name is of the correct format)
-                    let Some(height) = name
-                        .to_string_lossy()
-                        .strip_prefix("snapshot_")
-                        .and_then(|n| {
-                            n.strip_suffix(".meta").or(n.strip_suffix(".snap"))
-                        })
-                        .and_then(|h| BlockHeight::from_str(h).ok())
-                    else {
-                        continue;
-                    };
-                    // check if we have found the metadata file or snapshot file
-                    // for a given block height
-                    if entry_ext == Some(meta) {
-                        let metadata = std::fs::read_to_string(entry_path)?;
-                        let metadata_bytes = HEXLOWER
-                            .decode(metadata.as_bytes())
-                            .map_err(|e| {
-                                std::io::Error::new(ErrorKind::InvalidData, e)
-                            })?;
-                        let chunks: Vec<Chunk> =
-                            BorshDeserialize::try_from_slice(
-                                &metadata_bytes[..],
-                            )?;
-                        files.entry(height).or_default().1 = Some(chunks);
-                    } else {
-                        files.entry(height).or_default().0 = Some(
-                            base_dir
-                                .join(format!("snapshot_{}", height))
-                                .to_string_lossy()
-                                .into(),
-                        );
-                    }
-                };
-            }
+    /// Recover from an [`Error::CorruptConversionState`] by restoring the
+    /// conversion state from its copy under the `pred/` prefix, after
+    /// checking that the copy can be decoded.
+    ///
+    /// The conversion state is only written when the epoch changes, so the
+    /// copy is the conversion state from before the last epoch change. The
+    /// restored state is therefore an epoch behind the last block and the
+    /// DB is meant to be rolled back to the previous epoch afterwards, e.g.
+    /// by re-syncing the blocks of the last epoch.
+    pub fn reconstruct_conversion_state_from_pred(&mut self) -> Result<()> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let pred_key = format!("{PRED_KEY_PREFIX}/{CONVERSION_STATE_KEY}");
+        let pred_value = self
+            .read_value_bytes(state_cf, &pred_key)?
+            .ok_or(Error::UnknownKey { key: pred_key })?;
+        decode::<ConversionState>(&pred_value).map_err(Error::CodingError)?;
+
+        let mut batch = Self::batch();
+        self.add_state_value_hash_to_batch(
+            state_cf,
+            CONVERSION_STATE_KEY,
+            &pred_value,
+            &mut batch,
+        );
+        self.add_value_bytes_to_batch(
+            state_cf,
+            CONVERSION_STATE_KEY,
+            pred_value,
+            &mut batch,
+        );
+        self.exec_batch(batch)
+    }
+
+    /// Look up the epoch of the block at the given height. The epoch is read
+    /// from the block's own `epoch` key when present, otherwise it's derived
+    /// from the predecessor epochs of the last committed block. Returns `None`
+    /// for heights above the last committed block or below the first block.
+    pub fn epoch_at_height(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<Epoch>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+
+        let Some(last_height) = self.last_committed_height()? else {
+            return Ok(None);
+        };
+        if height > last_height {
+            return Ok(None);
         }
-        let mut res = Vec::with_capacity(files.len());
-        for (height, (path, chunks)) in files {
-            // only include snapshots which have both a .snap and .meta file.
-            if let Some((path_stem, chunks)) = path.zip(chunks) {
-                res.push(SnapshotMetadata {
-                    height,
-                    path_stem,
-                    chunks,
-                });
+
+        let epoch_key = format!("{}/{EPOCH_KEY_SEGMENT}", height.raw());
+        if let Some(epoch) = self.read_value(block_cf, epoch_key)? {
+            return Ok(Some(epoch));
+        }
+
+        // The block data at this height is not available, fall back to the
+        // predecessor epochs of the last block
+        let pred_epochs_key =
+            format!("{}/{PRED_EPOCHS_KEY_SEGMENT}", last_height.raw());
+        let pred_epochs: Epochs =
+            match self.read_value(block_cf, pred_epochs_key)? {
+                Some(pred_epochs) => pred_epochs,
+                None => return Ok(None),
+            };
+        match pred_epochs.first_block_heights().first() {
+            Some(first_height) if height >= *first_height => {
+                Ok(pred_epochs.get_epoch(height))
             }
+            _ => Ok(None),
         }
-        Ok(res)
     }
 
-    /// Create a path to save a snapshot at a specific block height.
-    pub fn paths(height: BlockHeight, base_dir: PathBuf) -> [PathBuf; 2] {
-        let snap_file = base_dir.join(format!("snapshot_{}.snap", height));
-        let metadata_file = base_dir.join(format!("snapshot_{}.meta", height));
-        [snap_file, metadata_file]
+    /// Read the time of the block at the given height. Returns `None` if the
+    /// block never existed or its data has been pruned.
+    pub fn block_time(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Option<DateTimeUtc>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let time_key = format!("{}/{BLOCK_TIME_KEY_SEGMENT}", height.raw());
+        self.read_value(block_cf, time_key)
     }
 
-    /// Load the specified chunk of a snapshot at the given block height
-    pub fn load_chunk(
+    /// Read the established address generator as of the block at the given
+    /// height, e.g. to replay the execution of its txs with the same
+    /// generated addresses. Returns `None` if the block never existed or its
+    /// data has been pruned.
+    pub fn address_gen_at(
+        &self,
         height: BlockHeight,
-        chunk: u64,
-        base_dir: &Path,
-    ) -> std::io::Result<Vec<u8>> {
-        let files = Self::files(base_dir)?;
-        let Some(metadata) = files.into_iter().find(|m| m.height == height)
-        else {
-            return Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                format!(
-                    "Could not find the metadata file for the snapshot at \
-                     height {}",
-                    height,
-                ),
-            ));
-        };
-        let chunk_start = if chunk == 0 {
-            0usize
-        } else {
-            let prev = checked!(usize::try_from(chunk).unwrap() - 1).unwrap();
-            usize::try_from(metadata.chunks[prev].boundary).unwrap()
-        };
-        let chunk_end = metadata
-            .chunks
-            .get(usize::try_from(chunk).unwrap())
-            .ok_or_else(|| {
-                std::io::Error::new(
-                    ErrorKind::InvalidInput,
-                    format!("Chunk {} not found", chunk),
-                )
-            })?
-            .boundary;
-        let chunk_end = usize::try_from(chunk_end).unwrap();
+    ) -> Result<Option<EstablishedAddressGen>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let address_gen_key =
+            format!("{}/{ADDRESS_GEN_KEY_SEGMENT}", height.raw());
+        self.read_value(block_cf, address_gen_key)
+    }
 
-        let file = File::open(
-            PathBuf::from(metadata.path_stem).with_extension("snap"),
-        )?;
-        let reader = BufReader::new(file);
-        let mut bytes: Vec<u8> = vec![];
-        for line in reader
-            .lines()
-            .skip(chunk_start)
-            .take(checked!(chunk_end - chunk_start).unwrap())
-        {
-            bytes.extend(line?.as_bytes());
+    /// Read the total gas used by the txs of each block in the given
+    /// inclusive range of heights, e.g. to chart the gas usage over time.
+    ///
+    /// The heights whose gas is not available, because their block data has
+    /// been pruned or was written before the gas got recorded, are skipped
+    /// with a warning.
+    pub fn gas_per_height(
+        &self,
+        from: BlockHeight,
+        to: BlockHeight,
+    ) -> Result<Vec<(BlockHeight, u64)>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let mut gas_per_height = vec![];
+        for height in (from.0..=to.0).map(BlockHeight) {
+            let gas_key = format!("{}/{BLOCK_GAS_KEY_SEGMENT}", height.raw());
+            match self.read_value(block_cf, gas_key)? {
+                Some(gas) => gas_per_height.push((height, gas)),
+                None => {
+                    tracing::warn!(
+                        "The gas of the block at height {height} is not \
+                         available, skipping it"
+                    );
+                }
+            }
         }
-        Ok(bytes)
+        Ok(gas_per_height)
     }
-}
 
-/// A chunk of a snapshot. Includes the last line number in the file
-/// for this chunk and a hash of the chunk contents.
-#[derive(
-    Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize, Hash,
-)]
-pub struct Chunk {
-    /// The line number ending the chunk
-    pub boundary: u64,
-    /// Sha256 hash of the chunk
-    pub hash: Hash,
-}
+    /// Get the epochs whose merkle tree stores of the given type are still
+    /// retained in the DB, in ascending order.
+    ///
+    /// The stores that are written at every height (e.g. the base tree) are
+    /// keyed by height rather than epoch, so for these the epochs of the
+    /// retained heights are returned.
+    pub fn retained_merkle_epochs(
+        &self,
+        store_type: &StoreType,
+    ) -> Result<Vec<Epoch>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let is_stored_every_block = store_type.is_stored_every_block();
+        let store_type = store_type.to_string();
+        let mut prefixes = vec![];
+        // Merkle tree roots are under
+        // `{epoch_or_height}/tree/{store_type}/root`
+        for (key, _val) in iter_prefix_no_gas(self, block_cf, None, None) {
+            let segments: Vec<_> = key.split(KEY_SEGMENT_SEPARATOR).collect();
+            if let [prefix, "tree", st, MERKLE_TREE_ROOT_KEY_SEGMENT] =
+                segments[..]
+            {
+                if st == store_type {
+                    prefixes.push(
+                        u64::parse(prefix.to_owned())
+                            .map_err(Error::KeyError)?,
+                    );
+                }
+            }
+        }
 
-/// Builds a set of chunks from a stream of lines to be
-/// written to a file.
-#[derive(Debug, Clone)]
-struct Chunker {
-    chunks: Vec<Chunk>,
-    max_size: usize,
-    current_boundary: u64,
-    current_size: usize,
-    hasher: Sha256,
-}
-impl Chunker {
-    fn new(max_size: usize) -> Self {
-        Self {
-            chunks: vec![],
-            max_size,
-            current_boundary: 0,
-            current_size: 0,
-            hasher: Sha256::default(),
+        if !is_stored_every_block {
+            prefixes.sort_unstable();
+            return Ok(prefixes.into_iter().map(Epoch).collect());
         }
-    }
 
-    fn add_line(&mut self, line: &str) {
-        if checked!(self.current_size + line.as_bytes().len()).unwrap()
-            > self.max_size
-            && self.current_boundary != 0
-        {
-            let mut hasher = Sha256::default();
-            std::mem::swap(&mut hasher, &mut self.hasher);
-            let hash: [u8; 32] = hasher.finalize().into();
-            self.chunks.push(Chunk {
-                boundary: self.current_boundary,
-                hash: Hash(hash),
-            });
-            self.current_size = 0;
+        let mut epochs = BTreeSet::new();
+        for height in prefixes {
+            if let Some(epoch) = self.epoch_at_height(BlockHeight(height))? {
+                epochs.insert(epoch);
+            }
         }
-
-        checked!(self.current_size += line.as_bytes().len()).unwrap();
-        self.hasher.update(line.as_bytes());
-        checked!(self.current_boundary += 1).unwrap();
+        Ok(epochs.into_iter().collect())
     }
 
-    fn finalize(mut self) -> Vec<Chunk> {
-        let hash: [u8; 32] = self.hasher.finalize().into();
-        self.chunks.push(Chunk {
-            boundary: self.current_boundary,
-            hash: Hash(hash),
-        });
-        self.chunks
+    /// Recompute the merkle root of the given store type from its persisted
+    /// store and check it matches the persisted root. The stores that are
+    /// written at every height (e.g. the base tree) are checked at the last
+    /// retained height of the epoch.
+    ///
+    /// A mismatch, including a store that cannot be decoded, indicates a
+    /// corruption and returns `false`. Only a missing root or store is an
+    /// error.
+    pub fn verify_merkle_root(
+        &self,
+        store_type: &StoreType,
+        epoch: Epoch,
+    ) -> Result<bool> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let key_prefix = if store_type.is_stored_every_block() {
+            let mut height = None;
+            for h in self.committed_block_heights()?.into_iter().rev() {
+                if self.epoch_at_height(h)? == Some(epoch) {
+                    height = Some(h);
+                    break;
+                }
+            }
+            let height = height.ok_or_else(|| {
+                Error::DBError(format!(
+                    "No retained block in epoch {epoch} to verify the \
+                     {store_type} merkle root"
+                ))
+            })?;
+            tree_key_prefix_with_height(store_type, height)
+        } else {
+            tree_key_prefix_with_epoch(store_type, epoch)
+        };
+
+        let root_key = format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
+        let root: Hash =
+            self.read_value(block_cf, &root_key)?.ok_or_else(|| {
+                Error::DBError(format!(
+                    "The merkle root under \"{root_key}\" is missing"
+                ))
+            })?;
+        let store_key = format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
+        let bytes =
+            self.read_value_bytes(block_cf, &store_key)?
+                .ok_or_else(|| {
+                    Error::DBError(format!(
+                        "The merkle tree store under \"{store_key}\" is \
+                         missing"
+                    ))
+                })?;
+        let Ok(store) = store_type.decode_store(bytes) else {
+            tracing::warn!(
+                "The merkle tree store under \"{store_key}\" cannot be decoded"
+            );
+            return Ok(false);
+        };
+        Ok(store.validate_root::<storage::PersistentStorageHasher>(root))
     }
-}
 
-impl DB for RocksDB {
-    type Cache = rocksdb::Cache;
-    type Migrator = DbUpdateType;
-    type WriteBatch = RocksDBWriteBatch;
+    /// Write the root and store of the given store type from `stores` under
+    /// the given epoch, e.g. to repair the tree of an index at an arbitrary
+    /// height without a full block commit.
+    ///
+    /// This bypasses the commit gating of [`DB::add_block_to_batch`], which
+    /// only writes these stores on a new epoch, and overwrites the stores
+    /// already committed in the epoch, if any. The store types that are
+    /// written at every height (e.g. the base tree) are keyed by height
+    /// rather than epoch, so they're rejected. So is a store type with no
+    /// root in `stores` or whose store doesn't match its root.
+    pub fn commit_store_type(
+        &self,
+        batch: &mut RocksDBWriteBatch,
+        store_type: &StoreType,
+        epoch: Epoch,
+        stores: &MerkleTreeStoresRead,
+    ) -> Result<()> {
+        if store_type.is_stored_every_block() {
+            return Err(Error::DBError(format!(
+                "The {store_type} merkle tree is committed at every height, \
+                 it can't be committed under an epoch"
+            )));
+        }
+        if !stores.has_root(store_type) {
+            return Err(Error::DBError(format!(
+                "The {store_type} merkle root is missing from the stores"
+            )));
+        }
+        let root = stores.get_root(*store_type);
+        let store_bytes = stores.get_store(*store_type).encode();
+        let store = store_type.decode_store(&store_bytes)?;
+        if !store.validate_root::<storage::PersistentStorageHasher>(root) {
+            return Err(Error::DBError(format!(
+                "The {store_type} merkle tree store doesn't match its root"
+            )));
+        }
 
-    fn open(
-        db_path: impl AsRef<std::path::Path>,
-        cache: Option<&Self::Cache>,
-    ) -> Self {
-        open(db_path, false, cache).expect("cannot open the DB")
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let key_prefix = tree_key_prefix_with_epoch(store_type, epoch);
+        let root_key = format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, root_key, &root, batch);
+        let store_key = format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
+        self.add_value_bytes_to_batch(block_cf, store_key, store_bytes, batch);
+        Ok(())
     }
 
-    fn path(&self) -> Option<&Path> {
-        Some(self.inner.path())
+    /// Get the heights of all the committed blocks whose data is retained in
+    /// the DB, in ascending order. The last of the heights is checked to match
+    /// the height of the last committed block.
+    pub fn committed_block_heights(&self) -> Result<Vec<BlockHeight>> {
+        // Every committed block has its results written under the height
+        let heights = self
+            .iter_results()
+            .map(|(key, _val, _gas)| {
+                u64::parse(key).map(BlockHeight).map_err(Error::KeyError)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let last_height = self.last_committed_height()?;
+        if heights.last() != last_height.as_ref() {
+            return Err(Error::DBError(format!(
+                "The last committed block height {last_height:?} doesn't \
+                 match the last height with block data {:?}",
+                heights.last()
+            )));
+        }
+        Ok(heights)
     }
 
-    fn flush(&self, wait: bool) -> Result<()> {
-        let mut flush_opts = FlushOptions::default();
-        flush_opts.set_wait(wait);
-        self.inner
-            .flush_opt(&flush_opts)
-            .map_err(|e| Error::DBError(e.into_string()))
+    /// Iterate over the decoded results of the committed blocks whose data
+    /// is retained in the DB, in ascending order of their heights.
+    ///
+    /// A value that can't be decoded is yielded as an error, unless
+    /// `skip_corrupt`, in which case it's logged and skipped, and its height
+    /// recorded in [`BlockResultsIter::corrupt_heights`]. Skipping is meant
+    /// for bulk indexing that should carry on past an isolated corruption,
+    /// while the integrity checks should fail on it.
+    pub fn iter_block_results(
+        &self,
+        skip_corrupt: bool,
+    ) -> BlockResultsIter<'_> {
+        BlockResultsIter {
+            iter: self.iter_results(),
+            skip_corrupt,
+            corrupt_heights: vec![],
+        }
     }
 
-    fn read_last_block(&self) -> Result<Option<BlockStateRead>> {
-        let state_cf = self.get_column_family(STATE_CF)?;
-        let block_cf = self.get_column_family(BLOCK_CF)?;
+    /// Reconstruct the subspace under a prefix, e.g. the state of an account,
+    /// as it was at the given height. Returns each key under the prefix that
+    /// had a value at that height, with its value.
+    ///
+    /// At the last committed height the subspace is read directly. Below it,
+    /// the values are read with [`DB::read_subspace_val_with_height`] as in
+    /// [`RocksDB::dump_block`], which relies on the diffs of the heights
+    /// since then being retained. The keys deleted since then are found from
+    /// the old values of these diffs.
+    pub fn account_state_at(
+        &self,
+        addr_prefix: &Key,
+        height: BlockHeight,
+    ) -> Result<BTreeMap<Key, Vec<u8>>> {
+        let last_height = self.last_committed_height()?.ok_or_else(|| {
+            Error::DBError("No block has been committed".to_string())
+        })?;
+        if height > last_height {
+            return Err(Error::DBError(format!(
+                "The height {height} is above the last committed height \
+                 {last_height}"
+            )));
+        }
 
-        // Block height
-        let height: BlockHeight =
-            match self.read_value(state_cf, BLOCK_HEIGHT_KEY)? {
-                Some(h) => h,
-                None => return Ok(None),
-            };
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let current =
+            iter_prefix_no_gas(self, subspace_cf, None, Some(addr_prefix));
+        if height == last_height {
+            return current
+                .map(|(key, raw_val)| {
+                    let key = Key::parse(key).map_err(Error::KeyError)?;
+                    let value = self
+                        .decrypt_subspace_val(Some(raw_val))?
+                        .unwrap_or_default();
+                    Ok((key, value))
+                })
+                .collect();
+        }
 
-        // Epoch start height and time
-        let next_epoch_min_start_height =
-            match self.read_value(state_cf, NEXT_EPOCH_MIN_START_HEIGHT_KEY)? {
-                Some(h) => h,
-                None => return Ok(None),
-            };
+        let mut keys: BTreeSet<String> =
+            current.map(|(key, _val)| key).collect();
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let prefix = addr_prefix.to_string();
+        let sub_prefix = format!("{prefix}/");
+        for diff_height in
+            (height.next_height().0..=last_height.0).map(BlockHeight)
+        {
+            keys.extend(
+                iter_diffs_prefix_no_gas(self, diffs_cf, diff_height, true)
+                    .map(|(key, _val)| key)
+                    .filter(|key| {
+                        prefix.is_empty() || key.starts_with(&sub_prefix)
+                    }),
+            );
+        }
 
-        let next_epoch_min_start_time =
-            match self.read_value(state_cf, NEXT_EPOCH_MIN_START_TIME_KEY)? {
-                Some(t) => t,
-                None => return Ok(None),
-            };
+        let mut state = BTreeMap::new();
+        for key in keys {
+            let key = Key::parse(key).map_err(Error::KeyError)?;
+            if let Some(value) =
+                self.read_subspace_val_with_height(&key, height, last_height)?
+            {
+                state.insert(key, value);
+            }
+        }
+        Ok(state)
+    }
 
-        let update_epoch_blocks_delay =
-            match self.read_value(state_cf, UPDATE_EPOCH_BLOCKS_DELAY_KEY)? {
-                Some(d) => d,
-                None => return Ok(None),
-            };
+    /// Iterate the subspace key-vals with the given prefix, with the keys
+    /// parsed and the values Borsh-decoded into `T`. A key that can't be
+    /// parsed or a value that can't be decoded yields an error, after which
+    /// the iteration may go on.
+    pub fn iter_prefix_typed<T: BorshDeserialize>(
+        &self,
+        prefix: Option<&Key>,
+    ) -> impl Iterator<Item = Result<(Key, T)>> + '_ {
+        self.iter_prefix(prefix).map(|(key, val, _gas)| {
+            let key = Key::parse(key).map_err(Error::KeyError)?;
+            let val = decode(val).map_err(Error::CodingError)?;
+            Ok((key, val))
+        })
+    }
 
-        let commit_only_data =
-            match self.read_value(state_cf, COMMIT_ONLY_DATA_KEY)? {
-                Some(d) => d,
-                None => return Ok(None),
-            };
+    /// The subspace keys that changed in the block at the given height,
+    /// sorted by key. The kind of each change is derived from the diffs of
+    /// the key at the height: a key with only an old value was deleted, one
+    /// with only a new value was created and one with both was updated.
+    ///
+    /// Only the keys whose diffs are persisted are listed. The diffs of
+    /// pruned heights are gone, so this errors for a height below the
+    /// lowest one whose diffs are retained.
+    pub fn changed_keys(
+        &self,
+        height: BlockHeight,
+    ) -> Result<Vec<(Key, ChangeKind)>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        if let Some(diffs_retained_from) =
+            self.read_value::<BlockHeight>(state_cf, DIFFS_RETAINED_FROM_KEY)?
+        {
+            if height < diffs_retained_from {
+                return Err(Error::DBError(format!(
+                    "The diffs of height {height} have been pruned, they are \
+                     only retained from height {diffs_retained_from}"
+                )));
+            }
+        }
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let diff_keys = |is_old| -> BTreeSet<String> {
+            iter_diffs_prefix_no_gas(self, diffs_cf, height, is_old)
+                .map(|(key, _val)| key)
+                .collect()
+        };
+        let old_keys = diff_keys(true);
+        let new_keys = diff_keys(false);
+        old_keys
+            .union(&new_keys)
+            .map(|key| {
+                let kind = ChangeKind::from_diffs(
+                    old_keys.contains(key),
+                    new_keys.contains(key),
+                );
+                Ok((Key::parse(key).map_err(Error::KeyError)?, kind))
+            })
+            .collect()
+    }
 
-        let conversion_state =
-            match self.read_value(state_cf, CONVERSION_STATE_KEY)? {
-                Some(c) => c,
-                None => return Ok(None),
-            };
+    /// Compare a column family of this DB against the same column family of
+    /// another DB, e.g. to find the keys on which two validators diverged
+    /// after disagreeing on the app hash. Returns the divergent keys in
+    /// ascending order, optionally narrowed to the keys under a prefix.
+    ///
+    /// Both column families are scanned in lockstep in the order of their
+    /// keys, so only the divergences are held in memory. The values of the
+    /// subspace and of its diffs are compared once decrypted, so a DB with
+    /// encrypted values can be compared against one without.
+    pub fn diff_against(
+        &self,
+        other: &RocksDB,
+        cf: &DbColFam,
+        prefix: Option<&Key>,
+    ) -> Result<Vec<KeyDivergence>> {
+        let cf_name = cf.to_str();
+        let decrypt = matches!(cf, DbColFam::SUBSPACE | DbColFam::DIFFS);
+        let value = |db: &RocksDB, raw_val: Vec<u8>| -> Result<Vec<u8>> {
+            if decrypt {
+                Ok(db.decrypt_subspace_val(Some(raw_val))?.unwrap_or_default())
+            } else {
+                Ok(raw_val)
+            }
+        };
+        let mut ours = iter_prefix_no_gas(
+            self,
+            self.get_column_family(cf_name)?,
+            None,
+            prefix,
+        )
+        .peekable();
+        let mut theirs = iter_prefix_no_gas(
+            other,
+            other.get_column_family(cf_name)?,
+            None,
+            prefix,
+        )
+        .peekable();
 
-        let ethereum_height =
-            match self.read_value(state_cf, ETHEREUM_HEIGHT_KEY)? {
-                Some(h) => h,
-                None => return Ok(None),
+        let mut divergences = vec![];
+        loop {
+            let order = match (ours.peek(), theirs.peek()) {
+                (None, None) => break,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some((our_key, _)), Some((their_key, _))) => {
+                    our_key.cmp(their_key)
+                }
             };
+            match order {
+                std::cmp::Ordering::Less => {
+                    let (key, _val) = ours.next().expect("Peeked above");
+                    divergences.push(KeyDivergence::OnlyInSelf(key));
+                }
+                std::cmp::Ordering::Greater => {
+                    let (key, _val) = theirs.next().expect("Peeked above");
+                    divergences.push(KeyDivergence::OnlyInOther(key));
+                }
+                std::cmp::Ordering::Equal => {
+                    let (key, our_val) = ours.next().expect("Peeked above");
+                    let (_, their_val) = theirs.next().expect("Peeked above");
+                    if value(self, our_val)? != value(other, their_val)? {
+                        divergences.push(KeyDivergence::ValueMismatch(key));
+                    }
+                }
+            }
+        }
+        Ok(divergences)
+    }
 
-        let eth_events_queue =
-            match self.read_value(state_cf, ETH_EVENTS_QUEUE_KEY)? {
-                Some(q) => q,
-                None => return Ok(None),
-            };
+    /// Compute a fingerprint of the whole subspace at the last committed
+    /// block, for a quick consistency check between nodes before comparing
+    /// them in detail with [`RocksDB::diff_against`].
+    ///
+    /// The subspace is read in a single pass, in the sorted order of its
+    /// keys, and each key and decrypted value is folded into a running
+    /// SHA-256, prefixed with its length so that the boundaries between them
+    /// are unambiguous. Two DBs with the same subspace therefore have the
+    /// same fingerprint, whether their values are encrypted at rest or not.
+    pub fn subspace_fingerprint(&self) -> Result<Hash> {
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let mut hasher = Sha256::default();
+        for (key, value) in iter_prefix_no_gas(self, subspace_cf, None, None) {
+            let value =
+                self.decrypt_subspace_val(Some(value))?.unwrap_or_default();
+            for bytes in [key.as_bytes(), value.as_slice()] {
+                hasher.update(u64::try_from(bytes.len())?.to_le_bytes());
+                hasher.update(bytes);
+            }
+        }
+        let hash: [u8; 32] = hasher.finalize().into();
+        Ok(Hash(hash))
+    }
 
-        // Block results
-        let results_key = format!("{RESULTS_KEY_PREFIX}/{}", height.raw());
-        let results = match self.read_value(block_cf, results_key)? {
-            Some(r) => r,
-            None => return Ok(None),
-        };
+    /// Warm the block cache for the given range of heights, e.g. before
+    /// serving a burst of historical queries over it. The block data and
+    /// the diffs of each height in the range are read and discarded.
+    ///
+    /// This is best-effort: the data is only kept in memory for as long as
+    /// the block cache has room for it, so a range larger than the cache
+    /// evicts its own first heights.
+    pub fn warm_range(&self, from: BlockHeight, to: BlockHeight) -> Result<()> {
+        if from > to {
+            return Err(Error::DBError(format!(
+                "Invalid range of heights from {from} to {to}"
+            )));
+        }
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        for height in (from.0..=to.0).map(BlockHeight) {
+            let prefix = Key::from(height.to_db_key());
+            for cf in [block_cf, diffs_cf] {
+                let keys: Vec<String> =
+                    iter_prefix_no_gas(self, cf, None, Some(&prefix))
+                        .map(|(key, _val)| key)
+                        .collect();
+                for key in keys {
+                    let _ = self.read_value_bytes(cf, key)?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // Read the block state one by one for simplicity because we need only 5
-        // values for now. We can revert to use `iterator_cf_opt` with
-        // the prefix to read more state values.
-        let prefix = height.raw();
+    /// Get the history of a subspace key from its diffs: for each height at
+    /// which the key changed, in ascending order, its old and new values. A
+    /// key written at a height has a new value and a key deleted at a height
+    /// only has an old value.
+    ///
+    /// Only the retained diffs are looked up, so the changes at the pruned
+    /// heights don't appear in the history, nor do the changes whose diffs
+    /// were not persisted.
+    #[allow(clippy::type_complexity)]
+    pub fn key_history(
+        &self,
+        key: &Key,
+    ) -> Result<Vec<(BlockHeight, Option<Vec<u8>>, Option<Vec<u8>>)>> {
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let Some((first_height, last_height)) =
+            self.retained_diffs_heights(diffs_cf)?
+        else {
+            return Ok(vec![]);
+        };
 
-        // Restoring the Merkle tree later
+        let mut history = vec![];
+        for height in (first_height..=last_height).map(BlockHeight) {
+            let (old_key, new_key) = old_and_new_diff_key(key, height)?;
+            let old_value = self.decrypt_subspace_val(
+                self.read_value_bytes(diffs_cf, old_key)?,
+            )?;
+            let new_value = self.decrypt_subspace_val(
+                self.read_value_bytes(diffs_cf, new_key)?,
+            )?;
+            if old_value.is_some() || new_value.is_some() {
+                history.push((height, old_value, new_value));
+            }
+        }
+        Ok(history)
+    }
 
-        let time_key = format!("{prefix}/{BLOCK_TIME_KEY_SEGMENT}");
-        let time = match self.read_value(block_cf, time_key)? {
-            Some(t) => t,
-            None => return Ok(None),
+    /// Read the value of a subspace key along with the height at which it
+    /// last changed, e.g. to tell whether a cached value is stale. Returns
+    /// `None` if the key has no value.
+    ///
+    /// The height is that of the key's most recent new value, either in the
+    /// persisted diffs or in the non-persisted ones kept to roll back the
+    /// last block. The diffs are looked up from the last height backward, in
+    /// batches of [`LAST_CHANGE_LOOKUP_BATCH`] heights. The height is `None`
+    /// if it's unknown, i.e. if the diffs of the key's last change have been
+    /// pruned, or were not persisted and are no longer kept for rollback.
+    pub fn read_subspace_val_and_height(
+        &self,
+        key: &Key,
+    ) -> Result<Option<(Vec<u8>, Option<BlockHeight>)>> {
+        let Some(value) = self.read_subspace_val(key)? else {
+            return Ok(None);
         };
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
+        let height = self
+            .last_new_diff_height(diffs_cf, key)?
+            .max(self.last_new_diff_height(rollback_cf, key)?);
+        Ok(Some((value, height)))
+    }
 
-        let epoch_key = format!("{prefix}/{EPOCH_KEY_SEGMENT}");
-        let epoch = match self.read_value(block_cf, epoch_key)? {
-            Some(e) => e,
-            None => return Ok(None),
+    /// The last height at which the given key has a new value in the given
+    /// diffs column family, if any
+    fn last_new_diff_height(
+        &self,
+        diffs_cf: &ColumnFamily,
+        key: &Key,
+    ) -> Result<Option<BlockHeight>> {
+        let Some((first_height, last_height)) =
+            self.retained_diffs_heights(diffs_cf)?
+        else {
+            return Ok(None);
         };
+        let heights: Vec<BlockHeight> =
+            (first_height..=last_height).rev().map(BlockHeight).collect();
+        for heights in heights.chunks(LAST_CHANGE_LOOKUP_BATCH) {
+            let new_keys = heights
+                .iter()
+                .map(|height| {
+                    let (_old_key, new_key) =
+                        old_and_new_diff_key(key, *height)?;
+                    Ok(new_key)
+                })
+                .collect::<Result<Vec<String>>>()?;
+            let new_values =
+                self.inner.batched_multi_get_cf(diffs_cf, &new_keys, false);
+            for (height, new_value) in heights.iter().zip(new_values) {
+                let new_value =
+                    new_value.map_err(|e| Error::DBError(e.into_string()))?;
+                if new_value.is_some() {
+                    return Ok(Some(*height));
+                }
+            }
+        }
+        Ok(None)
+    }
 
-        let pred_epochs_key = format!("{prefix}/{PRED_EPOCHS_KEY_SEGMENT}");
-        let pred_epochs = match self.read_value(block_cf, pred_epochs_key)? {
-            Some(e) => e,
-            None => return Ok(None),
+    /// The first and last heights of the diffs retained in the given diffs
+    /// column family, if any. The diffs are keyed by height first, so the
+    /// first and last diffs bound the retained heights.
+    fn retained_diffs_heights(
+        &self,
+        diffs_cf: &ColumnFamily,
+    ) -> Result<Option<(u64, u64)>> {
+        let diff_height = |mode: IteratorMode<'_>| -> Result<Option<u64>> {
+            let Some(entry) = self.inner.iterator_cf(diffs_cf, mode).next()
+            else {
+                return Ok(None);
+            };
+            let (diff_key, _val) =
+                entry.map_err(|e| Error::DBError(e.into_string()))?;
+            let diff_key = String::from_utf8(diff_key.into_vec())
+                .map_err(|e| Error::DBError(e.to_string()))?;
+            let segment = diff_key.split(KEY_SEGMENT_SEPARATOR).next();
+            u64::parse(segment.unwrap_or_default().to_owned())
+                .map(Some)
+                .map_err(Error::KeyError)
         };
+        Ok(diff_height(IteratorMode::Start)?
+            .zip(diff_height(IteratorMode::End)?))
+    }
 
-        let address_gen_key = format!("{prefix}/{ADDRESS_GEN_KEY_SEGMENT}");
-        let address_gen = match self.read_value(block_cf, address_gen_key)? {
-            Some(a) => a,
-            None => return Ok(None),
-        };
+    /// Delete all the subspace keys matching the given pattern under the
+    /// optional prefix. The deletions are recorded in the diffs at the last
+    /// committed height, so they can be reverted with a rollback. Returns the
+    /// number of deleted keys.
+    pub fn delete_matching(
+        &mut self,
+        prefix: Option<&Key>,
+        pattern: Regex,
+    ) -> Result<u64> {
+        let last_height = self.last_committed_height()?.ok_or_else(|| {
+            Error::DBError("No block height found".to_string())
+        })?;
 
-        Ok(Some(BlockStateRead {
-            height,
-            time,
-            epoch,
-            pred_epochs,
-            results,
-            conversion_state,
-            next_epoch_min_start_height,
-            next_epoch_min_start_time,
-            update_epoch_blocks_delay,
-            address_gen,
-            ethereum_height,
-            eth_events_queue,
-            commit_only_data,
-        }))
-    }
+        let keys = self
+            .iter_pattern(prefix, pattern)
+            .map(|(key, _val, _gas)| Key::parse(key).map_err(Error::KeyError))
+            .collect::<Result<Vec<_>>>()?;
 
-    fn add_block_to_batch(
-        &self,
-        state: BlockStateWrite<'_>,
-        batch: &mut Self::WriteBatch,
-        is_full_commit: bool,
-    ) -> Result<()> {
-        let BlockStateWrite {
-            merkle_tree_stores,
-            header,
-            height,
-            time,
-            epoch,
-            pred_epochs,
-            next_epoch_min_start_height,
-            next_epoch_min_start_time,
-            update_epoch_blocks_delay,
-            address_gen,
-            results,
-            conversion_state,
-            ethereum_height,
-            eth_events_queue,
-            commit_only_data,
-        }: BlockStateWrite<'_> = state;
-
-        let state_cf = self.get_column_family(STATE_CF)?;
+        let mut batch = RocksDB::batch();
+        for key in &keys {
+            self.batch_delete_subspace_val(&mut batch, last_height, key, true)?;
+        }
+        self.exec_batch(batch)?;
 
-        // Epoch start height and time
-        self.add_state_value_to_batch(
-            state_cf,
-            NEXT_EPOCH_MIN_START_HEIGHT_KEY,
-            &next_epoch_min_start_height,
-            batch,
-        )?;
-        self.add_state_value_to_batch(
-            state_cf,
-            NEXT_EPOCH_MIN_START_TIME_KEY,
-            &next_epoch_min_start_time,
-            batch,
-        )?;
+        Ok(u64::try_from(keys.len())?)
+    }
 
-        self.add_state_value_to_batch(
-            state_cf,
-            UPDATE_EPOCH_BLOCKS_DELAY_KEY,
-            &update_epoch_blocks_delay,
-            batch,
-        )?;
+    /// Replace all the subspace keys under the given prefix with the given
+    /// entries, which must all be under the prefix too. The old keys are
+    /// deleted and the new entries written in a single batch, so the swap is
+    /// atomic: if it fails, the old key-vals are left intact. The changes are
+    /// recorded in the diffs at the last committed height, so they can be
+    /// reverted with a rollback.
+    pub fn replace_subspace_range(
+        &mut self,
+        prefix: &Key,
+        new_entries: impl Iterator<Item = (Key, Vec<u8>)>,
+    ) -> Result<()> {
+        let batch = self.batch_replace_subspace_range(prefix, new_entries)?;
+        self.exec_batch(batch)
+    }
 
-        self.add_state_value_to_batch(
-            state_cf,
-            COMMIT_ONLY_DATA_KEY,
-            &commit_only_data,
-            batch,
-        )?;
+    /// Build the batch of [`RocksDB::replace_subspace_range`]
+    fn batch_replace_subspace_range(
+        &self,
+        prefix: &Key,
+        new_entries: impl Iterator<Item = (Key, Vec<u8>)>,
+    ) -> Result<RocksDBWriteBatch> {
+        let last_height = self.last_committed_height()?.ok_or_else(|| {
+            Error::DBError("No block height found".to_string())
+        })?;
+
+        let new_entries: Vec<(Key, Vec<u8>)> = new_entries.collect();
+        let new_keys: HashSet<String> =
+            new_entries.iter().map(|(key, _)| key.to_string()).collect();
+        let old_keys = self
+            .iter_prefix(Some(prefix))
+            .filter(|(key, _val, _gas)| !new_keys.contains(key))
+            .map(|(key, _val, _gas)| Key::parse(key).map_err(Error::KeyError))
+            .collect::<Result<Vec<_>>>()?;
 
-        // Save the conversion state when the epoch is updated
-        if is_full_commit {
-            self.add_state_value_to_batch(
-                state_cf,
-                CONVERSION_STATE_KEY,
-                &conversion_state,
-                batch,
+        let mut batch = RocksDB::batch();
+        for key in &old_keys {
+            self.batch_delete_subspace_val(&mut batch, last_height, key, true)?;
+        }
+        for (key, value) in new_entries {
+            if !matches!(key.split_prefix(prefix), Some(Some(_))) {
+                return Err(Error::DBError(format!(
+                    "The key {key} is not under the replaced prefix {prefix}"
+                )));
+            }
+            self.batch_write_subspace_val(
+                &mut batch,
+                last_height,
+                &key,
+                value,
+                true,
             )?;
         }
+        Ok(batch)
+    }
 
-        self.add_value_to_batch(
-            state_cf,
-            ETHEREUM_HEIGHT_KEY,
-            &ethereum_height,
-            batch,
-        );
-        self.add_value_to_batch(
-            state_cf,
-            ETH_EVENTS_QUEUE_KEY,
-            &eth_events_queue,
-            batch,
-        );
-
-        let block_cf = self.get_column_family(BLOCK_CF)?;
-        let prefix = height.raw();
+    /// Cross-check the diffs written at the given height against the subspace
+    /// and repair the subspace values that don't match them, e.g. after a
+    /// crash between the write of a value and of its diff. The diffs are
+    /// trusted, as they record the writes that the merkle tree committed to:
+    /// a subspace value that is missing for a new diff is filled in from it
+    /// and one that is present despite a recorded deletion is removed. The
+    /// keys changed again at a later height are not checked, as their value
+    /// is no longer the one set at the given height.
+    pub fn repair_orphaned_diffs(
+        &mut self,
+        height: BlockHeight,
+    ) -> Result<RepairReport> {
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let diffs_cfs = [
+            self.get_column_family(DIFFS_CF)?,
+            self.get_column_family(ROLLBACK_CF)?,
+        ];
 
-        // Merkle tree
-        for st in StoreType::iter() {
-            if st.is_stored_every_block() || is_full_commit {
-                let key_prefix = if st.is_stored_every_block() {
-                    tree_key_prefix_with_height(st, height)
-                } else {
-                    tree_key_prefix_with_epoch(st, epoch)
-                };
-                let root_key =
-                    format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
-                self.add_value_to_batch(
-                    block_cf,
-                    root_key,
-                    merkle_tree_stores.root(st),
-                    batch,
-                );
-                let store_key =
-                    format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
-                self.add_value_bytes_to_batch(
-                    block_cf,
-                    store_key,
-                    merkle_tree_stores.store(st).encode(),
-                    batch,
-                );
+        let mut changed_later = HashSet::<String>::new();
+        for cf in diffs_cfs {
+            for (diff_key, _val) in iter_prefix_no_gas(self, cf, None, None) {
+                // Diffs are under `{height}/{old|new}/{key}`
+                let mut segments = diff_key.splitn(3, KEY_SEGMENT_SEPARATOR);
+                let diff_height =
+                    u64::parse(segments.next().unwrap_or_default().to_owned())
+                        .map_err(Error::KeyError)?;
+                if diff_height > height.0 {
+                    if let Some(key) = segments.nth(1) {
+                        changed_later.insert(key.to_owned());
+                    }
+                }
             }
         }
 
-        // Block header
-        if let Some(h) = header {
-            let header_key = format!("{prefix}/{BLOCK_HEADER_KEY_SEGMENT}");
-            self.add_value_to_batch(block_cf, header_key, &h, batch);
+        let mut batch = RocksDB::batch();
+        let mut report = RepairReport::default();
+        for cf in diffs_cfs {
+            let mut new_keys = HashSet::<String>::new();
+            for (key, new_val) in
+                iter_diffs_prefix_no_gas(self, cf, height, false)
+            {
+                if !changed_later.contains(&key)
+                    && self.read_value_bytes(subspace_cf, &key)?.as_ref()
+                        != Some(&new_val)
+                {
+                    batch.inner.put_cf(subspace_cf, &key, new_val);
+                    report.filled.push(key.clone());
+                }
+                new_keys.insert(key);
+            }
+            for (key, _old_val) in
+                iter_diffs_prefix_no_gas(self, cf, height, true)
+            {
+                if !new_keys.contains(&key)
+                    && !changed_later.contains(&key)
+                    && self.read_value_bytes(subspace_cf, &key)?.is_some()
+                {
+                    batch.inner.delete_cf(subspace_cf, &key);
+                    report.removed.push(key);
+                }
+            }
         }
-        // Block time
-        let time_key = format!("{prefix}/{BLOCK_TIME_KEY_SEGMENT}");
-        self.add_value_to_batch(block_cf, time_key, &time, batch);
-        // Block epoch
-        let epoch_key = format!("{prefix}/{EPOCH_KEY_SEGMENT}");
-        self.add_value_to_batch(block_cf, epoch_key, &epoch, batch);
-        // Block results
-        let results_key = format!("{RESULTS_KEY_PREFIX}/{}", height.raw());
-        self.add_value_to_batch(block_cf, results_key, &results, batch);
-        // Predecessor block epochs
-        let pred_epochs_key = format!("{prefix}/{PRED_EPOCHS_KEY_SEGMENT}");
-        self.add_value_to_batch(block_cf, pred_epochs_key, &pred_epochs, batch);
-        // Address gen
-        let address_gen_key = format!("{prefix}/{ADDRESS_GEN_KEY_SEGMENT}");
-        self.add_value_to_batch(block_cf, address_gen_key, &address_gen, batch);
 
-        // Block height
-        self.add_value_to_batch(state_cf, BLOCK_HEIGHT_KEY, &height, batch);
-
-        Ok(())
-    }
-
-    fn read_block_header(&self, height: BlockHeight) -> Result<Option<Header>> {
-        let block_cf = self.get_column_family(BLOCK_CF)?;
-        let header_key = format!("{}/{BLOCK_HEADER_KEY_SEGMENT}", height.raw());
-        self.read_value(block_cf, header_key)
+        self.exec_batch(batch)?;
+        Ok(report)
     }
 
-    fn read_merkle_tree_stores(
+    /// Check, without changing anything, that the diffs written at the given
+    /// height are consistent with the subspace and with the diffs written
+    /// before. This is the diagnostic counterpart of
+    /// [`RocksDB::repair_orphaned_diffs`]. The subspace value of a key must
+    /// match its new value, or be absent if it was deleted, unless the key was
+    /// changed again at a later height. The old value of a key must match its
+    /// value at the last height it was changed at before, if its diffs at
+    /// that height haven't been pruned.
+    pub fn validate_diffs_at(
         &self,
-        epoch: Epoch,
-        base_height: BlockHeight,
-        store_type: Option<StoreType>,
-    ) -> Result<Option<MerkleTreeStoresRead>> {
-        // Get the latest height at which the tree stores were written
-        let block_cf = self.get_column_family(BLOCK_CF)?;
-        let mut merkle_tree_stores = MerkleTreeStoresRead::default();
-        let store_types = store_type
-            .as_ref()
-            .map(|st| Either::Left(std::iter::once(st)))
-            .unwrap_or_else(|| Either::Right(StoreType::iter()));
-        for st in store_types {
-            let key_prefix = if st.is_stored_every_block() {
-                tree_key_prefix_with_height(st, base_height)
-            } else {
-                tree_key_prefix_with_epoch(st, epoch)
-            };
-            let root_key =
-                format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
-            match self.read_value(block_cf, root_key)? {
-                Some(root) => merkle_tree_stores.set_root(st, root),
-                None => return Ok(None),
-            }
+        height: BlockHeight,
+    ) -> Result<Vec<DiffInconsistency>> {
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let diffs_cfs = [
+            self.get_column_family(DIFFS_CF)?,
+            self.get_column_family(ROLLBACK_CF)?,
+        ];
 
-            let store_key =
-                format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
-            match self.read_value_bytes(block_cf, store_key)? {
-                Some(bytes) => {
-                    merkle_tree_stores.set_store(st.decode_store(bytes)?)
+        // The keys changed after the height and the last height each key was
+        // changed at before, with its value at that height
+        let mut changed_later = HashSet::<String>::new();
+        let mut last_change = HashMap::<String, (u64, Option<Vec<u8>>)>::new();
+        for cf in diffs_cfs {
+            for (diff_key, val) in iter_prefix_no_gas(self, cf, None, None) {
+                // Diffs are under `{height}/{old|new}/{key}`
+                let mut segments = diff_key.splitn(3, KEY_SEGMENT_SEPARATOR);
+                let diff_height =
+                    u64::parse(segments.next().unwrap_or_default().to_owned())
+                        .map_err(Error::KeyError)?;
+                let (Some(kind), Some(key)) =
+                    (segments.next(), segments.next())
+                else {
+                    continue;
+                };
+                if diff_height > height.0 {
+                    changed_later.insert(key.to_owned());
+                } else if diff_height < height.0 {
+                    // Only the new value at a height is the value of the key
+                    // after it, an old value alone stands for a deletion
+                    let last = last_change
+                        .entry(key.to_owned())
+                        .or_insert((diff_height, None));
+                    if diff_height > last.0 {
+                        *last = (diff_height, None);
+                    }
+                    if diff_height == last.0 && kind == NEW_DIFF_PREFIX {
+                        last.1 = self.decrypt_subspace_val(Some(val))?;
+                    }
                 }
-                None => return Ok(None),
             }
         }
-        Ok(Some(merkle_tree_stores))
-    }
 
-    fn has_replay_protection_entry(
-        &self,
-        hash: &namada_sdk::hash::Hash,
-    ) -> Result<bool> {
-        let replay_protection_cf =
-            self.get_column_family(REPLAY_PROTECTION_CF)?;
-
-        for key in [
-            replay_protection::current_key(hash),
-            replay_protection::key(hash),
-        ] {
-            if self
-                .inner
-                .get_pinned_cf(replay_protection_cf, key.to_string())
-                .map_err(|e| Error::DBError(e.into_string()))?
-                .is_some()
+        let mut new_vals = BTreeMap::<String, Vec<u8>>::new();
+        let mut old_vals = BTreeMap::<String, Vec<u8>>::new();
+        for cf in diffs_cfs {
+            for (is_old, vals) in
+                [(false, &mut new_vals), (true, &mut old_vals)]
             {
-                return Ok(true);
+                for (key, val) in
+                    iter_diffs_prefix_no_gas(self, cf, height, is_old)
+                {
+                    let val = self.decrypt_subspace_val(Some(val))?;
+                    vals.insert(key, val.unwrap_or_default());
+                }
             }
         }
-        Ok(false)
+
+        let mut inconsistencies = vec![];
+        let changed_keys: BTreeSet<&String> =
+            new_vals.keys().chain(old_vals.keys()).collect();
+        for key in changed_keys {
+            let new_val = new_vals.get(key);
+            if !changed_later.contains(key) {
+                let actual = self.decrypt_subspace_val(
+                    self.read_value_bytes(subspace_cf, key)?,
+                )?;
+                if actual.as_ref() != new_val {
+                    inconsistencies.push(DiffInconsistency::Subspace {
+                        key: key.clone(),
+                        expected: new_val.cloned(),
+                        actual,
+                    });
+                }
+            }
+            if let Some((_, expected)) = last_change.get(key) {
+                let old_val = old_vals.get(key);
+                if expected.as_ref() != old_val {
+                    inconsistencies.push(DiffInconsistency::OldValue {
+                        key: key.clone(),
+                        expected: expected.clone(),
+                        actual: old_val.cloned(),
+                    });
+                }
+            }
+        }
+        Ok(inconsistencies)
     }
 
-    fn read_diffs_val(
-        &self,
-        key: &Key,
-        height: BlockHeight,
-        is_old: bool,
-    ) -> Result<Option<Vec<u8>>> {
-        let diffs_cf = self.get_column_family(DIFFS_CF)?;
-        let key = if is_old {
-            old_and_new_diff_key(key, height)?.0
-        } else {
-            old_and_new_diff_key(key, height)?.1
-        };
-        self.read_value_bytes(diffs_cf, key)
+    /// The heights exempted from pruning, see [`RocksDB::pin_height`]
+    pub fn pinned_heights(&self) -> Result<BTreeSet<BlockHeight>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        Ok(self
+            .read_value(state_cf, PINNED_HEIGHTS_KEY)?
+            .unwrap_or_default())
     }
 
-    fn read_subspace_val(&self, key: &Key) -> Result<Option<Vec<u8>>> {
-        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
-        self.read_value_bytes(subspace_cf, key.to_string())
+    /// Exempt the data of the given height from pruning, e.g. to keep
+    /// serving a block under investigation. The diffs are kept from the
+    /// lowest pinned height onward, so that the subspace can still be
+    /// reconstructed at the pinned heights, along with the headers of the
+    /// pinned heights and the merkle tree stores of their epochs. The
+    /// non-persisted diffs are never retained past the last height, pinned
+    /// or not. Returns `false` if the height was already pinned.
+    pub fn pin_height(&mut self, height: BlockHeight) -> Result<bool> {
+        let mut pinned = self.pinned_heights()?;
+        let newly_pinned = pinned.insert(height);
+        self.write_pinned_heights(&pinned)?;
+        Ok(newly_pinned)
     }
 
-    fn read_subspace_val_with_height(
+    /// Let the data of the given height be pruned again, see
+    /// [`RocksDB::pin_height`]. Returns `false` if the height wasn't pinned.
+    pub fn unpin_height(&mut self, height: BlockHeight) -> Result<bool> {
+        let mut pinned = self.pinned_heights()?;
+        let was_pinned = pinned.remove(&height);
+        self.write_pinned_heights(&pinned)?;
+        Ok(was_pinned)
+    }
+
+    fn write_pinned_heights(
         &self,
-        key: &Key,
-        height: BlockHeight,
-        last_height: BlockHeight,
-    ) -> Result<Option<Vec<u8>>> {
-        // Check if the value changed at this height
-        let diffs_cf = self.get_column_family(DIFFS_CF)?;
-        let (old_val_key, new_val_key) = old_and_new_diff_key(key, height)?;
+        pinned: &BTreeSet<BlockHeight>,
+    ) -> Result<()> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let mut batch = RocksDB::batch();
+        if pinned.is_empty() {
+            batch.inner.delete_cf(state_cf, PINNED_HEIGHTS_KEY);
+        } else {
+            self.add_value_to_batch(
+                state_cf,
+                PINNED_HEIGHTS_KEY,
+                pinned,
+                &mut batch,
+            );
+        }
+        self.exec_batch(batch)
+    }
 
-        // If it has a "new" val, it was written at this height
-        match self.read_value_bytes(diffs_cf, new_val_key)? {
-            Some(new_val) => {
-                return Ok(Some(new_val));
+    /// The epochs of the pinned heights, whose merkle tree stores are not
+    /// pruned
+    fn pinned_epochs(&self) -> Result<BTreeSet<u64>> {
+        let mut epochs = BTreeSet::new();
+        for height in self.pinned_heights()? {
+            if let Some(epoch) = self.epoch_at_height(height)? {
+                epochs.insert(epoch.0);
             }
-            None => {
-                // If it has an "old" val, it was deleted at this height
-                if self.inner.key_may_exist_cf(diffs_cf, &old_val_key) {
-                    // check if it actually exists
-                    if self.read_value_bytes(diffs_cf, old_val_key)?.is_some() {
-                        return Ok(None);
-                    }
-                }
+        }
+        Ok(epochs)
+    }
+
+    /// Prune the data that is older than what the given retention policy
+    /// keeps, in a single batch, and report the number of deleted entries.
+    ///
+    /// The diffs are kept for the last `keep_diffs_blocks` heights up to the
+    /// current height, the non-persisted diffs are only kept for the current
+    /// height and the merkle tree stores written per epoch are kept for the
+    /// last `keep_merkle_epochs` epochs up to the current epoch. The stores
+    /// written at every height are not pruned. The block headers are kept for
+    /// the last `keep_headers_blocks` heights, independently of the diffs.
+    /// Regardless of the policy, the data needed to roll back the current
+    /// height is never pruned, nor is the data of the pinned heights (see
+    /// [`RocksDB::pin_height`]).
+    pub fn prune_to_policy(
+        &mut self,
+        current_height: BlockHeight,
+        current_epoch: Epoch,
+        policy: &RetentionPolicy,
+    ) -> Result<PruneReport> {
+        let pinned_heights = self.pinned_heights()?;
+        let pinned_epochs = self.pinned_epochs()?;
+        let mut min_diffs_height = current_height
+            .0
+            .saturating_sub(policy.keep_diffs_blocks.max(1))
+            .saturating_add(1);
+        // The diffs from the lowest pinned height onward are needed to
+        // reconstruct the subspace at the pinned heights
+        if let Some(lowest_pinned) = pinned_heights.first() {
+            min_diffs_height = min_diffs_height.min(lowest_pinned.0);
+        }
+        let min_headers_height = current_height
+            .0
+            .saturating_sub(policy.keep_headers_blocks.max(1))
+            .saturating_add(1);
+        let mut min_merkle_epoch = current_epoch
+            .0
+            .saturating_sub(policy.keep_merkle_epochs.max(1))
+            .saturating_add(1);
+        // A rollback restores the merkle tree from the stores of the epoch of
+        // the previous height
+        if let Some(prev_height) = current_height.prev_height() {
+            if let Some(epoch) = self.epoch_at_height(prev_height)? {
+                min_merkle_epoch = min_merkle_epoch.min(epoch.0);
             }
         }
 
-        // If the value didn't change at the given height, we try to look for it
-        // at successor heights, up to the `last_height`
-        let mut raw_height = checked!(height.0 + 1)?;
-        loop {
-            // Try to find the next diff on this key
-            let (old_val_key, new_val_key) =
-                old_and_new_diff_key(key, BlockHeight(raw_height))?;
-            let old_val = self.read_value_bytes(diffs_cf, &old_val_key)?;
-            // If it has an "old" val, it's the one we're looking for
-            match old_val {
-                Some(bytes) => return Ok(Some(bytes)),
-                None => {
-                    // Check if the value was created at this height instead,
-                    // which would mean that it wasn't present before
-                    if self.inner.key_may_exist_cf(diffs_cf, &new_val_key) {
-                        // check if it actually exists
-                        if self
-                            .read_value_bytes(diffs_cf, new_val_key)?
-                            .is_some()
-                        {
-                            return Ok(None);
-                        }
-                    }
+        let parse_height = |key: &str| -> Result<u64> {
+            let segment = key.split(KEY_SEGMENT_SEPARATOR).next();
+            u64::parse(segment.unwrap_or_default().to_owned())
+                .map_err(Error::KeyError)
+        };
 
-                    if raw_height >= last_height.0 {
-                        // Read from latest height
-                        return self.read_subspace_val(key);
-                    } else {
-                        checked!(raw_height += 1)?
-                    }
+        let mut batch = RocksDB::batch();
+        let mut report = PruneReport::default();
+
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        for (key, _val) in iter_prefix_no_gas(self, diffs_cf, None, None) {
+            if parse_height(&key)? < min_diffs_height {
+                batch.inner.delete_cf(diffs_cf, key);
+                checked!(report.diffs += 1)?;
+            }
+        }
+        // Record the lowest height whose diffs are retained, to prevent
+        // rolling back past it
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let diffs_retained_from = self
+            .read_value::<BlockHeight>(state_cf, DIFFS_RETAINED_FROM_KEY)?
+            .map_or(min_diffs_height, |height| height.0.max(min_diffs_height));
+        self.add_value_to_batch(
+            state_cf,
+            DIFFS_RETAINED_FROM_KEY,
+            &BlockHeight(diffs_retained_from),
+            &mut batch,
+        );
+
+        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
+        for (key, _val) in iter_prefix_no_gas(self, rollback_cf, None, None) {
+            if parse_height(&key)? < current_height.0 {
+                batch.inner.delete_cf(rollback_cf, key);
+                checked!(report.rollback_entries += 1)?;
+            }
+        }
+
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        for (key, _val) in iter_prefix_no_gas(self, block_cf, None, None) {
+            let segments: Vec<_> = key.split(KEY_SEGMENT_SEPARATOR).collect();
+            // Block headers are under `{height}/header`
+            if let [height, BLOCK_HEADER_KEY_SEGMENT] = segments[..] {
+                let height = parse_height(height)?;
+                if height < min_headers_height
+                    && !pinned_heights.contains(&BlockHeight(height))
+                {
+                    batch.inner.delete_cf(block_cf, key);
+                    checked!(report.headers += 1)?;
                 }
+                continue;
+            }
+            // Merkle tree stores are under `{epoch}/tree/{store_type}/root`
+            // and `{epoch}/tree/{store_type}/store`
+            let [epoch, "tree", store_type, segment] = segments[..] else {
+                continue;
+            };
+            let Ok(store_type) = StoreType::from_str(store_type) else {
+                continue;
+            };
+            let epoch = parse_height(epoch)?;
+            if store_type.is_stored_every_block()
+                || epoch >= min_merkle_epoch
+                || pinned_epochs.contains(&epoch)
+            {
+                continue;
             }
+            if segment == MERKLE_TREE_ROOT_KEY_SEGMENT {
+                checked!(report.merkle_stores += 1)?;
+            }
+            batch.inner.delete_cf(block_cf, key);
         }
-    }
 
-    fn write_subspace_val(
-        &mut self,
-        height: BlockHeight,
-        key: &Key,
-        value: impl AsRef<[u8]>,
-        persist_diffs: bool,
-    ) -> Result<i64> {
-        let mut batch = RocksDB::batch();
-        let size_diff = self.batch_write_subspace_val(
-            &mut batch,
-            height,
-            key,
-            value,
-            persist_diffs,
-        )?;
         self.exec_batch(batch)?;
-        Ok(size_diff)
+        Ok(report)
     }
 
-    fn delete_subspace_val(
+    /// Prune the DB with [`RocksDB::prune_to_policy`] and compact the pruned
+    /// column families to reclaim the disk space of the deleted entries.
+    ///
+    /// The compaction is awaited, so the "after" size is measured once it
+    /// has completed. Note that RocksDB may still hold on to some obsolete
+    /// files for a while after the compaction, in which case the size on
+    /// disk only drops some time later.
+    pub fn prune_and_compact(
         &mut self,
-        height: BlockHeight,
-        key: &Key,
-        persist_diffs: bool,
-    ) -> Result<i64> {
-        let mut batch = RocksDB::batch();
-        let prev_len = self.batch_delete_subspace_val(
-            &mut batch,
-            height,
-            key,
-            persist_diffs,
-        )?;
-        self.exec_batch(batch)?;
-        Ok(prev_len)
-    }
+        current_height: BlockHeight,
+        current_epoch: Epoch,
+        policy: &RetentionPolicy,
+    ) -> Result<SpaceReport> {
+        const PRUNED_CFS: [&str; 3] = [DIFFS_CF, ROLLBACK_CF, BLOCK_CF];
+
+        // Flush the memtables first, so that all the pruned data is measured
+        for cf_name in PRUNED_CFS {
+            let cf = self.get_column_family(cf_name)?;
+            self.inner
+                .flush_cf(cf)
+                .map_err(|e| Error::DBError(e.into_string()))?;
+        }
+        let before = self.sst_files_size(&PRUNED_CFS)?;
 
-    fn batch() -> Self::WriteBatch {
-        RocksDBWriteBatch::default()
-    }
+        let pruned =
+            self.prune_to_policy(current_height, current_epoch, policy)?;
 
-    fn exec_batch(&self, batch: Self::WriteBatch) -> Result<()> {
-        self.inner
-            .write(batch.0)
-            .map_err(|e| Error::DBError(e.into_string()))
+        for cf_name in PRUNED_CFS {
+            let cf = self.get_column_family(cf_name)?;
+            tracing::info!("Compacting the {cf_name} column family");
+            self.inner
+                .compact_range_cf(cf, None::<&[u8]>, None::<&[u8]>);
+        }
+        let after = self.sst_files_size(&PRUNED_CFS)?;
+
+        let report = SpaceReport {
+            pruned,
+            before,
+            after,
+        };
+        tracing::info!(
+            "Pruned the DB and reclaimed {} bytes ({} -> {} bytes)",
+            report.reclaimed(),
+            before,
+            after
+        );
+        Ok(report)
     }
 
-    fn batch_write_subspace_val(
+    /// Read back the block at the given height from the archive, see
+    /// [`OpenOptions::archive`]. Returns `None` if the block isn't archived,
+    /// e.g. if it was committed without the archive enabled or has been
+    /// pruned from it.
+    pub fn replay_block(
         &self,
-        batch: &mut Self::WriteBatch,
         height: BlockHeight,
-        key: &Key,
-        value: impl AsRef<[u8]>,
-        persist_diffs: bool,
-    ) -> Result<i64> {
-        let value = value.as_ref();
-        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
-        let size_diff =
-            match self.read_value_bytes(subspace_cf, key.to_string())? {
-                Some(old_value) => {
-                    let len = i64::try_from(value.len())?;
-                    let old_len = i64::try_from(old_value.len())?;
-                    let size_diff = checked!(len - old_len)?;
-                    // Persist the previous value
-                    self.batch_write_subspace_diff(
-                        batch,
-                        height,
-                        key,
-                        Some(&old_value),
-                        Some(value),
-                        persist_diffs,
-                    )?;
-                    size_diff
-                }
-                None => {
-                    self.batch_write_subspace_diff(
-                        batch,
-                        height,
-                        key,
-                        None,
-                        Some(value),
-                        persist_diffs,
-                    )?;
-                    i64::try_from(value.len())?
-                }
-            };
-
-        // Write the new key-val
-        batch.0.put_cf(subspace_cf, key.to_string(), value);
-
-        Ok(size_diff)
+    ) -> Result<Option<ArchivedBlock>> {
+        let Some(archive_cf) = self.inner.cf_handle(ARCHIVE_CF) else {
+            return Ok(None);
+        };
+        let block_key = format!("{}/{ARCHIVE_BLOCK_KEY_SEGMENT}", height.raw());
+        let Some((header, time, epoch, results)) =
+            self.read_value(archive_cf, block_key)?
+        else {
+            return Ok(None);
+        };
+        let changes_prefix = Key::from(height.to_db_key())
+            .push(&ARCHIVE_CHANGES_KEY_SEGMENT.to_string())
+            .map_err(Error::KeyError)?;
+        let mut changes = BTreeMap::new();
+        for (key, value) in
+            iter_prefix_no_gas(self, archive_cf, Some(&changes_prefix), None)
+        {
+            let key = Key::parse(key).map_err(Error::KeyError)?;
+            let value = decode::<Option<Vec<u8>>>(value)?;
+            changes.insert(key, self.decrypt_subspace_val(value)?);
+        }
+        Ok(Some(ArchivedBlock {
+            header,
+            time,
+            epoch,
+            results,
+            changes,
+        }))
     }
 
-    fn batch_delete_subspace_val(
+    /// Prune the archive to the last `keep_blocks` heights up to the current
+    /// height, independently of the [`RetentionPolicy`] of the rest of the
+    /// DB, and return the number of deleted entries.
+    pub fn prune_archive(
         &self,
-        batch: &mut Self::WriteBatch,
-        height: BlockHeight,
-        key: &Key,
-        persist_diffs: bool,
-    ) -> Result<i64> {
-        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        current_height: BlockHeight,
+        keep_blocks: u64,
+    ) -> Result<u64> {
+        let Some(archive_cf) = self.inner.cf_handle(ARCHIVE_CF) else {
+            return Ok(0);
+        };
+        let min_height = current_height
+            .0
+            .saturating_sub(keep_blocks.max(1))
+            .saturating_add(1);
+        let mut batch = RocksDB::batch();
+        let mut deleted = 0_u64;
+        for (key, _val) in iter_prefix_no_gas(self, archive_cf, None, None) {
+            let segment = key.split(KEY_SEGMENT_SEPARATOR).next();
+            let height = u64::parse(segment.unwrap_or_default().to_owned())
+                .map_err(Error::KeyError)?;
+            if height < min_height {
+                batch.inner.delete_cf(archive_cf, key);
+                checked!(deleted += 1)?;
+            }
+        }
+        self.exec_batch(batch)?;
+        Ok(deleted)
+    }
 
-        // Check the length of previous value, if any
-        let prev_len =
-            match self.read_value_bytes(subspace_cf, key.to_string())? {
-                Some(prev_value) => {
-                    let prev_len = i64::try_from(prev_value.len())?;
-                    // Persist the previous value
-                    self.batch_write_subspace_diff(
-                        batch,
-                        height,
-                        key,
-                        Some(&prev_value),
-                        None,
-                        persist_diffs,
-                    )?;
-                    prev_len
-                }
-                None => 0,
-            };
+    /// Get the total size in bytes of the SST files of the given column
+    /// families
+    fn sst_files_size(&self, cf_names: &[&str]) -> Result<u64> {
+        let mut total = 0_u64;
+        for cf_name in cf_names {
+            let cf = self.get_column_family(cf_name)?;
+            let size = self
+                .inner
+                .property_int_value_cf(cf, "rocksdb.total-sst-files-size")
+                .map_err(|e| Error::DBError(e.into_string()))?
+                .unwrap_or_default();
+            total = checked!(total + size)?;
+        }
+        Ok(total)
+    }
 
-        // Delete the key-val
-        batch.0.delete_cf(subspace_cf, key.to_string());
+    /// Export the tx hashes of the replay protection column family to the
+    /// given file, as hex, and return the number of exported hashes.
+    ///
+    /// The hashes are grouped by bucket: the `[all]` section lists the hashes
+    /// committed before the last block and the `[current]` section the ones
+    /// committed in the last block, which are moved to the general bucket
+    /// when the next block is committed.
+    pub fn export_replay_protection(&self, out: &Path) -> Result<usize> {
+        let io_error = |e: std::io::Error| {
+            Error::DBError(format!("{}: {e}", out.display()))
+        };
 
-        Ok(prev_len)
+        let reprot_cf = self.get_column_family(REPLAY_PROTECTION_CF)?;
+        let current_prefix = replay_protection::current_prefix();
+        let current =
+            iter_prefix_no_gas(self, reprot_cf, Some(&current_prefix), None)
+                .map(|(hash, _val)| hash)
+                .collect::<Vec<_>>();
+        let current_key_prefix = format!("{current_prefix}/");
+        let all = iter_prefix_no_gas(self, reprot_cf, None, None)
+            .map(|(key, _val)| key)
+            .filter(|key| !key.starts_with(&current_key_prefix))
+            .collect::<Vec<_>>();
+
+        let file = File::create(out).map_err(io_error)?;
+        let mut buf = BufWriter::new(file);
+        for (bucket, hashes) in [("all", &all), ("current", &current)] {
+            writeln!(buf, "[{bucket}]").map_err(io_error)?;
+            for hash in hashes {
+                writeln!(buf, "{hash}").map_err(io_error)?;
+            }
+        }
+        buf.flush().map_err(io_error)?;
+
+        Ok(checked!(all.len() + current.len())?)
     }
 
-    fn prune_merkle_tree_store(
-        &mut self,
-        batch: &mut Self::WriteBatch,
-        store_type: &StoreType,
-        epoch: Epoch,
-    ) -> Result<()> {
-        let block_cf = self.get_column_family(BLOCK_CF)?;
-        let key_prefix = tree_key_prefix_with_epoch(store_type, epoch);
-        let root_key = format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
-        batch.0.delete_cf(block_cf, root_key);
-        let store_key = format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
-        batch.0.delete_cf(block_cf, store_key);
-        Ok(())
+    /// Build a bloom filter of all the replay protection hashes, both the
+    /// committed and the current ones, sized for their number and the given
+    /// false-positive rate, see [`ReplayProtectionBloom`]. Returns the
+    /// Borsh-encoded filter, which includes its number of hashes such that
+    /// the consumer can reconstruct its parameters.
+    pub fn replay_protection_bloom(&self, fp_rate: f64) -> Result<Vec<u8>> {
+        let reprot_cf = self.get_column_family(REPLAY_PROTECTION_CF)?;
+        let current_prefix = replay_protection::current_prefix();
+        let current_key_prefix = format!("{current_prefix}/");
+        let hashes = iter_prefix_no_gas(self, reprot_cf, None, None)
+            .map(|(key, _val)| {
+                let hash =
+                    key.strip_prefix(&current_key_prefix).unwrap_or(&key);
+                Hash::from_str(hash).map_err(|e| {
+                    Error::DBError(format!(
+                        "Invalid replay protection hash {hash}: {e}"
+                    ))
+                })
+            })
+            .collect::<Result<HashSet<_>>>()?;
+
+        let mut bloom =
+            ReplayProtectionBloom::new(u64::try_from(hashes.len())?, fp_rate)?;
+        for hash in &hashes {
+            bloom.insert(hash);
+        }
+        Ok(encode(&bloom))
     }
 
-    fn read_bridge_pool_signed_nonce(
+    /// Read diffs of non-persisted key-vals that are only kept for rollback of
+    /// one block height.
+    #[cfg(test)]
+    pub fn read_rollback_val(
         &self,
+        key: &Key,
         height: BlockHeight,
-        last_height: BlockHeight,
-    ) -> Result<Option<ethereum_events::Uint>> {
-        let nonce_key = bridge_pool::get_signed_root_key();
-        let bytes = if height == BlockHeight(0) || height >= last_height {
-            self.read_subspace_val(&nonce_key)?
+        is_old: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
+        let key = if is_old {
+            old_and_new_diff_key(key, height)?.0
         } else {
-            self.read_subspace_val_with_height(&nonce_key, height, last_height)?
+            old_and_new_diff_key(key, height)?.1
         };
-        match bytes {
-            Some(bytes) => {
-                let bp_root_proof = BridgePoolRootProof::try_from_slice(&bytes)
-                    .map_err(Error::BorshCodingError)?;
-                Ok(Some(bp_root_proof.data.1))
-            }
-            None => Ok(None),
-        }
-    }
 
-    fn write_replay_protection_entry(
-        &mut self,
-        batch: &mut Self::WriteBatch,
-        key: &Key,
-    ) -> Result<()> {
-        let replay_protection_cf =
-            self.get_column_family(REPLAY_PROTECTION_CF)?;
+        let value = self
+            .inner
+            .get_cf(rollback_cf, key)
+            .map_err(|e| Error::DBError(e.into_string()))?;
+        self.decrypt_subspace_val(value)
+    }
+}
 
-        self.add_value_bytes_to_batch(
-            replay_protection_cf,
-            key.to_string(),
-            vec![],
-            batch,
+/// Information about a particular snapshot
+/// owned by a node
+pub struct SnapshotMetadata {
+    /// The height at which the snapshot was taken
+    pub height: BlockHeight,
+    /// The name of the paths to the file and metadata
+    /// holding the snapshot minus extensions
+    pub path_stem: String,
+    /// Data about the chunks that the snapshot is
+    /// partitioned into
+    pub chunks: Vec<Chunk>,
+}
+
+pub struct DbSnapshot<'a>(pub rocksdb::Snapshot<'a>);
+
+impl<'a> DbSnapshot<'a> {
+    /// Write a snapshot of the database out to file. The last line
+    /// of the file contains metadata about how to break the file into
+    /// chunks.
+    pub fn write_to_file(
+        &self,
+        cfs: [(&'static str, &'a ColumnFamily); 6],
+        base_dir: PathBuf,
+        height: BlockHeight,
+    ) -> std::io::Result<()> {
+        let [snap_file, metadata_file] = Self::paths(height, base_dir);
+        let file = File::create(snap_file)?;
+        let chunks = self.write_to(cfs, file, MAX_CHUNK_SIZE)?;
+        let metadata = base64::encode(chunks.serialize_to_vec());
+        std::fs::write(metadata_file, metadata.as_bytes())?;
+        Ok(())
+    }
+
+    /// Stream a snapshot of the database to the given writer, e.g. a network
+    /// socket, without landing it on disk. Returns the chunks of at most
+    /// `max_chunk_size` bytes that the snapshot is partitioned into, which
+    /// [`RocksDB::import_snapshot`] verifies the snapshot against.
+    pub fn write_to(
+        &self,
+        cfs: [(&'static str, &'a ColumnFamily); 6],
+        writer: impl Write,
+        max_chunk_size: usize,
+    ) -> std::io::Result<Vec<Chunk>> {
+        let mut buf = BufWriter::new(writer);
+        let mut chunker = Chunker::new(max_chunk_size);
+        for (cf_name, cf) in cfs {
+            let read_opts = make_iter_read_opts(None);
+            let iter =
+                self.0.iterator_cf_opt(cf, read_opts, IteratorMode::Start);
+
+            for (key, raw_val, _gas) in PersistentPrefixIterator(
+                PrefixIterator::new(iter, String::default()),
+                // Empty string to prevent prefix stripping, the prefix is
+                // already in the enclosed iterator
+                None,
+            ) {
+                let val = base64::encode(raw_val);
+                let bytes = format!("{cf_name}:{key}={val}\n");
+                chunker.add_line(&bytes);
+                buf.write_all(bytes.as_bytes())?;
+            }
+            buf.flush()?;
+        }
+        buf.flush()?;
+        Ok(chunker.finalize())
+    }
+
+    /// Remove snapshots older than the latest
+    pub fn cleanup(
+        latest_height: BlockHeight,
+        base_dir: &Path,
+    ) -> std::io::Result<()> {
+        for SnapshotMetadata {
+            height, path_stem, ..
+        } in Self::files(base_dir)?
+        {
+            if height < latest_height {
+                let path = PathBuf::from(path_stem);
+                _ = std::fs::remove_file(&path.with_extension("snap"));
+                _ = std::fs::remove_file(path.with_extension("meta"));
+            }
+        }
+        Ok(())
+    }
+
+    /// List all snapshot files along with the block height at which
+    /// they were created and their chunks.
+    pub fn files(base_dir: &Path) -> std::io::Result<Vec<SnapshotMetadata>> {
+        let snap = OsStr::new("snap");
+        let meta = OsStr::new("meta");
+        let mut files =
+            HashMap::<BlockHeight, (Option<String>, Option<Vec<Chunk>>)>::new();
+        for entry in std::fs::read_dir(base_dir)? {
+            let entry = entry?;
+            let entry_path = entry.path();
+            let entry_ext = entry_path.extension();
+            if entry_path.is_file()
+                && (Some(snap) == entry_ext || Some(meta) == entry_ext)
+            {
+                if let Some(name) = entry.path().file_name() {
+                    // Extract the block height from the file name
+                    // (assuming the file name is of the correct format)
+                    let Some(height) = name
+                        .to_string_lossy()
+                        .strip_prefix("snapshot_")
+                        .and_then(|n| {
+                            n.strip_suffix(".meta").or(n.strip_suffix(".snap"))
+                        })
+                        .and_then(|h| BlockHeight::from_str(h).ok())
+                    else {
+                        continue;
+                    };
+                    // check if we have found the metadata file or snapshot file
+                    // for a given block height
+                    if entry_ext == Some(meta) {
+                        let metadata = std::fs::read_to_string(entry_path)?;
+                        let metadata_bytes = HEXLOWER
+                            .decode(metadata.as_bytes())
+                            .map_err(|e| {
+                                std::io::Error::new(ErrorKind::InvalidData, e)
+                            })?;
+                        let chunks: Vec<Chunk> =
+                            BorshDeserialize::try_from_slice(
+                                &metadata_bytes[..],
+                            )?;
+                        files.entry(height).or_default().1 = Some(chunks);
+                    } else {
+                        files.entry(height).or_default().0 = Some(
+                            base_dir
+                                .join(format!("snapshot_{}", height))
+                                .to_string_lossy()
+                                .into(),
+                        );
+                    }
+                };
+            }
+        }
+        let mut res = Vec::with_capacity(files.len());
+        for (height, (path, chunks)) in files {
+            // only include snapshots which have both a .snap and .meta file.
+            if let Some((path_stem, chunks)) = path.zip(chunks) {
+                res.push(SnapshotMetadata {
+                    height,
+                    path_stem,
+                    chunks,
+                });
+            }
+        }
+        Ok(res)
+    }
+
+    /// Create a path to save a snapshot at a specific block height.
+    pub fn paths(height: BlockHeight, base_dir: PathBuf) -> [PathBuf; 2] {
+        let snap_file = base_dir.join(format!("snapshot_{}.snap", height));
+        let metadata_file = base_dir.join(format!("snapshot_{}.meta", height));
+        [snap_file, metadata_file]
+    }
+
+    /// Load the specified chunk of a snapshot at the given block height
+    pub fn load_chunk(
+        height: BlockHeight,
+        chunk: u64,
+        base_dir: &Path,
+    ) -> std::io::Result<Vec<u8>> {
+        let files = Self::files(base_dir)?;
+        let Some(metadata) = files.into_iter().find(|m| m.height == height)
+        else {
+            return Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "Could not find the metadata file for the snapshot at \
+                     height {}",
+                    height,
+                ),
+            ));
+        };
+        let chunk_start = if chunk == 0 {
+            0usize
+        } else {
+            let prev = checked!(usize::try_from(chunk).unwrap() - 1).unwrap();
+            usize::try_from(metadata.chunks[prev].boundary).unwrap()
+        };
+        let chunk_end = metadata
+            .chunks
+            .get(usize::try_from(chunk).unwrap())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("Chunk {} not found", chunk),
+                )
+            })?
+            .boundary;
+        let chunk_end = usize::try_from(chunk_end).unwrap();
+
+        let file = File::open(
+            PathBuf::from(metadata.path_stem).with_extension("snap"),
+        )?;
+        let reader = BufReader::new(file);
+        let mut bytes: Vec<u8> = vec![];
+        for line in reader
+            .lines()
+            .skip(chunk_start)
+            .take(checked!(chunk_end - chunk_start).unwrap())
+        {
+            bytes.extend(line?.as_bytes());
+        }
+        Ok(bytes)
+    }
+}
+
+/// A chunk of a snapshot. Includes the last line number in the file
+/// for this chunk and a hash of the chunk contents.
+#[derive(
+    Debug, Clone, Default, PartialEq, Eq, BorshSerialize, BorshDeserialize, Hash,
+)]
+pub struct Chunk {
+    /// The line number ending the chunk
+    pub boundary: u64,
+    /// Sha256 hash of the chunk
+    pub hash: Hash,
+}
+
+/// The key of the progress of the import of the snapshot at the given height
+/// with the given chunks, see [`RocksDB::import_snapshot`]
+fn snapshot_import_chunk_key(height: BlockHeight, chunks: &[Chunk]) -> String {
+    let snapshot_hash = Hash::sha256(chunks.serialize_to_vec());
+    format!("{SNAPSHOT_IMPORT_CHUNK_KEY}/{}/{snapshot_hash}", height.raw())
+}
+
+/// Builds a set of chunks from a stream of lines to be
+/// written to a file.
+#[derive(Debug, Clone)]
+struct Chunker {
+    chunks: Vec<Chunk>,
+    max_size: usize,
+    current_boundary: u64,
+    current_size: usize,
+    hasher: Sha256,
+}
+impl Chunker {
+    fn new(max_size: usize) -> Self {
+        Self {
+            chunks: vec![],
+            max_size,
+            current_boundary: 0,
+            current_size: 0,
+            hasher: Sha256::default(),
+        }
+    }
+
+    fn add_line(&mut self, line: &str) {
+        if checked!(self.current_size + line.as_bytes().len()).unwrap()
+            > self.max_size
+            && self.current_boundary != 0
+        {
+            let mut hasher = Sha256::default();
+            std::mem::swap(&mut hasher, &mut self.hasher);
+            let hash: [u8; 32] = hasher.finalize().into();
+            self.chunks.push(Chunk {
+                boundary: self.current_boundary,
+                hash: Hash(hash),
+            });
+            self.current_size = 0;
+        }
+
+        checked!(self.current_size += line.as_bytes().len()).unwrap();
+        self.hasher.update(line.as_bytes());
+        checked!(self.current_boundary += 1).unwrap();
+    }
+
+    fn finalize(mut self) -> Vec<Chunk> {
+        let hash: [u8; 32] = self.hasher.finalize().into();
+        self.chunks.push(Chunk {
+            boundary: self.current_boundary,
+            hash: Hash(hash),
+        });
+        self.chunks
+    }
+}
+
+impl DB for RocksDB {
+    type Cache = rocksdb::Cache;
+    type Migrator = DbUpdateType;
+    type WriteBatch = RocksDBWriteBatch;
+
+    fn open(
+        db_path: impl AsRef<std::path::Path>,
+        cache: Option<&Self::Cache>,
+    ) -> Self {
+        open(db_path, false, cache).expect("cannot open the DB")
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(self.inner.path())
+    }
+
+    fn flush(&self, wait: bool) -> Result<()> {
+        let mut flush_opts = FlushOptions::default();
+        flush_opts.set_wait(wait);
+        self.inner
+            .flush_opt(&flush_opts)
+            .map_err(|e| Error::DBError(e.into_string()))
+    }
+
+    fn read_last_block(&self) -> Result<Option<BlockStateRead>> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+
+        // Block height
+        let height: BlockHeight =
+            match self.read_verified_value(state_cf, BLOCK_HEIGHT_KEY)? {
+                Some(h) => h,
+                None => return Ok(None),
+            };
+        // From here on, a missing value means that the block at this height
+        // has only been partially written
+
+        // Epoch start height and time
+        let next_epoch_min_start_height = match self
+            .read_verified_value(state_cf, NEXT_EPOCH_MIN_START_HEIGHT_KEY)?
+        {
+            Some(h) => h,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let next_epoch_min_start_time = match self
+            .read_verified_value(state_cf, NEXT_EPOCH_MIN_START_TIME_KEY)?
+        {
+            Some(t) => t,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let update_epoch_blocks_delay = match self
+            .read_verified_value(state_cf, UPDATE_EPOCH_BLOCKS_DELAY_KEY)?
+        {
+            Some(d) => d,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let commit_only_data =
+            match self.read_value(state_cf, COMMIT_ONLY_DATA_KEY)? {
+                Some(d) => d,
+                None => return Err(Error::TornBlockWrite { height }),
+            };
+
+        let conversion_state =
+            match self.read_verified_value(state_cf, CONVERSION_STATE_KEY) {
+                Ok(Some(c)) => c,
+                Ok(None) => return Err(Error::TornBlockWrite { height }),
+                Err(Error::CodingError(_) | Error::CorruptValue { .. }) => {
+                    return Err(Error::CorruptConversionState { height });
+                }
+                Err(e) => return Err(e),
+            };
+
+        let ethereum_height =
+            match self.read_value(state_cf, ETHEREUM_HEIGHT_KEY)? {
+                Some(h) => h,
+                None => return Err(Error::TornBlockWrite { height }),
+            };
+
+        let eth_events_queue =
+            match self.read_value(state_cf, ETH_EVENTS_QUEUE_KEY)? {
+                Some(q) => q,
+                None => return Err(Error::TornBlockWrite { height }),
+            };
+
+        // Block results
+        let results_key = format!("{RESULTS_KEY_PREFIX}/{}", height.raw());
+        let results = match self.read_value(block_cf, results_key)? {
+            Some(r) => r,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        // Read the block state one by one for simplicity because we need only 5
+        // values for now. We can revert to use `iterator_cf_opt` with
+        // the prefix to read more state values.
+        let prefix = height.raw();
+
+        // Restoring the Merkle tree later
+
+        let time_key = format!("{prefix}/{BLOCK_TIME_KEY_SEGMENT}");
+        let time = match self.read_value(block_cf, time_key)? {
+            Some(t) => t,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let epoch_key = format!("{prefix}/{EPOCH_KEY_SEGMENT}");
+        let epoch = match self.read_value(block_cf, epoch_key)? {
+            Some(e) => e,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let pred_epochs_key = format!("{prefix}/{PRED_EPOCHS_KEY_SEGMENT}");
+        let pred_epochs = match self.read_value(block_cf, pred_epochs_key)? {
+            Some(e) => e,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        let address_gen_key = format!("{prefix}/{ADDRESS_GEN_KEY_SEGMENT}");
+        let address_gen = match self.read_value(block_cf, address_gen_key)? {
+            Some(a) => a,
+            None => return Err(Error::TornBlockWrite { height }),
+        };
+
+        Ok(Some(BlockStateRead {
+            height,
+            time,
+            epoch,
+            pred_epochs,
+            results,
+            conversion_state,
+            next_epoch_min_start_height,
+            next_epoch_min_start_time,
+            update_epoch_blocks_delay,
+            address_gen,
+            ethereum_height,
+            eth_events_queue,
+            commit_only_data,
+        }))
+    }
+
+    fn add_block_to_batch(
+        &self,
+        state: BlockStateWrite<'_>,
+        batch: &mut Self::WriteBatch,
+        is_full_commit: bool,
+    ) -> Result<()> {
+        let BlockStateWrite {
+            merkle_tree_stores,
+            header,
+            height,
+            time,
+            epoch,
+            pred_epochs,
+            next_epoch_min_start_height,
+            next_epoch_min_start_time,
+            update_epoch_blocks_delay,
+            address_gen,
+            results,
+            gas,
+            conversion_state,
+            ethereum_height,
+            eth_events_queue,
+            commit_only_data,
+        }: BlockStateWrite<'_> = state;
+
+        // Guard the height invariant of the chain, the first block may be at
+        // any height
+        if !self.allow_non_contiguous_blocks {
+            if let Some(last_height) = self.last_committed_height()? {
+                let expected = last_height.next_height();
+                if height != expected {
+                    return Err(Error::NonContiguousBlock {
+                        expected,
+                        got: height,
+                    });
+                }
+            }
+        }
+        batch.block_height = Some(height);
+
+        let state_cf = self.get_column_family(STATE_CF)?;
+
+        // Epoch start height and time
+        self.add_state_value_to_batch(
+            state_cf,
+            NEXT_EPOCH_MIN_START_HEIGHT_KEY,
+            &next_epoch_min_start_height,
+            batch,
+        )?;
+        self.add_state_value_to_batch(
+            state_cf,
+            NEXT_EPOCH_MIN_START_TIME_KEY,
+            &next_epoch_min_start_time,
+            batch,
+        )?;
+
+        self.add_state_value_to_batch(
+            state_cf,
+            UPDATE_EPOCH_BLOCKS_DELAY_KEY,
+            &update_epoch_blocks_delay,
+            batch,
+        )?;
+
+        self.add_state_value_to_batch(
+            state_cf,
+            COMMIT_ONLY_DATA_KEY,
+            &commit_only_data,
+            batch,
+        )?;
+
+        // Save the conversion state when the epoch is updated
+        if is_full_commit {
+            self.add_state_value_to_batch(
+                state_cf,
+                CONVERSION_STATE_KEY,
+                &conversion_state,
+                batch,
+            )?;
+        }
+
+        self.add_value_to_batch(
+            state_cf,
+            ETHEREUM_HEIGHT_KEY,
+            &ethereum_height,
+            batch,
+        );
+        self.add_value_to_batch(
+            state_cf,
+            ETH_EVENTS_QUEUE_KEY,
+            &eth_events_queue,
+            batch,
+        );
+
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let prefix = height.raw();
+
+        // Merkle tree
+        for st in StoreType::iter() {
+            if st.is_stored_every_block() || is_full_commit {
+                let key_prefix = if st.is_stored_every_block() {
+                    tree_key_prefix_with_height(st, height)
+                } else {
+                    tree_key_prefix_with_epoch(st, epoch)
+                };
+                let root_key =
+                    format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
+                if self.check_merkle_store_overwrites
+                    && !st.is_stored_every_block()
+                    && self.read_value_bytes(block_cf, &root_key)?.is_some()
+                {
+                    tracing::warn!(
+                        "The {st:?} merkle tree store of the epoch {epoch} is \
+                         overwritten at height {height}, it should only be \
+                         written once per epoch"
+                    );
+                    self.merkle_store_overwrites.lock().unwrap().push(
+                        MerkleStoreOverwrite {
+                            store_type: *st,
+                            epoch,
+                            height,
+                        },
+                    );
+                }
+                self.add_value_to_batch(
+                    block_cf,
+                    root_key,
+                    merkle_tree_stores.root(st),
+                    batch,
+                );
+                let store_key =
+                    format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
+                self.add_value_bytes_to_batch(
+                    block_cf,
+                    store_key,
+                    merkle_tree_stores.store(st).encode(),
+                    batch,
+                );
+            }
+        }
+
+        // Block header
+        if let Some(h) = header {
+            let header_key = format!("{prefix}/{BLOCK_HEADER_KEY_SEGMENT}");
+            self.add_value_to_batch(block_cf, header_key, &h, batch);
+        }
+        // Block time
+        let time_key = format!("{prefix}/{BLOCK_TIME_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, time_key, &time, batch);
+        // Block epoch
+        let epoch_key = format!("{prefix}/{EPOCH_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, epoch_key, &epoch, batch);
+        // Block gas
+        let gas_key = format!("{prefix}/{BLOCK_GAS_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, gas_key, &gas, batch);
+        // Block results
+        let results_key = format!("{RESULTS_KEY_PREFIX}/{}", height.raw());
+        self.add_value_to_batch(block_cf, results_key, &results, batch);
+        // Block archive, the changed keys are archived with their diffs
+        if self.archive {
+            let archive_cf = self.get_column_family(ARCHIVE_CF)?;
+            let archive_key = format!("{prefix}/{ARCHIVE_BLOCK_KEY_SEGMENT}");
+            self.add_value_to_batch(
+                archive_cf,
+                archive_key,
+                &(header, time, epoch, results),
+                batch,
+            );
+        }
+        // Predecessor block epochs
+        let pred_epochs_key = format!("{prefix}/{PRED_EPOCHS_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, pred_epochs_key, &pred_epochs, batch);
+        // Address gen
+        let address_gen_key = format!("{prefix}/{ADDRESS_GEN_KEY_SEGMENT}");
+        self.add_value_to_batch(block_cf, address_gen_key, &address_gen, batch);
+
+        // Block height
+        let height_bytes = encode(&height);
+        self.add_state_value_hash_to_batch(
+            state_cf,
+            BLOCK_HEIGHT_KEY,
+            &height_bytes,
+            batch,
+        );
+        self.add_value_bytes_to_batch(
+            state_cf,
+            BLOCK_HEIGHT_KEY,
+            height_bytes,
+            batch,
+        );
+
+        Ok(())
+    }
+
+    fn read_block_header(&self, height: BlockHeight) -> Result<Option<Header>> {
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let header_key = format!("{}/{BLOCK_HEADER_KEY_SEGMENT}", height.raw());
+        self.read_value(block_cf, header_key)
+    }
+
+    fn read_merkle_tree_stores(
+        &self,
+        epoch: Epoch,
+        base_height: BlockHeight,
+        store_type: Option<StoreType>,
+    ) -> Result<Option<MerkleTreeStoresRead>> {
+        // Get the latest height at which the tree stores were written
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let mut merkle_tree_stores = MerkleTreeStoresRead::default();
+        let store_types = store_type
+            .as_ref()
+            .map(|st| Either::Left(std::iter::once(st)))
+            .unwrap_or_else(|| Either::Right(StoreType::iter()));
+        for st in store_types {
+            let key_prefix = if st.is_stored_every_block() {
+                tree_key_prefix_with_height(st, base_height)
+            } else {
+                tree_key_prefix_with_epoch(st, epoch)
+            };
+            let root_key =
+                format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
+            match self.read_value(block_cf, root_key)? {
+                Some(root) => merkle_tree_stores.set_root(st, root),
+                None => return Ok(None),
+            }
+
+            let store_key =
+                format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
+            match self.read_value_bytes(block_cf, store_key)? {
+                Some(bytes) => {
+                    merkle_tree_stores.set_store(st.decode_store(bytes)?)
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(merkle_tree_stores))
+    }
+
+    fn has_replay_protection_entry(
+        &self,
+        hash: &namada_sdk::hash::Hash,
+    ) -> Result<bool> {
+        let replay_protection_cf =
+            self.get_column_family(REPLAY_PROTECTION_CF)?;
+
+        for key in [
+            replay_protection::current_key(hash),
+            replay_protection::key(hash),
+        ] {
+            let key = key.to_string();
+            // Skip the read if the key is definitely not present, which is
+            // the case for most of the txs. The check can be a false
+            // positive, so the key must still be read otherwise.
+            if self.inner.key_may_exist_cf(replay_protection_cf, &key)
+                && self
+                    .inner
+                    .get_pinned_cf(replay_protection_cf, key)
+                    .map_err(|e| Error::DBError(e.into_string()))?
+                    .is_some()
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn read_diffs_val(
+        &self,
+        key: &Key,
+        height: BlockHeight,
+        is_old: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let key = if is_old {
+            old_and_new_diff_key(key, height)?.0
+        } else {
+            old_and_new_diff_key(key, height)?.1
+        };
+        let value = self.read_value_bytes(diffs_cf, key)?;
+        self.decrypt_subspace_val(value)
+    }
+
+    fn read_subspace_val(&self, key: &Key) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "storage-metrics")]
+        self.metrics.subspace_reads.fetch_add(1, Ordering::Relaxed);
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let value = self.read_value_bytes(subspace_cf, key.to_string())?;
+        self.decrypt_subspace_val(value)
+    }
+
+    fn read_subspace_val_with_height(
+        &self,
+        key: &Key,
+        height: BlockHeight,
+        last_height: BlockHeight,
+    ) -> Result<Option<Vec<u8>>> {
+        // Check if the value changed at this height
+        let diffs_cf = self.get_column_family(DIFFS_CF)?;
+        let (old_val_key, new_val_key) = old_and_new_diff_key(key, height)?;
+
+        // If it has a "new" val, it was written at this height
+        match self.read_value_bytes(diffs_cf, new_val_key)? {
+            Some(new_val) => {
+                return self.decrypt_subspace_val(Some(new_val));
+            }
+            None => {
+                // If it has an "old" val, it was deleted at this height
+                if self.inner.key_may_exist_cf(diffs_cf, &old_val_key) {
+                    // check if it actually exists
+                    if self.read_value_bytes(diffs_cf, old_val_key)?.is_some() {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+
+        // If the value didn't change at the given height, we try to look for it
+        // at successor heights, up to the `last_height`
+        let mut raw_height = checked!(height.0 + 1)?;
+        loop {
+            // Try to find the next diff on this key
+            let (old_val_key, new_val_key) =
+                old_and_new_diff_key(key, BlockHeight(raw_height))?;
+            let old_val = self.read_value_bytes(diffs_cf, &old_val_key)?;
+            // If it has an "old" val, it's the one we're looking for
+            match old_val {
+                Some(bytes) => return self.decrypt_subspace_val(Some(bytes)),
+                None => {
+                    // Check if the value was created at this height instead,
+                    // which would mean that it wasn't present before
+                    if self.inner.key_may_exist_cf(diffs_cf, &new_val_key) {
+                        // check if it actually exists
+                        if self
+                            .read_value_bytes(diffs_cf, new_val_key)?
+                            .is_some()
+                        {
+                            return Ok(None);
+                        }
+                    }
+
+                    if raw_height >= last_height.0 {
+                        // Read from latest height
+                        return self.read_subspace_val(key);
+                    } else {
+                        checked!(raw_height += 1)?
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_subspace_val(
+        &mut self,
+        height: BlockHeight,
+        key: &Key,
+        value: impl AsRef<[u8]>,
+        persist_diffs: bool,
+    ) -> Result<i64> {
+        let mut batch = RocksDB::batch();
+        let size_diff = self.batch_write_subspace_val(
+            &mut batch,
+            height,
+            key,
+            value,
+            persist_diffs,
+        )?;
+        self.exec_batch(batch)?;
+        Ok(size_diff)
+    }
+
+    fn delete_subspace_val(
+        &mut self,
+        height: BlockHeight,
+        key: &Key,
+        persist_diffs: bool,
+    ) -> Result<i64> {
+        let mut batch = RocksDB::batch();
+        let prev_len = self.batch_delete_subspace_val(
+            &mut batch,
+            height,
+            key,
+            persist_diffs,
+        )?;
+        self.exec_batch(batch)?;
+        Ok(prev_len)
+    }
+
+    fn batch() -> Self::WriteBatch {
+        RocksDBWriteBatch::default()
+    }
+
+    fn exec_batch(&self, batch: Self::WriteBatch) -> Result<()> {
+        let _write_lock = self.lock_writes()?;
+        self.write_batch(batch, &self.write_options())?;
+        self.flush_periodically()
+    }
+
+    fn batch_write_subspace_val(
+        &self,
+        batch: &mut Self::WriteBatch,
+        height: BlockHeight,
+        key: &Key,
+        value: impl AsRef<[u8]>,
+        persist_diffs: bool,
+    ) -> Result<i64> {
+        #[cfg(feature = "storage-metrics")]
+        self.metrics.subspace_writes.fetch_add(1, Ordering::Relaxed);
+        let len = i64::try_from(value.as_ref().len())?;
+        let stored_value = self.encrypt_subspace_val(value.as_ref());
+        let value = stored_value.as_ref();
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+        let size_diff =
+            match self.read_value_bytes(subspace_cf, key.to_string())? {
+                Some(old_value) => {
+                    let old_len = self.subspace_val_len(&old_value);
+                    let size_diff = checked!(len - i64::try_from(old_len)?)?;
+                    // Persist the previous value
+                    self.batch_write_subspace_diff(
+                        batch,
+                        height,
+                        key,
+                        Some(&old_value),
+                        Some(value),
+                        persist_diffs,
+                    )?;
+                    size_diff
+                }
+                None => {
+                    self.batch_write_subspace_diff(
+                        batch,
+                        height,
+                        key,
+                        None,
+                        Some(value),
+                        persist_diffs,
+                    )?;
+                    len
+                }
+            };
+
+        // Write the new key-val
+        batch.inner.put_cf(subspace_cf, key.to_string(), value);
+        checked!(batch.size_diff += size_diff)?;
+
+        Ok(size_diff)
+    }
+
+    fn batch_delete_subspace_val(
+        &self,
+        batch: &mut Self::WriteBatch,
+        height: BlockHeight,
+        key: &Key,
+        persist_diffs: bool,
+    ) -> Result<i64> {
+        let subspace_cf = self.get_column_family(SUBSPACE_CF)?;
+
+        // Check the length of previous value, if any
+        let prev_len =
+            match self.read_value_bytes(subspace_cf, key.to_string())? {
+                Some(prev_value) => {
+                    let prev_len =
+                        i64::try_from(self.subspace_val_len(&prev_value))?;
+                    // Persist the previous value
+                    self.batch_write_subspace_diff(
+                        batch,
+                        height,
+                        key,
+                        Some(&prev_value),
+                        None,
+                        persist_diffs,
+                    )?;
+                    prev_len
+                }
+                None => 0,
+            };
+
+        // Delete the key-val
+        batch.inner.delete_cf(subspace_cf, key.to_string());
+        checked!(batch.size_diff -= prev_len)?;
+
+        Ok(prev_len)
+    }
+
+    fn prune_merkle_tree_store(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        store_type: &StoreType,
+        epoch: Epoch,
+    ) -> Result<()> {
+        // The stores of the epochs of the pinned heights are kept
+        if self.pinned_epochs()?.contains(&epoch.0) {
+            return Ok(());
+        }
+        let block_cf = self.get_column_family(BLOCK_CF)?;
+        let key_prefix = tree_key_prefix_with_epoch(store_type, epoch);
+        let root_key = format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}");
+        batch.inner.delete_cf(block_cf, root_key);
+        let store_key = format!("{key_prefix}/{MERKLE_TREE_STORE_KEY_SEGMENT}");
+        batch.inner.delete_cf(block_cf, store_key);
+        Ok(())
+    }
+
+    fn read_bridge_pool_signed_nonce(
+        &self,
+        height: BlockHeight,
+        last_height: BlockHeight,
+    ) -> Result<Option<ethereum_events::Uint>> {
+        let nonce_key = bridge_pool::get_signed_root_key();
+        let bytes = if height == BlockHeight(0) || height >= last_height {
+            self.read_subspace_val(&nonce_key)?
+        } else {
+            self.read_subspace_val_with_height(&nonce_key, height, last_height)?
+        };
+        match bytes {
+            Some(bytes) => {
+                let bp_root_proof = BridgePoolRootProof::try_from_slice(&bytes)
+                    .map_err(Error::BorshCodingError)?;
+                Ok(Some(bp_root_proof.data.1))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn write_replay_protection_entry(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        key: &Key,
+    ) -> Result<()> {
+        #[cfg(feature = "storage-metrics")]
+        self.metrics.rp_writes.fetch_add(1, Ordering::Relaxed);
+        let replay_protection_cf =
+            self.get_column_family(REPLAY_PROTECTION_CF)?;
+
+        self.add_value_bytes_to_batch(
+            replay_protection_cf,
+            key.to_string(),
+            vec![],
+            batch,
+        );
+
+        Ok(())
+    }
+
+    fn write_replay_protection_entries(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        keys: &[Key],
+    ) -> Result<()> {
+        #[cfg(feature = "storage-metrics")]
+        self.metrics
+            .rp_writes
+            .fetch_add(u64::try_from(keys.len())?, Ordering::Relaxed);
+        let replay_protection_cf =
+            self.get_column_family(REPLAY_PROTECTION_CF)?;
+
+        for key in keys {
+            self.add_value_bytes_to_batch(
+                replay_protection_cf,
+                key.to_string(),
+                vec![],
+                batch,
+            );
+        }
+
+        Ok(())
+    }
+
+    fn delete_replay_protection_entry(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        key: &Key,
+    ) -> Result<()> {
+        let replay_protection_cf =
+            self.get_column_family(REPLAY_PROTECTION_CF)?;
+        batch.inner.delete_cf(replay_protection_cf, key.to_string());
+
+        Ok(())
+    }
+
+    fn move_current_replay_protection_entries(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+    ) -> Result<()> {
+        let replay_protection_cf =
+            self.get_column_family(REPLAY_PROTECTION_CF)?;
+        let stripped_prefix = Some(replay_protection::current_prefix());
+
+        for (ref hash_str, _) in iter_prefix_no_gas(
+            self,
+            replay_protection_cf,
+            stripped_prefix.as_ref(),
+            None,
+        ) {
+            let hash = namada_sdk::hash::Hash::from_str(hash_str)
+                .expect("Failed hash conversion");
+            let current_key = replay_protection::current_key(&hash);
+            let key = replay_protection::key(&hash);
+
+            // Delete the current key and move it to the general bucket
+            batch
+                .inner
+                .delete_cf(replay_protection_cf, current_key.to_string());
+            batch.inner.put_cf(replay_protection_cf, key.to_string(), vec![]);
+        }
+
+        Ok(())
+    }
+
+    fn prune_non_persisted_diffs(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        height: BlockHeight,
+    ) -> Result<()> {
+        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
+
+        let diff_old_key_prefix = Key {
+            segments: vec![
+                height.to_db_key(),
+                OLD_DIFF_PREFIX.to_string().to_db_key(),
+            ],
+        };
+        for (key_str, _val) in iter_prefix_no_gas(
+            self,
+            rollback_cf,
+            None,
+            Some(&diff_old_key_prefix),
+        ) {
+            batch.inner.delete_cf(rollback_cf, key_str)
+        }
+
+        let diff_new_key_prefix = Key {
+            segments: vec![
+                height.to_db_key(),
+                NEW_DIFF_PREFIX.to_string().to_db_key(),
+            ],
+        };
+        for (key_str, _val) in iter_prefix_no_gas(
+            self,
+            rollback_cf,
+            None,
+            Some(&diff_new_key_prefix),
+        ) {
+            batch.inner.delete_cf(rollback_cf, key_str)
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn overwrite_entry(
+        &self,
+        batch: &mut Self::WriteBatch,
+        height: Option<BlockHeight>,
+        cf: &DbColFam,
+        key: &Key,
+        new_value: impl AsRef<[u8]>,
+    ) -> Result<()> {
+        let state_cf = self.get_column_family(STATE_CF)?;
+        let last_height: BlockHeight = self
+            .read_value(state_cf, BLOCK_HEIGHT_KEY)?
+            .ok_or_else(|| {
+                Error::DBError("No block height found".to_string())
+            })?;
+        let desired_height = height.unwrap_or(last_height);
+
+        if desired_height != last_height {
+            todo!(
+                "Overwriting values at heights different than the last \
+                 committed height hast yet to be implemented"
+            );
+        }
+        // NB: the following code only updates values
+        // written to at the last committed height
+
+        let len = new_value.as_ref().len();
+        let val = if cf == &DbColFam::SUBSPACE {
+            self.encrypt_subspace_val(new_value.as_ref())
+        } else {
+            Cow::Borrowed(new_value.as_ref())
+        };
+        let val = val.as_ref();
+
+        // Write the new key-val in the Db column family
+        let cf_name = self.get_column_family(cf.to_str())?;
+        let old_len = self.read_value_bytes(cf_name, key.to_string())?.map_or(
+            0,
+            |old_value| match cf {
+                DbColFam::SUBSPACE => self.subspace_val_len(&old_value),
+                _ => old_value.len(),
+            },
+        );
+        let size_diff =
+            checked!(i64::try_from(len)? - i64::try_from(old_len)?)?;
+        checked!(batch.size_diff += size_diff)?;
+        if cf == &DbColFam::STATE {
+            self.add_state_value_hash_to_batch(
+                cf_name,
+                &key.to_string(),
+                val,
+                batch,
+            );
+        }
+        self.add_value_bytes_to_batch(
+            cf_name,
+            key.to_string(),
+            val.to_vec(),
+            batch,
+        );
+
+        // If the CF is subspace, additionally update the diffs
+        if cf == &DbColFam::SUBSPACE {
+            let diffs_cf = self.get_column_family(DIFFS_CF)?;
+            let diffs_key = Key::from(last_height.to_db_key())
+                .with_segment("new".to_owned())
+                .join(key)
+                .to_string();
+
+            self.add_value_bytes_to_batch(
+                diffs_cf,
+                diffs_key,
+                val.to_vec(),
+                batch,
+            );
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn apply_migration_to_batch(
+        &self,
+        updates: impl IntoIterator<Item = DbUpdateType>,
+    ) -> Result<RocksDBWriteBatch> {
+        let mut db_visitor = storage::RocksDBUpdateVisitor::new(self);
+        for change in updates.into_iter() {
+            match change.update(&mut db_visitor) {
+                Ok(status) => {
+                    tracing::info!("{}", status);
+                }
+                Err(e) => {
+                    let error = format!(
+                        "Attempt to write to key/pattern <{}> failed:\n{}.",
+                        change.pattern(),
+                        e
+                    );
+                    tracing::error!(error);
+                    return Err(Error::DBError(error));
+                }
+            }
+        }
+        Ok(db_visitor.take_batch())
+    }
+}
+
+/// A struct that can visit a set of updates,
+/// registering them all in the batch
+pub struct RocksDBUpdateVisitor<'db> {
+    db: &'db RocksDB,
+    batch: RocksDBWriteBatch,
+}
+
+impl<'db> RocksDBUpdateVisitor<'db> {
+    pub fn new(db: &'db RocksDB) -> Self {
+        Self {
+            db,
+            batch: Default::default(),
+        }
+    }
+
+    pub fn take_batch(self) -> RocksDBWriteBatch {
+        self.batch
+    }
+
+    /// Migrate the value of a key from the Borsh encoding of the type `Old`
+    /// to that of the type `New`. The old value is decoded and transformed
+    /// with `f`, and its new encoding is written with
+    /// [`DB::overwrite_entry`]. A missing value or a value that cannot be
+    /// decoded as `Old` is an error.
+    pub fn migrate_value<Old, New>(
+        &mut self,
+        key: &Key,
+        cf: &DbColFam,
+        f: impl FnOnce(Old) -> New,
+    ) -> Result<()>
+    where
+        Old: BorshDeserialize,
+        New: BorshSerialize,
+    {
+        let bytes = self.read(key, cf).ok_or_else(|| {
+            Error::DBError(format!(
+                "No value to migrate under the key {key} in the {} column \
+                 family",
+                cf.to_str()
+            ))
+        })?;
+        let old: Old = decode(bytes).map_err(|e| {
+            Error::DBError(format!(
+                "Unable to decode the value to migrate under the key {key}: \
+                 {e}"
+            ))
+        })?;
+        self.db
+            .overwrite_entry(&mut self.batch, None, cf, key, encode(&f(old)))
+    }
+
+    /// Move the value of the key `from` to the key `to` in the given column
+    /// family, e.g. for a migration changing the storage path of some data.
+    /// Returns `false` without any change if `from` has no value. If `to`
+    /// already has a value, it's overwritten if `overwrite` is set,
+    /// otherwise it's an error.
+    ///
+    /// In the subspace, the rename is recorded in the diffs of the last
+    /// committed height as if the block had made it, so rolling back the
+    /// block also reverts the rename. The diffs of a key are persisted or
+    /// only kept for rollback as for the other writes, see
+    /// [`is_key_diff_storable`]. The old values that the keys already have in
+    /// the diffs of this height are kept, as they're their values from before
+    /// the block.
+    ///
+    /// The keys are read from the DB, not from the batch of this visitor, so
+    /// a rename doesn't see the changes made earlier by the same visitor. In
+    /// particular, renames can't be chained in one visitor: after renaming
+    /// `a` to `b`, renaming `b` to `c` finds no value under `b` and returns
+    /// `false`. Such renames must be written one visitor at a time, or be
+    /// merged into a single rename from `a` to `c`.
+    pub fn rename_key(
+        &mut self,
+        from: &Key,
+        to: &Key,
+        cf: &DbColFam,
+        overwrite: bool,
+    ) -> Result<bool> {
+        let cf_name = self.db.get_column_family(cf.to_str())?;
+        let Some(value) =
+            self.db.read_value_bytes(cf_name, from.to_string())?
+        else {
+            return Ok(false);
+        };
+        if from == to {
+            return Ok(true);
+        }
+        let to_value = self.db.read_value_bytes(cf_name, to.to_string())?;
+        if to_value.is_some() && !overwrite {
+            return Err(Error::DBError(format!(
+                "Cannot rename the key {from} to {to} in the {} column \
+                 family, the key {to} already has a value",
+                cf.to_str()
+            )));
+        }
+
+        if cf != &DbColFam::SUBSPACE {
+            self.db
+                .overwrite_entry(&mut self.batch, None, cf, to, value)?;
+            self.batch.inner.delete_cf(cf_name, from.to_string());
+            return Ok(true);
+        }
+
+        // The values are moved as stored, i.e. encrypted if the encryption
+        // is enabled
+        let state_cf = self.db.get_column_family(STATE_CF)?;
+        let height: BlockHeight =
+            self.db.read_value(state_cf, BLOCK_HEIGHT_KEY)?.ok_or_else(
+                || Error::DBError("No block height found".to_string()),
+            )?;
+        let db = self.db;
+        let diffs_cf = |key: &Key| {
+            db.get_column_family(if is_key_diff_storable(key) {
+                DIFFS_CF
+            } else {
+                ROLLBACK_CF
+            })
+        };
+        for (key, current) in [(from, Some(&value)), (to, to_value.as_ref())] {
+            let diffs_cf = diffs_cf(key)?;
+            let (old_key, new_key) = old_and_new_diff_key(key, height)?;
+            let has_diffs =
+                self.db.read_value_bytes(diffs_cf, &old_key)?.is_some()
+                    || self.db.read_value_bytes(diffs_cf, &new_key)?.is_some();
+            if let (false, Some(current)) = (has_diffs, current) {
+                self.batch.inner.put_cf(diffs_cf, old_key, current);
+            }
+        }
+        let (_, from_new_key) = old_and_new_diff_key(from, height)?;
+        self.batch.inner.delete_cf(diffs_cf(from)?, from_new_key);
+        self.batch.inner.delete_cf(cf_name, from.to_string());
+        let (_, to_new_key) = old_and_new_diff_key(to, height)?;
+        self.batch.inner.put_cf(diffs_cf(to)?, to_new_key, &value);
+        self.batch.inner.put_cf(cf_name, to.to_string(), &value);
+
+        // Only the overwritten value of `to` is gone
+        let removed = to_value.map_or(0, |val| self.db.subspace_val_len(&val));
+        checked!(self.batch.size_diff -= i64::try_from(removed)?)?;
+        Ok(true)
+    }
+}
+
+impl<'db> DBUpdateVisitor for RocksDBUpdateVisitor<'db> {
+    fn read(&self, key: &Key, cf: &DbColFam) -> Option<Vec<u8>> {
+        match cf {
+            DbColFam::SUBSPACE => self
+                .db
+                .read_subspace_val(key)
+                .expect("Failed to read from storage"),
+            _ => {
+                let cf_str = cf.to_str();
+                let cf = self
+                    .db
+                    .get_column_family(cf_str)
+                    .expect("Failed to read column family from storage");
+                self.db
+                    .read_value_bytes(cf, key.to_string())
+                    .expect("Failed to get key from storage")
+            }
+        }
+    }
+
+    fn write(&mut self, key: &Key, cf: &DbColFam, value: impl AsRef<[u8]>) {
+        self.db
+            .overwrite_entry(&mut self.batch, None, cf, key, value)
+            .expect("Failed to overwrite a key in storage")
+    }
+
+    fn delete(&mut self, key: &Key, cf: &DbColFam) {
+        let state_cf = self.db.get_column_family(STATE_CF).unwrap();
+        let last_height: BlockHeight = self
+            .db
+            .read_value(state_cf, BLOCK_HEIGHT_KEY)
+            .unwrap()
+            .unwrap();
+        match cf {
+            DbColFam::SUBSPACE => {
+                self.db
+                    .batch_delete_subspace_val(
+                        &mut self.batch,
+                        last_height,
+                        key,
+                        true,
+                    )
+                    .expect("Failed to delete key from storage");
+            }
+            _ => {
+                let cf_str = cf.to_str();
+                let cf = self
+                    .db
+                    .get_column_family(cf_str)
+                    .expect("Failed to get read column family from storage");
+                self.batch.inner.delete_cf(cf, key.to_string());
+            }
+        };
+    }
+
+    fn get_pattern(&self, pattern: Regex) -> Vec<(String, Vec<u8>)> {
+        self.db
+            .iter_pattern(None, pattern)
+            .map(|(k, v, _)| (k, v))
+            .collect()
+    }
+}
+
+impl<'iter> DBIter<'iter> for RocksDB {
+    type PatternIter = PersistentPatternIterator<'iter>;
+    type PrefixIter = PersistentPrefixIterator<'iter>;
+
+    fn iter_prefix(
+        &'iter self,
+        prefix: Option<&Key>,
+    ) -> PersistentPrefixIterator<'iter> {
+        iter_subspace_prefix(self, prefix)
+    }
+
+    fn iter_pattern(
+        &'iter self,
+        prefix: Option<&Key>,
+        pattern: Regex,
+    ) -> PersistentPatternIterator<'iter> {
+        iter_subspace_pattern(self, prefix, pattern)
+    }
+
+    fn iter_results(&'iter self) -> PersistentPrefixIterator<'iter> {
+        let db_prefix = "results/".to_owned();
+        let prefix = "results".to_owned();
+
+        let block_cf = self
+            .get_column_family(BLOCK_CF)
+            .expect("Block column family should exist");
+        let read_opts = make_iter_read_opts(Some(prefix.clone()));
+        let iter = self.inner.iterator_cf_opt(
+            block_cf,
+            read_opts,
+            IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+        );
+        PersistentPrefixIterator(PrefixIterator::new(iter, db_prefix), None)
+    }
+
+    fn iter_old_diffs(
+        &'iter self,
+        height: BlockHeight,
+        prefix: Option<&'iter Key>,
+    ) -> PersistentPrefixIterator<'iter> {
+        let diffs_cf = self
+            .get_column_family(DIFFS_CF)
+            .expect("Diffs column family should exist");
+        iter_diffs_prefix(self, diffs_cf, height, prefix, true)
+    }
+
+    fn iter_new_diffs(
+        &'iter self,
+        height: BlockHeight,
+        prefix: Option<&'iter Key>,
+    ) -> PersistentPrefixIterator<'iter> {
+        let diffs_cf = self
+            .get_column_family(DIFFS_CF)
+            .expect("Diffs column family should exist");
+        iter_diffs_prefix(self, diffs_cf, height, prefix, false)
+    }
+
+    fn iter_current_replay_protection(&'iter self) -> Self::PrefixIter {
+        let replay_protection_cf = self
+            .get_column_family(REPLAY_PROTECTION_CF)
+            .expect("Replay protection column family should exist");
+
+        let prefix = Some(replay_protection::current_prefix());
+        iter_prefix(self, replay_protection_cf, None, prefix.as_ref())
+    }
+}
+
+fn iter_subspace_prefix<'iter>(
+    db: &'iter RocksDB,
+    prefix: Option<&Key>,
+) -> PersistentPrefixIterator<'iter> {
+    let subspace_cf = db
+        .get_column_family(SUBSPACE_CF)
+        .expect("Subspace column family should exist");
+    let stripped_prefix = None;
+    iter_prefix(db, subspace_cf, stripped_prefix, prefix)
+        .decrypted(db.subspace_cipher.as_ref())
+}
+
+fn iter_subspace_pattern<'iter>(
+    db: &'iter RocksDB,
+    prefix: Option<&Key>,
+    pattern: Regex,
+) -> PersistentPatternIterator<'iter> {
+    PersistentPatternIterator {
+        inner: PatternIterator {
+            iter: iter_subspace_prefix(db, prefix),
+            pattern,
+        },
+    }
+}
+
+fn iter_diffs_prefix<'a>(
+    db: &'a RocksDB,
+    cf: &'a ColumnFamily,
+    height: BlockHeight,
+    prefix: Option<&Key>,
+    is_old: bool,
+) -> PersistentPrefixIterator<'a> {
+    let stripped_prefix = diffs_stripped_prefix(height, is_old);
+    // get keys without the `stripped_prefix`
+    iter_prefix(db, cf, Some(&stripped_prefix), prefix)
+        .decrypted(db.subspace_cipher.as_ref())
+}
+
+/// Like [`iter_diffs_prefix`], but without computing the gas cost, for
+/// internal callers.
+fn iter_diffs_prefix_no_gas<'a>(
+    db: &'a RocksDB,
+    cf: &'a ColumnFamily,
+    height: BlockHeight,
+    is_old: bool,
+) -> PrefixIteratorNoGas<'a> {
+    let stripped_prefix = diffs_stripped_prefix(height, is_old);
+    // get keys without the `stripped_prefix`
+    iter_prefix_no_gas(db, cf, Some(&stripped_prefix), None)
+}
+
+/// The prefix of the old or new diffs at the given height
+fn diffs_stripped_prefix(height: BlockHeight, is_old: bool) -> Key {
+    let kind = if is_old {
+        OLD_DIFF_PREFIX
+    } else {
+        NEW_DIFF_PREFIX
+    };
+    Key::from(height.to_db_key())
+        .push(&kind.to_string())
+        .unwrap()
+}
+
+/// Create an iterator over key-vals in the given CF matching the given
+/// prefix(es). If any, the `stripped_prefix` is matched first and will be
+/// removed from the matched keys. If any, the second `prefix` is matched
+/// against the stripped keys and remains in the matched keys.
+fn iter_prefix<'a>(
+    db: &'a RocksDB,
+    cf: &'a ColumnFamily,
+    stripped_prefix: Option<&Key>,
+    prefix: Option<&Key>,
+) -> PersistentPrefixIterator<'a> {
+    PersistentPrefixIterator(
+        prefix_iterator(db, cf, stripped_prefix, prefix),
+        None,
+    )
+}
+
+/// Like [`iter_prefix`], but the iterator doesn't compute the gas cost of the
+/// key-vals. Meant for internal maintenance scans that don't need metering.
+fn iter_prefix_no_gas<'a>(
+    db: &'a RocksDB,
+    cf: &'a ColumnFamily,
+    stripped_prefix: Option<&Key>,
+    prefix: Option<&Key>,
+) -> PrefixIteratorNoGas<'a> {
+    PrefixIteratorNoGas(prefix_iterator(db, cf, stripped_prefix, prefix))
+}
+
+fn prefix_iterator<'a>(
+    db: &'a RocksDB,
+    cf: &'a ColumnFamily,
+    stripped_prefix: Option<&Key>,
+    prefix: Option<&Key>,
+) -> PrefixIterator<rocksdb::DBIterator<'a>> {
+    let stripped_prefix = match stripped_prefix {
+        Some(p) if !p.is_empty() => format!("{p}/"),
+        _ => "".to_owned(),
+    };
+    let prefix = match prefix {
+        Some(p) if !p.is_empty() => {
+            format!("{stripped_prefix}{p}/")
+        }
+        _ => stripped_prefix.clone(),
+    };
+    let read_opts = make_iter_read_opts(Some(prefix.clone()));
+    let iter = db.inner.iterator_cf_opt(
+        cf,
+        read_opts,
+        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+    );
+    PrefixIterator::new(iter, stripped_prefix)
+}
+
+#[derive(Debug)]
+pub struct PersistentPrefixIterator<'a>(
+    PrefixIterator<rocksdb::DBIterator<'a>>,
+    /// The cipher to decrypt the values with, if they're encrypted
+    Option<&'a SubspaceCipher>,
+);
+
+impl<'a> PersistentPrefixIterator<'a> {
+    /// Decrypt the iterated values with the given cipher, if any
+    fn decrypted(self, cipher: Option<&'a SubspaceCipher>) -> Self {
+        Self(self.0, cipher)
+    }
+
+    /// Make the keys that don't start with the iterator's prefix an error,
+    /// instead of skipping them with a warning
+    pub fn checked(self) -> CheckedPrefixIterator<'a> {
+        CheckedPrefixIterator(self)
+    }
+
+    /// Get the next pair and its gas cost. An unmatched prefix is skipped
+    /// with a warning if `skip_unmatched`, otherwise it's an error. A value
+    /// that can't be decrypted is an error.
+    fn next_with_gas(
+        &mut self,
+        skip_unmatched: bool,
+    ) -> Option<Result<(String, Vec<u8>, u64)>> {
+        let (key, val) =
+            match try_next_stripped_key_val(&mut self.0, skip_unmatched)? {
+                Ok(key_val) => key_val,
+                Err(e) => return Some(Err(e)),
+            };
+        // The gas is computed from the plaintext value
+        let val = match self.1 {
+            Some(cipher) => match cipher.decrypt(&val) {
+                Ok(val) => val,
+                Err(_) => {
+                    return Some(Err(Error::CorruptValue {
+                        key: format!("{}{key}", self.0.stripped_prefix),
+                    }));
+                }
+            },
+            None => val,
+        };
+        let gas = key.len().checked_add(val.len())?;
+        Some(Ok((key, val, gas as _)))
+    }
+}
+
+impl<'a> Iterator for PersistentPrefixIterator<'a> {
+    type Item = (String, Vec<u8>, u64);
+
+    /// Returns the next pair and the gas cost. The unmatched prefixes are
+    /// skipped, but as the iterated items can't carry an error, a value that
+    /// can't be decrypted panics: iterate with
+    /// [`PersistentPrefixIterator::checked`] to handle it instead.
+    fn next(&mut self) -> Option<(String, Vec<u8>, u64)> {
+        self.next_with_gas(true).map(|res| {
+            res.unwrap_or_else(|e| panic!("Failed to iterate the DB: {e}"))
+        })
+    }
+}
+
+/// Like [`PersistentPrefixIterator`], but a key that doesn't start with the
+/// iterator's prefix yields an error instead of being skipped. Meant for the
+/// maintenance and verification scans, where a key in the wrong place must
+/// be reported rather than dropped.
+#[derive(Debug)]
+pub struct CheckedPrefixIterator<'a>(PersistentPrefixIterator<'a>);
+
+impl<'a> Iterator for CheckedPrefixIterator<'a> {
+    type Item = Result<(String, Vec<u8>, u64)>;
+
+    /// Returns the next pair and the gas cost, or an unmatched prefix error
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_with_gas(false)
+    }
+}
+
+/// An iterator over the key-vals of a prefix that, unlike
+/// [`PersistentPrefixIterator`], doesn't compute their gas cost.
+#[derive(Debug)]
+struct PrefixIteratorNoGas<'a>(PrefixIterator<rocksdb::DBIterator<'a>>);
+
+impl<'a> Iterator for PrefixIteratorNoGas<'a> {
+    type Item = (String, Vec<u8>);
+
+    /// Returns the next pair
+    fn next(&mut self) -> Option<(String, Vec<u8>)> {
+        next_stripped_key_val(&mut self.0)
+    }
+}
+
+/// Get the next key-val from the iterator, with the key stripped of the
+/// iterator's `stripped_prefix`
+fn next_stripped_key_val(
+    iter: &mut PrefixIterator<rocksdb::DBIterator<'_>>,
+) -> Option<(String, Vec<u8>)> {
+    try_next_stripped_key_val(iter, true)
+        .map(|res| res.expect("Unmatched prefixes are skipped"))
+}
+
+/// Get the next key-val from the iterator, with the key stripped of the
+/// iterator's `stripped_prefix`. A key that doesn't start with the
+/// `stripped_prefix` is skipped with a warning if `skip_unmatched`,
+/// otherwise it's returned as an error.
+fn try_next_stripped_key_val(
+    iter: &mut PrefixIterator<rocksdb::DBIterator<'_>>,
+    skip_unmatched: bool,
+) -> Option<Result<(String, Vec<u8>)>> {
+    loop {
+        match iter.iter.next() {
+            Some(result) => {
+                let (key, val) =
+                    result.expect("Prefix iterator shouldn't fail");
+                let key = String::from_utf8(key.to_vec())
+                    .expect("Cannot convert from bytes to key string");
+                if let Some(k) = key.strip_prefix(&iter.stripped_prefix) {
+                    return Some(Ok((k.to_owned(), val.to_vec())));
+                } else if skip_unmatched {
+                    tracing::warn!(
+                        "Unmatched prefix \"{}\" in iterator's key \"{key}\"",
+                        iter.stripped_prefix
+                    );
+                } else {
+                    return Some(Err(Error::DBError(format!(
+                        "Unmatched prefix \"{}\" in iterator's key \"{key}\"",
+                        iter.stripped_prefix
+                    ))));
+                }
+            }
+            None => return None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PersistentPatternIterator<'a> {
+    inner: PatternIterator<PersistentPrefixIterator<'a>>,
+}
+
+impl<'a> Iterator for PersistentPatternIterator<'a> {
+    type Item = (String, Vec<u8>, u64);
+
+    /// Returns the next pair and the gas cost
+    fn next(&mut self) -> Option<(String, Vec<u8>, u64)> {
+        loop {
+            let next_result = self.inner.iter.next()?;
+            if self.inner.pattern.is_match(&next_result.0) {
+                return Some(next_result);
+            }
+        }
+    }
+}
+
+/// Make read options for RocksDB iterator with the given prefix
+fn make_iter_read_opts(prefix: Option<String>) -> ReadOptions {
+    let mut read_opts = ReadOptions::default();
+    // don't use the prefix bloom filter
+    read_opts.set_total_order_seek(true);
+
+    if let Some(prefix) = prefix {
+        let mut upper_prefix = prefix.into_bytes();
+        if let Some(last) = upper_prefix.last_mut() {
+            *last = last.checked_add(1).expect("cannot overflow");
+            read_opts.set_iterate_upper_bound(upper_prefix);
+        }
+    }
+
+    read_opts
+}
+
+impl DBWriteBatch for RocksDBWriteBatch {}
+
+fn old_and_new_diff_key(
+    key: &Key,
+    height: BlockHeight,
+) -> Result<(String, String)> {
+    let key_prefix = Key::from(height.to_db_key());
+    let old = key_prefix
+        .push(&OLD_DIFF_PREFIX.to_owned())
+        .map_err(Error::KeyError)?
+        .join(key);
+    let new = key_prefix
+        .push(&NEW_DIFF_PREFIX.to_owned())
+        .map_err(Error::KeyError)?
+        .join(key);
+    Ok((old.to_string(), new.to_string()))
+}
+
+/// Try to increase NOFILE limit and set the `max_open_files` limit to it in
+/// RocksDB options.
+fn set_max_open_files(cf_opts: &mut rocksdb::Options) {
+    #[cfg(unix)]
+    imp::set_max_open_files(cf_opts);
+    // Nothing to do on non-unix
+    #[cfg(not(unix))]
+    let _ = cf_opts;
+}
+
+#[cfg(unix)]
+mod imp {
+    use rlimit::{Resource, Rlim};
+
+    const DEFAULT_NOFILE_LIMIT: Rlim = Rlim::from_raw(16384);
+
+    pub fn set_max_open_files(cf_opts: &mut rocksdb::Options) {
+        let max_open_files = match increase_nofile_limit() {
+            Ok(max_open_files) => Some(max_open_files),
+            Err(err) => {
+                tracing::error!("Failed to increase NOFILE limit: {}", err);
+                None
+            }
+        };
+        if let Some(max_open_files) =
+            max_open_files.and_then(|max| max.as_raw().try_into().ok())
+        {
+            cf_opts.set_max_open_files(max_open_files);
+        }
+    }
+
+    /// Try to increase NOFILE limit and return the current soft limit.
+    fn increase_nofile_limit() -> std::io::Result<Rlim> {
+        let (soft, hard) = Resource::NOFILE.get()?;
+        tracing::debug!("Current NOFILE limit, soft={}, hard={}", soft, hard);
+
+        let target = std::cmp::min(DEFAULT_NOFILE_LIMIT, hard);
+        if soft >= target {
+            tracing::debug!(
+                "NOFILE limit already large enough, not attempting to increase"
+            );
+            Ok(soft)
+        } else {
+            tracing::debug!("Try to increase to {}", target);
+            Resource::NOFILE.set(target, target)?;
+
+            let (soft, hard) = Resource::NOFILE.get()?;
+            tracing::debug!(
+                "Increased NOFILE limit, soft={}, hard={}",
+                soft,
+                hard
+            );
+            Ok(soft)
+        }
+    }
+}
+
+#[allow(clippy::arithmetic_side_effects)]
+#[cfg(test)]
+mod test {
+    use namada_sdk::address::{
+        Address, EstablishedAddressGen, InternalAddress,
+    };
+    use namada_sdk::collections::HashMap;
+    use namada_sdk::hash::Hash;
+    use namada_sdk::masp::MaspEpoch;
+    use namada_sdk::masp_primitives::asset_type::AssetType;
+    use namada_sdk::masp_primitives::transaction::components::I128Sum;
+    use namada_sdk::state::{MerkleTree, Sha256Hasher, StorageHasher};
+    use namada_sdk::storage::conversion_state::{
+        ConversionLeaf, ConversionState,
+    };
+    use namada_sdk::storage::testing::arb_key;
+    use namada_sdk::storage::types::CommitOnlyData;
+    use namada_sdk::storage::{DbKeySeg, Epochs, EthEventsQueue};
+    use namada_sdk::token::storage_key::masp_commitment_tree_key;
+    use namada_sdk::token::{Denomination, MaspDigitPos};
+    use proptest::prelude::*;
+    use proptest::sample::{select, Index};
+    use tempfile::tempdir;
+    use test_log::test;
+
+    use super::*;
+    use crate::storage::PersistentStorageHasher;
+
+    /// Test that a block written can be loaded back from DB.
+    #[test]
+    fn test_load_state() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        let mut batch = RocksDB::batch();
+        let last_height = BlockHeight::default();
+        db.batch_write_subspace_val(
+            &mut batch,
+            last_height,
+            &Key::parse("test").unwrap(),
+            vec![1_u8, 1, 1, 1],
+            true,
+        )
+        .unwrap();
+
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            BlockHeight::default(),
+            Epoch::default(),
+            Epochs::default(),
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        let _state = db
+            .read_last_block()
+            .expect("Should be able to read last block")
+            .expect("Block should have been written");
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let key = Key::parse("test").unwrap();
+        let batch_key = Key::parse("batch").unwrap();
+
+        let mut batch = RocksDB::batch();
+        let last_height = BlockHeight(100);
+        db.batch_write_subspace_val(
+            &mut batch,
+            last_height,
+            &batch_key,
+            vec![1_u8, 1, 1, 1],
+            true,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        db.write_subspace_val(last_height, &key, vec![1_u8, 1, 1, 0], true)
+            .unwrap();
+
+        let mut batch = RocksDB::batch();
+        let last_height = BlockHeight(111);
+        db.batch_write_subspace_val(
+            &mut batch,
+            last_height,
+            &batch_key,
+            vec![2_u8, 2, 2, 2],
+            true,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        db.write_subspace_val(last_height, &key, vec![2_u8, 2, 2, 0], true)
+            .unwrap();
+
+        let prev_value = db
+            .read_subspace_val_with_height(
+                &batch_key,
+                BlockHeight(100),
+                last_height,
+            )
+            .expect("read should succeed");
+        assert_eq!(prev_value, Some(vec![1_u8, 1, 1, 1]));
+        let prev_value = db
+            .read_subspace_val_with_height(&key, BlockHeight(100), last_height)
+            .expect("read should succeed");
+        assert_eq!(prev_value, Some(vec![1_u8, 1, 1, 0]));
+
+        let updated_value = db
+            .read_subspace_val_with_height(
+                &batch_key,
+                BlockHeight(111),
+                last_height,
+            )
+            .expect("read should succeed");
+        assert_eq!(updated_value, Some(vec![2_u8, 2, 2, 2]));
+        let updated_value = db
+            .read_subspace_val_with_height(&key, BlockHeight(111), last_height)
+            .expect("read should succeed");
+        assert_eq!(updated_value, Some(vec![2_u8, 2, 2, 0]));
+
+        let latest_value = db
+            .read_subspace_val(&batch_key)
+            .expect("read should succeed");
+        assert_eq!(latest_value, Some(vec![2_u8, 2, 2, 2]));
+        let latest_value =
+            db.read_subspace_val(&key).expect("read should succeed");
+        assert_eq!(latest_value, Some(vec![2_u8, 2, 2, 0]));
+
+        let mut batch = RocksDB::batch();
+        let last_height = BlockHeight(222);
+        db.batch_delete_subspace_val(&mut batch, last_height, &batch_key, true)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        db.delete_subspace_val(last_height, &key, true).unwrap();
+
+        let deleted_value = db
+            .read_subspace_val_with_height(
+                &batch_key,
+                BlockHeight(222),
+                last_height,
+            )
+            .expect("read should succeed");
+        assert_eq!(deleted_value, None);
+        let deleted_value = db
+            .read_subspace_val_with_height(&key, BlockHeight(222), last_height)
+            .expect("read should succeed");
+        assert_eq!(deleted_value, None);
+
+        let latest_value = db
+            .read_subspace_val(&batch_key)
+            .expect("read should succeed");
+        assert_eq!(latest_value, None);
+        let latest_value =
+            db.read_subspace_val(&key).expect("read should succeed");
+        assert_eq!(latest_value, None);
+    }
+
+    #[test]
+    fn test_prefix_iter() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        let prefix_0 = Key::parse("0").unwrap();
+        let key_0_a = prefix_0.push(&"a".to_string()).unwrap();
+        let key_0_b = prefix_0.push(&"b".to_string()).unwrap();
+        let key_0_c = prefix_0.push(&"c".to_string()).unwrap();
+        let prefix_1 = Key::parse("1").unwrap();
+        let key_1_a = prefix_1.push(&"a".to_string()).unwrap();
+        let key_1_b = prefix_1.push(&"b".to_string()).unwrap();
+        let key_1_c = prefix_1.push(&"c".to_string()).unwrap();
+        let prefix_01 = Key::parse("01").unwrap();
+        let key_01_a = prefix_01.push(&"a".to_string()).unwrap();
+
+        let keys_0 = vec![key_0_a, key_0_b, key_0_c];
+        let keys_1 = vec![key_1_a, key_1_b, key_1_c];
+        let keys_01 = vec![key_01_a];
+        let all_keys = [keys_0.clone(), keys_01, keys_1.clone()].concat();
+
+        // Write the keys
+        let mut batch = RocksDB::batch();
+        let height = BlockHeight(1);
+        for key in &all_keys {
+            db.batch_write_subspace_val(&mut batch, height, key, [0_u8], true)
+                .unwrap();
+        }
+        db.exec_batch(batch).unwrap();
+
+        // Prefix "0" shouldn't match prefix "01"
+        let itered_keys: Vec<Key> = db
+            .iter_prefix(Some(&prefix_0))
+            .map(|(key, _val, _)| Key::parse(key).unwrap())
+            .collect();
+        itertools::assert_equal(keys_0, itered_keys);
+
+        let itered_keys: Vec<Key> = db
+            .iter_prefix(Some(&prefix_1))
+            .map(|(key, _val, _)| Key::parse(key).unwrap())
+            .collect();
+        itertools::assert_equal(keys_1, itered_keys);
+
+        let itered_keys: Vec<Key> = db
+            .iter_prefix(None)
+            .map(|(key, _val, _)| Key::parse(key).unwrap())
+            .collect();
+        itertools::assert_equal(all_keys, itered_keys);
+    }
+
+    #[test]
+    fn test_rollback() {
+        for persist_diffs in [true, false] {
+            println!("Running with persist_diffs: {persist_diffs}");
+
+            let dir = tempdir().unwrap();
+            let mut db = RocksDB::open(dir.path(), None);
+
+            // A key that's gonna be added on a second block
+            let add_key = Key::parse("add").unwrap();
+            // A key that's gonna be deleted on a second block
+            let delete_key = Key::parse("delete").unwrap();
+            // A key that's gonna be overwritten on a second block
+            let overwrite_key = Key::parse("overwrite").unwrap();
+
+            // Write first block
+            let mut batch = RocksDB::batch();
+            let height_0 = BlockHeight(100);
+            let mut pred_epochs = Epochs::default();
+            pred_epochs.new_epoch(height_0);
+            let conversion_state_0 = ConversionState::default();
+            let to_delete_val = vec![1_u8, 1, 0, 0];
+            let to_overwrite_val = vec![1_u8, 1, 1, 0];
+            db.batch_write_subspace_val(
+                &mut batch,
+                height_0,
+                &delete_key,
+                &to_delete_val,
+                persist_diffs,
+            )
+            .unwrap();
+            db.batch_write_subspace_val(
+                &mut batch,
+                height_0,
+                &overwrite_key,
+                &to_overwrite_val,
+                persist_diffs,
+            )
+            .unwrap();
+            for tx in [b"tx1", b"tx2"] {
+                db.write_replay_protection_entry(
+                    &mut batch,
+                    &replay_protection::key(&Hash::sha256(tx)),
+                )
+                .unwrap();
+            }
+
+            for tx in [b"tx3", b"tx4"] {
+                db.write_replay_protection_entry(
+                    &mut batch,
+                    &replay_protection::current_key(&Hash::sha256(tx)),
+                )
+                .unwrap();
+            }
+
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height_0,
+                Epoch(1),
+                pred_epochs.clone(),
+                &conversion_state_0,
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+
+            // Write second block
+            let mut batch = RocksDB::batch();
+            let height_1 = BlockHeight(101);
+            pred_epochs.new_epoch(height_1);
+            let conversion_state_1 = ConversionState::default();
+            let add_val = vec![1_u8, 0, 0, 0];
+            let overwrite_val = vec![1_u8, 1, 1, 1];
+            db.batch_write_subspace_val(
+                &mut batch,
+                height_1,
+                &add_key,
+                &add_val,
+                persist_diffs,
+            )
+            .unwrap();
+            db.batch_write_subspace_val(
+                &mut batch,
+                height_1,
+                &overwrite_key,
+                &overwrite_val,
+                persist_diffs,
+            )
+            .unwrap();
+            db.batch_delete_subspace_val(
+                &mut batch,
+                height_1,
+                &delete_key,
+                persist_diffs,
+            )
+            .unwrap();
+
+            db.move_current_replay_protection_entries(&mut batch)
+                .unwrap();
+
+            for tx in [b"tx5", b"tx6"] {
+                db.write_replay_protection_entry(
+                    &mut batch,
+                    &replay_protection::current_key(&Hash::sha256(tx)),
+                )
+                .unwrap();
+            }
+
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height_1,
+                Epoch(2),
+                pred_epochs,
+                &conversion_state_1,
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+
+            // Check that the values are as expected from second block
+            let added = db.read_subspace_val(&add_key).unwrap();
+            assert_eq!(added, Some(add_val));
+            let overwritten = db.read_subspace_val(&overwrite_key).unwrap();
+            assert_eq!(overwritten, Some(overwrite_val));
+            let deleted = db.read_subspace_val(&delete_key).unwrap();
+            assert_eq!(deleted, None);
+
+            for tx in [b"tx1", b"tx2", b"tx3", b"tx4", b"tx5", b"tx6"] {
+                assert!(
+                    db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
+                );
+            }
+
+            // The keys of the second block that are deleted by the rollback,
+            // besides its results
+            let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+            let height_1_prefix = Key::from(height_1.to_db_key());
+            let block_keys =
+                iter_prefix_no_gas(&db, block_cf, None, Some(&height_1_prefix))
+                    .count() as u64;
+            assert!(block_keys > 0);
+
+            // Rollback to the first block height
+            let stats = db.rollback(height_0).unwrap();
+            assert_eq!(
+                stats,
+                RollbackStats {
+                    // The overwritten and the deleted keys
+                    subspace_keys_restored: 2,
+                    // The added key
+                    subspace_keys_deleted: 1,
+                    // The old and new values of the overwritten key, the old
+                    // value of the deleted key and the new value of the added
+                    // key
+                    diff_keys_deleted: if persist_diffs { 4 } else { 0 },
+                    block_keys_deleted: block_keys + 1,
+                    replay_protection_entries_removed: 2,
+                }
+            );
+
+            // Check that the values are back to the state at the first block
+            let added = db.read_subspace_val(&add_key).unwrap();
+            assert_eq!(added, None);
+            let overwritten = db.read_subspace_val(&overwrite_key).unwrap();
+            assert_eq!(overwritten, Some(to_overwrite_val));
+            let deleted = db.read_subspace_val(&delete_key).unwrap();
+            assert_eq!(deleted, Some(to_delete_val));
+            // Check the conversion state
+            let state_cf = db.get_column_family(STATE_CF).unwrap();
+            let conversion_state = db
+                .inner
+                .get_cf(state_cf, "conversion_state".as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(conversion_state, encode(&conversion_state_0));
+            for tx in [b"tx1", b"tx2", b"tx3", b"tx4"] {
+                assert!(
+                    db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
+                );
+            }
+
+            for tx in [b"tx5", b"tx6"] {
+                assert!(
+                    !db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
+                );
+            }
+        }
+    }
+
+    /// Test that a rollback deletes the block keys, the results and the diffs
+    /// of the rolled back height, which are keyed by its height segment
+    #[test]
+    fn test_rollback_deletes_last_block_keys() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        let heights = [BlockHeight(100), BlockHeight(101)];
+        let mut pred_epochs = Epochs::default();
+        for (epoch, height) in (1_u64..).zip(heights) {
+            let mut batch = RocksDB::batch();
+            pred_epochs.new_epoch(height);
+            db.batch_write_subspace_val(&mut batch, height, &key, [1_u8], true)
+                .unwrap();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+        let height_keys = |db: &RocksDB, cf_name: &str, height: BlockHeight| {
+            let cf = db.get_column_family(cf_name).unwrap();
+            let prefix = Key::from(height.to_db_key());
+            iter_prefix_no_gas(db, cf, None, Some(&prefix)).count()
+        };
+        let results_key = |height: BlockHeight| {
+            format!("{RESULTS_KEY_PREFIX}/{}", height.raw())
+        };
+        for cf_name in [BLOCK_CF, DIFFS_CF] {
+            assert!(height_keys(&db, cf_name, heights[1]) > 0);
+        }
+
+        db.rollback(heights[0]).unwrap();
+
+        // Only the keys of the rolled back height are deleted
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        for cf_name in [BLOCK_CF, DIFFS_CF] {
+            assert_eq!(height_keys(&db, cf_name, heights[1]), 0);
+            assert!(height_keys(&db, cf_name, heights[0]) > 0);
+        }
+        assert!(
+            db.read_value_bytes(block_cf, results_key(heights[1]))
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            db.read_value_bytes(block_cf, results_key(heights[0]))
+                .unwrap()
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_diffs() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let key_with_diffs = Key::parse("with_diffs").unwrap();
+        let key_without_diffs = Key::parse("without_diffs").unwrap();
+
+        let initial_val = vec![1_u8, 1, 0, 0];
+        let overwrite_val = vec![1_u8, 1, 1, 0];
+
+        // Write first block
+        let mut batch = RocksDB::batch();
+        let height_0 = BlockHeight::first();
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_0,
+            &key_with_diffs,
+            &initial_val,
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_0,
+            &key_without_diffs,
+            &initial_val,
+            false,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        {
+            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
+            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+
+            // Diffs new key for `key_with_diffs` at height_0 must be
+            // present
+            let (old_with_h0, new_with_h0) =
+                old_and_new_diff_key(&key_with_diffs, height_0).unwrap();
+            assert!(db.inner.get_cf(diffs_cf, old_with_h0).unwrap().is_none());
+            assert!(db.inner.get_cf(diffs_cf, new_with_h0).unwrap().is_some());
+
+            // Diffs new key for `key_without_diffs` at height_0 must be
+            // present
+            let (old_wo_h0, new_wo_h0) =
+                old_and_new_diff_key(&key_without_diffs, height_0).unwrap();
+            assert!(db.inner.get_cf(rollback_cf, old_wo_h0).unwrap().is_none());
+            assert!(db.inner.get_cf(rollback_cf, new_wo_h0).unwrap().is_some());
+        }
+
+        // Write second block
+        let mut batch = RocksDB::batch();
+        let height_1 = height_0 + 10;
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_1,
+            &key_with_diffs,
+            &overwrite_val,
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_1,
+            &key_without_diffs,
+            &overwrite_val,
+            false,
+        )
+        .unwrap();
+        db.prune_non_persisted_diffs(&mut batch, height_0).unwrap();
+        db.exec_batch(batch).unwrap();
+
+        {
+            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
+            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+
+            // Diffs keys for `key_with_diffs` at height_0 must be present
+            let (old_with_h0, new_with_h0) =
+                old_and_new_diff_key(&key_with_diffs, height_0).unwrap();
+            assert!(db.inner.get_cf(diffs_cf, old_with_h0).unwrap().is_none());
+            assert!(db.inner.get_cf(diffs_cf, new_with_h0).unwrap().is_some());
+
+            // Diffs keys for `key_without_diffs` at height_0 must be gone
+            let (old_wo_h0, new_wo_h0) =
+                old_and_new_diff_key(&key_without_diffs, height_0).unwrap();
+            assert!(db.inner.get_cf(rollback_cf, old_wo_h0).unwrap().is_none());
+            assert!(db.inner.get_cf(rollback_cf, new_wo_h0).unwrap().is_none());
+
+            // Diffs keys for `key_with_diffs` at height_1 must be present
+            let (old_with_h1, new_with_h1) =
+                old_and_new_diff_key(&key_with_diffs, height_1).unwrap();
+            assert!(db.inner.get_cf(diffs_cf, old_with_h1).unwrap().is_some());
+            assert!(db.inner.get_cf(diffs_cf, new_with_h1).unwrap().is_some());
+
+            // Diffs keys for `key_without_diffs` at height_1 must be
+            // present
+            let (old_wo_h1, new_wo_h1) =
+                old_and_new_diff_key(&key_without_diffs, height_1).unwrap();
+            assert!(db.inner.get_cf(rollback_cf, old_wo_h1).unwrap().is_some());
+            assert!(db.inner.get_cf(rollback_cf, new_wo_h1).unwrap().is_some());
+        }
+
+        // Write third block
+        let mut batch = RocksDB::batch();
+        let height_2 = height_1 + 10;
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_2,
+            &key_with_diffs,
+            &initial_val,
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch,
+            height_2,
+            &key_without_diffs,
+            &initial_val,
+            false,
+        )
+        .unwrap();
+        db.prune_non_persisted_diffs(&mut batch, height_1).unwrap();
+        db.exec_batch(batch).unwrap();
+
+        {
+            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
+            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+
+            // Diffs keys for `key_with_diffs` at height_1 must be present
+            let (old_with_h1, new_with_h1) =
+                old_and_new_diff_key(&key_with_diffs, height_1).unwrap();
+            assert!(db.inner.get_cf(diffs_cf, old_with_h1).unwrap().is_some());
+            assert!(db.inner.get_cf(diffs_cf, new_with_h1).unwrap().is_some());
+
+            // Diffs keys for `key_without_diffs` at height_1 must be gone
+            let (old_wo_h1, new_wo_h1) =
+                old_and_new_diff_key(&key_without_diffs, height_1).unwrap();
+            assert!(db.inner.get_cf(rollback_cf, old_wo_h1).unwrap().is_none());
+            assert!(db.inner.get_cf(rollback_cf, new_wo_h1).unwrap().is_none());
+
+            // Diffs keys for `key_with_diffs` at height_2 must be present
+            let (old_with_h2, new_with_h2) =
+                old_and_new_diff_key(&key_with_diffs, height_2).unwrap();
+            assert!(db.inner.get_cf(diffs_cf, old_with_h2).unwrap().is_some());
+            assert!(db.inner.get_cf(diffs_cf, new_with_h2).unwrap().is_some());
+
+            // Diffs keys for `key_without_diffs` at height_2 must be
+            // present
+            let (old_wo_h2, new_wo_h2) =
+                old_and_new_diff_key(&key_without_diffs, height_2).unwrap();
+            assert!(db.inner.get_cf(rollback_cf, old_wo_h2).unwrap().is_some());
+            assert!(db.inner.get_cf(rollback_cf, new_wo_h2).unwrap().is_some());
+        }
+    }
+
+    /// Test the look-up of a block's epoch by its height
+    #[test]
+    fn test_epoch_at_height() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        // Epoch 0 starts at height 1 and epoch 1 at height 3
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for (height, epoch) in [(1, 0), (2, 0), (3, 1), (4, 1)] {
+            if height == 3 {
+                pred_epochs.new_epoch(BlockHeight(3));
+            }
+            let mut batch = RocksDB::batch();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                BlockHeight(height),
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        assert_eq!(db.epoch_at_height(BlockHeight(0)).unwrap(), None);
+        assert_eq!(db.epoch_at_height(BlockHeight(1)).unwrap(), Some(Epoch(0)));
+        assert_eq!(db.epoch_at_height(BlockHeight(3)).unwrap(), Some(Epoch(1)));
+        assert_eq!(db.epoch_at_height(BlockHeight(4)).unwrap(), Some(Epoch(1)));
+        assert_eq!(db.epoch_at_height(BlockHeight(5)).unwrap(), None);
+
+        // Without the block's own epoch, it's derived from the predecessor
+        // epochs of the last block
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        for height in [BlockHeight(2), BlockHeight(3)] {
+            db.inner
+                .delete_cf(
+                    block_cf,
+                    format!("{}/{EPOCH_KEY_SEGMENT}", height.raw()),
+                )
+                .unwrap();
+        }
+        assert_eq!(db.epoch_at_height(BlockHeight(2)).unwrap(), Some(Epoch(0)));
+        assert_eq!(db.epoch_at_height(BlockHeight(3)).unwrap(), Some(Epoch(1)));
+    }
+
+    /// Test that the last committed height is read without the rest of the
+    /// block state
+    #[test]
+    fn test_last_committed_height() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        assert_eq!(db.last_committed_height().unwrap(), None);
+
+        commit_blocks(&db, [BlockHeight(7)]);
+        assert_eq!(db.last_committed_height().unwrap(), Some(BlockHeight(7)));
+    }
+
+    /// Test reading the commit-only data at the last height and the one
+    /// before
+    #[test]
+    fn test_commit_only_data_at() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        assert!(db.commit_only_data_at(BlockHeight(1)).unwrap().is_none());
+
+        for height in 1..=3_u64 {
+            let mut commit_only_data = CommitOnlyData::default();
+            commit_only_data
+                .tx_gas
+                .insert(Hash::sha256(height.serialize_to_vec()), height);
+            let mut batch = RocksDB::batch();
+            TestBlock::new(BlockHeight(height))
+                .commit_only_data(commit_only_data)
+                .add_to_batch(&db, &mut batch)
+                .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        let gas = |height| {
+            db.commit_only_data_at(BlockHeight(height))
+                .unwrap()
+                .unwrap()
+                .tx_gas
+                .into_values()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(gas(3), vec![3]);
+        assert_eq!(gas(2), vec![2]);
+        assert!(db.commit_only_data_at(BlockHeight(1)).is_err());
+        assert!(db.commit_only_data_at(BlockHeight(4)).is_err());
+
+        // The predecessor of the rolled back block is not known
+        db.rollback(BlockHeight(2)).unwrap();
+        let restored = db.commit_only_data_at(BlockHeight(2)).unwrap().unwrap();
+        assert_eq!(restored.tx_gas.into_values().collect::<Vec<_>>(), vec![2]);
+        assert!(db.commit_only_data_at(BlockHeight(1)).is_err());
+    }
+
+    /// Test that a corrupted critical state value is detected when the
+    /// integrity checks are enabled
+    #[test]
+    fn test_state_integrity_checks() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        db.set_state_integrity_checks(true);
+
+        commit_blocks(&db, [BlockHeight(1)]);
+        db.read_last_block().unwrap().unwrap();
+
+        // Overwrite the block height with a value that still decodes
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        db.inner
+            .put_cf(state_cf, BLOCK_HEIGHT_KEY, encode(&BlockHeight(2)))
+            .unwrap();
+        match db.read_last_block() {
+            Err(Error::CorruptValue { key }) => {
+                assert_eq!(key, BLOCK_HEIGHT_KEY)
+            }
+            _ => panic!("The corrupted block height should be detected"),
+        }
+
+        // Without the checks, the value is read as is
+        db.set_state_integrity_checks(false);
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        assert_eq!(
+            db.read_verified_value::<BlockHeight>(state_cf, BLOCK_HEIGHT_KEY)
+                .unwrap(),
+            Some(BlockHeight(2))
+        );
+    }
+
+    /// Test that a block can only be written at the height following the
+    /// last committed block, unless explicitly allowed
+    #[test]
+    fn test_non_contiguous_block() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let write_block = |db: &RocksDB, height| {
+            let mut batch = RocksDB::batch();
+            TestBlock::new(BlockHeight(height)).add_to_batch(db, &mut batch)?;
+            db.exec_batch(batch)
+        };
+
+        // The first block may be at any height
+        write_block(&db, 5).unwrap();
+        write_block(&db, 6).unwrap();
+        for height in [6, 8, 2] {
+            match write_block(&db, height) {
+                Err(Error::NonContiguousBlock { expected, got }) => {
+                    assert_eq!(expected, BlockHeight(7));
+                    assert_eq!(got, BlockHeight(height));
+                }
+                _ => panic!("The block at height {height} should be rejected"),
+            }
+        }
+        assert_eq!(db.last_committed_height().unwrap(), Some(BlockHeight(6)));
+
+        // The guard can be lifted, e.g. to restore a snapshot
+        db.set_allow_non_contiguous_blocks(true);
+        write_block(&db, 10).unwrap();
+        db.set_allow_non_contiguous_blocks(false);
+        write_block(&db, 11).unwrap();
+        assert_eq!(db.last_committed_height().unwrap(), Some(BlockHeight(11)));
+    }
+
+    /// Test iterating over the block results with and without skipping the
+    /// corrupt ones
+    #[test]
+    fn test_iter_block_results() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        commit_blocks(&db, (1..=3).map(BlockHeight));
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        db.inner
+            .put_cf(
+                block_cf,
+                format!("{RESULTS_KEY_PREFIX}/{}", BlockHeight(2).raw()),
+                [1_u8, 2, 3],
+            )
+            .unwrap();
+
+        // The corrupt results are an error in the strict mode
+        let mut iter = db.iter_block_results(false);
+        assert_eq!(iter.next().unwrap().unwrap().0, BlockHeight(1));
+        assert!(matches!(iter.next(), Some(Err(Error::CodingError(_)))));
+        assert_eq!(iter.next().unwrap().unwrap().0, BlockHeight(3));
+        assert!(iter.next().is_none());
+        assert!(iter.corrupt_heights().is_empty());
+
+        // Or they're skipped and recorded
+        let mut iter = db.iter_block_results(true);
+        let heights = iter
+            .by_ref()
+            .map(|results| results.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(heights, vec![BlockHeight(1), BlockHeight(3)]);
+        assert_eq!(iter.corrupt_heights(), &[BlockHeight(2)]);
+    }
+
+    /// Test that the heights of the committed blocks are listed in order
+    #[test]
+    fn test_committed_block_heights() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        db.set_allow_non_contiguous_blocks(true);
+        assert!(db.committed_block_heights().unwrap().is_empty());
+
+        let heights = [1, 2, 10, 256].map(BlockHeight);
+        commit_blocks(&db, heights);
+        assert_eq!(db.committed_block_heights().unwrap(), heights.to_vec());
+
+        // The last height must match the last committed block
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        db.inner
+            .delete_cf(
+                block_cf,
+                format!("{RESULTS_KEY_PREFIX}/{}", BlockHeight(256).raw()),
+            )
+            .unwrap();
+        assert!(db.committed_block_heights().is_err());
+    }
+
+    /// Test that the keys matching a pattern are deleted and can be restored
+    /// with a rollback
+    #[test]
+    fn test_delete_matching() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let prefix = Key::parse("vp").unwrap();
+        let deprecated_keys = ["deprecated_1", "deprecated_2"]
+            .map(|seg| prefix.push(&seg.to_string()).unwrap());
+        let kept_keys = ["kept_1", "kept_2"]
+            .map(|seg| prefix.push(&seg.to_string()).unwrap());
+        let other_key = Key::parse("deprecated_3").unwrap();
+        let val = vec![1_u8, 2, 3];
+
+        // Write the keys in the first block
+        let mut batch = RocksDB::batch();
+        let height_0 = BlockHeight(100);
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(height_0);
+        for key in deprecated_keys.iter().chain(&kept_keys).chain([&other_key])
+        {
+            db.batch_write_subspace_val(&mut batch, height_0, key, &val, true)
+                .unwrap();
+        }
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            height_0,
+            Epoch(1),
+            pred_epochs.clone(),
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        // Delete the matching keys in the second block
+        let mut batch = RocksDB::batch();
+        let height_1 = BlockHeight(101);
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            height_1,
+            Epoch(1),
+            pred_epochs,
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        let deleted = db
+            .delete_matching(Some(&prefix), Regex::new("deprecated").unwrap())
+            .unwrap();
+        assert_eq!(deleted, 2);
+
+        for key in &deprecated_keys {
+            assert_eq!(db.read_subspace_val(key).unwrap(), None);
+        }
+        for key in kept_keys.iter().chain([&other_key]) {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(val.clone()));
+        }
+
+        // Rollback to the first block height restores the deleted keys
+        db.rollback(height_0).unwrap();
+        for key in deprecated_keys.iter().chain(&kept_keys).chain([&other_key])
+        {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(val.clone()));
+        }
+    }
+
+    /// Test replacing the subspace keys under a prefix
+    #[test]
+    fn test_replace_subspace_range() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let prefix = Key::parse("vp").unwrap();
+        let old_keys =
+            ["old", "kept"].map(|seg| prefix.push(&seg.to_string()).unwrap());
+        let other_key = Key::parse("other").unwrap();
+        let val = vec![1_u8, 2, 3];
+        let new_val = vec![4_u8];
+
+        // Write the keys in the first block
+        let mut batch = RocksDB::batch();
+        let height_0 = BlockHeight(100);
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(height_0);
+        for key in old_keys.iter().chain([&other_key]) {
+            db.batch_write_subspace_val(&mut batch, height_0, key, &val, true)
+                .unwrap();
+        }
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            height_0,
+            Epoch(1),
+            pred_epochs.clone(),
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        // Swap the keys in the second block
+        let mut batch = RocksDB::batch();
+        let height_1 = BlockHeight(101);
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            height_1,
+            Epoch(1),
+            pred_epochs,
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        let new_key = prefix.push(&"new".to_string()).unwrap();
+        let new_entries = || {
+            [
+                (old_keys[1].clone(), new_val.clone()),
+                (new_key.clone(), new_val.clone()),
+            ]
+        };
+
+        // A swap that fails partway, on an entry outside of the prefix,
+        // leaves the old data intact
+        let result = db.replace_subspace_range(
+            &prefix,
+            new_entries()
+                .into_iter()
+                .chain([(other_key.clone(), new_val.clone())]),
+        );
+        assert!(result.is_err());
+        for key in old_keys.iter().chain([&other_key]) {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(val.clone()));
+        }
+        assert_eq!(db.read_subspace_val(&new_key).unwrap(), None);
+
+        db.replace_subspace_range(&prefix, new_entries().into_iter())
+            .unwrap();
+        assert_eq!(db.read_subspace_val(&old_keys[0]).unwrap(), None);
+        assert_eq!(
+            db.read_subspace_val(&old_keys[1]).unwrap(),
+            Some(new_val.clone())
+        );
+        assert_eq!(db.read_subspace_val(&new_key).unwrap(), Some(new_val));
+        assert_eq!(
+            db.read_subspace_val(&other_key).unwrap(),
+            Some(val.clone())
+        );
+
+        // Rollback to the first block height restores the old keys
+        db.rollback(height_0).unwrap();
+        for key in old_keys.iter().chain([&other_key]) {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(val.clone()));
+        }
+        assert_eq!(db.read_subspace_val(&new_key).unwrap(), None);
+    }
+
+    /// Test that a batch written without the WAL is persisted by a flush
+    #[test]
+    fn test_exec_batch_no_wal() {
+        let dir = tempdir().unwrap();
+        let key = Key::parse("key").unwrap();
+        let val = vec![1_u8, 2, 3];
+        {
+            let db = RocksDB::open(dir.path(), None);
+            let mut batch = RocksDB::batch();
+            db.batch_write_subspace_val(
+                &mut batch,
+                BlockHeight(1),
+                &key,
+                &val,
+                true,
+            )
+            .unwrap();
+            db.exec_batch_no_wal(batch).unwrap();
+            db.flush(true).unwrap();
+        }
+
+        let db = RocksDB::open(dir.path(), None);
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(val));
+    }
+
+    /// Test repairing the subspace values that don't match their diffs
+    #[test]
+    fn test_repair_orphaned_diffs() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let [written_key, deleted_key, rewritten_key, ok_key] =
+            ["written", "deleted", "rewritten", "ok"]
+                .map(|key| Key::parse(key).unwrap());
+        let val = vec![1_u8];
+        let new_val = vec![2_u8];
+
+        let mut batch = RocksDB::batch();
+        for key in [&deleted_key, &ok_key] {
+            db.batch_write_subspace_val(
+                &mut batch,
+                BlockHeight(1),
+                key,
+                &val,
+                true,
+            )
+            .unwrap();
+        }
+        db.exec_batch(batch).unwrap();
+
+        // Construct orphaned diffs at height 2, whose subspace writes are
+        // missing
+        let mut batch = RocksDB::batch();
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &written_key,
+            None,
+            Some(&new_val),
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &deleted_key,
+            Some(&val),
+            None,
+            false,
+        )
+        .unwrap();
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &rewritten_key,
+            None,
+            Some(&new_val),
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch,
+            BlockHeight(2),
+            &ok_key,
+            &new_val,
+            true,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        // The rewritten key is written again at a later height
+        db.write_subspace_val(BlockHeight(3), &rewritten_key, &val, true)
+            .unwrap();
+
+        let report = db.repair_orphaned_diffs(BlockHeight(2)).unwrap();
+        assert_eq!(
+            report,
+            RepairReport {
+                filled: vec![written_key.to_string()],
+                removed: vec![deleted_key.to_string()],
+            }
+        );
+        assert_eq!(
+            db.read_subspace_val(&written_key).unwrap(),
+            Some(new_val.clone())
+        );
+        assert_eq!(db.read_subspace_val(&deleted_key).unwrap(), None);
+        assert_eq!(
+            db.read_subspace_val(&rewritten_key).unwrap(),
+            Some(val.clone())
+        );
+        assert_eq!(db.read_subspace_val(&ok_key).unwrap(), Some(new_val));
+
+        // Once repaired, there's nothing left to repair
+        let report = db.repair_orphaned_diffs(BlockHeight(2)).unwrap();
+        assert_eq!(report, RepairReport::default());
+    }
+
+    /// Test finding the diffs that are inconsistent with the subspace or with
+    /// the diffs written before
+    #[test]
+    fn test_validate_diffs_at() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let [ok_key, orphaned_key, stale_key, wrong_old_key, rewritten_key] =
+            ["ok", "orphaned", "stale", "wrong_old", "rewritten"]
+                .map(|key| Key::parse(key).unwrap());
+        let val = vec![1_u8];
+        let new_val = vec![2_u8];
+
+        for key in [&ok_key, &stale_key, &wrong_old_key, &rewritten_key] {
+            db.write_subspace_val(BlockHeight(1), key, &val, true)
+                .unwrap();
+        }
+        // The keys written in full at height 2 are consistent
+        for key in [&ok_key, &rewritten_key] {
+            db.write_subspace_val(BlockHeight(2), key, &new_val, true)
+                .unwrap();
+        }
+        assert!(db.validate_diffs_at(BlockHeight(2)).unwrap().is_empty());
+
+        // Diffs at height 2 whose subspace writes are missing
+        let mut batch = RocksDB::batch();
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &orphaned_key,
+            None,
+            Some(&new_val),
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &stale_key,
+            Some(&val),
+            None,
+            true,
+        )
+        .unwrap();
+        // A diff whose old value doesn't match the value at height 1
+        db.batch_write_subspace_diff(
+            &mut batch,
+            BlockHeight(2),
+            &wrong_old_key,
+            Some(&new_val),
+            None,
+            true,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        db.delete_subspace_val(BlockHeight(3), &wrong_old_key, true)
+            .unwrap();
+        // A key whose subspace value is superseded by a later write
+        db.write_subspace_val(BlockHeight(3), &rewritten_key, &val, true)
+            .unwrap();
+
+        assert_eq!(
+            db.validate_diffs_at(BlockHeight(2)).unwrap(),
+            vec![
+                DiffInconsistency::Subspace {
+                    key: orphaned_key.to_string(),
+                    expected: Some(new_val.clone()),
+                    actual: None,
+                },
+                DiffInconsistency::Subspace {
+                    key: stale_key.to_string(),
+                    expected: None,
+                    actual: Some(val.clone()),
+                },
+                DiffInconsistency::OldValue {
+                    key: wrong_old_key.to_string(),
+                    expected: Some(val.clone()),
+                    actual: Some(new_val.clone()),
+                },
+            ]
+        );
+        // The heights before are unaffected
+        assert!(db.validate_diffs_at(BlockHeight(1)).unwrap().is_empty());
+    }
+
+    /// Test lending the raw handle of the DB
+    #[test]
+    fn test_with_raw() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let key = Key::parse("test").unwrap();
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], false)
+            .unwrap();
+
+        let value = db.with_raw(|raw| {
+            let cf = raw.cf_handle(SUBSPACE_CF).unwrap();
+            raw.get_cf(cf, key.to_string()).unwrap()
+        });
+        assert_eq!(value, Some(vec![1_u8]));
+        let num_keys = db.with_raw(|raw| {
+            raw.property_int_value("rocksdb.estimate-num-keys").unwrap()
+        });
+        assert!(num_keys.is_some());
+    }
+
+    /// Test reading the gas used by the blocks in a range of heights
+    #[test]
+    fn test_gas_per_height() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        let mut batch = RocksDB::batch();
+        for (height, gas) in [(1, 1_000), (2, 2_000), (3, 3_000)] {
+            TestBlock::new(BlockHeight(height))
+                .gas(gas)
+                .add_to_batch(&db, &mut batch)
+                .unwrap();
+        }
+        db.exec_batch(batch).unwrap();
+
+        assert_eq!(
+            db.gas_per_height(BlockHeight(1), BlockHeight(3)).unwrap(),
+            vec![
+                (BlockHeight(1), 1_000),
+                (BlockHeight(2), 2_000),
+                (BlockHeight(3), 3_000)
+            ]
+        );
+
+        // The heights without gas are skipped
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        let gas_key =
+            format!("{}/{BLOCK_GAS_KEY_SEGMENT}", BlockHeight(2).raw());
+        db.inner.delete_cf(block_cf, gas_key).unwrap();
+        assert_eq!(
+            db.gas_per_height(BlockHeight(0), BlockHeight(5)).unwrap(),
+            vec![(BlockHeight(1), 1_000), (BlockHeight(3), 3_000)]
+        );
+    }
+
+    /// Test that the dumps don't depend on the size of their buffer
+    #[test]
+    fn test_dump_buffer_size() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        commit_blocks(&db, [BlockHeight(1)]);
+        for i in 0..10_u8 {
+            let key = Key::parse(format!("key{i}")).unwrap();
+            db.write_subspace_val(BlockHeight(1), &key, [i; 16], true)
+                .unwrap();
+        }
+
+        let dump_dir = tempdir().unwrap();
+        for (name, buffer_size) in
+            [("small", 16), ("default", DEFAULT_DUMP_BUFFER_SIZE)]
+        {
+            db.dump_block(
+                dump_dir.path().join(name),
+                true,
+                None,
+                DumpFormat::Toml,
+                buffer_size,
+            );
+        }
+        let read_dump = |name: &str| {
+            std::fs::read_to_string(
+                dump_dir.path().join(format!("{name}_1.toml")),
+            )
+            .unwrap()
+        };
+        let dump = read_dump("default");
+        assert!(dump.contains("\"key9\" = "));
+        assert_eq!(read_dump("small"), dump);
+    }
+
+    /// Test verifying a dump against the DB
+    #[test]
+    fn test_verify_dump() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let key_a = Key::parse("a").unwrap();
+        let key_b = Key::parse("b").unwrap();
+        for (height, value) in [(1_u64, 1_u8), (2, 2)] {
+            commit_blocks(&db, [BlockHeight(height)]);
+            db.write_subspace_val(BlockHeight(height), &key_a, [value], true)
+                .unwrap();
+        }
+        db.write_subspace_val(BlockHeight(2), &key_b, [2_u8], true)
+            .unwrap();
+
+        // A historic dump below the last height and a dump at the last height
+        let dump_dir = tempdir().unwrap();
+        for (height, historic) in [(1_u64, true), (2, false)] {
+            db.dump_block(
+                dump_dir.path().join("dump"),
+                historic,
+                Some(BlockHeight(height)),
+                DumpFormat::Toml,
+                DEFAULT_DUMP_BUFFER_SIZE,
+            );
+            let dump_path = dump_dir.path().join(format!("dump_{height}.toml"));
+            let verification = db.verify_dump(&dump_path).unwrap();
+            assert!(verification.is_faithful(), "{verification:?}");
+            assert_eq!(verification.height, BlockHeight(height));
+            assert!(verification.matching > 0);
+        }
+
+        // Tamper with the dump at the last height
+        let dump_path = dump_dir.path().join("dump_2.toml");
+        let dump = std::fs::read_to_string(&dump_path).unwrap();
+        let tampered: String = dump
+            .lines()
+            .filter(|line| !line.starts_with("\"b\""))
+            .map(|line| {
+                if line.starts_with("\"a\"") {
+                    "\"a\" = \"ff\"\n".to_string()
+                } else {
+                    format!("{line}\n")
+                }
+            })
+            .chain(["\"c\" = \"03\"\n".to_string()])
+            .collect();
+        std::fs::write(&dump_path, tampered).unwrap();
+        let verification = db.verify_dump(&dump_path).unwrap();
+        assert!(!verification.is_faithful());
+        assert_eq!(verification.mismatched, 1);
+        assert_eq!(verification.missing, 1);
+        assert_eq!(verification.extra, 1);
+        assert_eq!(
+            verification.discrepancies,
+            vec![
+                DumpDiscrepancy::Mismatch("a".to_string()),
+                DumpDiscrepancy::Missing("b".to_string()),
+                DumpDiscrepancy::Extra("c".to_string()),
+            ]
+        );
+    }
+
+    /// Test restoring a binary dump into a fresh DB
+    #[test]
+    fn test_binary_dump_roundtrip() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        commit_blocks(&db, [BlockHeight(1)]);
+        for i in 0..10_u8 {
+            let key = Key::parse(format!("key{i}")).unwrap();
+            db.write_subspace_val(BlockHeight(1), &key, [i; 4], true)
+                .unwrap();
+        }
+        let mut batch = RocksDB::batch();
+        db.write_replay_protection_entry(
+            &mut batch,
+            &replay_protection::key(&Hash::sha256(b"tx")),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        let dump_dir = tempdir().unwrap();
+        db.dump_block(
+            dump_dir.path().join("dump"),
+            true,
+            None,
+            DumpFormat::Binary,
+            DEFAULT_DUMP_BUFFER_SIZE,
+        );
+        let dump_path = dump_dir.path().join("dump_1.bin");
+        assert!(db.verify_dump(&dump_path).unwrap().is_faithful());
+
+        let restored_dir = tempdir().unwrap();
+        let restored = RocksDB::open(restored_dir.path(), None);
+        assert_eq!(
+            restored.restore_from_dump(&dump_path).unwrap(),
+            BlockHeight(1)
+        );
+
+        let entries = |db: &RocksDB, cf: &str, prefix: Option<String>| {
+            let cf = db.get_column_family(cf).unwrap();
+            db.dump_iter(cf, prefix).collect::<Vec<_>>()
+        };
+        let prefix = Some(BlockHeight(1).raw());
+        for (cf, prefix) in [
+            (SUBSPACE_CF, None),
+            (DIFFS_CF, prefix.clone()),
+            (BLOCK_CF, prefix),
+            (REPLAY_PROTECTION_CF, None),
+        ] {
+            let original = entries(&db, cf, prefix.clone());
+            assert!(!original.is_empty(), "{cf}");
+            assert_eq!(entries(&restored, cf, prefix), original, "{cf}");
+        }
+
+        // A TOML dump doesn't record the column families to restore to
+        db.dump_block(
+            dump_dir.path().join("toml"),
+            true,
+            None,
+            DumpFormat::Toml,
+            DEFAULT_DUMP_BUFFER_SIZE,
+        );
+        assert!(
+            restored
+                .restore_from_dump(&dump_dir.path().join("toml_1.toml"))
+                .is_err()
+        );
+    }
+
+    /// Test chaining the deltas of the subspace to a full dump
+    #[test]
+    fn test_dump_delta() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let key_a = Key::parse("a").unwrap();
+        let key_b = Key::parse("b").unwrap();
+        let key_c = Key::parse("c").unwrap();
+        commit_blocks(&db, (1..=3).map(BlockHeight));
+        db.write_subspace_val(BlockHeight(1), &key_a, [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &key_b, [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &key_a, [2_u8], true)
+            .unwrap();
+        db.delete_subspace_val(BlockHeight(3), &key_b, true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(3), &key_c, [3_u8], true)
+            .unwrap();
+
+        let dump_dir = tempdir().unwrap();
+        let out_file_path = dump_dir.path().join("dump");
+        db.dump_block(
+            out_file_path.clone(),
+            false,
+            Some(BlockHeight(1)),
+            DumpFormat::Toml,
+            DEFAULT_DUMP_BUFFER_SIZE,
+        );
+        let base = dump_dir.path().join("dump_1.toml");
+
+        // A delta from the full dump
+        let delta = db
+            .dump_delta(
+                &base,
+                out_file_path.clone(),
+                Some(BlockHeight(2)),
+                DEFAULT_DUMP_BUFFER_SIZE,
+            )
+            .unwrap();
+        assert_eq!(delta, dump_dir.path().join("dump_1_2.delta"));
+        assert_eq!(
+            std::fs::read_to_string(&delta).unwrap(),
+            "# Changes of the subspace from height 1 to 2\n[write]\n\"a\" = \
+             \"02\"\n[delete]\n"
+        );
+
+        // A delta chained to the previous one, up to the last height
+        let delta = db
+            .dump_delta(
+                &delta,
+                out_file_path.clone(),
+                None,
+                DEFAULT_DUMP_BUFFER_SIZE,
+            )
+            .unwrap();
+        assert_eq!(delta, dump_dir.path().join("dump_2_3.delta"));
+        assert_eq!(
+            std::fs::read_to_string(&delta).unwrap(),
+            "# Changes of the subspace from height 2 to 3\n[write]\n\"c\" = \
+             \"03\"\n[delete]\n\"b\" = true\n"
+        );
+
+        // There are no changes to dump past the last height
+        assert!(
+            db.dump_delta(
+                &delta,
+                out_file_path.clone(),
+                None,
+                DEFAULT_DUMP_BUFFER_SIZE
+            )
+            .is_err()
+        );
+
+        // A key whose diffs are not persisted is dumped with its current
+        // value, which is only known at the last height
+        let masp_key = masp_commitment_tree_key();
+        db.write_subspace_val(BlockHeight(3), &masp_key, [3_u8], false)
+            .unwrap();
+        let masp_out_file_path = dump_dir.path().join("masp");
+        assert!(
+            db.dump_delta(
+                &base,
+                masp_out_file_path.clone(),
+                Some(BlockHeight(2)),
+                DEFAULT_DUMP_BUFFER_SIZE
+            )
+            .is_err()
+        );
+        let delta = db
+            .dump_delta(
+                &base,
+                masp_out_file_path,
+                None,
+                DEFAULT_DUMP_BUFFER_SIZE,
+            )
+            .unwrap();
+        let written = std::fs::read_to_string(delta).unwrap();
+        assert!(written.contains(&format!("\"{masp_key}\" = \"03\"\n")));
+    }
+
+    /// Test that a rollback past the pruned diffs is refused
+    #[test]
+    fn test_rollback_beyond_retention() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for height in 1..=3_u64 {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            let val = [u8::try_from(height.0).unwrap()];
+            db.batch_write_subspace_val(&mut batch, height, &key, val, true)
+                .unwrap();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(0),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        // Only the diffs of the last height are retained
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 1,
+        };
+        db.prune_to_policy(BlockHeight(3), Epoch(0), &policy)
+            .unwrap();
+
+        // Rolling back the last height only needs its own diffs
+        db.rollback(BlockHeight(2)).unwrap();
+        assert_eq!(db.last_committed_height().unwrap(), Some(BlockHeight(2)));
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![2_u8]));
+
+        // The diffs of the height 2 have been pruned
+        let result = db.rollback(BlockHeight(1));
+        assert!(matches!(
+            result,
+            Err(Error::RollbackBeyondRetention {
+                target: BlockHeight(1),
+                oldest_retained: BlockHeight(3),
+            })
+        ));
+        assert_eq!(db.last_committed_height().unwrap(), Some(BlockHeight(2)));
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![2_u8]));
+    }
+
+    /// Test keeping the block headers for longer than the diffs
+    #[test]
+    fn test_prune_keeping_headers() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for height in 1..=4_u64 {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            let val = [u8::try_from(height.0).unwrap()];
+            db.batch_write_subspace_val(&mut batch, height, &key, val, true)
+                .unwrap();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(0),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+            let header_key =
+                format!("{}/{BLOCK_HEADER_KEY_SEGMENT}", height.raw());
+            db.add_value_to_batch(
+                block_cf,
+                header_key,
+                &Header::default(),
+                &mut batch,
+            );
+            db.exec_batch(batch).unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 3,
+        };
+        let report = db
+            .prune_to_policy(BlockHeight(4), Epoch(0), &policy)
+            .unwrap();
+        assert_eq!(report.headers, 1);
+
+        // The diffs are only kept for the last height, but the headers are
+        // kept for the last 3 heights
+        for height in [2, 3] {
+            assert_eq!(db.iter_new_diffs(BlockHeight(height), None).count(), 0);
+            let header = db.read_block_header(BlockHeight(height)).unwrap();
+            assert!(header.is_some());
+        }
+        assert!(db.read_block_header(BlockHeight(1)).unwrap().is_none());
+        assert!(db.read_block_header(BlockHeight(4)).unwrap().is_some());
+    }
+
+    /// Test reading the history of a key from its diffs
+    #[test]
+    fn test_key_history() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        let other_key = Key::parse("other").unwrap();
+
+        assert!(db.key_history(&key).unwrap().is_empty());
+
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &other_key, [2_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(3), &key, [3_u8], true)
+            .unwrap();
+        db.delete_subspace_val(BlockHeight(4), &key, true).unwrap();
+        // The changes without persisted diffs are not in the history
+        db.write_subspace_val(BlockHeight(5), &key, [5_u8], false)
+            .unwrap();
+
+        assert_eq!(
+            db.key_history(&key).unwrap(),
+            vec![
+                (BlockHeight(1), None, Some(vec![1_u8])),
+                (BlockHeight(3), Some(vec![1_u8]), Some(vec![3_u8])),
+                (BlockHeight(4), Some(vec![3_u8]), None),
+            ]
+        );
+        assert_eq!(
+            db.key_history(&other_key).unwrap(),
+            vec![(BlockHeight(2), None, Some(vec![2_u8]))]
+        );
+    }
+
+    /// Test reading a subspace value with the height it last changed at
+    #[test]
+    fn test_read_subspace_val_and_height() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let [key, other_key, deleted_key, untracked_key] =
+            ["key", "other", "deleted", "untracked"]
+                .map(|key| Key::parse(key).unwrap());
+
+        assert_eq!(db.read_subspace_val_and_height(&key).unwrap(), None);
+
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &deleted_key, [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &other_key, [2_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(3), &key, [3_u8], true)
+            .unwrap();
+        db.delete_subspace_val(BlockHeight(4), &deleted_key, true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(4), &untracked_key, [4_u8], false)
+            .unwrap();
+
+        assert_eq!(
+            db.read_subspace_val_and_height(&key).unwrap(),
+            Some((vec![3_u8], Some(BlockHeight(3))))
+        );
+        assert_eq!(
+            db.read_subspace_val_and_height(&other_key).unwrap(),
+            Some((vec![2_u8], Some(BlockHeight(2))))
+        );
+        assert_eq!(
+            db.read_subspace_val_and_height(&deleted_key).unwrap(),
+            None
+        );
+        // Without persisted diffs, the change is found in the diffs kept for
+        // rollback
+        assert_eq!(
+            db.read_subspace_val_and_height(&untracked_key).unwrap(),
+            Some((vec![4_u8], Some(BlockHeight(4))))
+        );
+
+        // Once the diffs are pruned, the heights of the changes are unknown
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 1,
+        };
+        db.prune_to_policy(BlockHeight(5), Epoch(0), &policy)
+            .unwrap();
+        assert_eq!(
+            db.read_subspace_val_and_height(&key).unwrap(),
+            Some((vec![3_u8], None))
+        );
+        assert_eq!(
+            db.read_subspace_val_and_height(&untracked_key).unwrap(),
+            Some((vec![4_u8], None))
+        );
+    }
+
+    /// Write a merkle tree with the given hasher and restore it from the
+    /// stores read back from the DB
+    fn merkle_tree_stores_roundtrip<H: StorageHasher + Default>() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        let mut merkle_tree = MerkleTree::<H>::default();
+        merkle_tree.update(&key, [1_u8]).unwrap();
+
+        let mut batch = RocksDB::batch();
+        TestBlock::new(BlockHeight(1))
+            .tree(&merkle_tree)
+            .add_to_batch(&db, &mut batch)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        let stores = db
+            .read_merkle_tree_stores(Epoch(0), BlockHeight(1), None)
+            .unwrap()
+            .unwrap();
+        let restored = MerkleTree::<H>::new(stores).unwrap();
+        assert_eq!(restored.root().0, merkle_tree.root().0);
+        assert!(restored.has_key(&key).unwrap());
+    }
+
+    /// Test that the merkle tree stores roundtrip through the DB with
+    /// either the default hasher or the node's hasher
+    #[test]
+    fn test_merkle_tree_stores_hashers() {
+        merkle_tree_stores_roundtrip::<Sha256Hasher>();
+        merkle_tree_stores_roundtrip::<PersistentStorageHasher>();
+    }
+
+    /// Test recomputing the merkle roots from the persisted stores
+    #[test]
+    fn test_verify_merkle_root() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let mut merkle_tree = MerkleTree::<PersistentStorageHasher>::default();
+        merkle_tree
+            .update(&Key::parse("key").unwrap(), [1_u8])
+            .unwrap();
+
+        let mut batch = RocksDB::batch();
+        TestBlock::new(BlockHeight(1))
+            .tree(&merkle_tree)
+            .add_to_batch(&db, &mut batch)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        for st in StoreType::iter() {
+            assert!(db.verify_merkle_root(st, Epoch(0)).unwrap());
+        }
+        // There is no data for the next epoch
+        assert!(db.verify_merkle_root(&StoreType::Account, Epoch(1)).is_err());
+
+        // Corrupt the persisted root of the account tree
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        let key_prefix =
+            tree_key_prefix_with_epoch(&StoreType::Account, Epoch(0));
+        db.inner
+            .put_cf(
+                block_cf,
+                format!("{key_prefix}/{MERKLE_TREE_ROOT_KEY_SEGMENT}"),
+                encode(&Hash::sha256(b"corrupted")),
+            )
+            .unwrap();
+        assert!(!db.verify_merkle_root(&StoreType::Account, Epoch(0)).unwrap());
+        assert!(db.verify_merkle_root(&StoreType::PoS, Epoch(0)).unwrap());
+    }
+
+    /// Test committing the merkle tree of a single store type under an epoch
+    #[test]
+    fn test_commit_store_type() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let mut merkle_tree = MerkleTree::<PersistentStorageHasher>::default();
+        // The key is in the account tree
+        merkle_tree
+            .update(&Key::parse("key").unwrap(), [1_u8])
+            .unwrap();
+
+        let mut batch = RocksDB::batch();
+        TestBlock::new(BlockHeight(1))
+            .tree(&merkle_tree)
+            .add_to_batch(&db, &mut batch)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+        let stores = db
+            .read_merkle_tree_stores(Epoch(0), BlockHeight(1), None)
+            .unwrap()
+            .unwrap();
+
+        let mut batch = RocksDB::batch();
+        db.commit_store_type(
+            &mut batch,
+            &StoreType::Account,
+            Epoch(3),
+            &stores,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        assert!(db.verify_merkle_root(&StoreType::Account, Epoch(3)).unwrap());
+        let committed = db
+            .read_merkle_tree_stores(
+                Epoch(3),
+                BlockHeight(1),
+                Some(StoreType::Account),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            committed.get_root(StoreType::Account),
+            stores.get_root(StoreType::Account)
         );
+        // Only the given store type is committed
+        assert!(db.verify_merkle_root(&StoreType::PoS, Epoch(3)).is_err());
 
-        Ok(())
+        let mut batch = RocksDB::batch();
+        // The base tree is committed at every height
+        let base = StoreType::Base;
+        assert!(
+            db.commit_store_type(&mut batch, &base, Epoch(3), &stores)
+                .is_err()
+        );
+        // Missing stores
+        assert!(
+            db.commit_store_type(
+                &mut batch,
+                &StoreType::Account,
+                Epoch(3),
+                &MerkleTreeStoresRead::default()
+            )
+            .is_err()
+        );
+
+        // The PoS tree is empty, but it can still be committed
+        let mut batch = RocksDB::batch();
+        assert_eq!(stores.get_root(StoreType::PoS), Hash::zero());
+        db.commit_store_type(&mut batch, &StoreType::PoS, Epoch(3), &stores)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+        assert!(db.verify_merkle_root(&StoreType::PoS, Epoch(3)).unwrap());
     }
 
-    fn move_current_replay_protection_entries(
-        &mut self,
-        batch: &mut Self::WriteBatch,
-    ) -> Result<()> {
-        let replay_protection_cf =
-            self.get_column_family(REPLAY_PROTECTION_CF)?;
-        let stripped_prefix = Some(replay_protection::current_prefix());
+    /// Test migrating a value to a type with an extra field
+    #[test]
+    fn test_migrate_value() {
+        #[derive(BorshSerialize, BorshDeserialize)]
+        struct Old {
+            a: u64,
+            b: String,
+        }
+        #[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+        struct New {
+            a: u64,
+            b: String,
+            c: bool,
+        }
 
-        for (ref hash_str, _, _) in iter_prefix(
-            self,
-            replay_protection_cf,
-            stripped_prefix.as_ref(),
-            None,
-        ) {
-            let hash = namada_sdk::hash::Hash::from_str(hash_str)
-                .expect("Failed hash conversion");
-            let current_key = replay_protection::current_key(&hash);
-            let key = replay_protection::key(&hash);
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        commit_blocks(&db, [BlockHeight(1)]);
+        let key = Key::parse("key").unwrap();
+        let old = Old {
+            a: 1,
+            b: "b".to_string(),
+        };
+        db.write_subspace_val(BlockHeight(1), &key, encode(&old), true)
+            .unwrap();
 
-            // Delete the current key and move it to the general bucket
-            batch
-                .0
-                .delete_cf(replay_protection_cf, current_key.to_string());
-            batch
-                .0
-                .put_cf(replay_protection_cf, key.to_string(), vec![]);
-        }
+        let mut visitor = RocksDBUpdateVisitor::new(&db);
+        // The value cannot be decoded as the new type yet
+        assert!(
+            visitor
+                .migrate_value(&key, &DbColFam::SUBSPACE, |new: New| new)
+                .is_err()
+        );
+        // There is no value to migrate
+        assert!(
+            visitor
+                .migrate_value(
+                    &Key::parse("missing").unwrap(),
+                    &DbColFam::SUBSPACE,
+                    |old: Old| old,
+                )
+                .is_err()
+        );
+        visitor
+            .migrate_value(&key, &DbColFam::SUBSPACE, |Old { a, b }: Old| New {
+                a,
+                b,
+                c: true,
+            })
+            .unwrap();
+        let batch = visitor.take_batch();
+        db.exec_batch(batch).unwrap();
 
-        Ok(())
+        let migrated: New =
+            decode(db.read_subspace_val(&key).unwrap().unwrap()).unwrap();
+        assert_eq!(
+            migrated,
+            New {
+                a: 1,
+                b: "b".to_string(),
+                c: true,
+            }
+        );
     }
 
-    fn prune_non_persisted_diffs(
-        &mut self,
-        batch: &mut Self::WriteBatch,
-        height: BlockHeight,
-    ) -> Result<()> {
-        let rollback_cf = self.get_column_family(ROLLBACK_CF)?;
-
-        let diff_old_key_prefix = Key {
-            segments: vec![
-                height.to_db_key(),
-                OLD_DIFF_PREFIX.to_string().to_db_key(),
-            ],
-        };
-        for (key_str, _val, _) in
-            iter_prefix(self, rollback_cf, None, Some(&diff_old_key_prefix))
-        {
-            batch.0.delete_cf(rollback_cf, key_str)
+    /// Test renaming a subspace key in a migration, and that the rename is
+    /// reverted with the rollback of the last block
+    #[test]
+    fn test_rename_key() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = |key: &str| Key::parse(key).unwrap();
+        // A key whose diffs are not persisted
+        let masp_key = masp_commitment_tree_key();
+
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for height in [1, 2] {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            if height == BlockHeight(1) {
+                for (name, val) in [("from", 1_u8), ("taken", 2)] {
+                    db.batch_write_subspace_val(
+                        &mut batch,
+                        height,
+                        &key(name),
+                        [val],
+                        true,
+                    )
+                    .unwrap();
+                }
+                db.batch_write_subspace_val(
+                    &mut batch,
+                    height,
+                    &masp_key,
+                    [3_u8],
+                    false,
+                )
+                .unwrap();
+            }
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(0),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
         }
 
-        let diff_new_key_prefix = Key {
-            segments: vec![
-                height.to_db_key(),
-                NEW_DIFF_PREFIX.to_string().to_db_key(),
-            ],
+        let rename = |visitor: &mut RocksDBUpdateVisitor<'_>,
+                      from: &Key,
+                      to: &Key,
+                      overwrite: bool| {
+            visitor.rename_key(from, to, &DbColFam::SUBSPACE, overwrite)
         };
-        for (key_str, _val, _) in
-            iter_prefix(self, rollback_cf, None, Some(&diff_new_key_prefix))
-        {
-            batch.0.delete_cf(rollback_cf, key_str)
-        }
-        Ok(())
+        let mut visitor = RocksDBUpdateVisitor::new(&db);
+        // Nothing to rename
+        assert!(
+            !rename(&mut visitor, &key("missing"), &key("to"), false).unwrap()
+        );
+        // The target is taken
+        assert!(
+            rename(&mut visitor, &key("from"), &key("taken"), false).is_err()
+        );
+        assert!(rename(&mut visitor, &key("from"), &key("to"), false).unwrap());
+        assert!(rename(&mut visitor, &masp_key, &key("masp"), false).unwrap());
+        let batch = visitor.take_batch();
+        assert_eq!(batch.size_diff(), 0);
+        db.exec_batch(batch).unwrap();
+
+        assert_eq!(db.read_subspace_val(&key("from")).unwrap(), None);
+        assert_eq!(db.read_subspace_val(&key("to")).unwrap(), Some(vec![1]));
+        assert_eq!(db.read_subspace_val(&key("masp")).unwrap(), Some(vec![3]));
+        assert_eq!(
+            db.changed_keys(BlockHeight(2)).unwrap(),
+            vec![
+                (key("from"), ChangeKind::Deleted),
+                (key("masp"), ChangeKind::Created),
+                (key("to"), ChangeKind::Created)
+            ]
+        );
+        // The diffs of the key that aren't persisted are kept for rollback
+        let (old_key, _new_key) =
+            old_and_new_diff_key(&masp_key, BlockHeight(2)).unwrap();
+        let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
+        let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+        assert!(db.read_value_bytes(diffs_cf, &old_key).unwrap().is_none());
+        assert_eq!(
+            db.read_value_bytes(rollback_cf, &old_key).unwrap(),
+            Some(vec![3])
+        );
+
+        // Overwriting the target
+        let mut visitor = RocksDBUpdateVisitor::new(&db);
+        assert!(rename(&mut visitor, &key("to"), &key("taken"), true).unwrap());
+        let batch = visitor.take_batch();
+        assert_eq!(batch.size_diff(), -1);
+        db.exec_batch(batch).unwrap();
+        assert_eq!(db.read_subspace_val(&key("to")).unwrap(), None);
+        assert_eq!(
+            db.read_subspace_val(&key("taken")).unwrap(),
+            Some(vec![1])
+        );
+
+        // Rolling back the last block reverts the renames
+        db.rollback(BlockHeight(1)).unwrap();
+        assert_eq!(
+            db.read_subspace_val(&key("from")).unwrap(),
+            Some(vec![1])
+        );
+        assert_eq!(db.read_subspace_val(&key("to")).unwrap(), None);
+        assert_eq!(
+            db.read_subspace_val(&key("taken")).unwrap(),
+            Some(vec![2])
+        );
+        assert_eq!(db.read_subspace_val(&masp_key).unwrap(), Some(vec![3]));
+        assert_eq!(db.read_subspace_val(&key("masp")).unwrap(), None);
     }
 
-    #[inline]
-    fn overwrite_entry(
-        &self,
-        batch: &mut Self::WriteBatch,
-        height: Option<BlockHeight>,
-        cf: &DbColFam,
-        key: &Key,
-        new_value: impl AsRef<[u8]>,
-    ) -> Result<()> {
-        let state_cf = self.get_column_family(STATE_CF)?;
-        let last_height: BlockHeight = self
-            .read_value(state_cf, BLOCK_HEIGHT_KEY)?
-            .ok_or_else(|| {
-                Error::DBError("No block height found".to_string())
-            })?;
-        let desired_height = height.unwrap_or(last_height);
+    /// Test that the read-modify-writes of concurrent writers don't
+    /// interleave
+    #[test]
+    fn test_write_exclusively() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("counter").unwrap();
+        db.write_exclusively(|batch| {
+            db.batch_write_subspace_val(
+                batch,
+                BlockHeight(1),
+                &key,
+                encode(&0_u64),
+                false,
+            )
+        })
+        .unwrap();
 
-        if desired_height != last_height {
-            todo!(
-                "Overwriting values at heights different than the last \
-                 committed height hast yet to be implemented"
-            );
-        }
-        // NB: the following code only updates values
-        // written to at the last committed height
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        db.write_exclusively(|batch| {
+                            let counter: u64 =
+                                decode(db.read_subspace_val(&key)?.unwrap())
+                                    .map_err(Error::CodingError)?;
+                            db.batch_write_subspace_val(
+                                batch,
+                                BlockHeight(1),
+                                &key,
+                                encode(&counter.checked_add(1).unwrap()),
+                                false,
+                            )
+                        })
+                        .unwrap();
+                    }
+                });
+            }
+        });
 
-        let val = new_value.as_ref();
+        let counter: u64 =
+            decode(db.read_subspace_val(&key).unwrap().unwrap()).unwrap();
+        assert_eq!(counter, 100);
+    }
 
-        // Write the new key-val in the Db column family
-        let cf_name = self.get_column_family(cf.to_str())?;
-        self.add_value_bytes_to_batch(
-            cf_name,
-            key.to_string(),
-            val.to_vec(),
-            batch,
-        );
+    /// Test that a write from the batch built in `write_exclusively` is
+    /// refused instead of deadlocking, and that the lock is released however
+    /// the building ends
+    #[test]
+    fn test_write_exclusively_reentrant() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("counter").unwrap();
 
-        // If the CF is subspace, additionally update the diffs
-        if cf == &DbColFam::SUBSPACE {
-            let diffs_cf = self.get_column_family(DIFFS_CF)?;
-            let diffs_key = Key::from(last_height.to_db_key())
-                .with_segment("new".to_owned())
-                .join(key)
-                .to_string();
+        let result = db.write_exclusively(|_batch| {
+            let mut inner = RocksDB::batch();
+            db.batch_write_subspace_val(
+                &mut inner,
+                BlockHeight(1),
+                &key,
+                encode(&0_u64),
+                false,
+            )?;
+            db.exec_batch(inner)
+        });
+        assert!(result.is_err());
+        assert!(db.read_subspace_val(&key).unwrap().is_none());
 
-            self.add_value_bytes_to_batch(
-                diffs_cf,
-                diffs_key,
-                val.to_vec(),
+        // The lock is released and writable again
+        db.write_exclusively(|batch| {
+            db.batch_write_subspace_val(
                 batch,
-            );
-        }
-
-        Ok(())
+                BlockHeight(1),
+                &key,
+                encode(&0_u64),
+                false,
+            )
+        })
+        .unwrap();
+        assert!(db.read_subspace_val(&key).unwrap().is_some());
+
+        // So it is after a panic while building a batch
+        let panicked =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                db.write_exclusively(|_batch| -> Result<()> {
+                    panic!("Failed to build the batch")
+                })
+            }));
+        assert!(panicked.is_err());
+        let mut batch = RocksDB::batch();
+        db.batch_write_subspace_val(
+            &mut batch,
+            BlockHeight(2),
+            &key,
+            encode(&1_u64),
+            false,
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
     }
 
-    #[inline]
-    fn apply_migration_to_batch(
-        &self,
-        updates: impl IntoIterator<Item = DbUpdateType>,
-    ) -> Result<RocksDBWriteBatch> {
-        let mut db_visitor = storage::RocksDBUpdateVisitor::new(self);
-        for change in updates.into_iter() {
-            match change.update(&mut db_visitor) {
-                Ok(status) => {
-                    tracing::info!("{}", status);
-                }
-                Err(e) => {
-                    let error = format!(
-                        "Attempt to write to key/pattern <{}> failed:\n{}.",
-                        change.pattern(),
-                        e
-                    );
-                    tracing::error!(error);
-                    return Err(Error::DBError(error));
-                }
-            }
-        }
-        Ok(db_visitor.take_batch())
+    /// Test reading the Ethereum bridge's state on its own
+    #[test]
+    fn test_read_ethereum_state() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        assert!(db.ethereum_height().unwrap().is_none());
+        assert!(db.eth_events_queue().unwrap().is_none());
+
+        commit_blocks(&db, [BlockHeight(1)]);
+        // No Ethereum block has been processed yet
+        assert!(db.ethereum_height().unwrap().is_none());
+        assert!(db.eth_events_queue().unwrap().is_some());
+
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        let height = ethereum_structs::BlockHeight::from(42_u64);
+        let mut batch = RocksDB::batch();
+        db.add_value_to_batch(
+            state_cf,
+            ETHEREUM_HEIGHT_KEY,
+            &Some(&height),
+            &mut batch,
+        );
+        db.exec_batch(batch).unwrap();
+        assert_eq!(db.ethereum_height().unwrap(), Some(height));
     }
-}
 
-/// A struct that can visit a set of updates,
-/// registering them all in the batch
-pub struct RocksDBUpdateVisitor<'db> {
-    db: &'db RocksDB,
-    batch: RocksDBWriteBatch,
-}
+    /// Test writing a batch only if the values it was built against haven't
+    /// changed
+    #[test]
+    fn test_exec_batch_if_unchanged() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        let absent_key = Key::parse("absent").unwrap();
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], false)
+            .unwrap();
+        let read_hash = Some(Hash::sha256([1_u8]));
 
-impl<'db> RocksDBUpdateVisitor<'db> {
-    pub fn new(db: &'db RocksDB) -> Self {
-        Self {
-            db,
-            batch: Default::default(),
-        }
+        let build_batch = |db: &RocksDB, value: u8| {
+            let mut batch = RocksDB::batch();
+            db.batch_write_subspace_val(
+                &mut batch,
+                BlockHeight(1),
+                &key,
+                [value],
+                false,
+            )
+            .unwrap();
+            batch
+        };
+        // Nothing has changed since the read
+        let expected = [(key.clone(), read_hash), (absent_key.clone(), None)];
+        assert!(
+            db.exec_batch_if_unchanged(build_batch(&db, 2), &expected)
+                .unwrap()
+        );
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![2_u8]));
+
+        // The value read has since been overwritten
+        assert!(
+            !db.exec_batch_if_unchanged(build_batch(&db, 3), &expected)
+                .unwrap()
+        );
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![2_u8]));
+
+        // The key expected to be absent has since been written
+        db.write_subspace_val(BlockHeight(1), &absent_key, [1_u8], false)
+            .unwrap();
+        let expected = [
+            (key.clone(), Some(Hash::sha256([2_u8]))),
+            (absent_key, None),
+        ];
+        assert!(
+            !db.exec_batch_if_unchanged(build_batch(&db, 3), &expected)
+                .unwrap()
+        );
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![2_u8]));
     }
 
-    pub fn take_batch(self) -> RocksDBWriteBatch {
-        self.batch
+    /// Test reconstructing the subspace under a prefix at past heights
+    #[test]
+    fn test_account_state_at() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let account = Key::parse("account").unwrap();
+        let key = |sub_key: &str| account.push(&sub_key.to_owned()).unwrap();
+        commit_blocks(&db, [1, 2].map(BlockHeight));
+        db.write_subspace_val(BlockHeight(1), &key("a"), [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &key("c"), [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(
+            BlockHeight(1),
+            &Key::parse("other").unwrap(),
+            [1_u8],
+            true,
+        )
+        .unwrap();
+        db.write_subspace_val(BlockHeight(2), &key("a"), [2_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &key("b"), [2_u8], true)
+            .unwrap();
+        db.delete_subspace_val(BlockHeight(2), &key("c"), true)
+            .unwrap();
+
+        assert_eq!(
+            db.account_state_at(&account, BlockHeight(1)).unwrap(),
+            BTreeMap::from([(key("a"), vec![1_u8]), (key("c"), vec![1_u8])])
+        );
+        assert_eq!(
+            db.account_state_at(&account, BlockHeight(2)).unwrap(),
+            BTreeMap::from([(key("a"), vec![2_u8]), (key("b"), vec![2_u8])])
+        );
+        assert!(db.account_state_at(&account, BlockHeight(3)).is_err());
     }
-}
 
-impl<'db> DBUpdateVisitor for RocksDBUpdateVisitor<'db> {
-    fn read(&self, key: &Key, cf: &DbColFam) -> Option<Vec<u8>> {
-        match cf {
-            DbColFam::SUBSPACE => self
-                .db
-                .read_subspace_val(key)
-                .expect("Failed to read from storage"),
-            _ => {
-                let cf_str = cf.to_str();
-                let cf = self
-                    .db
-                    .get_column_family(cf_str)
-                    .expect("Failed to read column family from storage");
-                self.db
-                    .read_value_bytes(cf, key.to_string())
-                    .expect("Failed to get key from storage")
-            }
+    /// Test iterating the typed subspace values with a prefix
+    #[test]
+    fn test_iter_prefix_typed() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = |key: &str| Key::parse(key).unwrap();
+
+        for (k, val) in [("a/1", 1_u64), ("a/2", 2), ("b/1", 3)] {
+            db.write_subspace_val(BlockHeight(1), &key(k), encode(&val), true)
+                .unwrap();
         }
-    }
 
-    fn write(&mut self, key: &Key, cf: &DbColFam, value: impl AsRef<[u8]>) {
-        self.db
-            .overwrite_entry(&mut self.batch, None, cf, key, value)
-            .expect("Failed to overwrite a key in storage")
+        let vals = db
+            .iter_prefix_typed::<u64>(Some(&key("a")))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(vals, vec![(key("a/1"), 1), (key("a/2"), 2)]);
     }
 
-    fn delete(&mut self, key: &Key, cf: &DbColFam) {
-        let state_cf = self.db.get_column_family(STATE_CF).unwrap();
-        let last_height: BlockHeight = self
-            .db
-            .read_value(state_cf, BLOCK_HEIGHT_KEY)
-            .unwrap()
+    /// Test that a value that can't be decoded into the iterated type is an
+    /// error
+    #[test]
+    fn test_iter_prefix_typed_decode_error() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = |key: &str| Key::parse(key).unwrap();
+
+        db.write_subspace_val(
+            BlockHeight(1),
+            &key("a/1"),
+            encode(&1_u64),
+            true,
+        )
+        .unwrap();
+        // Too short for a `u64`
+        db.write_subspace_val(BlockHeight(1), &key("a/2"), [1_u8], true)
             .unwrap();
-        match cf {
-            DbColFam::SUBSPACE => {
-                self.db
-                    .batch_delete_subspace_val(
-                        &mut self.batch,
-                        last_height,
-                        key,
-                        true,
-                    )
-                    .expect("Failed to delete key from storage");
-            }
-            _ => {
-                let cf_str = cf.to_str();
-                let cf = self
-                    .db
-                    .get_column_family(cf_str)
-                    .expect("Failed to get read column family from storage");
-                self.batch.0.delete_cf(cf, key.to_string());
-            }
-        };
-    }
 
-    fn get_pattern(&self, pattern: Regex) -> Vec<(String, Vec<u8>)> {
-        self.db
-            .iter_pattern(None, pattern)
-            .map(|(k, v, _)| (k, v))
-            .collect()
+        let mut iter = db.iter_prefix_typed::<u64>(Some(&key("a")));
+        assert_eq!(iter.next().unwrap().unwrap(), (key("a/1"), 1));
+        assert!(matches!(iter.next().unwrap(), Err(Error::CodingError(_))));
+        assert!(iter.next().is_none());
     }
-}
 
-impl<'iter> DBIter<'iter> for RocksDB {
-    type PatternIter = PersistentPatternIterator<'iter>;
-    type PrefixIter = PersistentPrefixIterator<'iter>;
+    /// Test listing the keys changed in a block
+    #[test]
+    fn test_changed_keys() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = |key: &str| Key::parse(key).unwrap();
+        db.write_subspace_val(BlockHeight(1), &key("updated"), [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &key("deleted"), [1_u8], true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &key("updated"), [2_u8], true)
+            .unwrap();
+        db.delete_subspace_val(BlockHeight(2), &key("deleted"), true)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(2), &key("created"), [2_u8], true)
+            .unwrap();
+        // A key whose diffs are not persisted
+        db.write_subspace_val(BlockHeight(2), &key("other"), [2_u8], false)
+            .unwrap();
 
-    fn iter_prefix(
-        &'iter self,
-        prefix: Option<&Key>,
-    ) -> PersistentPrefixIterator<'iter> {
-        iter_subspace_prefix(self, prefix)
-    }
+        assert_eq!(
+            db.changed_keys(BlockHeight(1)).unwrap(),
+            vec![
+                (key("deleted"), ChangeKind::Created),
+                (key("updated"), ChangeKind::Created),
+            ]
+        );
+        assert_eq!(
+            db.changed_keys(BlockHeight(2)).unwrap(),
+            vec![
+                (key("created"), ChangeKind::Created),
+                (key("deleted"), ChangeKind::Deleted),
+                (key("updated"), ChangeKind::Updated),
+            ]
+        );
+        assert!(db.changed_keys(BlockHeight(3)).unwrap().is_empty());
 
-    fn iter_pattern(
-        &'iter self,
-        prefix: Option<&Key>,
-        pattern: Regex,
-    ) -> PersistentPatternIterator<'iter> {
-        iter_subspace_pattern(self, prefix, pattern)
+        // The changes of the pruned heights are unknown
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 1,
+        };
+        db.prune_to_policy(BlockHeight(2), Epoch(0), &policy)
+            .unwrap();
+        assert!(db.changed_keys(BlockHeight(1)).is_err());
+        assert_eq!(db.changed_keys(BlockHeight(2)).unwrap().len(), 3);
     }
 
-    fn iter_results(&'iter self) -> PersistentPrefixIterator<'iter> {
-        let db_prefix = "results/".to_owned();
-        let prefix = "results".to_owned();
+    /// Test comparing the column families of two DBs
+    #[test]
+    fn test_diff_against() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let other_dir = tempdir().unwrap();
+        let mut other = open_with_encryption(
+            other_dir.path(),
+            false,
+            None,
+            Some(SubspaceCipher::new([7; 32])),
+        )
+        .unwrap();
+        let key = |key: &str| Key::parse(key).unwrap();
+        for db in [&mut db, &mut other] {
+            db.write_subspace_val(BlockHeight(1), &key("a/same"), [1_u8], true)
+                .unwrap();
+            db.write_subspace_val(BlockHeight(1), &key("b/same"), [1_u8], true)
+                .unwrap();
+        }
+        db.write_subspace_val(BlockHeight(1), &key("a/diverged"), [1_u8], true)
+            .unwrap();
+        other
+            .write_subspace_val(
+                BlockHeight(1),
+                &key("a/diverged"),
+                [2_u8],
+                true,
+            )
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &key("a/ours"), [1_u8], true)
+            .unwrap();
+        other
+            .write_subspace_val(BlockHeight(1), &key("b/theirs"), [1_u8], true)
+            .unwrap();
 
-        let block_cf = self
-            .get_column_family(BLOCK_CF)
-            .expect("{BLOCK_CF} column family should exist");
-        let read_opts = make_iter_read_opts(Some(prefix.clone()));
-        let iter = self.inner.iterator_cf_opt(
-            block_cf,
-            read_opts,
-            IteratorMode::From(prefix.as_bytes(), Direction::Forward),
+        assert_eq!(
+            db.diff_against(&other, &DbColFam::SUBSPACE, None).unwrap(),
+            vec![
+                KeyDivergence::ValueMismatch("a/diverged".to_string()),
+                KeyDivergence::OnlyInSelf("a/ours".to_string()),
+                KeyDivergence::OnlyInOther("b/theirs".to_string()),
+            ]
+        );
+        assert_eq!(
+            db.diff_against(&other, &DbColFam::SUBSPACE, Some(&key("b")))
+                .unwrap(),
+            vec![KeyDivergence::OnlyInOther("b/theirs".to_string())]
+        );
+        assert!(
+            db.diff_against(&db, &DbColFam::SUBSPACE, None)
+                .unwrap()
+                .is_empty()
         );
-        PersistentPrefixIterator(PrefixIterator::new(iter, db_prefix))
     }
 
-    fn iter_old_diffs(
-        &'iter self,
-        height: BlockHeight,
-        prefix: Option<&'iter Key>,
-    ) -> PersistentPrefixIterator<'iter> {
-        let diffs_cf = self
-            .get_column_family(DIFFS_CF)
-            .expect("{DIFFS_CF} column family should exist");
-        iter_diffs_prefix(self, diffs_cf, height, prefix, true)
-    }
+    /// Test that the subspace fingerprints of two DBs only match if their
+    /// subspaces do
+    #[test]
+    fn test_subspace_fingerprint() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let other_dir = tempdir().unwrap();
+        let mut other = open_with_encryption(
+            other_dir.path(),
+            false,
+            None,
+            Some(SubspaceCipher::new([7; 32])),
+        )
+        .unwrap();
+        assert_eq!(
+            db.subspace_fingerprint().unwrap(),
+            other.subspace_fingerprint().unwrap()
+        );
 
-    fn iter_new_diffs(
-        &'iter self,
-        height: BlockHeight,
-        prefix: Option<&'iter Key>,
-    ) -> PersistentPrefixIterator<'iter> {
-        let diffs_cf = self
-            .get_column_family(DIFFS_CF)
-            .expect("{DIFFS_CF} column family should exist");
-        iter_diffs_prefix(self, diffs_cf, height, prefix, false)
+        let key = |key: &str| Key::parse(key).unwrap();
+        // The same subspace written in a different order
+        for (height, name) in [(1, "a"), (2, "b"), (3, "c")] {
+            db.write_subspace_val(
+                BlockHeight(height),
+                &key(name),
+                [1_u8],
+                true,
+            )
+            .unwrap();
+        }
+        for (height, name) in [(1, "c"), (2, "a"), (3, "b")] {
+            other
+                .write_subspace_val(
+                    BlockHeight(height),
+                    &key(name),
+                    [1_u8],
+                    true,
+                )
+                .unwrap();
+        }
+        let fingerprint = db.subspace_fingerprint().unwrap();
+        assert_eq!(fingerprint, db.subspace_fingerprint().unwrap());
+        assert_eq!(fingerprint, other.subspace_fingerprint().unwrap());
+
+        // A single different value diverges
+        other
+            .write_subspace_val(BlockHeight(4), &key("b"), [2_u8], true)
+            .unwrap();
+        assert_ne!(fingerprint, other.subspace_fingerprint().unwrap());
+        // As does a key moved to another path with the same value
+        other
+            .write_subspace_val(BlockHeight(5), &key("b"), [1_u8], true)
+            .unwrap();
+        assert_eq!(fingerprint, other.subspace_fingerprint().unwrap());
+        other
+            .delete_subspace_val(BlockHeight(6), &key("c"), true)
+            .unwrap();
+        other
+            .write_subspace_val(BlockHeight(6), &key("d"), [1_u8], true)
+            .unwrap();
+        assert_ne!(fingerprint, other.subspace_fingerprint().unwrap());
     }
 
-    fn iter_current_replay_protection(&'iter self) -> Self::PrefixIter {
-        let replay_protection_cf = self
-            .get_column_family(REPLAY_PROTECTION_CF)
-            .expect("{REPLAY_PROTECTION_CF} column family should exist");
+    /// Test warming the block cache for a range of heights
+    #[test]
+    fn test_warm_range() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        for height in [1_u64, 2, 3] {
+            commit_blocks(&db, [BlockHeight(height)]);
+            db.write_subspace_val(BlockHeight(height), &key, [1_u8], true)
+                .unwrap();
+        }
 
-        let prefix = Some(replay_protection::current_prefix());
-        iter_prefix(self, replay_protection_cf, None, prefix.as_ref())
+        db.warm_range(BlockHeight(1), BlockHeight(2)).unwrap();
+        db.warm_range(BlockHeight(3), BlockHeight(3)).unwrap();
+        // Heights without any data are skipped
+        db.warm_range(BlockHeight(3), BlockHeight(10)).unwrap();
+        assert!(db.warm_range(BlockHeight(2), BlockHeight(1)).is_err());
+
+        // The data is left as is
+        assert_eq!(
+            db.read_subspace_val_with_height(
+                &key,
+                BlockHeight(1),
+                BlockHeight(3)
+            )
+            .unwrap(),
+            Some(vec![1_u8])
+        );
     }
-}
 
-fn iter_subspace_prefix<'iter>(
-    db: &'iter RocksDB,
-    prefix: Option<&Key>,
-) -> PersistentPrefixIterator<'iter> {
-    let subspace_cf = db
-        .get_column_family(SUBSPACE_CF)
-        .expect("{SUBSPACE_CF} column family should exist");
-    let stripped_prefix = None;
-    iter_prefix(db, subspace_cf, stripped_prefix, prefix)
-}
+    /// Parse a diff key back into its height, whether it's an old diff and
+    /// the key of the diff. The height and the kind of the diff are the
+    /// first two segments, so a key may contain any segment.
+    fn parse_diff_key(diff_key: &str) -> (BlockHeight, bool, Key) {
+        let mut segments = Key::parse(diff_key).unwrap().segments.into_iter();
+        let height =
+            BlockHeight(u64::parse(segments.next().unwrap().raw()).unwrap());
+        let is_old = match segments.next().unwrap().raw().as_str() {
+            OLD_DIFF_PREFIX => true,
+            NEW_DIFF_PREFIX => false,
+            kind => panic!("Unexpected diff kind {kind}"),
+        };
+        (
+            height,
+            is_old,
+            Key {
+                segments: segments.collect(),
+            },
+        )
+    }
 
-fn iter_subspace_pattern<'iter>(
-    db: &'iter RocksDB,
-    prefix: Option<&Key>,
-    pattern: Regex,
-) -> PersistentPatternIterator<'iter> {
-    let subspace_cf = db
-        .get_column_family(SUBSPACE_CF)
-        .expect("{SUBSPACE_CF} column family should exist");
-    let stripped_prefix = None;
-    iter_pattern(db, subspace_cf, stripped_prefix, prefix, pattern)
-}
+    proptest! {
+        /// Test that a diff key is parsed back into its height, kind and key,
+        /// including a key with a segment clashing with the kind of a diff
+        #[test]
+        fn test_diff_key_roundtrip(
+            key in arb_key(),
+            height: u64,
+            is_old: bool,
+            clashing_seg in select(vec![OLD_DIFF_PREFIX, NEW_DIFF_PREFIX]),
+            clashing_at: Index,
+        ) {
+            let mut key = key;
+            let at = clashing_at.index(key.segments.len());
+            key.segments
+                .insert(at, DbKeySeg::StringSeg(clashing_seg.to_string()));
+            let height = BlockHeight(height);
+
+            let (old, new) = old_and_new_diff_key(&key, height).unwrap();
+            let diff_key = if is_old { old } else { new };
+            prop_assert_eq!(
+                parse_diff_key(&diff_key),
+                (height, is_old, key.clone())
+            );
 
-fn iter_diffs_prefix<'a>(
-    db: &'a RocksDB,
-    cf: &'a ColumnFamily,
-    height: BlockHeight,
-    prefix: Option<&Key>,
-    is_old: bool,
-) -> PersistentPrefixIterator<'a> {
-    let kind = if is_old {
-        OLD_DIFF_PREFIX
-    } else {
-        NEW_DIFF_PREFIX
-    };
-    let stripped_prefix = Some(
-        Key::from(height.to_db_key())
-            .push(&kind.to_string())
-            .unwrap(),
-    );
-    // get keys without the `stripped_prefix`
-    iter_prefix(db, cf, stripped_prefix.as_ref(), prefix)
-}
+            // The key is also recovered by stripping the prefix of the diffs
+            // at the height, as the diffs iterators do
+            let stripped_prefix = diffs_stripped_prefix(height, is_old);
+            let stripped = diff_key
+                .strip_prefix(&format!("{stripped_prefix}/"))
+                .unwrap();
+            prop_assert_eq!(Key::parse(stripped).unwrap(), key);
+        }
+    }
 
-/// Create an iterator over key-vals in the given CF matching the given
-/// prefix(es). If any, the `stripped_prefix` is matched first and will be
-/// removed from the matched keys. If any, the second `prefix` is matched
-/// against the stripped keys and remains in the matched keys.
-fn iter_prefix<'a>(
-    db: &'a RocksDB,
-    cf: &'a ColumnFamily,
-    stripped_prefix: Option<&Key>,
-    prefix: Option<&Key>,
-) -> PersistentPrefixIterator<'a> {
-    let stripped_prefix = match stripped_prefix {
-        Some(p) if !p.is_empty() => format!("{p}/"),
-        _ => "".to_owned(),
-    };
-    let prefix = match prefix {
-        Some(p) if !p.is_empty() => {
-            format!("{stripped_prefix}{p}/")
+    /// Test that an iterator skips a key that doesn't match its prefix,
+    /// unless it's checked, in which case the key is an error
+    #[test]
+    fn test_unmatched_prefix() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("a/key").unwrap();
+        let misplaced_key = Key::parse("b/key").unwrap();
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], false)
+            .unwrap();
+        db.write_subspace_val(BlockHeight(1), &misplaced_key, [2_u8], false)
+            .unwrap();
+
+        // An iterator over the whole CF, which is only expected to contain
+        // the keys under the "a/" prefix
+        fn iter(db: &RocksDB) -> PersistentPrefixIterator<'_> {
+            let subspace_cf = db.get_column_family(SUBSPACE_CF).unwrap();
+            let iter = db.inner.iterator_cf_opt(
+                subspace_cf,
+                make_iter_read_opts(None),
+                IteratorMode::Start,
+            );
+            PersistentPrefixIterator(
+                PrefixIterator::new(iter, "a/".to_owned()),
+                None,
+            )
         }
-        _ => stripped_prefix.clone(),
-    };
-    let read_opts = make_iter_read_opts(Some(prefix.clone()));
-    let iter = db.inner.iterator_cf_opt(
-        cf,
-        read_opts,
-        IteratorMode::From(prefix.as_bytes(), Direction::Forward),
-    );
-    PersistentPrefixIterator(PrefixIterator::new(iter, stripped_prefix))
-}
 
-/// Create an iterator over key-vals in the given CF matching the given
-/// pattern(s).
-fn iter_pattern<'a>(
-    db: &'a RocksDB,
-    cf: &'a ColumnFamily,
-    stripped_prefix: Option<&Key>,
-    prefix: Option<&Key>,
-    pattern: Regex,
-) -> PersistentPatternIterator<'a> {
-    PersistentPatternIterator {
-        inner: PatternIterator {
-            iter: iter_prefix(db, cf, stripped_prefix, prefix),
-            pattern,
-        },
+        let skipped: Vec<_> = iter(&db).collect();
+        assert_eq!(skipped, vec![("key".to_owned(), vec![1_u8], 4)]);
+
+        let mut checked = iter(&db).checked();
+        assert_eq!(
+            checked.next().unwrap().unwrap(),
+            ("key".to_owned(), vec![1_u8], 4)
+        );
+        let err = checked.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            Error::DBError(
+                "Unmatched prefix \"a/\" in iterator's key \"b/key\""
+                    .to_owned()
+            )
+            .to_string()
+        );
+        assert!(checked.next().is_none());
     }
-}
 
-#[derive(Debug)]
-pub struct PersistentPrefixIterator<'a>(
-    PrefixIterator<rocksdb::DBIterator<'a>>,
-);
+    /// Test pruning the DB to a retention policy
+    #[test]
+    fn test_prune_to_policy() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let persisted_key = Key::parse("persisted").unwrap();
+        let non_persisted_key = Key::parse("non_persisted").unwrap();
+
+        // Epoch 0 starts at height 1, epoch 1 at height 3 and epoch 2 at
+        // height 5
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for (height, epoch) in [(1, 0), (2, 0), (3, 1), (4, 1), (5, 2), (6, 2)]
+        {
+            let height = BlockHeight(height);
+            if height == BlockHeight(3) || height == BlockHeight(5) {
+                pred_epochs.new_epoch(height);
+            }
+            let mut batch = RocksDB::batch();
+            let val = [u8::try_from(height.0).unwrap()];
+            db.batch_write_subspace_val(
+                &mut batch,
+                height,
+                &persisted_key,
+                val,
+                true,
+            )
+            .unwrap();
+            db.batch_write_subspace_val(
+                &mut batch,
+                height,
+                &non_persisted_key,
+                val,
+                false,
+            )
+            .unwrap();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
 
-impl<'a> Iterator for PersistentPrefixIterator<'a> {
-    type Item = (String, Vec<u8>, u64);
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 2,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 2,
+        };
 
-    /// Returns the next pair and the gas cost
-    fn next(&mut self) -> Option<(String, Vec<u8>, u64)> {
-        loop {
-            match self.0.iter.next() {
-                Some(result) => {
-                    let (key, val) =
-                        result.expect("Prefix iterator shouldn't fail");
-                    let key = String::from_utf8(key.to_vec())
-                        .expect("Cannot convert from bytes to key string");
-                    if let Some(k) = key.strip_prefix(&self.0.stripped_prefix) {
-                        let gas = k.len().checked_add(val.len())?;
-                        return Some((k.to_owned(), val.to_vec(), gas as _));
-                    } else {
-                        tracing::warn!(
-                            "Unmatched prefix \"{}\" in iterator's key \
-                             \"{key}\"",
-                            self.0.stripped_prefix
-                        );
-                    }
-                }
-                None => return None,
+        // At the first height of epoch 2, the stores of epoch 1 are kept for
+        // a rollback. Every height but the first one has an old and a new
+        // diff for each key.
+        let report = db
+            .prune_to_policy(BlockHeight(5), Epoch(2), &policy)
+            .unwrap();
+        assert_eq!(
+            report,
+            PruneReport {
+                diffs: 5,
+                rollback_entries: 7,
+                merkle_stores: 4,
+                headers: 0,
+            }
+        );
+
+        let report = db
+            .prune_to_policy(BlockHeight(6), Epoch(2), &policy)
+            .unwrap();
+        assert_eq!(
+            report,
+            PruneReport {
+                diffs: 2,
+                rollback_entries: 2,
+                merkle_stores: 4,
+                headers: 0,
+            }
+        );
+        assert_eq!(db.iter_old_diffs(BlockHeight(4), None).count(), 0);
+        assert_eq!(db.iter_old_diffs(BlockHeight(5), None).count(), 1);
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        for epoch in [Epoch(0), Epoch(1)] {
+            for st in StoreType::iter_non_provable() {
+                let key = format!(
+                    "{}/{MERKLE_TREE_ROOT_KEY_SEGMENT}",
+                    tree_key_prefix_with_epoch(st, epoch)
+                );
+                assert!(db.read_value_bytes(block_cf, key).unwrap().is_none());
             }
         }
-    }
-}
 
-#[derive(Debug)]
-pub struct PersistentPatternIterator<'a> {
-    inner: PatternIterator<PersistentPrefixIterator<'a>>,
-}
+        // The current height can still be rolled back
+        db.rollback(BlockHeight(5)).unwrap();
+        for key in [&persisted_key, &non_persisted_key] {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(vec![5_u8]));
+        }
+    }
 
-impl<'a> Iterator for PersistentPatternIterator<'a> {
-    type Item = (String, Vec<u8>, u64);
+    /// Test that the data of a pinned height is kept through the pruning
+    #[test]
+    fn test_pin_height() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
 
-    /// Returns the next pair and the gas cost
-    fn next(&mut self) -> Option<(String, Vec<u8>, u64)> {
-        loop {
-            let next_result = self.inner.iter.next()?;
-            if self.inner.pattern.is_match(&next_result.0) {
-                return Some(next_result);
+        // Epoch 0 starts at height 1, epoch 1 at height 3 and epoch 2 at
+        // height 5
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for (height, epoch) in [(1, 0), (2, 0), (3, 1), (4, 1), (5, 2), (6, 2)]
+        {
+            let height = BlockHeight(height);
+            if height == BlockHeight(3) || height == BlockHeight(5) {
+                pred_epochs.new_epoch(height);
             }
+            let mut batch = RocksDB::batch();
+            let val = [u8::try_from(height.0).unwrap()];
+            db.batch_write_subspace_val(&mut batch, height, &key, val, true)
+                .unwrap();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        assert!(db.pin_height(BlockHeight(2)).unwrap());
+        assert!(!db.pin_height(BlockHeight(2)).unwrap());
+        assert_eq!(
+            db.pinned_heights().unwrap(),
+            BTreeSet::from([BlockHeight(2)])
+        );
+
+        // The diffs from the pinned height onward and the stores of its
+        // epoch are kept, although they're out of the retention window
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 2,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 2,
+        };
+        db.prune_to_policy(BlockHeight(6), Epoch(2), &policy)
+            .unwrap();
+        assert_eq!(db.iter_old_diffs(BlockHeight(1), None).count(), 0);
+        assert_eq!(db.iter_new_diffs(BlockHeight(1), None).count(), 0);
+        for height in 2..=4 {
+            assert_eq!(db.iter_new_diffs(BlockHeight(height), None).count(), 1);
+        }
+        assert_eq!(
+            db.read_subspace_val_with_height(
+                &key,
+                BlockHeight(2),
+                BlockHeight(6)
+            )
+            .unwrap(),
+            Some(vec![2_u8])
+        );
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        let root_key = |st, epoch| {
+            format!(
+                "{}/{MERKLE_TREE_ROOT_KEY_SEGMENT}",
+                tree_key_prefix_with_epoch(st, epoch)
+            )
+        };
+        for st in StoreType::iter_non_provable() {
+            assert!(
+                db.read_value_bytes(block_cf, root_key(st, Epoch(0)))
+                    .unwrap()
+                    .is_some()
+            );
+            assert!(
+                db.read_value_bytes(block_cf, root_key(st, Epoch(1)))
+                    .unwrap()
+                    .is_none()
+            );
+        }
+
+        // Once unpinned, the height is pruned
+        assert!(db.unpin_height(BlockHeight(2)).unwrap());
+        assert!(!db.unpin_height(BlockHeight(2)).unwrap());
+        assert!(db.pinned_heights().unwrap().is_empty());
+        db.prune_to_policy(BlockHeight(6), Epoch(2), &policy)
+            .unwrap();
+        for height in 2..=4 {
+            assert_eq!(db.iter_new_diffs(BlockHeight(height), None).count(), 0);
+        }
+        for st in StoreType::iter_non_provable() {
+            assert!(
+                db.read_value_bytes(block_cf, root_key(st, Epoch(0)))
+                    .unwrap()
+                    .is_none()
+            );
         }
     }
-}
 
-/// Make read options for RocksDB iterator with the given prefix
-fn make_iter_read_opts(prefix: Option<String>) -> ReadOptions {
-    let mut read_opts = ReadOptions::default();
-    // don't use the prefix bloom filter
-    read_opts.set_total_order_seek(true);
+    /// A change sink recording the changes it's given
+    #[derive(Debug, Default)]
+    struct RecordingSink(
+        Arc<Mutex<Vec<(BlockHeight, Vec<(Key, ChangeKind)>)>>>,
+    );
 
-    if let Some(prefix) = prefix {
-        let mut upper_prefix = prefix.into_bytes();
-        if let Some(last) = upper_prefix.last_mut() {
-            *last = last.checked_add(1).expect("cannot overflow");
-            read_opts.set_iterate_upper_bound(upper_prefix);
+    impl ChangeSink for RecordingSink {
+        fn on_block(
+            &self,
+            height: BlockHeight,
+            changes: Vec<(Key, ChangeKind)>,
+        ) {
+            self.0.lock().unwrap().push((height, changes));
         }
     }
 
-    read_opts
-}
+    /// Test that the changes of the committed blocks are pushed to the
+    /// registered sink
+    #[test]
+    fn test_change_sink() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = |name: &str| Key::parse(name).unwrap();
+        let add_block =
+            |db: &RocksDB, batch: &mut RocksDBWriteBatch, height| {
+                TestBlock::new(BlockHeight(height))
+                    .add_to_batch(db, batch)
+                    .unwrap();
+            };
 
-impl DBWriteBatch for RocksDBWriteBatch {}
+        // Nothing is tracked without a sink
+        let mut batch = RocksDB::batch();
+        db.batch_write_subspace_val(
+            &mut batch,
+            BlockHeight(1),
+            &key("a"),
+            [1_u8],
+            true,
+        )
+        .unwrap();
+        add_block(&db, &mut batch, 1);
+        assert!(batch.changed_keys.is_empty());
+        db.exec_batch(batch).unwrap();
 
-fn old_and_new_diff_key(
-    key: &Key,
-    height: BlockHeight,
-) -> Result<(String, String)> {
-    let key_prefix = Key::from(height.to_db_key());
-    let old = key_prefix
-        .push(&OLD_DIFF_PREFIX.to_owned())
-        .map_err(Error::KeyError)?
-        .join(key);
-    let new = key_prefix
-        .push(&NEW_DIFF_PREFIX.to_owned())
-        .map_err(Error::KeyError)?
-        .join(key);
-    Ok((old.to_string(), new.to_string()))
-}
+        let sink = RecordingSink::default();
+        let recorded = sink.0.clone();
+        db.set_change_sink(Some(Box::new(sink)));
 
-/// Try to increase NOFILE limit and set the `max_open_files` limit to it in
-/// RocksDB options.
-fn set_max_open_files(cf_opts: &mut rocksdb::Options) {
-    #[cfg(unix)]
-    imp::set_max_open_files(cf_opts);
-    // Nothing to do on non-unix
-    #[cfg(not(unix))]
-    let _ = cf_opts;
-}
+        let mut batch = RocksDB::batch();
+        let height = BlockHeight(2);
+        db.batch_write_subspace_val(
+            &mut batch,
+            height,
+            &key("a"),
+            [2_u8],
+            true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch,
+            height,
+            &key("b"),
+            [2_u8],
+            true,
+        )
+        .unwrap();
+        // The changes of the non-persisted diffs aren't pushed
+        db.batch_write_subspace_val(
+            &mut batch,
+            height,
+            &key("c"),
+            [2_u8],
+            false,
+        )
+        .unwrap();
+        add_block(&db, &mut batch, 2);
+        // Nothing is pushed until the batch is written
+        assert!(recorded.lock().unwrap().is_empty());
+        db.exec_batch(batch).unwrap();
 
-#[cfg(unix)]
-mod imp {
-    use rlimit::{Resource, Rlim};
+        let mut batch = RocksDB::batch();
+        let height = BlockHeight(3);
+        db.batch_delete_subspace_val(&mut batch, height, &key("a"), true)
+            .unwrap();
+        add_block(&db, &mut batch, 3);
+        db.exec_batch(batch).unwrap();
 
-    const DEFAULT_NOFILE_LIMIT: Rlim = Rlim::from_raw(16384);
+        // A batch without a block isn't pushed
+        db.write_subspace_val(BlockHeight(4), &key("b"), [4_u8], true)
+            .unwrap();
 
-    pub fn set_max_open_files(cf_opts: &mut rocksdb::Options) {
-        let max_open_files = match increase_nofile_limit() {
-            Ok(max_open_files) => Some(max_open_files),
-            Err(err) => {
-                tracing::error!("Failed to increase NOFILE limit: {}", err);
-                None
-            }
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 2);
+        for (height, changes) in recorded.iter() {
+            assert_eq!(*changes, db.changed_keys(*height).unwrap());
+        }
+        assert_eq!(
+            recorded[0],
+            (
+                BlockHeight(2),
+                vec![
+                    (key("a"), ChangeKind::Updated),
+                    (key("b"), ChangeKind::Created)
+                ]
+            )
+        );
+        assert_eq!(
+            recorded[1],
+            (BlockHeight(3), vec![(key("a"), ChangeKind::Deleted)])
+        );
+    }
+
+    /// Test that the merkle tree stores written twice in an epoch are
+    /// reported when the check is enabled
+    #[test]
+    fn test_merkle_store_overwrites() {
+        let dir = tempdir().unwrap();
+        let mut db = open(dir.path(), false, None).unwrap();
+
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        let add_block = |db: &RocksDB, height: u64, epoch: u64| {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            // The test blocks are always fully committed
+            add_block_to_batch(
+                db,
+                &mut batch,
+                height,
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
         };
-        if let Some(max_open_files) =
-            max_open_files.and_then(|max| max.as_raw().try_into().ok())
-        {
-            cf_opts.set_max_open_files(max_open_files);
+
+        // Not checked by default
+        add_block(&db, 1, 0);
+        add_block(&db, 2, 0);
+        assert!(db.take_merkle_store_overwrites().is_empty());
+
+        db.set_check_merkle_store_overwrites(true);
+        add_block(&db, 3, 0);
+        let overwrites = db.take_merkle_store_overwrites();
+        let expected: Vec<_> = StoreType::iter()
+            .filter(|st| !st.is_stored_every_block())
+            .map(|st| MerkleStoreOverwrite {
+                store_type: *st,
+                epoch: Epoch(0),
+                height: BlockHeight(3),
+            })
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(overwrites, expected);
+        assert!(db.take_merkle_store_overwrites().is_empty());
+
+        // The first full commit of an epoch doesn't overwrite anything
+        add_block(&db, 4, 1);
+        assert!(db.take_merkle_store_overwrites().is_empty());
+    }
+
+    /// Test that the blocks are archived for replay when enabled
+    #[test]
+    fn test_replay_block() {
+        let dir = tempdir().unwrap();
+        let key = Key::parse("key").unwrap();
+        let other_key = Key::parse("other_key").unwrap();
+        let options = OpenOptions {
+            archive: true,
+            ..OpenOptions::default()
+        };
+        let mut db =
+            open_with_options(dir.path(), options, None, None).unwrap();
+
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for height in [1, 2, 3] {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            if height == BlockHeight(2) {
+                db.batch_delete_subspace_val(&mut batch, height, &key, true)
+                    .unwrap();
+            } else {
+                let val = [u8::try_from(height.0).unwrap()];
+                db.batch_write_subspace_val(
+                    &mut batch, height, &key, val, true,
+                )
+                .unwrap();
+                // The non-persisted diffs are archived too
+                db.batch_write_subspace_val(
+                    &mut batch, height, &other_key, val, false,
+                )
+                .unwrap();
+            }
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                height,
+                Epoch(0),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
         }
-    }
 
-    /// Try to increase NOFILE limit and return the current soft limit.
-    fn increase_nofile_limit() -> std::io::Result<Rlim> {
-        let (soft, hard) = Resource::NOFILE.get()?;
-        tracing::debug!("Current NOFILE limit, soft={}, hard={}", soft, hard);
+        let block = db.replay_block(BlockHeight(1)).unwrap().unwrap();
+        assert_eq!(block.epoch, Epoch(0));
+        assert_eq!(
+            block.changes,
+            BTreeMap::from([
+                (key.clone(), Some(vec![1_u8])),
+                (other_key.clone(), Some(vec![1_u8])),
+            ])
+        );
+        let block = db.replay_block(BlockHeight(2)).unwrap().unwrap();
+        assert_eq!(block.changes, BTreeMap::from([(key.clone(), None)]));
+        assert!(db.replay_block(BlockHeight(4)).unwrap().is_none());
+
+        // The archive is kept when the diffs are pruned
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 1,
+        };
+        db.prune_to_policy(BlockHeight(3), Epoch(0), &policy)
+            .unwrap();
+        assert!(db.replay_block(BlockHeight(1)).unwrap().is_some());
 
-        let target = std::cmp::min(DEFAULT_NOFILE_LIMIT, hard);
-        if soft >= target {
-            tracing::debug!(
-                "NOFILE limit already large enough, not attempting to increase"
-            );
-            Ok(soft)
-        } else {
-            tracing::debug!("Try to increase to {}", target);
-            Resource::NOFILE.set(target, target)?;
+        // The archive has its own retention
+        assert_eq!(db.prune_archive(BlockHeight(3), 2).unwrap(), 3);
+        assert!(db.replay_block(BlockHeight(1)).unwrap().is_none());
+        assert!(db.replay_block(BlockHeight(2)).unwrap().is_some());
+        drop(db);
 
-            let (soft, hard) = Resource::NOFILE.get()?;
-            tracing::debug!(
-                "Increased NOFILE limit, soft={}, hard={}",
-                soft,
-                hard
-            );
-            Ok(soft)
-        }
+        // The archived blocks stay readable without the archive, but no new
+        // block is archived
+        let db = open(dir.path(), false, None).unwrap();
+        assert!(db.replay_block(BlockHeight(3)).unwrap().is_some());
+        let mut batch = RocksDB::batch();
+        add_block_to_batch(
+            &db,
+            &mut batch,
+            BlockHeight(4),
+            Epoch(0),
+            pred_epochs,
+            &ConversionState::default(),
+        )
+        .unwrap();
+        db.exec_batch(batch).unwrap();
+        assert!(db.replay_block(BlockHeight(4)).unwrap().is_none());
     }
-}
-
-#[allow(clippy::arithmetic_side_effects)]
-#[cfg(test)]
-mod test {
-    use namada_sdk::address::EstablishedAddressGen;
-    use namada_sdk::collections::HashMap;
-    use namada_sdk::hash::Hash;
-    use namada_sdk::state::{MerkleTree, Sha256Hasher};
-    use namada_sdk::storage::conversion_state::ConversionState;
-    use namada_sdk::storage::types::CommitOnlyData;
-    use namada_sdk::storage::{BlockResults, Epochs, EthEventsQueue};
-    use namada_sdk::time::DateTimeUtc;
-    use tempfile::tempdir;
-    use test_log::test;
-
-    use super::*;
 
-    /// Test that a block written can be loaded back from DB.
+    /// Test exporting the replay protection hashes grouped by bucket
     #[test]
-    fn test_load_state() {
+    fn test_export_replay_protection() {
         let dir = tempdir().unwrap();
-        let db = RocksDB::open(dir.path(), None);
+        let mut db = RocksDB::open(dir.path(), None);
 
+        let committed = [Hash::sha256(b"tx_1"), Hash::sha256(b"tx_2")];
+        let last = Hash::sha256(b"tx_3");
         let mut batch = RocksDB::batch();
-        let last_height = BlockHeight::default();
-        db.batch_write_subspace_val(
+        for hash in &committed {
+            db.write_replay_protection_entry(
+                &mut batch,
+                &replay_protection::key(hash),
+            )
+            .unwrap();
+        }
+        db.write_replay_protection_entry(
             &mut batch,
-            last_height,
-            &Key::parse("test").unwrap(),
-            vec![1_u8, 1, 1, 1],
-            true,
+            &replay_protection::current_key(&last),
         )
         .unwrap();
+        db.exec_batch(batch).unwrap();
 
-        add_block_to_batch(
-            &db,
+        let out = dir.path().join("replay_protection.txt");
+        let count = db.export_replay_protection(&out).unwrap();
+        assert_eq!(count, 3);
+
+        let mut committed = committed.map(|hash| hash.to_string());
+        committed.sort();
+        let expected = format!(
+            "[all]\n{}\n{}\n[current]\n{last}\n",
+            committed[0], committed[1]
+        );
+        assert_eq!(std::fs::read_to_string(out).unwrap(), expected);
+    }
+
+    /// Test exporting the replay protection hashes as a bloom filter
+    #[test]
+    fn test_replay_protection_bloom() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let committed: Vec<Hash> =
+            (0..100_u8).map(|i| Hash::sha256([i])).collect();
+        let last = Hash::sha256(b"last");
+        let mut batch = RocksDB::batch();
+        for hash in &committed {
+            db.write_replay_protection_entry(
+                &mut batch,
+                &replay_protection::key(hash),
+            )
+            .unwrap();
+        }
+        db.write_replay_protection_entry(
             &mut batch,
-            BlockHeight::default(),
-            Epoch::default(),
-            Epochs::default(),
-            &ConversionState::default(),
+            &replay_protection::current_key(&last),
         )
         .unwrap();
         db.exec_batch(batch).unwrap();
 
-        let _state = db
-            .read_last_block()
-            .expect("Should be able to read last block")
-            .expect("Block should have been written");
+        let bytes = db.replay_protection_bloom(0.01).unwrap();
+        let bloom = ReplayProtectionBloom::try_from_slice(&bytes).unwrap();
+        assert_eq!(bloom.num_items, 101);
+        for hash in committed.iter().chain([&last]) {
+            assert!(bloom.may_contain(hash));
+        }
+        // Some false positives are expected, but only about 1% of them
+        let false_positives = (0..1000_u32)
+            .filter(|i| bloom.may_contain(&Hash::sha256(i.to_le_bytes())))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+
+        assert!(db.replay_protection_bloom(0.0).is_err());
+        assert!(db.replay_protection_bloom(1.0).is_err());
     }
 
+    /// Test that the DB operations are counted with the `storage-metrics`
+    /// feature
     #[test]
-    fn test_read() {
+    fn test_metrics_snapshot() {
         let dir = tempdir().unwrap();
         let mut db = RocksDB::open(dir.path(), None);
+        assert_eq!(db.metrics_snapshot(), DbMetrics::default());
 
-        let key = Key::parse("test").unwrap();
-        let batch_key = Key::parse("batch").unwrap();
-
+        let key = Key::parse("key").unwrap();
         let mut batch = RocksDB::batch();
-        let last_height = BlockHeight(100);
         db.batch_write_subspace_val(
             &mut batch,
-            last_height,
-            &batch_key,
-            vec![1_u8, 1, 1, 1],
+            BlockHeight(1),
+            &key,
+            [1],
             true,
         )
         .unwrap();
+        db.write_replay_protection_entry(
+            &mut batch,
+            &replay_protection::key(&Hash::sha256(b"tx")),
+        )
+        .unwrap();
         db.exec_batch(batch).unwrap();
+        db.read_subspace_val(&key).unwrap();
+
+        let expected = if cfg!(feature = "storage-metrics") {
+            DbMetrics {
+                subspace_reads: 1,
+                subspace_writes: 1,
+                diff_writes: 1,
+                rp_writes: 1,
+            }
+        } else {
+            DbMetrics::default()
+        };
+        assert_eq!(db.metrics_snapshot(), expected);
+    }
 
-        db.write_subspace_val(last_height, &key, vec![1_u8, 1, 1, 0], true)
-            .unwrap();
+    /// Test that a write batch tracks the net size change of its values
+    #[test]
+    fn test_batch_size_diff() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let height = BlockHeight(1);
+        let key_1 = Key::parse("key_1").unwrap();
+        let key_2 = Key::parse("key_2").unwrap();
 
         let mut batch = RocksDB::batch();
-        let last_height = BlockHeight(111);
+        assert_eq!(batch.size_diff(), 0);
         db.batch_write_subspace_val(
-            &mut batch,
-            last_height,
-            &batch_key,
-            vec![2_u8, 2, 2, 2],
-            true,
+            &mut batch, height, &key_1, [0_u8; 10], true,
+        )
+        .unwrap();
+        db.batch_write_subspace_val(
+            &mut batch, height, &key_2, [0_u8; 5], true,
         )
         .unwrap();
+        assert_eq!(batch.size_diff(), 15);
         db.exec_batch(batch).unwrap();
 
-        db.write_subspace_val(last_height, &key, vec![2_u8, 2, 2, 0], true)
-            .unwrap();
-
-        let prev_value = db
-            .read_subspace_val_with_height(
-                &batch_key,
-                BlockHeight(100),
-                last_height,
-            )
-            .expect("read should succeed");
-        assert_eq!(prev_value, Some(vec![1_u8, 1, 1, 1]));
-        let prev_value = db
-            .read_subspace_val_with_height(&key, BlockHeight(100), last_height)
-            .expect("read should succeed");
-        assert_eq!(prev_value, Some(vec![1_u8, 1, 1, 0]));
-
-        let updated_value = db
-            .read_subspace_val_with_height(
-                &batch_key,
-                BlockHeight(111),
-                last_height,
-            )
-            .expect("read should succeed");
-        assert_eq!(updated_value, Some(vec![2_u8, 2, 2, 2]));
-        let updated_value = db
-            .read_subspace_val_with_height(&key, BlockHeight(111), last_height)
-            .expect("read should succeed");
-        assert_eq!(updated_value, Some(vec![2_u8, 2, 2, 0]));
-
-        let latest_value = db
-            .read_subspace_val(&batch_key)
-            .expect("read should succeed");
-        assert_eq!(latest_value, Some(vec![2_u8, 2, 2, 2]));
-        let latest_value =
-            db.read_subspace_val(&key).expect("read should succeed");
-        assert_eq!(latest_value, Some(vec![2_u8, 2, 2, 0]));
-
         let mut batch = RocksDB::batch();
-        let last_height = BlockHeight(222);
-        db.batch_delete_subspace_val(&mut batch, last_height, &batch_key, true)
+        assert_eq!(batch.size_diff(), 0);
+        db.batch_write_subspace_val(
+            &mut batch, height, &key_1, [0_u8; 4], true,
+        )
+        .unwrap();
+        db.batch_delete_subspace_val(&mut batch, height, &key_2, true)
             .unwrap();
+        assert_eq!(batch.size_diff(), -11);
         db.exec_batch(batch).unwrap();
 
-        db.delete_subspace_val(last_height, &key, true).unwrap();
+        // The migrations' overwrite path is tracked too
+        commit_blocks(&db, [height]);
+        let mut batch = RocksDB::batch();
+        db.overwrite_entry(
+            &mut batch,
+            None,
+            &DbColFam::SUBSPACE,
+            &key_1,
+            [0_u8; 6],
+        )
+        .unwrap();
+        assert_eq!(batch.size_diff(), 2);
+    }
 
-        let deleted_value = db
-            .read_subspace_val_with_height(
-                &batch_key,
-                BlockHeight(222),
-                last_height,
-            )
-            .expect("read should succeed");
-        assert_eq!(deleted_value, None);
-        let deleted_value = db
-            .read_subspace_val_with_height(&key, BlockHeight(222), last_height)
-            .expect("read should succeed");
-        assert_eq!(deleted_value, None);
+    /// Test that a partially written last block is detected
+    #[test]
+    fn test_torn_block_write() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        let diagnosis = db.diagnose_last_block().unwrap();
+        assert_eq!(diagnosis.height, None);
+        assert!(!diagnosis.is_torn());
 
-        let latest_value = db
-            .read_subspace_val(&batch_key)
-            .expect("read should succeed");
-        assert_eq!(latest_value, None);
-        let latest_value =
-            db.read_subspace_val(&key).expect("read should succeed");
-        assert_eq!(latest_value, None);
+        let height = BlockHeight(1);
+        commit_blocks(&db, [height]);
+        let diagnosis = db.diagnose_last_block().unwrap();
+        assert_eq!(diagnosis.height, Some(height));
+        assert_eq!(diagnosis.present.len(), 12);
+        assert!(!diagnosis.is_torn());
+
+        // Remove one of the block's values
+        let time_key = format!("{}/{BLOCK_TIME_KEY_SEGMENT}", height.raw());
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        db.inner.delete_cf(block_cf, &time_key).unwrap();
+        match db.read_last_block() {
+            Err(Error::TornBlockWrite {
+                height: torn_height,
+            }) => {
+                assert_eq!(torn_height, height)
+            }
+            _ => panic!("expected a torn block write error"),
+        }
+        let diagnosis = db.diagnose_last_block().unwrap();
+        assert!(diagnosis.is_torn());
+        assert_eq!(diagnosis.missing, vec![time_key]);
+        assert_eq!(diagnosis.present.len(), 11);
     }
 
+    /// Test the summary of the DB's readiness
     #[test]
-    fn test_prefix_iter() {
+    fn test_health() {
         let dir = tempdir().unwrap();
         let db = RocksDB::open(dir.path(), None);
+        // A fresh DB is healthy
+        let health = db.health();
+        assert_eq!(health.status, DbStatus::Healthy);
+        assert!(health.missing_column_families.is_empty());
+        assert_eq!(health.last_height, None);
+        assert_eq!(health.read_error, None);
+        assert_eq!(health.bg_error, None);
+        assert!(!health.snapshot_import_in_progress);
+        assert!(!health.migration_in_progress);
 
-        let prefix_0 = Key::parse("0").unwrap();
-        let key_0_a = prefix_0.push(&"a".to_string()).unwrap();
-        let key_0_b = prefix_0.push(&"b".to_string()).unwrap();
-        let key_0_c = prefix_0.push(&"c".to_string()).unwrap();
-        let prefix_1 = Key::parse("1").unwrap();
-        let key_1_a = prefix_1.push(&"a".to_string()).unwrap();
-        let key_1_b = prefix_1.push(&"b".to_string()).unwrap();
-        let key_1_c = prefix_1.push(&"c".to_string()).unwrap();
-        let prefix_01 = Key::parse("01").unwrap();
-        let key_01_a = prefix_01.push(&"a".to_string()).unwrap();
+        let height = BlockHeight(1);
+        commit_blocks(&db, [height]);
+        let health = db.health();
+        assert_eq!(health.status, DbStatus::Healthy);
+        assert_eq!(health.last_height, Some(height));
+
+        // A snapshot import in progress degrades the DB
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        db.inner
+            .put_cf(state_cf, SNAPSHOT_IMPORT_IN_PROGRESS_KEY, b"")
+            .unwrap();
+        let health = db.health();
+        assert_eq!(health.status, DbStatus::Degraded);
+        assert!(health.snapshot_import_in_progress);
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["status"], "degraded");
+        db.inner
+            .delete_cf(state_cf, SNAPSHOT_IMPORT_IN_PROGRESS_KEY)
+            .unwrap();
 
-        let keys_0 = vec![key_0_a, key_0_b, key_0_c];
-        let keys_1 = vec![key_1_a, key_1_b, key_1_c];
-        let keys_01 = vec![key_01_a];
-        let all_keys = [keys_0.clone(), keys_01, keys_1.clone()].concat();
+        // So does an offline migration until it's completed
+        db.mark_migration_in_progress().unwrap();
+        let health = db.health();
+        assert_eq!(health.status, DbStatus::Degraded);
+        assert!(health.migration_in_progress);
+        db.clear_migration_marker().unwrap();
+        assert_eq!(db.health().status, DbStatus::Healthy);
+
+        // So does an unreadable last block height
+        db.inner.put_cf(state_cf, BLOCK_HEIGHT_KEY, b"").unwrap();
+        let health = db.health();
+        assert_eq!(health.status, DbStatus::Degraded);
+        assert_eq!(health.last_height, None);
+        assert!(health.read_error.is_some());
+    }
 
-        // Write the keys
-        let mut batch = RocksDB::batch();
-        let height = BlockHeight(1);
-        for key in &all_keys {
-            db.batch_write_subspace_val(&mut batch, height, key, [0_u8], true)
+    /// Test that the compactions drop the stale non-persisted diffs
+    #[test]
+    fn test_rollback_compaction_filter() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+        for height in 1..=3_u64 {
+            db.write_subspace_val(BlockHeight(height), &key, [1_u8], false)
                 .unwrap();
         }
-        db.exec_batch(batch).unwrap();
+        let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+        let rollback_heights = |db: &RocksDB| -> Vec<u64> {
+            iter_prefix_no_gas(db, rollback_cf, None, None)
+                .map(|(key, _val)| {
+                    let height =
+                        key.split(KEY_SEGMENT_SEPARATOR).next().unwrap();
+                    u64::parse(height.to_owned()).unwrap()
+                })
+                .collect()
+        };
+        let compact = |db: &RocksDB| {
+            db.inner
+                .compact_range_cf(rollback_cf, None::<&[u8]>, None::<&[u8]>)
+        };
 
-        // Prefix "0" shouldn't match prefix "01"
-        let itered_keys: Vec<Key> = db
-            .iter_prefix(Some(&prefix_0))
-            .map(|(key, _val, _)| Key::parse(key).unwrap())
-            .collect();
-        itertools::assert_equal(keys_0, itered_keys);
+        // Nothing is dropped as long as no block has been committed
+        compact(&db);
+        assert_eq!(rollback_heights(&db), vec![1, 2, 3]);
 
-        let itered_keys: Vec<Key> = db
-            .iter_prefix(Some(&prefix_1))
-            .map(|(key, _val, _)| Key::parse(key).unwrap())
-            .collect();
-        itertools::assert_equal(keys_1, itered_keys);
+        let mut batch = RocksDB::batch();
+        TestBlock::new(BlockHeight(3)).add_to_batch(&db, &mut batch).unwrap();
+        // The block only counts as committed once its batch is written
+        compact(&db);
+        assert_eq!(rollback_heights(&db), vec![1, 2, 3]);
+        db.exec_batch(batch).unwrap();
+        compact(&db);
+        assert_eq!(rollback_heights(&db), vec![2, 3]);
+    }
 
-        let itered_keys: Vec<Key> = db
-            .iter_prefix(None)
-            .map(|(key, _val, _)| Key::parse(key).unwrap())
+    /// Test writing a batch larger than the chunk size in chunks
+    #[test]
+    fn test_chunked_batch() {
+        let dir = tempdir().unwrap();
+        let db = open(dir.path(), false, None).unwrap();
+        let height = BlockHeight(1);
+        let keys: Vec<Key> = (0..10_u8)
+            .map(|i| Key::parse(format!("key{i}")).unwrap())
             .collect();
-        itertools::assert_equal(all_keys, itered_keys);
+
+        let mut batch = db.chunked_batch(4);
+        for (i, key) in (0_u8..).zip(&keys) {
+            db.batch_write_subspace_val(
+                batch.batch().unwrap(),
+                height,
+                key,
+                [i],
+                false,
+            )
+            .unwrap();
+        }
+        // The first chunks are already written
+        assert!(batch.written_chunks() > 0);
+        assert_eq!(db.read_subspace_val(&keys[0]).unwrap(), Some(vec![0]));
+        // The last one isn't yet
+        assert_eq!(db.read_subspace_val(&keys[9]).unwrap(), None);
+
+        let (written_chunks, size_diff) = batch.finish().unwrap();
+        assert!(written_chunks > 1);
+        assert_eq!(size_diff, 10);
+        for (i, key) in (0_u8..).zip(&keys) {
+            assert_eq!(db.read_subspace_val(key).unwrap(), Some(vec![i]));
+        }
     }
 
+    /// Test that an empty value is never conflated with a deleted one, in the
+    /// latest and historical reads, in the diffs and in a rollback
     #[test]
-    fn test_rollback() {
+    fn test_empty_value_vs_delete() {
         for persist_diffs in [true, false] {
             println!("Running with persist_diffs: {persist_diffs}");
 
             let dir = tempdir().unwrap();
             let mut db = RocksDB::open(dir.path(), None);
-
-            // A key that's gonna be added on a second block
-            let add_key = Key::parse("add").unwrap();
-            // A key that's gonna be deleted on a second block
+            // A key with an empty value that's gonna be overwritten
+            let empty_key = Key::parse("empty").unwrap();
+            // A key that's gonna be overwritten with an empty value
+            let to_empty_key = Key::parse("to_empty").unwrap();
+            // A key with an empty value that's gonna be deleted
             let delete_key = Key::parse("delete").unwrap();
-            // A key that's gonna be overwritten on a second block
-            let overwrite_key = Key::parse("overwrite").unwrap();
+            // A key that's gonna be added with an empty value
+            let add_key = Key::parse("add").unwrap();
 
             // Write first block
             let mut batch = RocksDB::batch();
             let height_0 = BlockHeight(100);
             let mut pred_epochs = Epochs::default();
             pred_epochs.new_epoch(height_0);
-            let conversion_state_0 = ConversionState::default();
-            let to_delete_val = vec![1_u8, 1, 0, 0];
-            let to_overwrite_val = vec![1_u8, 1, 1, 0];
-            db.batch_write_subspace_val(
-                &mut batch,
-                height_0,
-                &delete_key,
-                &to_delete_val,
-                persist_diffs,
-            )
-            .unwrap();
-            db.batch_write_subspace_val(
-                &mut batch,
-                height_0,
-                &overwrite_key,
-                &to_overwrite_val,
-                persist_diffs,
-            )
-            .unwrap();
-            for tx in [b"tx1", b"tx2"] {
-                db.write_replay_protection_entry(
-                    &mut batch,
-                    &replay_protection::key(&Hash::sha256(tx)),
-                )
-                .unwrap();
-            }
-
-            for tx in [b"tx3", b"tx4"] {
-                db.write_replay_protection_entry(
+            for (key, val) in [
+                (&empty_key, vec![]),
+                (&to_empty_key, vec![1_u8]),
+                (&delete_key, vec![]),
+            ] {
+                db.batch_write_subspace_val(
                     &mut batch,
-                    &replay_protection::current_key(&Hash::sha256(tx)),
+                    height_0,
+                    key,
+                    val,
+                    persist_diffs,
                 )
                 .unwrap();
             }
-
             add_block_to_batch(
                 &db,
                 &mut batch,
                 height_0,
                 Epoch(1),
                 pred_epochs.clone(),
-                &conversion_state_0,
+                &ConversionState::default(),
             )
             .unwrap();
             db.exec_batch(batch).unwrap();
@@ -2396,25 +9843,20 @@ mod test {
             let mut batch = RocksDB::batch();
             let height_1 = BlockHeight(101);
             pred_epochs.new_epoch(height_1);
-            let conversion_state_1 = ConversionState::default();
-            let add_val = vec![1_u8, 0, 0, 0];
-            let overwrite_val = vec![1_u8, 1, 1, 1];
-            db.batch_write_subspace_val(
-                &mut batch,
-                height_1,
-                &add_key,
-                &add_val,
-                persist_diffs,
-            )
-            .unwrap();
-            db.batch_write_subspace_val(
-                &mut batch,
-                height_1,
-                &overwrite_key,
-                &overwrite_val,
-                persist_diffs,
-            )
-            .unwrap();
+            for (key, val) in [
+                (&empty_key, vec![2_u8]),
+                (&to_empty_key, vec![]),
+                (&add_key, vec![]),
+            ] {
+                db.batch_write_subspace_val(
+                    &mut batch,
+                    height_1,
+                    key,
+                    val,
+                    persist_diffs,
+                )
+                .unwrap();
+            }
             db.batch_delete_subspace_val(
                 &mut batch,
                 height_1,
@@ -2422,229 +9864,686 @@ mod test {
                 persist_diffs,
             )
             .unwrap();
-
-            db.move_current_replay_protection_entries(&mut batch)
-                .unwrap();
-
-            for tx in [b"tx5", b"tx6"] {
-                db.write_replay_protection_entry(
-                    &mut batch,
-                    &replay_protection::current_key(&Hash::sha256(tx)),
-                )
-                .unwrap();
-            }
-
             add_block_to_batch(
                 &db,
                 &mut batch,
                 height_1,
                 Epoch(2),
                 pred_epochs,
-                &conversion_state_1,
+                &ConversionState::default(),
             )
             .unwrap();
             db.exec_batch(batch).unwrap();
 
-            // Check that the values are as expected from second block
-            let added = db.read_subspace_val(&add_key).unwrap();
-            assert_eq!(added, Some(add_val));
-            let overwritten = db.read_subspace_val(&overwrite_key).unwrap();
-            assert_eq!(overwritten, Some(overwrite_val));
-            let deleted = db.read_subspace_val(&delete_key).unwrap();
-            assert_eq!(deleted, None);
-
-            for tx in [b"tx1", b"tx2", b"tx3", b"tx4", b"tx5", b"tx6"] {
-                assert!(
-                    db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
+            assert_eq!(
+                db.read_subspace_val(&empty_key).unwrap(),
+                Some(vec![2])
+            );
+            assert_eq!(
+                db.read_subspace_val(&to_empty_key).unwrap(),
+                Some(vec![])
+            );
+            assert_eq!(db.read_subspace_val(&delete_key).unwrap(), None);
+            assert_eq!(db.read_subspace_val(&add_key).unwrap(), Some(vec![]));
+
+            if persist_diffs {
+                // The values at the first block
+                let read_at_height_0 = |key| {
+                    db.read_subspace_val_with_height(key, height_0, height_1)
+                        .unwrap()
+                };
+                assert_eq!(read_at_height_0(&empty_key), Some(vec![]));
+                assert_eq!(read_at_height_0(&to_empty_key), Some(vec![1]));
+                assert_eq!(read_at_height_0(&delete_key), Some(vec![]));
+                assert_eq!(read_at_height_0(&add_key), None);
+                assert_eq!(
+                    db.changed_keys(height_1).unwrap(),
+                    vec![
+                        (add_key.clone(), ChangeKind::Created),
+                        (delete_key.clone(), ChangeKind::Deleted),
+                        (empty_key.clone(), ChangeKind::Updated),
+                        (to_empty_key.clone(), ChangeKind::Updated),
+                    ]
                 );
             }
 
             // Rollback to the first block height
             db.rollback(height_0).unwrap();
 
-            // Check that the values are back to the state at the first block
-            let added = db.read_subspace_val(&add_key).unwrap();
-            assert_eq!(added, None);
-            let overwritten = db.read_subspace_val(&overwrite_key).unwrap();
-            assert_eq!(overwritten, Some(to_overwrite_val));
-            let deleted = db.read_subspace_val(&delete_key).unwrap();
-            assert_eq!(deleted, Some(to_delete_val));
-            // Check the conversion state
-            let state_cf = db.get_column_family(STATE_CF).unwrap();
-            let conversion_state = db
-                .inner
-                .get_cf(state_cf, "conversion_state".as_bytes())
-                .unwrap()
-                .unwrap();
-            assert_eq!(conversion_state, encode(&conversion_state_0));
-            for tx in [b"tx1", b"tx2", b"tx3", b"tx4"] {
-                assert!(
-                    db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
-                );
-            }
-
-            for tx in [b"tx5", b"tx6"] {
-                assert!(
-                    !db.has_replay_protection_entry(&Hash::sha256(tx)).unwrap()
-                );
-            }
+            assert_eq!(db.read_subspace_val(&empty_key).unwrap(), Some(vec![]));
+            assert_eq!(
+                db.read_subspace_val(&to_empty_key).unwrap(),
+                Some(vec![1])
+            );
+            assert_eq!(
+                db.read_subspace_val(&delete_key).unwrap(),
+                Some(vec![])
+            );
+            assert_eq!(db.read_subspace_val(&add_key).unwrap(), None);
         }
     }
 
+    /// Test that a corrupt conversion state is detected and recovered from
+    /// its predecessor
     #[test]
-    fn test_diffs() {
+    fn test_corrupt_conversion_state() {
         let dir = tempdir().unwrap();
         let mut db = RocksDB::open(dir.path(), None);
+        // No block has been written yet
+        assert!(db.read_last_block().unwrap().is_none());
 
-        let key_with_diffs = Key::parse("with_diffs").unwrap();
-        let key_without_diffs = Key::parse("without_diffs").unwrap();
+        commit_blocks(&db, [1, 2].map(BlockHeight));
 
-        let initial_val = vec![1_u8, 1, 0, 0];
-        let overwrite_val = vec![1_u8, 1, 1, 0];
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        db.inner
+            .put_cf(state_cf, CONVERSION_STATE_KEY, [0xff; 3])
+            .unwrap();
+        match db.read_last_block() {
+            Err(Error::CorruptConversionState { height }) => {
+                assert_eq!(height, BlockHeight(2))
+            }
+            _ => panic!("expected a corrupt conversion state error"),
+        }
 
-        // Write first block
+        db.reconstruct_conversion_state_from_pred().unwrap();
+        let last_block = db.read_last_block().unwrap().unwrap();
+        assert_eq!(last_block.height, BlockHeight(2));
+        assert_eq!(
+            last_block.conversion_state.serialize_to_vec(),
+            ConversionState::default().serialize_to_vec()
+        );
+    }
+
+    /// Test reading only the conversion state of the last block
+    #[test]
+    fn test_conversion_state() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+        assert!(db.conversion_state().unwrap().is_none());
+
+        let asset_type = AssetType::new(b"asset").unwrap();
+        let conversion = I128Sum::from_pair(asset_type, 2);
+        let mut conversion_state = ConversionState::default();
+        conversion_state.assets.insert(
+            asset_type,
+            ConversionLeaf {
+                token: Address::Internal(InternalAddress::Masp),
+                denom: Denomination(6),
+                digit_pos: MaspDigitPos::Zero,
+                epoch: MaspEpoch::new(0),
+                conversion: conversion.clone().into(),
+                leaf_pos: 0,
+            },
+        );
         let mut batch = RocksDB::batch();
-        let height_0 = BlockHeight::first();
-        db.batch_write_subspace_val(
+        add_block_to_batch(
+            &db,
             &mut batch,
-            height_0,
-            &key_with_diffs,
-            &initial_val,
-            true,
+            BlockHeight(1),
+            Epoch::default(),
+            Epochs::default(),
+            &conversion_state,
         )
         .unwrap();
-        db.batch_write_subspace_val(
+        db.exec_batch(batch).unwrap();
+
+        let read = db.conversion_state().unwrap().unwrap();
+        let conversions: Vec<_> = read
+            .allowed_conversions()
+            .map(|(asset_type, conversion)| {
+                (*asset_type, I128Sum::from(conversion.clone()))
+            })
+            .collect();
+        assert_eq!(conversions, vec![(asset_type, conversion)]);
+
+        let state_cf = db.get_column_family(STATE_CF).unwrap();
+        db.inner
+            .put_cf(state_cf, CONVERSION_STATE_KEY, [0xff; 3])
+            .unwrap();
+        assert!(matches!(
+            db.conversion_state(),
+            Err(Error::CorruptConversionState { height })
+                if height == BlockHeight(1)
+        ));
+    }
+
+    /// Test that operator annotations persist and that DBs created before
+    /// the annotations existed get them on open
+    #[test]
+    fn test_annotations() {
+        let dir = tempdir().unwrap();
+        // A DB with the layout preceding the annotations
+        {
+            let mut opts = Options::default();
+            opts.create_if_missing(true);
+            opts.create_missing_column_families(true);
+            rocksdb::DB::open_cf(&opts, dir.path(), DbColFam::all()).unwrap();
+        }
+
+        // The annotations cannot be created in a read-only DB
+        {
+            let db = open(dir.path(), true, None).unwrap();
+            assert_eq!(db.schema_version().unwrap(), None);
+            assert_eq!(db.get_annotation("snapshot").unwrap(), None);
+        }
+
+        {
+            let db = open(dir.path(), false, None).unwrap();
+            assert_eq!(db.schema_version().unwrap(), Some(DB_SCHEMA_VERSION));
+            db.set_annotation("snapshot", b"height 42").unwrap();
+            assert!(db.set_annotation(DB_SCHEMA_VERSION_KEY, b"0").is_err());
+        }
+
+        let db = open(dir.path(), true, None).unwrap();
+        assert_eq!(db.schema_version().unwrap(), Some(DB_SCHEMA_VERSION));
+        assert_eq!(
+            db.get_annotation("snapshot").unwrap(),
+            Some(b"height 42".to_vec())
+        );
+        assert_eq!(db.get_annotation("upgrade").unwrap(), None);
+    }
+
+    /// Test that the space of the pruned entries is reclaimed
+    #[test]
+    fn test_prune_and_compact() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+        let key = Key::parse("key").unwrap();
+
+        for height in 1..=50 {
+            let height = BlockHeight(height);
+            let mut batch = RocksDB::batch();
+            let val = vec![u8::try_from(height.0).unwrap(); 1024];
+            db.batch_write_subspace_val(&mut batch, height, &key, val, true)
+                .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            keep_diffs_blocks: 1,
+            keep_merkle_epochs: 1,
+            keep_headers_blocks: 1,
+        };
+        let report = db
+            .prune_and_compact(BlockHeight(50), Epoch(0), &policy)
+            .unwrap();
+        // Every height but the first one has an old and a new diff
+        assert_eq!(report.pruned.diffs, 97);
+        assert!(report.before > 0);
+        assert!(report.after < report.before);
+        assert_eq!(report.reclaimed(), report.before.abs_diff(report.after));
+    }
+
+    /// Test reading the time of a block at a given height
+    #[test]
+    fn test_block_time() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        commit_blocks(&db, [BlockHeight(1)]);
+
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        let time_key =
+            format!("{}/{BLOCK_TIME_KEY_SEGMENT}", BlockHeight(1).raw());
+        let time: DateTimeUtc =
+            db.read_value(block_cf, &time_key).unwrap().unwrap();
+        assert_eq!(db.block_time(BlockHeight(1)).unwrap(), Some(time));
+        assert_eq!(db.block_time(BlockHeight(2)).unwrap(), None);
+
+        // The time of a pruned block is not available
+        db.inner.delete_cf(block_cf, time_key).unwrap();
+        assert_eq!(db.block_time(BlockHeight(1)).unwrap(), None);
+    }
+
+    /// Test reading the address generator of a block at a given height
+    #[test]
+    fn test_address_gen_at() {
+        let dir = tempdir().unwrap();
+        let db = RocksDB::open(dir.path(), None);
+
+        commit_blocks(&db, [BlockHeight(1)]);
+
+        assert_eq!(
+            db.address_gen_at(BlockHeight(1)).unwrap(),
+            Some(EstablishedAddressGen::new("whatever"))
+        );
+        assert_eq!(db.address_gen_at(BlockHeight(2)).unwrap(), None);
+
+        // The address generator of a pruned block is not available
+        let block_cf = db.get_column_family(BLOCK_CF).unwrap();
+        let address_gen_key =
+            format!("{}/{ADDRESS_GEN_KEY_SEGMENT}", BlockHeight(1).raw());
+        db.inner.delete_cf(block_cf, address_gen_key).unwrap();
+        assert_eq!(db.address_gen_at(BlockHeight(1)).unwrap(), None);
+    }
+
+    /// Test writing and deleting replay protection entries in bulk
+    #[test]
+    fn test_bulk_replay_protection_entries() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let hashes = [Hash::sha256(b"tx_1"), Hash::sha256(b"tx_2")];
+        let keys = hashes.map(|hash| replay_protection::key(&hash));
+        let mut batch = RocksDB::batch();
+        db.write_replay_protection_entries(&mut batch, &keys)
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+        for hash in &hashes {
+            assert!(db.has_replay_protection_entry(hash).unwrap());
+        }
+
+        let mut batch = RocksDB::batch();
+        db.delete_replay_protection_entries(&mut batch, &keys[..1])
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+        assert!(!db.has_replay_protection_entry(&hashes[0]).unwrap());
+        assert!(db.has_replay_protection_entry(&hashes[1]).unwrap());
+    }
+
+    /// Test looking up replay protection entries that have been flushed to
+    /// the tables, which are filtered by the bloom filter
+    #[test]
+    fn test_flushed_replay_protection_lookup() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        let keys: Vec<Key> = (0..100_u8)
+            .map(|i| replay_protection::key(&Hash::sha256([i])))
+            .collect();
+        let current_hash = Hash::sha256(b"current");
+        let mut batch = RocksDB::batch();
+        db.write_replay_protection_entries(&mut batch, &keys)
+            .unwrap();
+        db.write_replay_protection_entry(
             &mut batch,
-            height_0,
-            &key_without_diffs,
-            &initial_val,
-            false,
+            &replay_protection::current_key(&current_hash),
         )
         .unwrap();
         db.exec_batch(batch).unwrap();
+        db.flush(true).unwrap();
+
+        for i in 0..100_u8 {
+            let hash = Hash::sha256([i]);
+            assert!(db.has_replay_protection_entry(&hash).unwrap());
+        }
+        assert!(db.has_replay_protection_entry(&current_hash).unwrap());
+        for i in 100..200_u8 {
+            assert!(
+                !db.has_replay_protection_entry(&Hash::sha256([i])).unwrap()
+            );
+        }
+    }
+
+    /// Test listing the epochs whose merkle tree stores are retained
+    #[test]
+    fn test_retained_merkle_epochs() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
+
+        // Epoch 0 starts at height 1, epoch 1 at height 3 and epoch 2 at
+        // height 5
+        let mut pred_epochs = Epochs::default();
+        pred_epochs.new_epoch(BlockHeight(1));
+        for (height, epoch) in [(1, 0), (2, 0), (3, 1), (4, 1), (5, 2)] {
+            if height == 3 || height == 5 {
+                pred_epochs.new_epoch(BlockHeight(height));
+            }
+            let mut batch = RocksDB::batch();
+            add_block_to_batch(
+                &db,
+                &mut batch,
+                BlockHeight(height),
+                Epoch(epoch),
+                pred_epochs.clone(),
+                &ConversionState::default(),
+            )
+            .unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+
+        let mut batch = RocksDB::batch();
+        db.prune_merkle_tree_store(&mut batch, &StoreType::Account, Epoch(0))
+            .unwrap();
+        db.exec_batch(batch).unwrap();
+
+        assert_eq!(
+            db.retained_merkle_epochs(&StoreType::Account).unwrap(),
+            vec![Epoch(1), Epoch(2)]
+        );
+        assert_eq!(
+            db.retained_merkle_epochs(&StoreType::Ibc).unwrap(),
+            vec![Epoch(0), Epoch(1), Epoch(2)]
+        );
+        // The base tree is stored at every height
+        assert_eq!(
+            db.retained_merkle_epochs(&StoreType::Base).unwrap(),
+            vec![Epoch(0), Epoch(1), Epoch(2)]
+        );
+    }
 
+    /// Test that the subspace values are encrypted at rest and transparently
+    /// decrypted
+    #[test]
+    fn test_subspace_encryption() {
+        let dir = tempdir().unwrap();
+        let key = Key::parse("key").unwrap();
+        let value = b"secret".to_vec();
+        let cipher = || Some(SubspaceCipher::new([7; 32]));
         {
-            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
-            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+            let mut db =
+                open_with_encryption(dir.path(), false, None, cipher())
+                    .unwrap();
+            db.write_subspace_val(BlockHeight(1), &key, &value, true)
+                .unwrap();
+            db.write_subspace_val(BlockHeight(2), &key, b"", true)
+                .unwrap();
 
-            // Diffs new key for `key_with_diffs` at height_0 must be
-            // present
-            let (old_with_h0, new_with_h0) =
-                old_and_new_diff_key(&key_with_diffs, height_0).unwrap();
-            assert!(db.inner.get_cf(diffs_cf, old_with_h0).unwrap().is_none());
-            assert!(db.inner.get_cf(diffs_cf, new_with_h0).unwrap().is_some());
+            // The stored bytes are not the plaintext
+            let subspace_cf = db.get_column_family(SUBSPACE_CF).unwrap();
+            let stored = db
+                .read_value_bytes(subspace_cf, key.to_string())
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.len(), SUBSPACE_NONCE_LEN);
+            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
+            let (_, new_val_key) =
+                old_and_new_diff_key(&key, BlockHeight(1)).unwrap();
+            let stored =
+                db.read_value_bytes(diffs_cf, new_val_key).unwrap().unwrap();
+            assert_ne!(stored[SUBSPACE_NONCE_LEN..], value[..]);
+
+            // The values are decrypted when read
+            assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![]));
+            assert_eq!(
+                db.read_diffs_val(&key, BlockHeight(2), true).unwrap(),
+                Some(value.clone())
+            );
+            assert_eq!(
+                db.read_subspace_val_with_height(
+                    &key,
+                    BlockHeight(1),
+                    BlockHeight(2)
+                )
+                .unwrap(),
+                Some(value.clone())
+            );
+            let (_, iterated, gas) = db.iter_prefix(None).next().unwrap();
+            assert!(iterated.is_empty());
+            assert_eq!(gas, 3);
 
-            // Diffs new key for `key_without_diffs` at height_0 must be
-            // present
-            let (old_wo_h0, new_wo_h0) =
-                old_and_new_diff_key(&key_without_diffs, height_0).unwrap();
-            assert!(db.inner.get_cf(rollback_cf, old_wo_h0).unwrap().is_none());
-            assert!(db.inner.get_cf(rollback_cf, new_wo_h0).unwrap().is_some());
+            // A value too short to be decrypted is reported by the iteration
+            db.inner
+                .put_cf(subspace_cf, key.to_string(), [0_u8; 4])
+                .unwrap();
+            assert!(matches!(
+                db.iter_prefix(None).checked().next(),
+                Some(Err(Error::CorruptValue { key: corrupt }))
+                    if corrupt == key.to_string()
+            ));
+            db.write_subspace_val(BlockHeight(3), &key, b"", true)
+                .unwrap();
         }
 
-        // Write second block
-        let mut batch = RocksDB::batch();
-        let height_1 = height_0 + 10;
-        db.batch_write_subspace_val(
-            &mut batch,
-            height_1,
-            &key_with_diffs,
-            &overwrite_val,
-            true,
-        )
-        .unwrap();
-        db.batch_write_subspace_val(
-            &mut batch,
-            height_1,
-            &key_without_diffs,
-            &overwrite_val,
-            false,
-        )
-        .unwrap();
-        db.prune_non_persisted_diffs(&mut batch, height_0).unwrap();
-        db.exec_batch(batch).unwrap();
+        // The DB cannot be opened without the key or with another one
+        assert!(open(dir.path(), false, None).is_err());
+        assert!(
+            open_with_encryption(
+                dir.path(),
+                true,
+                None,
+                Some(SubspaceCipher::new([8; 32]))
+            )
+            .is_err()
+        );
+        let db =
+            open_with_encryption(dir.path(), true, None, cipher()).unwrap();
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![]));
 
+        // The encryption cannot be enabled for an existing unencrypted DB
+        let dir = tempdir().unwrap();
         {
-            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
-            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+            let mut db = open(dir.path(), false, None).unwrap();
+            db.write_subspace_val(BlockHeight(1), &key, &value, true)
+                .unwrap();
+        }
+        assert!(
+            open_with_encryption(dir.path(), false, None, cipher()).is_err()
+        );
+    }
 
-            // Diffs keys for `key_with_diffs` at height_0 must be present
-            let (old_with_h0, new_with_h0) =
-                old_and_new_diff_key(&key_with_diffs, height_0).unwrap();
-            assert!(db.inner.get_cf(diffs_cf, old_with_h0).unwrap().is_none());
-            assert!(db.inner.get_cf(diffs_cf, new_with_h0).unwrap().is_some());
+    /// Test that the atomic flush can only be disabled for a read-only DB
+    #[test]
+    fn test_open_without_atomic_flush() {
+        let dir = tempdir().unwrap();
+        let key = Key::parse("key").unwrap();
+        let options = |read_only| OpenOptions {
+            read_only,
+            atomic_flush: false,
+            ..OpenOptions::default()
+        };
+        assert!(
+            open_with_options(dir.path(), options(false), None, None).is_err()
+        );
+        {
+            let mut db = open_with_options(
+                dir.path(),
+                OpenOptions::default(),
+                None,
+                None,
+            )
+            .unwrap();
+            db.write_subspace_val(BlockHeight(1), &key, [1_u8], true)
+                .unwrap();
+        }
+        let db =
+            open_with_options(dir.path(), options(true), None, None).unwrap();
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![1_u8]));
+    }
 
-            // Diffs keys for `key_without_diffs` at height_0 must be gone
-            let (old_wo_h0, new_wo_h0) =
-                old_and_new_diff_key(&key_without_diffs, height_0).unwrap();
-            assert!(db.inner.get_cf(rollback_cf, old_wo_h0).unwrap().is_none());
-            assert!(db.inner.get_cf(rollback_cf, new_wo_h0).unwrap().is_none());
+    /// Test that failing to acquire the lock of the DB is reported as such
+    #[test]
+    fn test_open_locked() {
+        let path = Path::new("/tmp/db");
+        for error in [
+            "IO error: While lock file: /tmp/db/LOCK: Resource temporarily \
+             unavailable",
+            "IO error: lock hold by current process, acquire time 1700000000 \
+             acquiring thread 140000000000000: /tmp/db/LOCK: No locks \
+             available",
+        ] {
+            assert!(matches!(
+                open_error(path, error.to_string()),
+                Error::DbLocked { path } if path == "/tmp/db"
+            ));
+        }
+        assert!(matches!(
+            open_error(path, "Corruption: bad block".to_string()),
+            Error::DBError(_)
+        ));
 
-            // Diffs keys for `key_with_diffs` at height_1 must be present
-            let (old_with_h1, new_with_h1) =
-                old_and_new_diff_key(&key_with_diffs, height_1).unwrap();
-            assert!(db.inner.get_cf(diffs_cf, old_with_h1).unwrap().is_some());
-            assert!(db.inner.get_cf(diffs_cf, new_with_h1).unwrap().is_some());
+        // The lock of an open DB is held until it's dropped
+        let dir = tempdir().unwrap();
+        let db = open(dir.path(), false, None).unwrap();
+        assert!(matches!(
+            open(dir.path(), false, None),
+            Err(Error::DbLocked { .. })
+        ));
+        let options = OpenOptions {
+            lock_timeout: Some(Duration::from_millis(300)),
+            ..OpenOptions::default()
+        };
+        let start = Instant::now();
+        assert!(matches!(
+            open_with_options(dir.path(), options.clone(), None, None),
+            Err(Error::DbLocked { .. })
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        // A read-only DB doesn't need the lock
+        assert!(open(dir.path(), true, None).is_ok());
+        drop(db);
+        assert!(open_with_options(dir.path(), options, None, None).is_ok());
+    }
 
-            // Diffs keys for `key_without_diffs` at height_1 must be
-            // present
-            let (old_wo_h1, new_wo_h1) =
-                old_and_new_diff_key(&key_without_diffs, height_1).unwrap();
-            assert!(db.inner.get_cf(rollback_cf, old_wo_h1).unwrap().is_some());
-            assert!(db.inner.get_cf(rollback_cf, new_wo_h1).unwrap().is_some());
+    /// Test that the full disk and the transient errors of the batch writes
+    /// are told apart from the other errors
+    #[test]
+    fn test_write_error() {
+        for error in [
+            "IO error: No space left on deviceWhile appending to file: \
+             /tmp/db/000012.log: No space left on device",
+            "IO error: Disk quota exceeded",
+        ] {
+            assert!(matches!(
+                write_error(error.to_string()),
+                Error::StorageFull { .. }
+            ));
+        }
+        for error in [
+            "Resource busy: ",
+            "Operation timed out: ",
+            "Operation failed. Try again.: ",
+        ] {
+            assert!(matches!(
+                write_error(error.to_string()),
+                Error::IoTransient { .. }
+            ));
+        }
+        for error in [
+            "Corruption: block checksum mismatch",
+            "IO error: While appending to file: /tmp/db/000012.log: \
+             Input/output error",
+            "IO error: While appending to file: /tmp/db/000012.log: \
+             Interrupted system call",
+            "Invalid argument: Column family not found",
+        ] {
+            assert!(matches!(
+                write_error(error.to_string()),
+                Error::DBError(_)
+            ));
         }
+    }
 
-        // Write third block
-        let mut batch = RocksDB::batch();
-        let height_2 = height_1 + 10;
-        db.batch_write_subspace_val(
-            &mut batch,
-            height_2,
-            &key_with_diffs,
-            &initial_val,
-            true,
-        )
-        .unwrap();
-        db.batch_write_subspace_val(
-            &mut batch,
-            height_2,
-            &key_without_diffs,
-            &initial_val,
-            false,
-        )
-        .unwrap();
-        db.prune_non_persisted_diffs(&mut batch, height_1).unwrap();
-        db.exec_batch(batch).unwrap();
+    /// Test committing blocks in each durability mode, and that the
+    /// memtables are flushed at the interval of the periodic mode
+    #[test]
+    fn test_durability_mode() {
+        let key = Key::parse("key").unwrap();
+        for durability in [
+            DurabilityMode::Sync,
+            DurabilityMode::WalDefault,
+            DurabilityMode::Periodic {
+                every: Duration::ZERO,
+            },
+            DurabilityMode::Periodic {
+                every: Duration::from_secs(3600),
+            },
+        ] {
+            let dir = tempdir().unwrap();
+            let options = OpenOptions {
+                durability,
+                ..OpenOptions::default()
+            };
+            let db = open_with_options(dir.path(), options.clone(), None, None)
+                .unwrap();
+            let opened_at = *db.last_flush.lock().unwrap();
+            let mut batch = RocksDB::batch();
+            db.batch_write_subspace_val(
+                &mut batch,
+                BlockHeight(1),
+                &key,
+                [1_u8],
+                true,
+            )
+            .unwrap();
+            TestBlock::new(BlockHeight(1))
+                .add_to_batch(&db, &mut batch)
+                .unwrap();
+            db.exec_batch(batch).unwrap();
+            let flushed = *db.last_flush.lock().unwrap() > opened_at;
+            assert_eq!(
+                flushed,
+                durability
+                    == DurabilityMode::Periodic {
+                        every: Duration::ZERO
+                    }
+            );
+            // The writes are flushed when the DB is closed
+            drop(db);
+            let db =
+                open_with_options(dir.path(), options, None, None).unwrap();
+            assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![1_u8]));
+            assert_eq!(
+                db.last_committed_height().unwrap(),
+                Some(BlockHeight(1))
+            );
+        }
+    }
 
-        {
-            let diffs_cf = db.get_column_family(DIFFS_CF).unwrap();
-            let rollback_cf = db.get_column_family(ROLLBACK_CF).unwrap();
+    /// Test changing the number of compaction threads of an open DB
+    #[test]
+    fn test_set_compaction_threads() {
+        let dir = tempdir().unwrap();
+        let mut db = RocksDB::open(dir.path(), None);
 
-            // Diffs keys for `key_with_diffs` at height_1 must be present
-            let (old_with_h1, new_with_h1) =
-                old_and_new_diff_key(&key_with_diffs, height_1).unwrap();
-            assert!(db.inner.get_cf(diffs_cf, old_with_h1).unwrap().is_some());
-            assert!(db.inner.get_cf(diffs_cf, new_with_h1).unwrap().is_some());
+        db.set_compaction_threads(4).unwrap();
+        db.set_compaction_threads(1).unwrap();
+        assert!(db.set_compaction_threads(0).is_err());
+        assert!(db.set_compaction_threads(-1).is_err());
 
-            // Diffs keys for `key_without_diffs` at height_1 must be gone
-            let (old_wo_h1, new_wo_h1) =
-                old_and_new_diff_key(&key_without_diffs, height_1).unwrap();
-            assert!(db.inner.get_cf(rollback_cf, old_wo_h1).unwrap().is_none());
-            assert!(db.inner.get_cf(rollback_cf, new_wo_h1).unwrap().is_none());
+        // The DB is still usable after the change
+        let key = Key::parse("key").unwrap();
+        db.write_subspace_val(BlockHeight(1), &key, [1_u8], false)
+            .unwrap();
+        assert_eq!(db.read_subspace_val(&key).unwrap(), Some(vec![1_u8]));
+    }
 
-            // Diffs keys for `key_with_diffs` at height_2 must be present
-            let (old_with_h2, new_with_h2) =
-                old_and_new_diff_key(&key_with_diffs, height_2).unwrap();
-            assert!(db.inner.get_cf(diffs_cf, old_with_h2).unwrap().is_some());
-            assert!(db.inner.get_cf(diffs_cf, new_with_h2).unwrap().is_some());
+    /// Test that a DB missing a column family reports it by name
+    #[test]
+    fn test_missing_column_family() {
+        let dir = tempdir().unwrap();
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        let inner =
+            rocksdb::DB::open_cf(&opts, dir.path(), [SUBSPACE_CF, STATE_CF])
+                .unwrap();
+        let mut db = RocksDB {
+            inner,
+            read_only: false,
+            state_integrity_checks: false,
+            allow_non_contiguous_blocks: false,
+            archive: false,
+            check_merkle_store_overwrites: false,
+            merkle_store_overwrites: Default::default(),
+            change_sink: None,
+            durability: DurabilityMode::default(),
+            last_flush: Mutex::new(Instant::now()),
+            bg_status: Default::default(),
+            metrics: Default::default(),
+            subspace_cipher: None,
+            write_lock: Default::default(),
+            write_lock_owner: Default::default(),
+            rollback_tip: Default::default(),
+        };
 
-            // Diffs keys for `key_without_diffs` at height_2 must be
-            // present
-            let (old_wo_h2, new_wo_h2) =
-                old_and_new_diff_key(&key_without_diffs, height_2).unwrap();
-            assert!(db.inner.get_cf(rollback_cf, old_wo_h2).unwrap().is_some());
-            assert!(db.inner.get_cf(rollback_cf, new_wo_h2).unwrap().is_some());
+        assert!(db.get_column_family(SUBSPACE_CF).is_ok());
+        match db.get_column_family(DIFFS_CF) {
+            Err(Error::MissingColumnFamily { name }) => {
+                assert_eq!(name, DIFFS_CF)
+            }
+            _ => panic!("expected a missing column family error"),
         }
+
+        // Writing a value with its diffs needs the diffs column family
+        let key = Key::parse("key").unwrap();
+        let err = db
+            .write_subspace_val(BlockHeight(1), &key, [1_u8], true)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("The column family {DIFFS_CF} is missing from the DB")
+        );
     }
 
     /// A test helper to write a block
@@ -2656,37 +10555,143 @@ mod test {
         pred_epochs: Epochs,
         conversion_state: &ConversionState,
     ) -> Result<()> {
-        let merkle_tree = MerkleTree::<Sha256Hasher>::default();
-        let merkle_tree_stores = merkle_tree.stores();
-        #[allow(clippy::disallowed_methods)]
-        let time = DateTimeUtc::now();
-        let next_epoch_min_start_height = BlockHeight::default();
-        #[allow(clippy::disallowed_methods)]
-        let next_epoch_min_start_time = DateTimeUtc::now();
-        let update_epoch_blocks_delay = None;
-        let address_gen = EstablishedAddressGen::new("whatever");
-        let results = BlockResults::default();
-        let eth_events_queue = EthEventsQueue::default();
-        let commit_only_data = CommitOnlyData::default();
-        let block = BlockStateWrite {
-            merkle_tree_stores,
-            header: None,
-            height,
-            time,
-            epoch,
-            results: &results,
-            conversion_state,
-            pred_epochs: &pred_epochs,
-            next_epoch_min_start_height,
-            next_epoch_min_start_time,
-            update_epoch_blocks_delay,
-            address_gen: &address_gen,
-            ethereum_height: None,
-            eth_events_queue: &eth_events_queue,
-            commit_only_data: &commit_only_data,
-        };
+        TestBlock::new(height)
+            .epoch(epoch, pred_epochs)
+            .conversion_state(conversion_state)
+            .add_to_batch(db, batch)
+    }
+
+    /// A test helper to commit empty blocks at the given heights, each in a
+    /// batch of its own
+    fn commit_blocks(
+        db: &RocksDB,
+        heights: impl IntoIterator<Item = BlockHeight>,
+    ) {
+        for height in heights {
+            let mut batch = RocksDB::batch();
+            TestBlock::new(height).add_to_batch(db, &mut batch).unwrap();
+            db.exec_batch(batch).unwrap();
+        }
+    }
+
+    /// A test block, written with [`TestBlock::add_to_batch`]. Unless set
+    /// otherwise, it's in the first epoch, with an empty merkle tree,
+    /// conversion state and commit-only data, and its txs used no gas.
+    struct TestBlock<'a, H: StorageHasher + Default = Sha256Hasher> {
+        height: BlockHeight,
+        epoch: Epoch,
+        pred_epochs: Epochs,
+        conversion_state: Option<&'a ConversionState>,
+        commit_only_data: CommitOnlyData,
+        gas: u64,
+        merkle_tree: Option<&'a MerkleTree<H>>,
+    }
 
-        db.add_block_to_batch(block, batch, true)
+    impl TestBlock<'_> {
+        fn new(height: BlockHeight) -> Self {
+            Self {
+                height,
+                epoch: Epoch::default(),
+                pred_epochs: Epochs::default(),
+                conversion_state: None,
+                commit_only_data: CommitOnlyData::default(),
+                gas: 0,
+                merkle_tree: None,
+            }
+        }
+    }
+
+    impl<'a, H: StorageHasher + Default> TestBlock<'a, H> {
+        fn epoch(mut self, epoch: Epoch, pred_epochs: Epochs) -> Self {
+            self.epoch = epoch;
+            self.pred_epochs = pred_epochs;
+            self
+        }
+
+        fn conversion_state(
+            mut self,
+            conversion_state: &'a ConversionState,
+        ) -> Self {
+            self.conversion_state = Some(conversion_state);
+            self
+        }
+
+        fn commit_only_data(
+            mut self,
+            commit_only_data: CommitOnlyData,
+        ) -> Self {
+            self.commit_only_data = commit_only_data;
+            self
+        }
+
+        fn gas(mut self, gas: u64) -> Self {
+            self.gas = gas;
+            self
+        }
+
+        /// Set the merkle tree, whose hasher may differ from the default one
+        fn tree<T: StorageHasher + Default>(
+            self,
+            merkle_tree: &'a MerkleTree<T>,
+        ) -> TestBlock<'a, T> {
+            TestBlock {
+                height: self.height,
+                epoch: self.epoch,
+                pred_epochs: self.pred_epochs,
+                conversion_state: self.conversion_state,
+                commit_only_data: self.commit_only_data,
+                gas: self.gas,
+                merkle_tree: Some(merkle_tree),
+            }
+        }
+
+        fn add_to_batch(
+            &self,
+            db: &RocksDB,
+            batch: &mut RocksDBWriteBatch,
+        ) -> Result<()> {
+            let default_tree;
+            let merkle_tree = match self.merkle_tree {
+                Some(merkle_tree) => merkle_tree,
+                None => {
+                    default_tree = MerkleTree::<H>::default();
+                    &default_tree
+                }
+            };
+            let default_conversion_state = ConversionState::default();
+            let merkle_tree_stores = merkle_tree.stores();
+            #[allow(clippy::disallowed_methods)]
+            let time = DateTimeUtc::now();
+            let next_epoch_min_start_height = BlockHeight::default();
+            #[allow(clippy::disallowed_methods)]
+            let next_epoch_min_start_time = DateTimeUtc::now();
+            let update_epoch_blocks_delay = None;
+            let address_gen = EstablishedAddressGen::new("whatever");
+            let results = BlockResults::default();
+            let eth_events_queue = EthEventsQueue::default();
+            let block = BlockStateWrite {
+                merkle_tree_stores,
+                header: None,
+                height: self.height,
+                time,
+                epoch: self.epoch,
+                results: &results,
+                gas: self.gas,
+                conversion_state: self
+                    .conversion_state
+                    .unwrap_or(&default_conversion_state),
+                pred_epochs: &self.pred_epochs,
+                next_epoch_min_start_height,
+                next_epoch_min_start_time,
+                update_epoch_blocks_delay,
+                address_gen: &address_gen,
+                ethereum_height: None,
+                eth_events_queue: &eth_events_queue,
+                commit_only_data: &self.commit_only_data,
+            };
+
+            db.add_block_to_batch(block, batch, true)
+        }
     }
 
     /// Test that we chunk a series of lines
@@ -2866,6 +10871,7 @@ mod test {
                 PrefixIterator::new(iter, String::default()),
                 // Empty string to prevent prefix stripping, the prefix is
                 // already in the enclosed iterator
+                None,
             ) {
                 snapshot_entries.insert(key, raw_val);
             }
@@ -2883,6 +10889,7 @@ mod test {
                 PrefixIterator::new(iter, String::default()),
                 // Empty string to prevent prefix stripping, the prefix is
                 // already in the enclosed iterator
+                None,
             ) {
                 db_entries.insert(key, raw_val);
             }
@@ -2957,6 +10964,141 @@ mod test {
         assert_eq!(metadata, chunk_val);
     }
 
+    /// Test streaming a snapshot to another DB, with an import interrupted
+    /// partway and resumed from its last verified chunk
+    #[test]
+    fn test_stream_snapshot() {
+        let temp = tempfile::tempdir().expect("Test failed");
+        let mut db = open(&temp, false, None).expect("Test failed");
+        let keys: Vec<Key> = (0..3)
+            .map(|i| Key::parse(format!("key{i}")).expect("Test failed"))
+            .collect();
+        for (i, key) in (0_u8..).zip(&keys) {
+            db.write_subspace_val(1.into(), key, [i; 8], false)
+                .expect("Test failed");
+        }
+        drop(db);
+        let db = open(&temp, true, None).expect("Test failed");
+        let snapshot = db.snapshot();
+
+        // Every line is a chunk of its own
+        let mut bytes = vec![];
+        let chunks = snapshot
+            .write_to(db.column_families(), &mut bytes, 1)
+            .expect("Test failed");
+        let line_starts: Vec<usize> = std::iter::once(0)
+            .chain(
+                bytes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, byte)| **byte == b'\n')
+                    .map(|(i, _)| i.checked_add(1).unwrap()),
+            )
+            .collect();
+        assert_eq!(chunks.len(), line_starts.len().checked_sub(1).unwrap());
+        assert!(chunks.len() > 2);
+
+        let target_dir = tempfile::tempdir().expect("Test failed");
+        let target = open(&target_dir, false, None).expect("Test failed");
+
+        let height = BlockHeight(1);
+        let progress = |chunks: &[Chunk]| {
+            target
+                .snapshot_import_progress(height, chunks)
+                .expect("Test failed")
+        };
+
+        // A tampered chunk is rejected
+        let mut tampered = chunks.clone();
+        tampered[0].hash = Hash::zero();
+        assert!(
+            target
+                .import_snapshot(&bytes[..], height, &tampered)
+                .is_err()
+        );
+        assert_eq!(progress(&tampered), 0);
+
+        // The transfer is interrupted within the third chunk
+        let interrupted = &bytes[..line_starts[2].checked_add(1).unwrap()];
+        assert!(target.import_snapshot(interrupted, height, &chunks).is_err());
+        assert_eq!(progress(&chunks), 2);
+        assert!(target.health().snapshot_import_in_progress);
+
+        // The progress is tracked per snapshot and the import of another
+        // snapshot is refused until this one completes
+        assert_eq!(
+            target
+                .snapshot_import_progress(BlockHeight(2), &chunks)
+                .expect("Test failed"),
+            0
+        );
+        assert_eq!(progress(&tampered), 0);
+        assert!(
+            target
+                .import_snapshot(&bytes[..], BlockHeight(2), &chunks)
+                .is_err()
+        );
+        assert_eq!(progress(&chunks), 2);
+
+        // The transfer resumes from the third chunk
+        let imported = target
+            .import_snapshot(&bytes[line_starts[2]..], height, &chunks)
+            .expect("Test failed");
+        assert_eq!(
+            imported,
+            u64::try_from(chunks.len()).unwrap().checked_sub(2).unwrap()
+        );
+        // The progress is deleted once the import completes
+        assert_eq!(progress(&chunks), 0);
+        assert!(!target.health().snapshot_import_in_progress);
+        for (i, key) in (0_u8..).zip(&keys) {
+            assert_eq!(
+                target.read_subspace_val(key).expect("Test failed"),
+                Some(vec![i; 8])
+            );
+        }
+    }
+
+    /// Test that a snapshot round-trips the keys containing a `=`, whatever
+    /// the padding of their base64 value
+    #[test]
+    fn test_snapshot_keys_with_equals() {
+        let temp = tempfile::tempdir().expect("Test failed");
+        let mut db = open(&temp, false, None).expect("Test failed");
+        let entries: Vec<(Key, Vec<u8>)> = [
+            ("a=b", vec![1_u8; 8]),
+            ("c=d=", vec![2_u8; 7]),
+            ("e=", vec![3_u8; 6]),
+            ("f==", vec![]),
+        ]
+        .into_iter()
+        .map(|(key, val)| (Key::parse(key).expect("Test failed"), val))
+        .collect();
+        for (key, val) in &entries {
+            db.write_subspace_val(1.into(), key, val, false)
+                .expect("Test failed");
+        }
+        drop(db);
+        let db = open(&temp, true, None).expect("Test failed");
+        let mut bytes = vec![];
+        let chunks = db
+            .snapshot()
+            .write_to(db.column_families(), &mut bytes, 1)
+            .expect("Test failed");
+
+        let target_dir = tempfile::tempdir().expect("Test failed");
+        let target = open(&target_dir, false, None).expect("Test failed");
+        target
+            .import_snapshot(&bytes[..], BlockHeight(1), &chunks)
+            .expect("Test failed");
+        for (key, val) in &entries {
+            assert_eq!(
+                target.read_subspace_val(key).expect("Test failed"),
+                Some(val.clone())
+            );
+        }
+    }
+
     /// Test that we load chunks correctly
     /// from the snapshot file
     #[test]
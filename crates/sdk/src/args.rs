@@ -14,7 +14,7 @@ use namada_core::keccak::KeccakHash;
 use namada_core::key::{common, SchemeType};
 use namada_core::masp::{MaspEpoch, PaymentAddress};
 use namada_core::storage::{BlockHeight, Epoch};
-use namada_core::time::DateTimeUtc;
+use namada_core::time::{DateTimeUtc, DurationSecs};
 use namada_core::{storage, token};
 use namada_governance::cli::onchain::{
     DefaultProposal, PgfFundingProposal, PgfStewardProposal,
@@ -2378,6 +2378,14 @@ pub trait TxBuilder<C: NamadaTypes>: Sized {
     fn expiration(self, expiration: TxExpiration) -> Self {
         self.tx(|x| Tx { expiration, ..x })
     }
+    /// Expire the transaction after the given duration from now
+    fn expire_in(self, duration: StdDuration) -> Self {
+        #[allow(clippy::disallowed_methods)]
+        let now = DateTimeUtc::now();
+        self.expiration(TxExpiration::Custom(
+            now + DurationSecs::from(duration),
+        ))
+    }
     /// Generate an ephimeral signing key to be used only once to sign a
     /// wrapper tx
     fn disposable_signing_key(self, disposable_signing_key: bool) -> Self {
@@ -2824,3 +2832,54 @@ pub struct GenIbcShieldingTransfer<C: NamadaTypes = SdkTypes> {
     /// Channel ID via which the token is received
     pub channel_id: ChannelId,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Test that the expiration set relative to now is correct
+    #[test]
+    fn test_expire_in() {
+        let args = Tx::<SdkTypes> {
+            dry_run: false,
+            dry_run_wrapper: false,
+            dump_tx: false,
+            output_folder: None,
+            force: false,
+            broadcast_only: false,
+            ledger_address: tendermint_rpc::Url::from_str(
+                "http://127.0.0.1:26657",
+            )
+            .unwrap(),
+            initialized_account_alias: None,
+            wallet_alias_force: false,
+            fee_amount: None,
+            wrapper_fee_payer: None,
+            fee_token: namada_core::address::testing::nam(),
+            gas_limit: GasLimit::from(1),
+            expiration: Default::default(),
+            disposable_signing_key: false,
+            chain_id: None,
+            signing_keys: vec![],
+            signatures: vec![],
+            tx_reveal_code_path: PathBuf::new(),
+            password: None,
+            memo: None,
+            use_device: false,
+        };
+
+        let duration = StdDuration::from_secs(3600);
+        #[allow(clippy::disallowed_methods)]
+        let expected = DateTimeUtc::now() + DurationSecs::from(duration);
+        let args = args.expire_in(duration);
+        let TxExpiration::Custom(expiration) = args.expiration else {
+            panic!("Expected a custom expiration");
+        };
+        let diff = if expiration > expected {
+            expiration.0 - expected.0
+        } else {
+            expected.0 - expiration.0
+        };
+        assert!(diff <= namada_core::time::Duration::seconds(1));
+    }
+}
@@ -318,6 +318,9 @@ pub enum TxSubmitError {
     /// The consensus key is not unique
     #[error("The consensus key has already been registered and is not unique")]
     ConsensusKeyNotUnique,
+    /// The built transaction cannot be submitted
+    #[error("The built transaction is invalid: {0}")]
+    InvalidBuiltTx(String),
     /// Other Errors that may show up when using the interface
     #[error("{0}")]
     Other(String),
@@ -7,6 +7,8 @@ use std::cmp::Ordering;
 use std::collections::{btree_map, BTreeMap, BTreeSet};
 use std::fmt::Debug;
 use std::io::{Read, Write};
+use std::ops::Bound;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use borsh::{BorshDeserialize, BorshSerialize};
@@ -26,12 +28,13 @@ use masp_primitives::merkle_tree::{
 use masp_primitives::sapling::keys::FullViewingKey;
 use masp_primitives::sapling::note_encryption::*;
 use masp_primitives::sapling::{
-    Diversifier, Node, Note, Nullifier, ViewingKey,
+    Diversifier, Node, Note, Nullifier, PaymentAddress, ViewingKey,
 };
 use masp_primitives::transaction::builder::{self, *};
 use masp_primitives::transaction::components::sapling::builder::{
     RngBuildParams, SaplingMetadata,
 };
+use masp_primitives::transaction::components::sapling::CompactOutputDescription;
 use masp_primitives::transaction::components::{
     I128Sum, OutputDescription, TxOut, U64Sum, ValueSum,
 };
@@ -70,6 +73,7 @@ use rand_core::{CryptoRng, OsRng, RngCore, SeedableRng};
 use smooth_operator::checked;
 use thiserror::Error;
 
+use crate::control_flow::time::{Duration, Instant};
 use crate::control_flow::ShutdownSignal;
 use crate::error::{Error, QueryError};
 use crate::io::Io;
@@ -176,6 +180,71 @@ pub struct MaspTokenRewardData {
     pub locked_amount_target: Uint,
 }
 
+/// Read back the MASP reward parameters of the given token from storage.
+///
+/// Returns `None` if none of the parameters are set for the token, and an
+/// error if only some of them are. The token name is taken from the MASP
+/// token map, falling back to the token's address if it is not listed.
+pub fn read_masp_reward_params<S>(
+    storage: &S,
+    token: &Address,
+) -> namada_storage::Result<Option<MaspTokenRewardData>>
+where
+    S: namada_storage::StorageRead,
+{
+    use namada_token::storage_key::{
+        masp_kd_gain_key, masp_kp_gain_key, masp_locked_amount_target_key,
+        masp_max_reward_rate_key, masp_token_map_key,
+    };
+
+    let max_reward_rate: Option<Dec> =
+        storage.read(&masp_max_reward_rate_key(token))?;
+    let kp_gain: Option<Dec> = storage.read(&masp_kp_gain_key(token))?;
+    let kd_gain: Option<Dec> = storage.read(&masp_kd_gain_key(token))?;
+    let locked_amount_target: Option<Uint> =
+        storage.read(&masp_locked_amount_target_key(token))?;
+
+    match (max_reward_rate, kp_gain, kd_gain, locked_amount_target) {
+        (None, None, None, None) => Ok(None),
+        (
+            Some(max_reward_rate),
+            Some(kp_gain),
+            Some(kd_gain),
+            Some(locked_amount_target),
+        ) => {
+            let token_map: TokenMap =
+                storage.read(&masp_token_map_key())?.unwrap_or_default();
+            let name = token_map
+                .into_iter()
+                .find_map(|(name, addr)| (&addr == token).then_some(name))
+                .unwrap_or_else(|| token.to_string());
+            Ok(Some(MaspTokenRewardData {
+                name,
+                address: token.clone(),
+                max_reward_rate,
+                kp_gain,
+                kd_gain,
+                locked_amount_target,
+            }))
+        }
+        (max_reward_rate, kp_gain, kd_gain, locked_amount_target) => {
+            let missing = [
+                ("max reward rate", max_reward_rate.is_none()),
+                ("kp gain", kp_gain.is_none()),
+                ("kd gain", kd_gain.is_none()),
+                ("locked amount target", locked_amount_target.is_none()),
+            ]
+            .into_iter()
+            .filter_map(|(param, missing)| missing.then_some(param))
+            .join(", ");
+            Err(namada_storage::Error::new_alloc(format!(
+                "The MASP reward parameters of token {token} are only \
+                 partially set, missing: {missing}"
+            )))
+        }
+    }
+}
+
 /// A return type for gen_shielded_transfer
 #[allow(clippy::large_enum_variant)]
 #[derive(Error, Debug)]
@@ -363,17 +432,275 @@ pub type TransactionDelta = HashMap<ViewingKey, I128Sum>;
 ///
 /// An invariant that shielded-sync maintains is that
 /// this cache either contains all transactions from
-/// a given height, or none.
+/// a given height, or none. The only exception is the
+/// height being scanned, whose transactions are removed
+/// one by one as they get applied to the shielded context.
+/// Together with the context, the cache thus always
+/// represents a consistent prefix of the chain.
+///
+/// The cache can spill to disk, see [`Unscanned::enable_disk_spill`].
+/// The transactions of a height are then always either all in memory
+/// or all on disk, such that the invariant holds across the spill.
 #[derive(Debug, Default, Clone)]
 pub struct Unscanned {
     txs: Arc<Mutex<IndexedNoteData>>,
+    /// The last index marked as scanned. This is not persisted, it
+    /// only serves to report partially scanned heights.
+    last_scanned: Arc<Mutex<Option<IndexedTx>>>,
+    /// The heights spilled to disk, if enabled. This is not persisted,
+    /// the spilled transactions are serialized with the others.
+    spill: Arc<Mutex<Option<DiskSpill>>>,
+}
+
+/// The heights of the [`Unscanned`] cache spilled to disk, with one
+/// file of Borsh encoded transactions per height
+#[derive(Debug)]
+struct DiskSpill {
+    /// The directory of the spilled heights' files
+    dir: PathBuf,
+    /// The maximum number of heights whose transactions are kept in
+    /// memory
+    max_heights_in_memory: usize,
+    /// The number of transactions of each spilled height
+    heights: BTreeMap<BlockHeight, usize>,
+    /// The first error reading back a spilled height, which is then left
+    /// on disk
+    read_error: Option<std::io::Error>,
+}
+
+impl DiskSpill {
+    /// The path of the file of a spilled height
+    fn path(&self, height: BlockHeight) -> PathBuf {
+        self.dir.join(format!("unscanned-{height}.borsh"))
+    }
+
+    /// Write all the transactions of a height to its file
+    fn write(
+        &mut self,
+        height: BlockHeight,
+        txs: &IndexedNoteData,
+    ) -> std::io::Result<()> {
+        let path = self.path(height);
+        // Write to a temporary file first, such that the file of a
+        // height always holds all of its transactions
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, txs.serialize_to_vec())?;
+        std::fs::rename(tmp_path, path)?;
+        self.heights.insert(height, txs.len());
+        Ok(())
+    }
+
+    /// Read the transactions of a spilled height
+    fn read(&self, height: BlockHeight) -> std::io::Result<IndexedNoteData> {
+        std::fs::read(self.path(height))
+            .and_then(|bytes| IndexedNoteData::try_from_slice(&bytes))
+    }
+
+    /// Read the transactions of a spilled height and remove its file. If
+    /// they cannot be read, the error is recorded and the height is left
+    /// on disk.
+    fn take(&mut self, height: BlockHeight) -> Option<IndexedNoteData> {
+        match self.read(height) {
+            Ok(txs) => {
+                self.remove(height);
+                Some(txs)
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Failed to read back the spilled unscanned transactions \
+                     of height {height}: {err}"
+                );
+                self.read_error.get_or_insert(err);
+                None
+            }
+        }
+    }
+
+    /// Remove the file of a spilled height
+    fn remove(&mut self, height: BlockHeight) {
+        self.heights.remove(&height);
+        _ = std::fs::remove_file(self.path(height));
+    }
+}
+
+impl Drop for DiskSpill {
+    fn drop(&mut self) {
+        for height in self.heights.keys() {
+            _ = std::fs::remove_file(self.path(*height));
+        }
+    }
+}
+
+/// Statistics about the contents of the [`Unscanned`] cache.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct UnscannedStats {
+    /// The number of cached transactions
+    pub txs: usize,
+    /// The lowest and highest block heights present in the cache, if
+    /// it is not empty
+    pub height_span: Option<(BlockHeight, BlockHeight)>,
+    /// The number of heights whose transactions are all in the cache
+    pub full_heights: usize,
+    /// The number of heights of which some transactions have already
+    /// been scanned and removed from the cache
+    pub partial_heights: usize,
+}
+
+/// The sizes of the [`Unscanned`] cache, in number of transactions, at
+/// which shielded-sync pauses and resumes fetching. Fetching pauses
+/// before the next block height once the cache holds at least `high`
+/// transactions, and resumes once scanning has brought the cache down
+/// to at most `low` transactions. As the transactions of a height are
+/// fetched together, the cache can exceed `high` by those of a single
+/// height. The watermarks must satisfy `0 < low < high`, such that each
+/// pause lets the sync make progress.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FetchWatermarks {
+    /// The number of cached transactions at which fetching pauses
+    pub high: usize,
+    /// The number of cached transactions at which fetching resumes
+    pub low: usize,
+}
+
+impl FetchWatermarks {
+    /// Check that `0 < low < high`
+    pub fn validate(&self) -> Result<(), Error> {
+        if 0 < self.low && self.low < self.high {
+            Ok(())
+        } else {
+            Err(Error::Other(format!(
+                "Invalid fetch watermarks: the low-water mark {} must be \
+                 positive and lower than the high-water mark {}",
+                self.low, self.high
+            )))
+        }
+    }
+}
+
+/// The settings with which the [`Unscanned`] cache spills to disk, see
+/// [`Unscanned::enable_disk_spill`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnscannedSpill {
+    /// The directory of the spilled heights' files
+    pub dir: PathBuf,
+    /// The maximum number of heights whose transactions are kept in
+    /// memory
+    pub max_heights_in_memory: usize,
+}
+
+/// The aggregate throughput of shielded-sync, as opposed to the progress
+/// of the heights reported by a [`ProgressTracker`]. The metrics are reset
+/// at the start of each sync and can be read while it's running from a
+/// clone of [`ShieldedContext::sync_metrics`].
+#[derive(Debug, Default, Clone)]
+pub struct SyncMetrics {
+    /// The number of fetched txs that have been scanned
+    pub txs: u64,
+    /// The number of notes decrypted with the viewing keys
+    pub notes: u64,
+    /// The number of block heights whose txs have all been scanned
+    pub heights: u64,
+    /// The start of the running sync, if any
+    started: Option<Instant>,
+    /// The duration of the last finished sync
+    elapsed: Duration,
+}
+
+impl SyncMetrics {
+    /// The time elapsed since the start of the running sync, or the
+    /// duration of the last sync if it has finished
+    pub fn elapsed(&self) -> Duration {
+        self.started
+            .map_or(self.elapsed, |started| started.elapsed())
+    }
+
+    /// The number of scanned txs per second
+    pub fn txs_per_sec(&self) -> f64 {
+        self.per_sec(self.txs)
+    }
+
+    /// The number of decrypted notes per second
+    pub fn notes_per_sec(&self) -> f64 {
+        self.per_sec(self.notes)
+    }
+
+    /// The number of scanned heights per second
+    pub fn heights_per_sec(&self) -> f64 {
+        self.per_sec(self.heights)
+    }
+
+    fn per_sec(&self, count: u64) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs > 0.0 {
+            count as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// Reset the metrics and start the clock of a new sync
+    fn start(&mut self) {
+        #[allow(clippy::disallowed_methods)]
+        let now = Instant::now();
+        *self = Self {
+            started: Some(now),
+            ..Default::default()
+        };
+    }
+
+    /// Stop the clock of the running sync
+    fn finish(&mut self) {
+        self.elapsed = self.elapsed();
+        self.started = None;
+    }
+}
+
+/// Stops the clock of the [`SyncMetrics`] of a sync once dropped, however
+/// the sync ends
+struct SyncMetricsGuard(Arc<Mutex<SyncMetrics>>);
+
+impl Drop for SyncMetricsGuard {
+    fn drop(&mut self) {
+        if let Ok(mut metrics) = self.0.lock() {
+            metrics.finish();
+        }
+    }
 }
 
 impl BorshSerialize for Unscanned {
     fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
         let locked = self.txs.lock().unwrap();
-        let bytes = locked.serialize_to_vec();
-        writer.write(&bytes).map(|_| ())
+        let spill = self.spill.lock().unwrap();
+        let Some(spill) = spill.as_ref() else {
+            let bytes = locked.serialize_to_vec();
+            return writer.write(&bytes).map(|_| ());
+        };
+        // Write the entries in the layout of a single map, reading the
+        // spilled heights one at a time
+        let len = spill.heights.values().sum::<usize>() + locked.len();
+        u32::try_from(len)
+            .map_err(|_| std::io::ErrorKind::InvalidData)?
+            .serialize(writer)?;
+        let mut spilled = spill.heights.keys().copied().peekable();
+        let mut entries = locked.iter().peekable();
+        loop {
+            let next_entry = entries.peek().map(|(ix, _)| ix.height);
+            match (next_entry, spilled.peek().copied()) {
+                (Some(entry_height), Some(height)) if entry_height < height => {
+                    entries.next().unwrap().serialize(writer)?;
+                }
+                (_, Some(height)) => {
+                    for entry in spill.read(height)? {
+                        entry.serialize(writer)?;
+                    }
+                    spilled.next();
+                }
+                (Some(_), None) => {
+                    entries.next().unwrap().serialize(writer)?;
+                }
+                (None, None) => return Ok(()),
+            }
+        }
     }
 }
 
@@ -382,53 +709,225 @@ impl BorshDeserialize for Unscanned {
         let unscanned = IndexedNoteData::deserialize_reader(reader)?;
         Ok(Self {
             txs: Arc::new(Mutex::new(unscanned)),
+            last_scanned: Default::default(),
+            spill: Default::default(),
         })
     }
 }
 
 impl Unscanned {
+    /// Spill the cached transactions to files in the given directory,
+    /// keeping only those of the lowest `max_heights_in_memory` heights
+    /// in memory. The spilled heights are read back one at a time as
+    /// they get scanned, such that syncing a long chain doesn't need to
+    /// hold all of its transactions in memory.
+    pub fn enable_disk_spill(
+        &self,
+        dir: impl Into<PathBuf>,
+        max_heights_in_memory: usize,
+    ) {
+        let mut locked = self.txs.lock().unwrap();
+        let mut spill = self.spill.lock().unwrap();
+        if spill.is_some() {
+            return;
+        }
+        *spill = Some(DiskSpill {
+            dir: dir.into(),
+            max_heights_in_memory: max_heights_in_memory.max(1),
+            heights: BTreeMap::new(),
+            read_error: None,
+        });
+        Self::spill_excess(&mut locked, &mut spill);
+    }
+
+    /// Move the transactions of the highest heights to disk, until at
+    /// most the maximum number of heights are left in memory. If a
+    /// height cannot be written, it stays in memory.
+    fn spill_excess(
+        locked: &mut IndexedNoteData,
+        spill: &mut Option<DiskSpill>,
+    ) {
+        let Some(spill) = spill.as_mut() else {
+            return;
+        };
+        let mut heights: Vec<_> =
+            locked.keys().map(|ix| ix.height).dedup().collect();
+        while heights.len() > spill.max_heights_in_memory {
+            let height = heights.pop().unwrap();
+            let txs = locked.split_off(&IndexedTx {
+                height,
+                index: TxIndex(0),
+            });
+            if let Err(err) = spill.write(height, &txs) {
+                tracing::warn!(
+                    "Failed to spill the unscanned transactions of height \
+                     {height} to disk: {err}"
+                );
+                locked.extend(txs);
+                return;
+            }
+        }
+    }
+
+    /// Read the spilled heights back into memory while they precede the
+    /// transactions in memory, such that the first entry of the cache is
+    /// in memory.
+    fn refill(locked: &mut IndexedNoteData, spill: &mut Option<DiskSpill>) {
+        let Some(spill) = spill.as_mut() else {
+            return;
+        };
+        while let Some(&height) = spill.heights.keys().next() {
+            if locked.keys().next().is_some_and(|ix| ix.height < height) {
+                return;
+            }
+            let Some(txs) = spill.take(height) else {
+                return;
+            };
+            locked.extend(txs);
+        }
+    }
+
+    /// Add an entry to the cache, into the file of its height if it has
+    /// been spilled
+    fn insert_entry(
+        locked: &mut IndexedNoteData,
+        spill: &mut Option<DiskSpill>,
+        (k, v): IndexedNoteEntry,
+    ) -> bool {
+        if let Some(spill) = spill
+            .as_mut()
+            .filter(|spill| spill.heights.contains_key(&k.height))
+        {
+            let height = k.height;
+            let mut txs = match spill.read(height) {
+                Ok(txs) => txs,
+                Err(err) => {
+                    spill.read_error.get_or_insert(err);
+                    return false;
+                }
+            };
+            let replaced = txs.insert(k, v).is_some();
+            if spill.write(height, &txs).is_err() {
+                spill.remove(height);
+                locked.extend(txs);
+            }
+            return replaced;
+        }
+        let replaced = locked.insert(k, v).is_some();
+        Self::spill_excess(locked, spill);
+        replaced
+    }
+
     /// Append elements to the cache from an iterator.
     pub fn extend<I>(&self, items: I)
     where
         I: IntoIterator<Item = IndexedNoteEntry>,
     {
         let mut locked = self.txs.lock().unwrap();
-        locked.extend(items);
+        let mut spill = self.spill.lock().unwrap();
+        for item in items {
+            Self::insert_entry(&mut locked, &mut spill, item);
+        }
     }
 
-    /// Add a single entry to the cache.
-    pub fn insert(&self, (k, v): IndexedNoteEntry) {
+    /// Add a single entry to the cache. Returns `true` if an entry
+    /// with the same index was already present and has been replaced,
+    /// which means that the index was fetched more than once.
+    pub fn insert(&self, entry: IndexedNoteEntry) -> bool {
         let mut locked = self.txs.lock().unwrap();
-        locked.insert(k, v);
+        let mut spill = self.spill.lock().unwrap();
+        Self::insert_entry(&mut locked, &mut spill, entry)
     }
 
     /// Check if this cache has already been populated for a given
     /// block height.
     pub fn contains_height(&self, height: u64) -> bool {
         let locked = self.txs.lock().unwrap();
+        let spill = self.spill.lock().unwrap();
         locked.keys().any(|k| k.height.0 == height)
+            || spill.as_ref().is_some_and(|spill| {
+                spill.heights.contains_key(&BlockHeight(height))
+            })
     }
 
-    /// We remove all indices from blocks that have been entirely scanned.
-    /// If a block is only partially scanned, we leave all the events in the
-    /// cache.
+    /// Remove the given scanned index and all the indices preceding it
+    /// from the cache.
     pub fn scanned(&self, ix: &IndexedTx) {
         let mut locked = self.txs.lock().unwrap();
-        locked.retain(|i, _| i.height >= ix.height);
+        let mut spill = self.spill.lock().unwrap();
+        if let Some(spill) = spill.as_mut() {
+            let heights: Vec<_> =
+                spill.heights.range(..=ix.height).map(|(h, _)| *h).collect();
+            for height in heights {
+                // The txs of the scanned height that follow it are kept
+                if height < ix.height {
+                    spill.remove(height);
+                } else if let Some(txs) = spill.take(height) {
+                    locked.extend(txs);
+                }
+            }
+        }
+        locked.retain(|i, _| i > ix);
+        *self.last_scanned.lock().unwrap() = Some(ix.clone());
+    }
+
+    /// Take the first error that occurred reading back the transactions
+    /// spilled to disk, if any. The transactions from the height that
+    /// failed onwards are then not yielded by the cache.
+    pub fn take_spill_error(&self) -> Option<std::io::Error> {
+        self.spill.lock().unwrap().as_mut()?.read_error.take()
+    }
+
+    /// Check if all the transactions of the given block height have
+    /// been scanned and hence removed from the cache.
+    pub fn height_scanned(&self, height: BlockHeight) -> bool {
+        let mut locked = self.txs.lock().unwrap();
+        Self::refill(&mut locked, &mut self.spill.lock().unwrap());
+        locked.keys().next().map_or(true, |ix| ix.height > height)
+    }
+
+    /// Get a copy of the first entry that follows the given index,
+    /// or of the first entry overall if no index is given.
+    fn next_after(&self, ix: Option<&IndexedTx>) -> Option<IndexedNoteEntry> {
+        let mut locked = self.txs.lock().unwrap();
+        let mut spill = self.spill.lock().unwrap();
+        Self::refill(&mut locked, &mut spill);
+        loop {
+            let next = match ix {
+                Some(ix) => {
+                    locked.range((Bound::Excluded(ix), Bound::Unbounded)).next()
+                }
+                None => locked.iter().next(),
+            };
+            if let Some(entry) = next {
+                return Some(cloned_pair(entry));
+            }
+            // Read back the next spilled height, if any
+            let spill = spill.as_mut()?;
+            let from = ix.map(|ix| ix.height).unwrap_or_default();
+            let height = *spill.heights.range(from..).next()?.0;
+            locked.extend(spill.take(height)?);
+        }
     }
 
     /// Gets the latest block height present in the cache
     pub fn latest_height(&self) -> BlockHeight {
         let txs = self.txs.lock().unwrap();
+        let spill = self.spill.lock().unwrap();
+        let spilled = spill
+            .as_ref()
+            .and_then(|spill| spill.heights.keys().next_back().copied());
         txs.keys()
             .max_by_key(|ix| ix.height)
             .map(|ix| ix.height)
+            .max(spilled)
             .unwrap_or_default()
     }
 
     /// Gets the first block height present in the cache
     pub fn first_height(&self) -> BlockHeight {
-        let txs = self.txs.lock().unwrap();
+        let mut txs = self.txs.lock().unwrap();
+        Self::refill(&mut txs, &mut self.spill.lock().unwrap());
         txs.keys()
             .min_by_key(|ix| ix.height)
             .map(|ix| ix.height)
@@ -438,13 +937,78 @@ impl Unscanned {
     /// Remove the first entry from the cache and return it.
     pub fn pop_first(&self) -> Option<IndexedNoteEntry> {
         let mut locked = self.txs.lock().unwrap();
+        Self::refill(&mut locked, &mut self.spill.lock().unwrap());
         locked.pop_first()
     }
 
+    /// The number of cached transactions, including the spilled ones
+    pub fn len(&self) -> usize {
+        let locked = self.txs.lock().unwrap();
+        let spill = self.spill.lock().unwrap();
+        locked.len()
+            + spill
+                .as_ref()
+                .map_or(0, |spill| spill.heights.values().sum::<usize>())
+    }
+
     /// Check if empty
     pub fn is_empty(&self) -> bool {
         let locked = self.txs.lock().unwrap();
+        let spill = self.spill.lock().unwrap();
         locked.is_empty()
+            && spill
+                .as_ref()
+                .map_or(true, |spill| spill.heights.is_empty())
+    }
+
+    /// Report statistics about the contents of the cache.
+    pub fn stats(&self) -> UnscannedStats {
+        let locked = self.txs.lock().unwrap();
+        let spill = self.spill.lock().unwrap();
+        let spilled = spill
+            .as_ref()
+            .map(|spill| spill.heights.clone())
+            .unwrap_or_default();
+        let partial_height = self
+            .last_scanned
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|ix| ix.height);
+        let first_height = locked
+            .keys()
+            .next()
+            .map(|ix| ix.height)
+            .into_iter()
+            .chain(spilled.keys().next().copied())
+            .min();
+        let last_height = locked
+            .keys()
+            .next_back()
+            .map(|ix| ix.height)
+            .into_iter()
+            .chain(spilled.keys().next_back().copied())
+            .max();
+        let mut stats = UnscannedStats {
+            txs: locked.len() + spilled.values().sum::<usize>(),
+            height_span: first_height.zip(last_height),
+            // The spilled heights are never partially scanned
+            full_heights: spilled.len(),
+            ..Default::default()
+        };
+        let mut last_height = None;
+        for ix in locked.keys() {
+            if last_height == Some(ix.height) {
+                continue;
+            }
+            last_height = Some(ix.height);
+            if partial_height == Some(ix.height) {
+                stats.partial_heights += 1;
+            } else {
+                stats.full_heights += 1;
+            }
+        }
+        stats
     }
 }
 
@@ -452,11 +1016,22 @@ impl IntoIterator for Unscanned {
     type IntoIter = <IndexedNoteData as IntoIterator>::IntoIter;
     type Item = IndexedNoteEntry;
 
+    /// Iterate over all the cached transactions, reading back those
+    /// spilled to disk.
     fn into_iter(self) -> Self::IntoIter {
-        let txs = {
+        let mut txs = {
             let mut locked = self.txs.lock().unwrap();
             std::mem::take(&mut *locked)
         };
+        if let Some(spill) = self.spill.lock().unwrap().as_mut() {
+            let heights: Vec<_> = spill.heights.keys().copied().collect();
+            for height in heights {
+                let Some(spilled) = spill.take(height) else {
+                    break;
+                };
+                txs.extend(spilled);
+            }
+        }
         txs.into_iter()
     }
 }
@@ -470,6 +1045,102 @@ pub enum ContextSyncStatus {
     Speculative,
 }
 
+/// What the shielded context keeps of the notes it tracks
+#[derive(
+    BorshSerialize, BorshDeserialize, Debug, Clone, Copy, Default, PartialEq,
+)]
+pub enum SyncMode {
+    /// The notes are kept in full along with their witnesses, such that they
+    /// can be spent
+    #[default]
+    Full,
+    /// Only the values and nullifiers of the notes are kept, which is enough
+    /// to compute the balances, e.g. for a read-only wallet. The notes cannot
+    /// be spent from such a context.
+    BalanceOnly,
+}
+
+/// The notes spent by a speculative tx, as returned by
+/// [`ShieldedContext::speculative_entries`]
+#[derive(
+    BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq,
+)]
+pub struct SpeculativeDelta {
+    /// The positions of the notes spent by the tx
+    pub spent_notes: BTreeSet<usize>,
+    /// The nullifiers revealed by the tx
+    pub nullifiers: Vec<Nullifier>,
+}
+
+/// A note received by a viewing key, as exported by
+/// [`ShieldedContext::export_notes`]
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct NoteRecord {
+    /// The tx in which the note was received
+    pub indexed_tx: IndexedTx,
+    /// The asset type of the note
+    pub asset_type: AssetType,
+    /// The amount of the note
+    pub amount: u64,
+    /// The memo attached to the note, if any
+    pub memo: Option<MemoBytes>,
+    /// The diversifier of the payment address that received the note
+    pub diversifier: Diversifier,
+    /// Whether the note has been spent
+    pub spent: bool,
+}
+
+/// The estimated sizes in bytes of the maps of a shielded context, as
+/// reported by [`ShieldedContext::estimated_size`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SizeBreakdown {
+    /// The notes
+    pub note_map: usize,
+    /// The asset types and values of the notes in the balance-only sync mode
+    pub note_values: usize,
+    /// The nullifiers of the notes
+    pub nf_map: usize,
+    /// The positions of the notes of each viewing key
+    pub pos_map: usize,
+    /// The diversifiers of the notes
+    pub div_map: usize,
+    /// The memos of the notes
+    pub memo_map: usize,
+    /// The viewing keys of the notes
+    pub vk_map: usize,
+    /// The witnesses of the notes
+    pub witness_map: usize,
+    /// The fetched txs that are cached in memory, the ones spilled to disk
+    /// are left out
+    pub unscanned: usize,
+}
+
+impl SizeBreakdown {
+    /// The sum of the sizes of all the maps
+    pub fn total(&self) -> usize {
+        let Self {
+            note_map,
+            note_values,
+            nf_map,
+            pos_map,
+            div_map,
+            memo_map,
+            vk_map,
+            witness_map,
+            unscanned,
+        } = self;
+        note_map
+            + note_values
+            + nf_map
+            + pos_map
+            + div_map
+            + memo_map
+            + vk_map
+            + witness_map
+            + unscanned
+    }
+}
+
 /// Represents the current state of the shielded pool from the perspective of
 /// the chosen viewing keys.
 #[derive(BorshSerialize, BorshDeserialize, Debug)]
@@ -494,18 +1165,45 @@ pub struct ShieldedContext<U: ShieldedUtils> {
     pub div_map: HashMap<usize, Diversifier>,
     /// Maps note positions to their witness (used to make merkle paths)
     pub witness_map: HashMap<usize, IncrementalWitness<Node>>,
-    /// The set of note positions that have been spent
+    /// The set of note positions that have been spent, see
+    /// [`ShieldedContext::mark_spent_from`]
     pub spents: HashSet<usize>,
     /// Maps asset types to their decodings
     pub asset_types: HashMap<AssetType, AssetData>,
+    /// A cache of the asset types encoded from their decodings. This is not
+    /// persisted, it's populated lazily by [`ShieldedContext::asset_type`].
+    #[borsh(skip)]
+    pub asset_type_cache: HashMap<AssetData, AssetType>,
     /// Maps note positions to their corresponding viewing keys
     pub vk_map: HashMap<usize, ViewingKey>,
     /// Maps a shielded tx to the index of its first output note.
     pub tx_note_map: BTreeMap<IndexedTx, usize>,
     /// A cache of fetched indexed txs.
     pub unscanned: Unscanned,
+    /// Maps the speculative txs to the notes they spent. This is only
+    /// populated while the context is speculative.
+    pub speculative_txs: BTreeMap<IndexedTx, SpeculativeDelta>,
+    /// The sizes of the cache of fetched txs at which syncing pauses and
+    /// resumes fetching. This is not persisted, fetching never pauses
+    /// if unset.
+    #[borsh(skip)]
+    pub fetch_watermarks: Option<FetchWatermarks>,
+    /// Where and how much of the cache of fetched txs spills to disk, see
+    /// [`ShieldedContext::set_unscanned_spill`]. This is not persisted.
+    #[borsh(skip)]
+    pub unscanned_spill: Option<UnscannedSpill>,
+    /// The throughput of the last or running sync. This is not persisted.
+    #[borsh(skip)]
+    pub sync_metrics: Arc<Mutex<SyncMetrics>>,
     /// The sync state of the context
     pub sync_status: ContextSyncStatus,
+    /// What the context keeps of the notes it tracks. The balance-only mode
+    /// set on a context is kept when the syncing reloads it.
+    pub sync_mode: SyncMode,
+    /// Maps note positions to the asset types and values of their notes.
+    /// This is only populated in the balance-only sync mode, in place of the
+    /// note map.
+    pub note_values: HashMap<usize, (AssetType, u64)>,
 }
 
 /// Default implementation to ease construction of TxContexts. Derive cannot be
@@ -525,9 +1223,96 @@ impl<U: ShieldedUtils + Default> Default for ShieldedContext<U> {
             witness_map: HashMap::default(),
             spents: HashSet::default(),
             asset_types: HashMap::default(),
+            asset_type_cache: HashMap::default(),
             vk_map: HashMap::default(),
             unscanned: Default::default(),
+            speculative_txs: BTreeMap::default(),
+            fetch_watermarks: None,
+            unscanned_spill: None,
+            sync_metrics: Default::default(),
             sync_status: ContextSyncStatus::Confirmed,
+            sync_mode: SyncMode::Full,
+            note_values: HashMap::default(),
+        }
+    }
+}
+
+/// The magic bytes prefixing a serialized [`ShieldedContext`] with the
+/// version of its layout. An unversioned context starts with its commitment
+/// tree, whose first byte is either 0 or 1, so it can't be mistaken for it.
+const SHIELDED_CONTEXT_MAGIC: &[u8; 8] = b"NAMSHCTX";
+
+/// The version of the layout of a serialized [`ShieldedContext`], see
+/// [`ShieldedContext::serialize_versioned`]:
+/// - 0: the unversioned layout, without a prefix
+/// - 1: added the speculative txs, the sync mode and the note values
+pub const SHIELDED_CONTEXT_VERSION: u8 = 1;
+
+/// The layout of a [`ShieldedContext`] before it was versioned, which is
+/// still read from the context files saved by the earlier versions
+#[derive(BorshSerialize, BorshDeserialize)]
+struct ShieldedContextV0 {
+    tree: CommitmentTree<Node>,
+    vk_heights: BTreeMap<ViewingKey, Option<IndexedTx>>,
+    pos_map: HashMap<ViewingKey, BTreeSet<usize>>,
+    nf_map: HashMap<Nullifier, usize>,
+    note_map: HashMap<usize, Note>,
+    memo_map: HashMap<usize, MemoBytes>,
+    div_map: HashMap<usize, Diversifier>,
+    witness_map: HashMap<usize, IncrementalWitness<Node>>,
+    spents: HashSet<usize>,
+    asset_types: HashMap<AssetType, AssetData>,
+    vk_map: HashMap<usize, ViewingKey>,
+    tx_note_map: BTreeMap<IndexedTx, usize>,
+    unscanned: Unscanned,
+    sync_status: ContextSyncStatus,
+}
+
+impl<U: ShieldedUtils> ShieldedContext<U> {
+    /// Serialize the context prefixed with the version of its layout, see
+    /// [`SHIELDED_CONTEXT_VERSION`]
+    pub fn serialize_versioned(&self) -> std::io::Result<Vec<u8>> {
+        let mut bytes = SHIELDED_CONTEXT_MAGIC.to_vec();
+        bytes.push(SHIELDED_CONTEXT_VERSION);
+        self.serialize(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Deserialize a context written by
+    /// [`ShieldedContext::serialize_versioned`], or an unversioned one in the
+    /// layout of the version 0. The fields missing from an older layout are
+    /// left to their default.
+    pub fn deserialize_versioned(bytes: &[u8]) -> std::io::Result<Self> {
+        let Some(versioned) = bytes.strip_prefix(SHIELDED_CONTEXT_MAGIC)
+        else {
+            let ctx = ShieldedContextV0::deserialize(&mut &bytes[..])?;
+            return Ok(Self {
+                tree: ctx.tree,
+                vk_heights: ctx.vk_heights,
+                pos_map: ctx.pos_map,
+                nf_map: ctx.nf_map,
+                note_map: ctx.note_map,
+                memo_map: ctx.memo_map,
+                div_map: ctx.div_map,
+                witness_map: ctx.witness_map,
+                spents: ctx.spents,
+                asset_types: ctx.asset_types,
+                vk_map: ctx.vk_map,
+                tx_note_map: ctx.tx_note_map,
+                unscanned: ctx.unscanned,
+                sync_status: ctx.sync_status,
+                ..Default::default()
+            });
+        };
+        match versioned {
+            [SHIELDED_CONTEXT_VERSION, ctx @ ..] => {
+                Self::deserialize(&mut &ctx[..])
+            }
+            [version, ..] => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported shielded context version {version}"),
+            )),
+            [] => Err(std::io::ErrorKind::UnexpectedEof.into()),
         }
     }
 }
@@ -536,18 +1321,40 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
     /// Try to load the last saved shielded context from the given context
     /// directory. If this fails, then leave the current context unchanged.
     pub async fn load(&mut self) -> std::io::Result<()> {
-        self.utils.clone().load(self, false).await
+        let spill = self.unscanned_spill.take();
+        let res = self.utils.clone().load(self, false).await;
+        self.set_unscanned_spill(spill);
+        res
     }
 
     /// Try to load the last saved confirmed shielded context from the given
     /// context directory. If this fails, then leave the current context
     /// unchanged.
     pub async fn load_confirmed(&mut self) -> std::io::Result<()> {
-        self.utils.clone().load(self, true).await?;
+        let spill = self.unscanned_spill.take();
+        let res = self.utils.clone().load(self, true).await;
+        self.set_unscanned_spill(spill);
+        res?;
+        // Speculative txs are not part of the confirmed state
+        self.speculative_txs.clear();
 
         Ok(())
     }
 
+    /// Spill the cache of fetched txs to disk with the given settings, or
+    /// keep it in memory if unset. The settings are kept when the context
+    /// is loaded, but a cache that already spills cannot be brought back
+    /// in memory.
+    pub fn set_unscanned_spill(&mut self, spill: Option<UnscannedSpill>) {
+        if let Some(spill) = &spill {
+            self.unscanned.enable_disk_spill(
+                spill.dir.clone(),
+                spill.max_heights_in_memory,
+            );
+        }
+        self.unscanned_spill = spill;
+    }
+
     /// Save this shielded context into its associated context directory. If the
     /// state to be saved is confirmed than also delete the speculative one (if
     /// available)
@@ -582,9 +1389,13 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                     Error::Other("note commitment tree is full".to_string())
                 })?;
                 // Finally, make it easier to construct merkle paths to this new
-                // note
-                let witness = IncrementalWitness::<Node>::from_tree(&self.tree);
-                self.witness_map.insert(note_pos, witness);
+                // note. The tree is still needed without witnesses to derive
+                // the positions of the notes.
+                if let SyncMode::Full = self.sync_mode {
+                    let witness =
+                        IncrementalWitness::<Node>::from_tree(&self.tree);
+                    self.witness_map.insert(note_pos, witness);
+                }
                 note_pos += 1;
             }
         }
@@ -623,6 +1434,127 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         .await
     }
 
+    /// Fetch and scan only the blocks in the given range of heights, for the
+    /// given viewing keys, and apply the results to the context. The MASP
+    /// clients skip the heights that are already cached.
+    ///
+    /// The notes are appended to the commitment tree in the order they are
+    /// scanned. Unless the range starts at most right after the last height
+    /// already scanned by the context, the positions and witnesses of the
+    /// notes found in the range are therefore not valid, and these notes
+    /// cannot be spent until the context is resynced from the first height.
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn shielded_sync_range<IO, M>(
+        &mut self,
+        client: M,
+        progress: &impl ProgressTracker<IO>,
+        from: BlockHeight,
+        to: BlockHeight,
+        retry: RetryStrategy,
+        vks: &[ViewingKey],
+    ) -> Result<(), Error>
+    where
+        IO: Io,
+        M: MaspClient,
+    {
+        // The confirmed context is the one the range is scanned on top of
+        let sync_mode = self.sync_mode;
+        let _ = self.load_confirmed().await;
+        if let SyncMode::BalanceOnly = sync_mode {
+            self.set_balance_only();
+        }
+        let next_height = self
+            .tx_note_map
+            .keys()
+            .max()
+            .map_or_else(BlockHeight::first, |ix| ix.height.next_height());
+        if from > next_height {
+            display_line!(
+                progress.io(),
+                "Warning: the heights {next_height} to {} have not been \
+                 scanned yet. The notes found from height {from} cannot be \
+                 spent until the shielded context is fully synced.",
+                from.prev_height().unwrap_or(from)
+            );
+        }
+
+        let shutdown_signal = control_flow::install_shutdown_signal();
+        self.fetch_aux(
+            client,
+            progress,
+            Some(from),
+            Some(to),
+            retry,
+            &[],
+            vks,
+            shutdown_signal,
+        )
+        .await
+    }
+
+    /// Add a viewing key to an already synced context and scan the blocks
+    /// from its birthday, i.e. the height from which it may have received
+    /// notes, only with this key.
+    ///
+    /// The txs scanned by the context are not kept, so the blocks from the
+    /// birthday up to the height that all the tracked keys are synced to are
+    /// fetched again, and the tracked keys are left as they are. The new key
+    /// is then synced along with the other keys by the next
+    /// [`ShieldedContext::fetch`], which also skips the blocks below its
+    /// birthday.
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn sync_new_viewing_key<IO, M>(
+        &mut self,
+        client: M,
+        progress: &impl ProgressTracker<IO>,
+        vk: ViewingKey,
+        birthday: BlockHeight,
+        retry: RetryStrategy,
+    ) -> Result<(), Error>
+    where
+        IO: Io,
+        M: MaspClient,
+    {
+        // The confirmed context is the one the key is added to
+        let _ = self.load_confirmed().await;
+        if self.vk_heights.contains_key(&vk) {
+            return Err(Error::Other(
+                "The viewing key is already tracked by the shielded context"
+                    .to_string(),
+            ));
+        }
+        // The blocks that every tracked key has been scanned through, if any
+        let synced_to = self.vk_heights.values().min().cloned().flatten();
+        // The key is synced to the last tx before its birthday
+        let before_birthday = birthday.prev_height().map(|height| IndexedTx {
+            height,
+            index: TxIndex(u32::MAX),
+        });
+        self.vk_heights.insert(vk, before_birthday);
+        // Save the context to persist the new key before it is reloaded
+        let _ = self.save().await;
+
+        let Some(synced_to) =
+            synced_to.filter(|synced_to| synced_to.height >= birthday)
+        else {
+            // There are no blocks the other keys have been scanned through
+            // that this key has to catch up with
+            return Ok(());
+        };
+        let shutdown_signal = control_flow::install_shutdown_signal();
+        self.fetch_aux(
+            client,
+            progress,
+            Some(birthday),
+            Some(synced_to.height),
+            retry,
+            &[],
+            &[],
+            shutdown_signal,
+        )
+        .await
+    }
+
     fn min_height_to_sync_from(&self) -> Result<BlockHeight, Error> {
         let Some(maybe_least_synced_vk_height) =
             self.vk_heights.values().min().cloned()
@@ -665,8 +1597,11 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                 Ok(None)
             }
         };
+        let full_sync = matches!(self.sync_mode, SyncMode::Full);
         let witness_map_fut = async {
-            if client.capabilities().may_fetch_pre_built_witness_map() {
+            if full_sync
+                && client.capabilities().may_fetch_pre_built_witness_map()
+            {
                 client.fetch_witness_map(height).await.map(Some)
             } else {
                 Ok(None)
@@ -713,6 +1648,9 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                  sync"
             )));
         }
+        if let Some(watermarks) = self.fetch_watermarks {
+            watermarks.validate()?;
+        }
 
         // add new viewing keys
         // Reload the state from file to get the last confirmed state and
@@ -720,14 +1658,28 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         // speculative state
         // Always reload the confirmed context or initialize a new one if not
         // found
+        let fetch_watermarks = self.fetch_watermarks;
+        let sync_metrics = self.sync_metrics.clone();
+        let sync_mode = self.sync_mode;
         if self.load_confirmed().await.is_err() {
             // Initialize a default context if we couldn't load a valid one
             // from storage
+            let spill = self.unscanned_spill.take();
             *self = Self {
                 utils: std::mem::take(&mut self.utils),
                 ..Default::default()
             };
+            self.set_unscanned_spill(spill);
         }
+        self.fetch_watermarks = fetch_watermarks;
+        self.sync_metrics = sync_metrics;
+        // The balance-only mode sticks to a loaded context, as the data it
+        // drops cannot be recovered
+        if let SyncMode::BalanceOnly = sync_mode {
+            self.set_balance_only();
+        }
+        self.sync_metrics.lock().unwrap().start();
+        let _metrics_guard = SyncMetricsGuard(self.sync_metrics.clone());
         for esk in sks {
             let vk = to_viewing_key(esk).vk;
             self.vk_heights.entry(vk).or_default();
@@ -764,60 +1716,116 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         self.update_with_pre_built_data(&client, last_query_height)
             .await?;
 
+        // Preparing an incoming viewing key is costly, so it is done once per
+        // key rather than once per trial decryption
+        let mut ivks = HashMap::new();
         for _ in retry {
             debug_assert!(start_height <= last_query_height);
 
-            // a stateful channel that communicates notes fetched to the trial
-            // decryption process
-            let (fetch_send, fetch_recv) =
-                fetch_channel::new(self.unscanned.clone());
-            let fetch_res = self
-                .fetch_shielded_transfers(
-                    &client,
-                    progress,
-                    &mut shutdown_signal,
-                    fetch_send,
-                    start_height,
-                    last_query_height,
-                )
-                .await;
-            // if fetching errored, log it. But this is recoverable.
-            match fetch_res {
-                Err(e @ Error::Interrupt(_)) => {
-                    display_line!(progress.io(), "{}", e.to_string(),);
-                    return Err(e);
+            // The fetching pauses whenever the cache of fetched txs grows
+            // past its high-water mark, and resumes from the height it
+            // paused at once enough of the cache has been scanned
+            let mut fetch_from = start_height;
+            loop {
+                // a stateful channel that communicates notes fetched to the
+                // trial decryption process
+                let (fetch_send, fetch_recv) = fetch_channel::new(
+                    self.unscanned.clone(),
+                    self.fetch_watermarks,
+                );
+                let fetch_res = self
+                    .fetch_shielded_transfers(
+                        &client,
+                        progress,
+                        &mut shutdown_signal,
+                        fetch_send,
+                        fetch_from,
+                        last_query_height,
+                    )
+                    .await;
+                // if fetching errored, log it. But this is recoverable.
+                match fetch_res {
+                    Err(e @ Error::Interrupt(_)) => {
+                        display_line!(progress.io(), "{}", e.to_string(),);
+                        return Err(e);
+                    }
+                    Err(e) => display_line!(
+                        progress.io(),
+                        "Error encountered while fetching: {}",
+                        e.to_string(),
+                    ),
+                    _ => {}
                 }
-                Err(e) => display_line!(
-                    progress.io(),
-                    "Error encountered while fetching: {}",
-                    e.to_string(),
-                ),
-                _ => {}
-            }
-            let txs = progress.scan(fetch_recv);
-            for (ref indexed_tx, ref stx) in txs {
-                if client.capabilities().needs_witness_map_update()
-                    && Some(indexed_tx) > last_witnessed_tx.as_ref()
-                {
-                    self.update_witness_map(indexed_tx.to_owned(), stx)?;
+                // If the fetching paused, only scan until the cache is back
+                // under its low-water mark
+                let paused = fetch_recv.paused_at().zip(self.fetch_watermarks);
+                let txs = progress.scan(fetch_recv);
+                for (ref indexed_tx, ref stx) in txs {
+                    if client.capabilities().needs_witness_map_update()
+                        && Some(indexed_tx) > last_witnessed_tx.as_ref()
+                    {
+                        self.update_witness_map(indexed_tx.to_owned(), stx)?;
+                    }
+                    let notes_before = self.nf_map.len();
+                    let mut vk_heights = BTreeMap::new();
+                    std::mem::swap(&mut vk_heights, &mut self.vk_heights);
+                    for (vk, h) in vk_heights
+                        .iter_mut()
+                        .filter(|(_vk, h)| h.as_ref() < Some(indexed_tx))
+                    {
+                        let ivk = ivks.entry(*vk).or_insert_with(|| {
+                            PreparedIncomingViewingKey::new(&vk.ivk())
+                        });
+                        self.scan_tx_with_ivk(
+                            indexed_tx.to_owned(),
+                            stx,
+                            vk,
+                            ivk,
+                        )?;
+                        *h = Some(indexed_tx.to_owned());
+                    }
+                    // remove the scanned tx from the cache.
+                    self.unscanned.scanned(indexed_tx);
+                    std::mem::swap(&mut vk_heights, &mut self.vk_heights);
+                    if shutdown_signal.received() {
+                        let _ = self.save().await;
+                        return Err(Error::Interrupt(
+                            "[ShieldedSync::Scanning]".to_string(),
+                        ));
+                    }
+                    // Checkpoint the context together with the cache once a
+                    // height has been fully scanned, such that an interrupted
+                    // sync resumes from where it left off
+                    let height_scanned =
+                        self.unscanned.height_scanned(indexed_tx.height);
+                    {
+                        let mut metrics = self.sync_metrics.lock().unwrap();
+                        metrics.txs += 1;
+                        metrics.notes +=
+                            (self.nf_map.len() - notes_before) as u64;
+                        metrics.heights += u64::from(height_scanned);
+                    }
+                    if height_scanned {
+                        let _ = self.save().await;
+                    }
+                    if paused.is_some_and(|(_, watermarks)| {
+                        self.unscanned.len() <= watermarks.low
+                    }) {
+                        break;
+                    }
                 }
-                let mut vk_heights = BTreeMap::new();
-                std::mem::swap(&mut vk_heights, &mut self.vk_heights);
-                for (vk, h) in vk_heights
-                    .iter_mut()
-                    .filter(|(_vk, h)| h.as_ref() < Some(indexed_tx))
-                {
-                    self.scan_tx(indexed_tx.to_owned(), stx, vk)?;
-                    *h = Some(indexed_tx.to_owned());
+                // The scanning stops short of the txs that couldn't be read
+                // back from disk
+                if let Some(err) = self.unscanned.take_spill_error() {
+                    return Err(Error::Other(format!(
+                        "Failed to read back the fetched MASP txs spilled to \
+                         disk: {err}"
+                    )));
                 }
-                // possibly remove unneeded elements from the cache.
-                self.unscanned.scanned(indexed_tx);
-                std::mem::swap(&mut vk_heights, &mut self.vk_heights);
-                if shutdown_signal.received() {
-                    let _ = self.save().await;
-                    return Err(Error::Interrupt(
-                        "[ShieldedSync::Scanning]".to_string(),
-                    ));
+
+                match paused {
+                    Some((height, _)) => fetch_from = height,
+                    None => break,
                 }
             }
 
@@ -885,13 +1893,20 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         shielded: &[Transaction],
         vk: &ViewingKey,
     ) -> Result<(), Error> {
-        type Proof = OutputDescription<
-            <
-                <Authorized as Authorization>::SaplingAuth
-                as masp_primitives::transaction::components::sapling::Authorization
-            >::Proof
-        >;
+        let ivk = PreparedIncomingViewingKey::new(&vk.ivk());
+        self.scan_tx_with_ivk(indexed_tx, shielded, vk, &ivk)
+    }
 
+    /// Same as [`Self::scan_tx`], but with the incoming viewing key of `vk`
+    /// already prepared for trial decryption, so that it can be reused
+    /// across transactions
+    fn scan_tx_with_ivk(
+        &mut self,
+        indexed_tx: IndexedTx,
+        shielded: &[Transaction],
+        vk: &ViewingKey,
+        ivk: &PreparedIncomingViewingKey,
+    ) -> Result<(), Error> {
         // For tracking the account changes caused by this Transaction
         let mut transaction_delta = TransactionDelta::new();
         if let ContextSyncStatus::Confirmed = self.sync_status {
@@ -905,12 +1920,7 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                     // Let's try to see if this viewing key can decrypt latest
                     // note
                     let notes = self.pos_map.entry(*vk).or_default();
-                    let decres = try_sapling_note_decryption::<_, Proof>(
-                        &NETWORK,
-                        1.into(),
-                        &PreparedIncomingViewingKey::new(&vk.ivk()),
-                        so,
-                    );
+                    let decres = try_note_decryption_prechecked(ivk, so);
                     // So this current viewing key does decrypt this current
                     // note...
                     if let Some((note, pa, memo)) = decres {
@@ -927,11 +1937,22 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                                 )
                             })?,
                         );
-                        self.note_map.insert(note_pos, note);
-                        self.memo_map.insert(note_pos, memo);
-                        // The payment address' diversifier is required to spend
-                        // note
-                        self.div_map.insert(note_pos, *pa.diversifier());
+                        match self.sync_mode {
+                            SyncMode::Full => {
+                                self.note_map.insert(note_pos, note);
+                                self.memo_map.insert(note_pos, memo);
+                                // The payment address' diversifier is required
+                                // to spend note
+                                self.div_map
+                                    .insert(note_pos, *pa.diversifier());
+                            }
+                            SyncMode::BalanceOnly => {
+                                self.note_values.insert(
+                                    note_pos,
+                                    (note.asset_type, note.value),
+                                );
+                            }
+                        }
                         self.nf_map.insert(nf, note_pos);
                         // Note the account changes
                         let balance = transaction_delta
@@ -961,24 +1982,15 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
             {
                 // If the shielded spend's nullifier is in our map, then target
                 // note is rendered unusable
-                if let Some(note_pos) = self.nf_map.get(&ss.nullifier) {
-                    self.spents.insert(*note_pos);
+                if let Some(note_pos) =
+                    self.spend_note(&indexed_tx, &ss.nullifier)
+                {
                     // Note the account changes
+                    let note_value = self.note_value(&note_pos)?;
                     let balance = transaction_delta
-                        .entry(self.vk_map[note_pos])
+                        .entry(self.vk_map[&note_pos])
                         .or_insert_with(I128Sum::zero);
-                    let note = self.note_map[note_pos];
-
-                    *balance -= I128Sum::from_nonnegative(
-                        note.asset_type,
-                        note.value as i128,
-                    )
-                    .map_err(|()| {
-                        Error::Other(
-                            "found note with invalid value or asset type"
-                                .to_string(),
-                        )
-                    })?;
+                    *balance -= note_value;
                 }
             }
         }
@@ -986,6 +1998,315 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         Ok(())
     }
 
+    /// The value of the note at the given position, from the note itself or
+    /// from its value in the balance-only sync mode.
+    fn note_value(&self, note_pos: &usize) -> Result<I128Sum, Error> {
+        let (asset_type, value) = match self.note_map.get(note_pos) {
+            Some(note) => (note.asset_type, note.value),
+            None => *self.note_values.get(note_pos).ok_or_else(|| {
+                Error::Other(format!("Unable to get note {note_pos}"))
+            })?,
+        };
+        I128Sum::from_nonnegative(asset_type, value as i128).map_err(|()| {
+            Error::Other(
+                "found note with invalid value or asset type".to_string(),
+            )
+        })
+    }
+
+    /// Check that the notes of this context can be spent, which is not the
+    /// case in the balance-only sync mode.
+    fn ensure_spendable(&self) -> Result<(), Error> {
+        match self.sync_mode {
+            SyncMode::Full => Ok(()),
+            SyncMode::BalanceOnly => Err(Error::Other(
+                "Spending notes is not available in balance-only mode"
+                    .to_string(),
+            )),
+        }
+    }
+
+    /// Switch this context to the balance-only sync mode, dropping the data
+    /// only needed to spend the notes: the notes themselves, their memos,
+    /// diversifiers and witnesses. Only the values of the notes are kept. The
+    /// dropped data can only be recovered by syncing a new context in the full
+    /// mode.
+    pub fn set_balance_only(&mut self) {
+        self.sync_mode = SyncMode::BalanceOnly;
+        self.note_values.extend(
+            std::mem::take(&mut self.note_map)
+                .into_iter()
+                .map(|(pos, note)| (pos, (note.asset_type, note.value))),
+        );
+        self.memo_map = HashMap::default();
+        self.div_map = HashMap::default();
+        self.witness_map = HashMap::default();
+    }
+
+    /// Mark the note of the given nullifier as spent by the given tx,
+    /// returning its position if the note is tracked by this context. The
+    /// spend is recorded against the tx if the context is speculative.
+    fn spend_note(
+        &mut self,
+        indexed_tx: &IndexedTx,
+        nf: &Nullifier,
+    ) -> Option<usize> {
+        let note_pos = *self.nf_map.get(nf)?;
+        self.spents.insert(note_pos);
+        if let ContextSyncStatus::Speculative = self.sync_status {
+            let delta =
+                self.speculative_txs.entry(indexed_tx.clone()).or_default();
+            delta.spent_notes.insert(note_pos);
+            if !delta.nullifiers.contains(nf) {
+                delta.nullifiers.push(*nf);
+            }
+        }
+        Some(note_pos)
+    }
+
+    /// Mark as spent the notes of this context whose nullifiers are in the
+    /// given set of on-chain nullifiers, in a single pass over the context's
+    /// nullifiers. The caller is meant to fetch the on-chain nullifier set
+    /// once per sync, rather than to check the notes one by one. Returns the
+    /// number of notes newly marked as spent.
+    pub fn mark_spent_from(
+        &mut self,
+        onchain_nullifiers: &HashSet<Nullifier>,
+    ) -> usize {
+        let mut marked = 0;
+        for (nf, note_pos) in &self.nf_map {
+            if onchain_nullifiers.contains(nf) && self.spents.insert(*note_pos)
+            {
+                marked += 1;
+            }
+        }
+        marked
+    }
+
+    /// Check if the note at the given position has been spent
+    pub fn is_spent(&self, note_pos: usize) -> bool {
+        self.spents.contains(&note_pos)
+    }
+
+    /// The positions of the notes of the given viewing key that have not
+    /// been spent, in ascending order
+    pub fn spendable_notes(&self, vk: &ViewingKey) -> Vec<usize> {
+        self.pos_map.get(vk).map_or_else(Vec::new, |positions| {
+            positions
+                .iter()
+                .filter(|note_pos| !self.is_spent(**note_pos))
+                .copied()
+                .collect()
+        })
+    }
+
+    /// The speculative txs of this context along with the notes they spent,
+    /// in ascending order of the txs.
+    pub fn speculative_entries(&self) -> Vec<(IndexedTx, SpeculativeDelta)> {
+        self.speculative_txs
+            .iter()
+            .map(|(indexed_tx, delta)| (indexed_tx.clone(), delta.clone()))
+            .collect()
+    }
+
+    /// Settle the given speculative tx once it has been confirmed or rejected
+    /// by the protocol, returning the notes it spent if it was tracked. The
+    /// notes spent by a rejected tx are made spendable again, while those of
+    /// a confirmed tx stay spent.
+    pub fn reconcile_speculative(
+        &mut self,
+        indexed_tx: &IndexedTx,
+        confirmed: bool,
+    ) -> Option<SpeculativeDelta> {
+        let delta = self.speculative_txs.remove(indexed_tx)?;
+        if !confirmed {
+            for note_pos in &delta.spent_notes {
+                self.spents.remove(note_pos);
+            }
+        }
+        Some(delta)
+    }
+
+    /// Check that the given viewing key is the one derived from the given
+    /// spending key, e.g. when importing keys to a wallet. A spending key
+    /// that doesn't match the viewing key used to track the notes cannot
+    /// spend them.
+    pub fn verify_key_pair(
+        vk: &ViewingKey,
+        sk: &MaspExtendedSpendingKey,
+    ) -> bool {
+        to_viewing_key(sk).vk == *vk
+    }
+
+    /// Check that the notes spent by the given transfer can still be spent
+    /// in this context, such that a transfer whose notes have meanwhile been
+    /// spent by another tx is not submitted. The spends are mapped back to
+    /// their notes by their nullifiers. Every note must be tracked by the
+    /// context, not marked as spent and have a witness that is up to date
+    /// with the commitment tree.
+    pub fn validate_transfer(
+        &self,
+        transfer: &ShieldedTransfer,
+    ) -> Result<(), TransferErr> {
+        let spends = transfer
+            .masp_tx
+            .sapling_bundle()
+            .map_or(&vec![], |bundle| &bundle.shielded_spends);
+        self.validate_spent_nullifiers(
+            spends.iter().map(|spend| &spend.nullifier),
+        )
+    }
+
+    /// Check that the notes of the given nullifiers can be spent, see
+    /// [`ShieldedContext::validate_transfer`].
+    fn validate_spent_nullifiers<'a>(
+        &self,
+        nullifiers: impl IntoIterator<Item = &'a Nullifier>,
+    ) -> Result<(), TransferErr> {
+        self.ensure_spendable()?;
+        let root = self.tree.root();
+        for (idx, nf) in nullifiers.into_iter().enumerate() {
+            let note_pos = self.nf_map.get(nf).ok_or_else(|| {
+                Error::Other(format!(
+                    "The note of the spend {idx} of the transfer is not \
+                     tracked by the shielded context"
+                ))
+            })?;
+            if self.spents.contains(note_pos) {
+                return Err(Error::Other(format!(
+                    "The note at position {note_pos} spent by the transfer \
+                     has already been spent"
+                ))
+                .into());
+            }
+            let witness = self.witness_map.get(note_pos).ok_or_else(|| {
+                Error::Other(format!(
+                    "The note at position {note_pos} spent by the transfer \
+                     has no witness"
+                ))
+            })?;
+            if witness.root() != root {
+                return Err(Error::Other(format!(
+                    "The witness of the note at position {note_pos} spent by \
+                     the transfer is not up to date with the commitment tree"
+                ))
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the state of this context that can no longer be used, returning
+    /// the number of witnesses removed. Spent notes cannot be spent again, so
+    /// their witnesses are no longer kept up to date. Note positions are left
+    /// untouched since they are the positions of the notes in the commitment
+    /// tree, from which their nullifiers are derived. A speculative context
+    /// is not compacted, as its spent notes may yet be reverted.
+    pub fn compact(&mut self) -> usize {
+        if matches!(self.sync_status, ContextSyncStatus::Speculative) {
+            return 0;
+        }
+        let witnesses = self.witness_map.len();
+        self.witness_map
+            .retain(|note_pos, _| !self.spents.contains(note_pos));
+        self.witness_map.shrink_to_fit();
+        self.note_map.shrink_to_fit();
+        self.note_values.shrink_to_fit();
+        self.memo_map.shrink_to_fit();
+        self.div_map.shrink_to_fit();
+        self.vk_map.shrink_to_fit();
+        self.nf_map.shrink_to_fit();
+        witnesses - self.witness_map.len()
+    }
+
+    /// Estimate the size of each of the maps of this context, e.g. to tell
+    /// when it's worth compacting it. The sizes are those of the maps
+    /// serialized with Borsh, which is also roughly what they take on disk.
+    /// They're only approximate for the memory footprint, which they
+    /// underestimate by the overhead of the maps themselves. The maps are
+    /// serialized to be measured, so this is not free for a large context.
+    pub fn estimated_size(&self) -> SizeBreakdown {
+        SizeBreakdown {
+            note_map: self.note_map.serialize_to_vec().len(),
+            note_values: self.note_values.serialize_to_vec().len(),
+            nf_map: self.nf_map.serialize_to_vec().len(),
+            pos_map: self.pos_map.serialize_to_vec().len(),
+            div_map: self.div_map.serialize_to_vec().len(),
+            memo_map: self.memo_map.serialize_to_vec().len(),
+            vk_map: self.vk_map.serialize_to_vec().len(),
+            witness_map: self.witness_map.serialize_to_vec().len(),
+            unscanned: self
+                .unscanned
+                .txs
+                .lock()
+                .unwrap()
+                .serialize_to_vec()
+                .len(),
+        }
+    }
+
+    /// The viewing keys that have notes tracked by this context, in
+    /// ascending order.
+    pub fn tracked_viewing_keys(&self) -> Vec<ViewingKey> {
+        let mut keys: Vec<_> = self.pos_map.keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    /// The number of notes, spent or not, that this context has tracked for
+    /// the given viewing key.
+    pub fn note_count(&self, vk: &ViewingKey) -> usize {
+        self.pos_map.get(vk).map_or(0, BTreeSet::len)
+    }
+
+    /// The notes tracked by this context in ascending order of their
+    /// positions, which is the order of their commitments in the chain,
+    /// regardless of the iteration order of the note map.
+    pub fn notes_in_order(&self) -> impl Iterator<Item = (usize, &Note)> {
+        let mut notes: Vec<_> = self
+            .note_map
+            .iter()
+            .map(|(pos, note)| (*pos, note))
+            .collect();
+        notes.sort_unstable_by_key(|(pos, _note)| *pos);
+        notes.into_iter()
+    }
+
+    /// Export the notes, spent or not, that this context has tracked for the
+    /// given viewing key, sorted by the tx in which they were received. The
+    /// notes whose data is incomplete in the context are skipped.
+    pub fn export_notes(&self, vk: &ViewingKey) -> Vec<NoteRecord> {
+        let Some(positions) = self.pos_map.get(vk) else {
+            return vec![];
+        };
+        // Index the txs by their first note position. The txs are visited in
+        // order, so the last tx wins when several of them share a position.
+        let txs_by_pos: BTreeMap<_, _> = self
+            .tx_note_map
+            .iter()
+            .map(|(indexed_tx, first_pos)| (*first_pos, indexed_tx))
+            .collect();
+        let mut records: Vec<_> = positions
+            .iter()
+            .filter_map(|pos| {
+                let note = self.note_map.get(pos)?;
+                // The note was received in the last tx whose first note
+                // position is not past the note's
+                let (_, indexed_tx) = txs_by_pos.range(..=*pos).next_back()?;
+                Some(NoteRecord {
+                    indexed_tx: indexed_tx.clone(),
+                    asset_type: note.asset_type,
+                    amount: note.value,
+                    memo: self.memo_map.get(pos).cloned(),
+                    diversifier: *self.div_map.get(pos)?,
+                    spent: self.spents.contains(pos),
+                })
+            })
+            .collect();
+        records.sort_by(|a, b| a.indexed_tx.cmp(&b.indexed_tx));
+        records
+    }
+
     /// Compute the total unspent notes associated with the viewing key in the
     /// context. If the key is not in the context, then we do not know the
     /// balance and hence we return None.
@@ -1005,21 +2326,9 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
                 if self.spents.contains(note_idx) {
                     continue;
                 }
-                // Get note associated with this ID
-                let note = self.note_map.get(note_idx).ok_or_else(|| {
-                    Error::Other(format!("Unable to get note {note_idx}"))
-                })?;
-                // Finally add value to multi-asset accumulator
-                val_acc += I128Sum::from_nonnegative(
-                    note.asset_type,
-                    note.value as i128,
-                )
-                .map_err(|()| {
-                    Error::Other(
-                        "found note with invalid value or asset type"
-                            .to_string(),
-                    )
-                })?
+                // Finally add the value of the note associated with this ID
+                // to multi-asset accumulator
+                val_acc += self.note_value(note_idx)?;
             }
         }
         Ok(Some(val_acc))
@@ -1154,6 +2463,148 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         }
     }
 
+    /// Compute the balance of the unspent notes associated with the viewing
+    /// key, bringing every epoched asset up to the target epoch using only
+    /// the given conversions and the asset types already decoded in this
+    /// context. No queries are made to the ledger.
+    ///
+    /// Each note's asset type is decoded to find the epoch it was minted in.
+    /// Assets without an epoch, or already at or after the target epoch, are
+    /// taken as they are. For any other asset, the allowed conversion keyed
+    /// by its asset type is applied as many times as the value permits; the
+    /// conversion consumes the old asset type and produces assets in a later
+    /// epoch, which are put back into the work list. This repeats until no
+    /// asset is left below the target epoch, so that a gap of several epochs
+    /// is bridged by chaining one conversion per hop. Trace amounts too small
+    /// to be converted are kept under their original epoch.
+    ///
+    /// An error is returned if an asset type cannot be decoded, if an asset
+    /// below the target epoch has no conversion in `conversions`, or if the
+    /// conversions would loop back to an asset type that was already
+    /// converted.
+    pub fn converted_balance(
+        &self,
+        vk: &ViewingKey,
+        target_epoch: MaspEpoch,
+        conversions: &Conversions,
+    ) -> Result<MaspAmount, Error> {
+        let mut input = I128Sum::zero();
+        for note_idx in self.pos_map.get(vk).into_iter().flatten() {
+            if self.spents.contains(note_idx) {
+                continue;
+            }
+            input += self.note_value(note_idx)?;
+        }
+        let mut output = MaspAmount::zero();
+        // The assets whose conversions led to each asset. An asset may be
+        // reached through several conversions, but only loops back onto
+        // itself if it's produced by the conversion of one of its ancestors.
+        let mut ancestors: HashMap<AssetType, HashSet<AssetType>> =
+            HashMap::new();
+        while let Some((asset_type, value)) =
+            input.components().next().map(cloned_pair)
+        {
+            let decoded =
+                self.asset_types.get(&asset_type).ok_or_else(|| {
+                    Error::Other(format!(
+                        "Unable to decode asset type {asset_type}"
+                    ))
+                })?;
+            let needs_conversion = value > 0
+                && decoded.epoch.map_or(false, |epoch| epoch < target_epoch);
+            let trace = if needs_conversion {
+                let (conv, _wit, _usage) =
+                    conversions.get(&asset_type).ok_or_else(|| {
+                        Error::Other(format!(
+                            "No conversion available for asset type \
+                             {asset_type} of token {} from epoch {:?} to \
+                             epoch {target_epoch}",
+                            decoded.token, decoded.epoch
+                        ))
+                    })?;
+                let conv = I128Sum::from_sum(conv.clone().into());
+                let threshold = -conv[&asset_type];
+                if threshold <= 0 {
+                    return Err(Error::Other(format!(
+                        "Invalid conversion threshold for asset type \
+                         {asset_type}"
+                    )));
+                }
+                let mut path =
+                    ancestors.get(&asset_type).cloned().unwrap_or_default();
+                path.insert(asset_type);
+                for (produced, produced_value) in conv.components() {
+                    if *produced == asset_type || *produced_value <= 0 {
+                        continue;
+                    }
+                    if path.contains(produced) {
+                        return Err(Error::Other(format!(
+                            "Conversion of asset type {produced} loops back \
+                             onto itself"
+                        )));
+                    }
+                    ancestors
+                        .entry(*produced)
+                        .or_default()
+                        .extend(path.iter().copied());
+                }
+                let required = value / threshold;
+                input += conv * required;
+                value % threshold
+            } else {
+                value
+            };
+            input -= I128Sum::from_pair(asset_type, trace);
+            let change =
+                token::Change::from_masp_denominated(trace, decoded.position)
+                    .map_err(|_| {
+                    Error::Other(
+                        "found amount that does not fit a token change"
+                            .to_string(),
+                    )
+                })?;
+            output += MaspAmount::from_pair(
+                (decoded.epoch, decoded.token.clone()),
+                change,
+            );
+        }
+        Ok(output)
+    }
+
+    /// Compute the spendable balance of all the viewing keys tracked by this
+    /// context, bringing every epoched asset up to the target epoch as in
+    /// [`ShieldedContext::converted_balance`]. No queries are made to the
+    /// ledger.
+    ///
+    /// A speculative context also counts as spent the notes spent by the txs
+    /// built from it that may not have been applied yet. If `status` is
+    /// [`ContextSyncStatus::Confirmed`], an error is returned for a
+    /// speculative context, whose confirmed state can be loaded with
+    /// [`ShieldedContext::load_confirmed`]. With
+    /// [`ContextSyncStatus::Speculative`], the balance of the context is
+    /// computed whatever its state.
+    pub fn total_spendable(
+        &self,
+        target_epoch: MaspEpoch,
+        conversions: &Conversions,
+        status: ContextSyncStatus,
+    ) -> Result<MaspAmount, Error> {
+        if matches!(status, ContextSyncStatus::Confirmed)
+            && matches!(self.sync_status, ContextSyncStatus::Speculative)
+        {
+            return Err(Error::Other(
+                "The shielded context holds speculative data, the confirmed \
+                 balance cannot be computed from it"
+                    .to_string(),
+            ));
+        }
+        let mut total = MaspAmount::zero();
+        for vk in self.tracked_viewing_keys() {
+            total += self.converted_balance(&vk, target_epoch, conversions)?;
+        }
+        Ok(total)
+    }
+
     /// Try to convert as much of the given asset type-value pair using the
     /// given allowed conversion. usage is incremented by the amount of the
     /// conversion used, the conversions are applied to the given input, and
@@ -1353,6 +2804,7 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         ),
         Error,
     > {
+        self.ensure_spendable()?;
         let vk = &to_viewing_key(&sk.into()).vk;
         // TODO: we should try to use the smallest notes possible to fund the
         // transaction to allow people to fetch less often
@@ -2420,6 +3872,31 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         Ok(())
     }
 
+    /// Get the asset type of the given token, denomination, digit position
+    /// and epoch. The encoded asset types are cached in the context.
+    pub fn asset_type(
+        &mut self,
+        token: &Address,
+        denom: Denomination,
+        position: MaspDigitPos,
+        epoch: Option<MaspEpoch>,
+    ) -> Result<AssetType, Error> {
+        let decoded = AssetData {
+            token: token.clone(),
+            denom,
+            position,
+            epoch,
+        };
+        if let Some(asset_type) = self.asset_type_cache.get(&decoded) {
+            return Ok(*asset_type);
+        }
+        let asset_type = decoded.encode().map_err(|_| {
+            Error::Other("unable to create asset type".to_string())
+        })?;
+        self.asset_type_cache.insert(decoded, asset_type);
+        Ok(asset_type)
+    }
+
     /// Get the asset type with the given epoch, token, and denomination. If it
     /// does not exist in the protocol, then remove the timestamp. Make sure to
     /// store the derived AssetType so that future decoding is possible.
@@ -2428,16 +3905,22 @@ impl<U: ShieldedUtils + MaybeSend + MaybeSync> ShieldedContext<U> {
         client: &C,
         decoded: &mut AssetData,
     ) -> Result<AssetType, Error> {
-        let mut asset_type = decoded.encode().map_err(|_| {
-            Error::Other("unable to create asset type".to_string())
-        })?;
+        let mut asset_type = self.asset_type(
+            &decoded.token,
+            decoded.denom,
+            decoded.position,
+            decoded.epoch,
+        )?;
         if self.decode_asset_type(client, asset_type).await.is_none() {
             // If we fail to decode the epoched asset type, then remove the
             // epoch
             decoded.undate();
-            asset_type = decoded.encode().map_err(|_| {
-                Error::Other("unable to create asset type".to_string())
-            })?;
+            asset_type = self.asset_type(
+                &decoded.token,
+                decoded.denom,
+                decoded.position,
+                decoded.epoch,
+            )?;
             self.asset_types.insert(asset_type, decoded.clone());
         }
         Ok(asset_type)
@@ -2529,6 +4012,38 @@ async fn extract_masp_tx(
         })
 }
 
+/// The type of the shielded outputs of an authorized MASP transaction
+type AuthorizedOutputDescription = OutputDescription<
+    <
+        <Authorized as Authorization>::SaplingAuth
+        as masp_primitives::transaction::components::sapling::Authorization
+    >::Proof
+>;
+
+/// Trial-decrypt a shielded output with the given prepared incoming viewing
+/// key. The output is first checked with a compact decryption, which only
+/// recovers the note plaintext without the memo nor authenticating the
+/// ciphertext, and rejects the outputs that do not belong to the key at a
+/// fraction of the cost. The compact plaintext is a prefix of the full one,
+/// hence any output that fully decrypts also passes the check.
+fn try_note_decryption_prechecked(
+    ivk: &PreparedIncomingViewingKey,
+    output: &AuthorizedOutputDescription,
+) -> Option<(Note, PaymentAddress, MemoBytes)> {
+    try_sapling_compact_note_decryption(
+        &NETWORK,
+        1.into(),
+        ivk,
+        &CompactOutputDescription::from(output.clone()),
+    )?;
+    try_sapling_note_decryption::<_, AuthorizedOutputDescription>(
+        &NETWORK,
+        1.into(),
+        ivk,
+        output,
+    )
+}
+
 /// Extract the relevant shield portions from the IBC messages in [`Tx`]
 fn extract_masp_tx_from_ibc_message(
     tx: &Tx,
@@ -2715,6 +4230,60 @@ mod tests {
             &fake_params_paths[2].0,
         );
     }
+
+    /// Test reading back the MASP reward parameters of a token
+    #[test]
+    fn test_read_masp_reward_params() {
+        use std::str::FromStr;
+
+        use namada_core::address::testing::btc;
+        use namada_state::testing::TestState;
+        use namada_storage::StorageWrite;
+        use namada_token::storage_key::{
+            masp_kd_gain_key, masp_kp_gain_key, masp_locked_amount_target_key,
+            masp_max_reward_rate_key, masp_token_map_key,
+        };
+
+        let mut state = TestState::default();
+        let token = btc();
+        assert!(
+            read_masp_reward_params(&state, &token)
+                .expect("Test failed")
+                .is_none()
+        );
+
+        let rate = Dec::from_str("0.1").unwrap();
+        let gain = Dec::from_str("0.25").unwrap();
+        state
+            .write(&masp_max_reward_rate_key(&token), rate)
+            .expect("Test failed");
+        state
+            .write(&masp_kp_gain_key(&token), gain)
+            .expect("Test failed");
+        let err = read_masp_reward_params(&state, &token).unwrap_err();
+        assert!(err.to_string().contains("kd gain, locked amount target"));
+
+        state
+            .write(&masp_kd_gain_key(&token), gain)
+            .expect("Test failed");
+        state
+            .write(&masp_locked_amount_target_key(&token), Uint::from(1000))
+            .expect("Test failed");
+        let mut token_map = TokenMap::new();
+        token_map.insert("btc".to_string(), token.clone());
+        state
+            .write(&masp_token_map_key(), token_map)
+            .expect("Test failed");
+        let data = read_masp_reward_params(&state, &token)
+            .expect("Test failed")
+            .expect("Test failed");
+        assert_eq!(data.name, "btc");
+        assert_eq!(data.address, token);
+        assert_eq!(data.max_reward_rate, rate);
+        assert_eq!(data.kp_gain, gain);
+        assert_eq!(data.kd_gain, gain);
+        assert_eq!(data.locked_amount_target, Uint::from(1000));
+    }
 }
 
 #[cfg(any(test, feature = "testing"))]
@@ -3493,7 +5062,7 @@ pub mod fs {
             // Fill the supplied context with the deserialized object
             *ctx = ShieldedContext {
                 utils: ctx.utils.clone(),
-                ..ShieldedContext::<U>::deserialize(&mut &bytes[..])?
+                ..ShieldedContext::<U>::deserialize_versioned(&bytes)?
             };
             Ok(())
         }
@@ -3523,8 +5092,8 @@ pub mod fs {
                     .write(true)
                     .create_new(true)
                     .open(tmp_path.clone())?;
-                let mut bytes = Vec::new();
-                ctx.serialize(&mut bytes)
+                let bytes = ctx
+                    .serialize_versioned()
                     .expect("cannot serialize shielded context");
                 ctx_file.write_all(&bytes[..])?;
             }
@@ -3552,10 +5121,25 @@ mod test_shielded_sync {
     use std::collections::BTreeSet;
 
     use borsh::BorshDeserialize;
+    use borsh_ext::BorshSerializeExt;
+    use masp_primitives::asset_type::AssetType;
+    use masp_primitives::convert::AllowedConversion;
+    use masp_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
+    use masp_primitives::sapling::note_encryption::{
+        try_sapling_note_decryption, PreparedIncomingViewingKey,
+    };
+    use masp_primitives::sapling::{Node, Nullifier, ViewingKey};
+    use masp_primitives::transaction::components::I128Sum;
     use masp_primitives::transaction::Transaction;
-    use masp_primitives::zip32::ExtendedFullViewingKey;
-    use namada_core::masp::ExtendedViewingKey;
+    use masp_primitives::zip32::{
+        ExtendedFullViewingKey, ExtendedSpendingKey as MaspExtendedSpendingKey,
+    };
+    use namada_core::address::testing::{btc, eth, nam};
+    use namada_core::address::Address;
+    use namada_core::collections::HashSet;
+    use namada_core::masp::{ExtendedViewingKey, MaspEpoch};
     use namada_core::storage::{BlockHeight, TxIndex};
+    use namada_token::{self as token, Denomination, MaspDigitPos};
     use namada_tx::IndexedTx;
     use tempfile::tempdir;
 
@@ -3564,9 +5148,17 @@ mod test_shielded_sync {
     use crate::io::StdIo;
     use crate::masp::fs::FsShieldedUtils;
     use crate::masp::test_utils::{
-        test_client, TestUnscannedTracker, TestingMaspClient,
+        test_client, TestFetchBatchesTracker, TestKilledScanTracker,
+        TestUnscannedTracker, TestingMaspClient,
     };
     use crate::masp::utils::{DefaultTracker, ProgressTracker, RetryStrategy};
+    use crate::masp::{
+        encode_asset_type, to_viewing_key, try_note_decryption_prechecked,
+        AssetData, AuthorizedOutputDescription, ContextSyncStatus, Conversions,
+        FetchWatermarks, IndexedNoteEntry, MaspAmount, ShieldedContext,
+        ShieldedContextV0, SpeculativeDelta, SyncMode, Unscanned,
+        UnscannedSpill, UnscannedStats, NETWORK,
+    };
 
     // A viewing key derived from A_SPENDING_KEY
     pub const AA_VIEWING_KEY: &str = "zvknam1qqqqqqqqqqqqqq9v0sls5r5de7njx8ehu49pqgmqr9ygelg87l5x8y4s9r0pjlvu6x74w9gjpw856zcu826qesdre628y6tjc26uhgj6d9zqur9l5u3p99d9ggc74ald6s8y3sdtka74qmheyqvdrasqpwyv2fsmxlz57lj4grm2pthzj3sflxc0jx0edrakx3vdcngrfjmru8ywkguru8mxss2uuqxdlglaz6undx5h8w7g70t2es850g48xzdkqay5qs0yw06rtxcpjdve6";
@@ -3680,6 +5272,61 @@ mod test_shielded_sync {
         .expect("Test failed")
     }
 
+    /// The viewing key of the notes of [`arbitrary_masp_tx`]
+    fn aa_viewing_key() -> ViewingKey {
+        ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk
+    }
+
+    /// Send [`arbitrary_masp_tx`] at the first index of each of the given
+    /// heights
+    fn send_masp_txs(
+        masp_tx_sender: &flume::Sender<Option<IndexedNoteEntry>>,
+        heights: &[u64],
+    ) {
+        for height in heights {
+            masp_tx_sender
+                .send(Some((
+                    IndexedTx {
+                        height: (*height).into(),
+                        index: TxIndex(0),
+                    },
+                    vec![arbitrary_masp_tx()],
+                )))
+                .expect("Test failed");
+        }
+    }
+
+    /// Sync the given context with [`aa_viewing_key`] over the range of the
+    /// given heights, at each of which [`arbitrary_masp_tx`] is sent, and
+    /// load the synced context
+    async fn sync_masp_txs(
+        shielded_ctx: &mut ShieldedContext<FsShieldedUtils>,
+        heights: &[u64],
+    ) {
+        let (client, masp_tx_sender) = test_client(10.into());
+        let io = StdIo;
+        let progress = DefaultTracker::new(&io);
+        send_masp_txs(&masp_tx_sender, heights);
+        let start = heights.iter().min().expect("Test failed");
+        let end = heights.iter().max().expect("Test failed");
+        shielded_ctx
+            .shielded_sync_range(
+                TestingMaspClient::new(&client),
+                &progress,
+                (*start).into(),
+                (*end).into(),
+                RetryStrategy::Times(1),
+                &[aa_viewing_key()],
+            )
+            .await
+            .expect("Test failed");
+        shielded_ctx.load_confirmed().await.expect("Test failed");
+    }
+
     /// Test that if fetching fails before finishing,
     /// we re-establish the fetching process
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -3784,37 +5431,1036 @@ mod test_shielded_sync {
         assert_eq!(shielded_ctx.note_map.len(), 2);
     }
 
-    /// Test that upon each retry, we either resume from the
-    /// latest height that had been previously stored in the
-    /// `tx_note_map`, or from the minimum height stored in
-    /// `vk_heights`.
-    #[test]
-    fn test_min_height_to_sync_from() {
+    /// Test that the fetching pauses once the cache of fetched txs reaches
+    /// its high-water mark, and resumes once the cache has been scanned
+    /// down to its low-water mark
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fetch_watermarks() {
         let temp_dir = tempdir().unwrap();
         let mut shielded_ctx =
             FsShieldedUtils::new(temp_dir.path().to_path_buf());
-
+        shielded_ctx.fetch_watermarks =
+            Some(FetchWatermarks { high: 3, low: 1 });
+        let (client, masp_tx_sender) = test_client(6.into());
+        let io = StdIo;
+        let progress = TestFetchBatchesTracker::new(&io);
         let vk = ExtendedFullViewingKey::from(
             ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
         )
         .fvk
         .vk;
+        let masp_tx = arbitrary_masp_tx();
+        for height in 1..=6 {
+            masp_tx_sender
+                .send(Some((
+                    IndexedTx {
+                        height: height.into(),
+                        index: TxIndex(0),
+                    },
+                    vec![masp_tx.clone()],
+                )))
+                .expect("Test failed");
+        }
 
-        // pretend we start with a tx observed at height 4 whose
-        // notes cannot be decrypted with `vk`
-        shielded_ctx.tx_note_map.insert(
+        shielded_ctx
+            .fetch(
+                TestingMaspClient::new(&client),
+                &progress,
+                None,
+                None,
+                RetryStrategy::Times(1),
+                &[],
+                &[vk],
+            )
+            .await
+            .expect("Test failed");
+
+        // The first 3 heights fill the cache, after which 2 of them are
+        // scanned before fetching 2 more, and so on. The cache thus never
+        // holds more than 3 txs.
+        assert_eq!(progress.batches(), vec![3, 2, 1]);
+        assert!(shielded_ctx.unscanned.is_empty());
+        assert_eq!(shielded_ctx.tx_note_map.len(), 6);
+        assert_eq!(
+            *shielded_ctx.vk_heights[&vk].as_ref().unwrap(),
             IndexedTx {
-                height: 4.into(),
+                height: 6.into(),
                 index: TxIndex(0),
-            },
-            0,
+            }
         );
 
-        // the min height here should be 1, since
-        // this vk hasn't decrypted any note yet
-        shielded_ctx.vk_heights.insert(vk, None);
+        // Watermarks that could keep the fetching paused are rejected
+        for (high, low) in [(0, 0), (3, 0), (3, 3)] {
+            shielded_ctx.fetch_watermarks = Some(FetchWatermarks { high, low });
+            shielded_ctx
+                .fetch(
+                    TestingMaspClient::new(&client),
+                    &progress,
+                    None,
+                    None,
+                    RetryStrategy::Times(1),
+                    &[],
+                    &[vk],
+                )
+                .await
+                .unwrap_err();
+        }
+    }
 
-        let height = shielded_ctx.min_height_to_sync_from().unwrap();
+    /// Test that the metrics of a sync count the scanned txs, decrypted
+    /// notes and heights
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sync_metrics() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let (client, masp_tx_sender) = test_client(3.into());
+        let io = StdIo;
+        let progress = DefaultTracker::new(&io);
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+        let masp_tx = arbitrary_masp_tx();
+        for (height, index) in [(1, 0), (2, 0), (3, 0), (3, 1)] {
+            masp_tx_sender
+                .send(Some((
+                    IndexedTx {
+                        height: height.into(),
+                        index: TxIndex(index),
+                    },
+                    vec![masp_tx.clone()],
+                )))
+                .expect("Test failed");
+        }
+
+        let sync_metrics = shielded_ctx.sync_metrics.clone();
+        shielded_ctx
+            .fetch(
+                TestingMaspClient::new(&client),
+                &progress,
+                None,
+                None,
+                RetryStrategy::Times(1),
+                &[],
+                &[vk],
+            )
+            .await
+            .expect("Test failed");
+
+        let metrics = sync_metrics.lock().unwrap().clone();
+        assert_eq!(metrics.txs, 4);
+        assert_eq!(metrics.notes, 4);
+        assert_eq!(metrics.heights, 3);
+        assert_eq!(shielded_ctx.note_map.len(), 4);
+        // The clock is stopped once the sync has finished
+        assert_eq!(metrics.elapsed(), sync_metrics.lock().unwrap().elapsed());
+    }
+
+    /// Test scanning a range of heights from the middle of the chain into
+    /// an empty context
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_shielded_sync_range() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let (client, masp_tx_sender) = test_client(10.into());
+        let io = StdIo;
+        let progress = DefaultTracker::new(&io);
+        let vk = aa_viewing_key();
+
+        // an inverted range is rejected
+        shielded_ctx
+            .shielded_sync_range(
+                TestingMaspClient::new(&client),
+                &progress,
+                6.into(),
+                5.into(),
+                RetryStrategy::Times(1),
+                &[vk],
+            )
+            .await
+            .unwrap_err();
+
+        // only the blocks at heights 5 and 6 are fetched
+        send_masp_txs(&masp_tx_sender, &[5, 6]);
+        shielded_ctx
+            .shielded_sync_range(
+                TestingMaspClient::new(&client),
+                &progress,
+                5.into(),
+                6.into(),
+                RetryStrategy::Times(1),
+                &[vk],
+            )
+            .await
+            .expect("Test failed");
+
+        shielded_ctx.load_confirmed().await.expect("Test failed");
+        let keys = shielded_ctx
+            .tx_note_map
+            .keys()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let last_tx = IndexedTx {
+            height: 6.into(),
+            index: TxIndex(0),
+        };
+        let expected = BTreeSet::from([
+            IndexedTx {
+                height: 5.into(),
+                index: TxIndex(0),
+            },
+            last_tx.clone(),
+        ]);
+        assert_eq!(keys, expected);
+        assert_eq!(*shielded_ctx.vk_heights[&vk].as_ref().unwrap(), last_tx);
+        assert_eq!(shielded_ctx.note_map.len(), 2);
+        assert!(shielded_ctx.unscanned.is_empty());
+    }
+
+    /// Test that a viewing key added to a synced context is synced from its
+    /// birthday only, without changing the state of the other keys
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sync_new_viewing_key() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let (client, masp_tx_sender) = test_client(3.into());
+        let io = StdIo;
+        let progress = DefaultTracker::new(&io);
+        let vk = aa_viewing_key();
+        let other_vk =
+            to_viewing_key(&MaspExtendedSpendingKey::master(&[1; 32])).vk;
+        let last_tx = IndexedTx {
+            height: 3.into(),
+            index: TxIndex(0),
+        };
+
+        // Fully sync the context with a key that doesn't own any note
+        send_masp_txs(&masp_tx_sender, &[1, 2, 3]);
+        shielded_ctx
+            .fetch(
+                TestingMaspClient::new(&client),
+                &progress,
+                None,
+                None,
+                RetryStrategy::Times(1),
+                &[],
+                &[other_vk],
+            )
+            .await
+            .expect("Test failed");
+        assert_eq!(shielded_ctx.note_count(&other_vk), 0);
+
+        // Add a key born at height 2, only the blocks from its birthday are
+        // fetched again
+        send_masp_txs(&masp_tx_sender, &[2, 3]);
+        shielded_ctx
+            .sync_new_viewing_key(
+                TestingMaspClient::new(&client),
+                &progress,
+                vk,
+                2.into(),
+                RetryStrategy::Times(1),
+            )
+            .await
+            .expect("Test failed");
+
+        shielded_ctx.load_confirmed().await.expect("Test failed");
+        assert_eq!(shielded_ctx.note_count(&vk), 2);
+        assert!(shielded_ctx.vk_map.values().all(|owner| *owner == vk));
+        assert_eq!(*shielded_ctx.vk_heights[&vk].as_ref().unwrap(), last_tx);
+        assert_eq!(shielded_ctx.note_count(&other_vk), 0);
+        assert_eq!(
+            *shielded_ctx.vk_heights[&other_vk].as_ref().unwrap(),
+            last_tx
+        );
+        assert_eq!(shielded_ctx.tx_note_map.len(), 3);
+        assert!(shielded_ctx.unscanned.is_empty());
+
+        // A tracked key cannot be added again
+        shielded_ctx
+            .sync_new_viewing_key(
+                TestingMaspClient::new(&client),
+                &progress,
+                vk,
+                1.into(),
+                RetryStrategy::Times(1),
+            )
+            .await
+            .unwrap_err();
+    }
+
+    /// Test that the cached asset types are the encoded ones
+    #[test]
+    fn test_asset_type_cache() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let token = btc();
+        let denom = Denomination(8);
+        for epoch in [None, Some(MaspEpoch::new(1))] {
+            for position in MaspDigitPos::iter() {
+                let expected = crate::masp::encode_asset_type(
+                    token.clone(),
+                    denom,
+                    position,
+                    epoch,
+                )
+                .unwrap();
+                for _ in 0..2 {
+                    let asset_type = shielded_ctx
+                        .asset_type(&token, denom, position, epoch)
+                        .unwrap();
+                    assert_eq!(asset_type, expected);
+                }
+            }
+        }
+        assert_eq!(shielded_ctx.asset_type_cache.len(), 8);
+    }
+
+    /// Test marking the notes spent from a set of on-chain nullifiers
+    #[test]
+    fn test_mark_spent_from() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+        for note_pos in 0..4 {
+            let nf = Nullifier([u8::try_from(note_pos).unwrap(); 32]);
+            shielded_ctx.nf_map.insert(nf, note_pos);
+        }
+        shielded_ctx
+            .pos_map
+            .insert(vk, BTreeSet::from([0, 1, 2, 3]));
+        shielded_ctx.spents.insert(3);
+
+        // An unknown nullifier is ignored and a spent note is not counted
+        let onchain_nullifiers = HashSet::from_iter([
+            Nullifier([0; 32]),
+            Nullifier([2; 32]),
+            Nullifier([3; 32]),
+            Nullifier([9; 32]),
+        ]);
+        assert_eq!(shielded_ctx.mark_spent_from(&onchain_nullifiers), 2);
+        assert!(shielded_ctx.is_spent(0));
+        assert!(!shielded_ctx.is_spent(1));
+        assert!(shielded_ctx.is_spent(2));
+        assert!(shielded_ctx.is_spent(3));
+        assert_eq!(shielded_ctx.spendable_notes(&vk), vec![1]);
+
+        // Marking the notes again is a no-op
+        assert_eq!(shielded_ctx.mark_spent_from(&onchain_nullifiers), 0);
+        assert_eq!(shielded_ctx.spents.len(), 3);
+    }
+
+    /// Register the asset type of the given token at the given epoch in the
+    /// context
+    fn register_asset(
+        shielded_ctx: &mut ShieldedContext<FsShieldedUtils>,
+        token: Address,
+        epoch: u64,
+    ) -> AssetType {
+        let asset_data = AssetData {
+            token,
+            denom: Denomination(0),
+            position: MaspDigitPos::Zero,
+            epoch: Some(MaspEpoch::new(epoch)),
+        };
+        let asset_type = asset_data.encode().unwrap();
+        shielded_ctx.asset_types.insert(asset_type, asset_data);
+        asset_type
+    }
+
+    /// Build the conversions of the given assets, each made of the asset
+    /// converted and the assets it is converted to
+    fn conversions(
+        convert: impl IntoIterator<Item = (AssetType, Vec<AssetType>)>,
+    ) -> Conversions {
+        let mut tree = CommitmentTree::<Node>::empty();
+        tree.append(Node::new([0; 32])).unwrap();
+        let path = IncrementalWitness::from_tree(&tree).path().unwrap();
+        convert
+            .into_iter()
+            .map(|(from, to)| {
+                let conv = to
+                    .into_iter()
+                    .fold(I128Sum::from_pair(from, -1), |conv, asset_type| {
+                        conv + I128Sum::from_pair(asset_type, 1)
+                    });
+                (from, (AllowedConversion::from(conv), path.clone(), 0))
+            })
+            .collect()
+    }
+
+    /// Test converting a balance through conversions that share an
+    /// intermediate asset, and detecting a conversion cycle
+    #[test]
+    fn test_converted_balance() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+        let [a0, a1, a2] =
+            [0, 1, 2].map(|e| register_asset(&mut shielded_ctx, btc(), e));
+        let [b0, b1, b2] =
+            [0, 1, 2].map(|e| register_asset(&mut shielded_ctx, eth(), e));
+        let [n1, n2] =
+            [1, 2].map(|e| register_asset(&mut shielded_ctx, nam(), e));
+        for (pos, asset_type) in [(0, a0), (1, b0)] {
+            shielded_ctx.note_values.insert(pos, (asset_type, 10));
+            shielded_ctx.pos_map.entry(vk).or_default().insert(pos);
+        }
+
+        // Both tokens are rewarded in the native token at each epoch, whose
+        // conversions are shared
+        let convs = conversions([
+            (a0, vec![a1, n1]),
+            (a1, vec![a2, n2]),
+            (b0, vec![b1, n1]),
+            (b1, vec![b2, n2]),
+            (n1, vec![n2]),
+        ]);
+        let balance = shielded_ctx
+            .converted_balance(&vk, MaspEpoch::new(2), &convs)
+            .unwrap();
+        let epoch = Some(MaspEpoch::new(2));
+        let expected = MaspAmount::from_pair((epoch, btc()), 10_i64.into())
+            + MaspAmount::from_pair((epoch, eth()), 10_i64.into())
+            + MaspAmount::from_pair((epoch, nam()), 40_i64.into());
+        assert_eq!(balance, expected);
+
+        // The conversions of two assets of the same epoch lead back to each
+        // other
+        let convs = conversions([(a0, vec![b0]), (b0, vec![a0])]);
+        assert!(
+            shielded_ctx
+                .converted_balance(&vk, MaspEpoch::new(2), &convs)
+                .is_err()
+        );
+    }
+
+    /// Test checking that a viewing key matches a spending key
+    #[test]
+    fn test_verify_key_pair() {
+        let sk = MaspExtendedSpendingKey::master(&[1; 32]);
+        let other_sk = MaspExtendedSpendingKey::master(&[2; 32]);
+        let vk = ExtendedFullViewingKey::from(&sk).fvk.vk;
+        assert!(ShieldedContext::<FsShieldedUtils>::verify_key_pair(
+            &vk, &sk
+        ));
+        assert!(!ShieldedContext::<FsShieldedUtils>::verify_key_pair(
+            &vk, &other_sk
+        ));
+    }
+
+    /// Test exporting the notes of a viewing key
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_export_notes() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let vk = aa_viewing_key();
+        assert!(shielded_ctx.export_notes(&vk).is_empty());
+
+        sync_masp_txs(&mut shielded_ctx, &[5, 6]).await;
+
+        // Spend the note received at height 6
+        let spent_pos = shielded_ctx.tx_note_map[&IndexedTx {
+            height: 6.into(),
+            index: TxIndex(0),
+        }];
+        shielded_ctx.spents.insert(spent_pos);
+
+        let records = shielded_ctx.export_notes(&vk);
+        let heights: Vec<_> = records
+            .iter()
+            .map(|record| record.indexed_tx.height)
+            .collect();
+        assert_eq!(heights, vec![BlockHeight(5), BlockHeight(6)]);
+        let spent: Vec<_> = records.iter().map(|record| record.spent).collect();
+        assert_eq!(spent, vec![false, true]);
+        for record in &records {
+            let pos = shielded_ctx.tx_note_map[&record.indexed_tx];
+            assert_eq!(record.amount, shielded_ctx.note_map[&pos].value);
+            assert_eq!(record.diversifier, shielded_ctx.div_map[&pos]);
+        }
+    }
+
+    /// Test summing the spendable balances of two keys holding different
+    /// assets
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_total_spendable() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let vk = aa_viewing_key();
+        let other_vk =
+            to_viewing_key(&MaspExtendedSpendingKey::master(&[1; 32])).vk;
+        let epoch = MaspEpoch::new(1);
+        assert!(
+            shielded_ctx
+                .total_spendable(
+                    epoch,
+                    &Default::default(),
+                    ContextSyncStatus::Confirmed
+                )
+                .unwrap()
+                .is_zero()
+        );
+
+        sync_masp_txs(&mut shielded_ctx, &[5]).await;
+
+        // Give the received note a known asset type, and copies of it in
+        // another asset type to the other key, one of which is spent
+        let pos = shielded_ctx.tx_note_map[&IndexedTx {
+            height: 5.into(),
+            index: TxIndex(0),
+        }];
+        let mut note = shielded_ctx.note_map[&pos];
+        let mut asset_type = |token: Address| {
+            let asset_data = AssetData {
+                token,
+                denom: Denomination(6),
+                position: MaspDigitPos::Zero,
+                epoch: None,
+            };
+            let asset_type = encode_asset_type(
+                asset_data.token.clone(),
+                asset_data.denom,
+                asset_data.position,
+                asset_data.epoch,
+            )
+            .unwrap();
+            shielded_ctx.asset_types.insert(asset_type, asset_data);
+            asset_type
+        };
+        let btc_asset_type = asset_type(btc());
+        let nam_asset_type = asset_type(nam());
+        note.asset_type = btc_asset_type;
+        shielded_ctx.note_map.insert(pos, note);
+        note.asset_type = nam_asset_type;
+        for other_pos in [pos + 1, pos + 2] {
+            shielded_ctx.note_map.insert(other_pos, note);
+            shielded_ctx
+                .pos_map
+                .entry(other_vk)
+                .or_default()
+                .insert(other_pos);
+        }
+        shielded_ctx.spents.insert(pos + 2);
+
+        let amount = |token: Address| {
+            MaspAmount::from_pair(
+                (None, token),
+                token::Change::from_masp_denominated(
+                    note.value.into(),
+                    MaspDigitPos::Zero,
+                )
+                .unwrap(),
+            )
+        };
+        let mut expected = amount(btc());
+        expected += amount(nam());
+        assert_eq!(
+            shielded_ctx
+                .total_spendable(
+                    epoch,
+                    &Default::default(),
+                    ContextSyncStatus::Confirmed
+                )
+                .unwrap(),
+            expected
+        );
+
+        // The confirmed balance cannot be computed from a speculative
+        // context
+        shielded_ctx.sync_status = ContextSyncStatus::Speculative;
+        assert!(
+            shielded_ctx
+                .total_spendable(
+                    epoch,
+                    &Default::default(),
+                    ContextSyncStatus::Confirmed
+                )
+                .is_err()
+        );
+        assert_eq!(
+            shielded_ctx
+                .total_spendable(
+                    epoch,
+                    &Default::default(),
+                    ContextSyncStatus::Speculative
+                )
+                .unwrap(),
+            expected
+        );
+    }
+
+    /// Test validating the notes spent by a transfer before submitting it
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_validate_spent_notes() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        sync_masp_txs(&mut shielded_ctx, &[5]).await;
+
+        let pos = shielded_ctx.tx_note_map[&IndexedTx {
+            height: 5.into(),
+            index: TxIndex(0),
+        }];
+        let (nf, _) = shielded_ctx
+            .nf_map
+            .iter()
+            .find(|(_, note_pos)| **note_pos == pos)
+            .expect("Test failed");
+        let nf = *nf;
+        shielded_ctx
+            .validate_spent_nullifiers([&nf])
+            .expect("Test failed");
+
+        // A note unknown to the context cannot be validated
+        let err = shielded_ctx
+            .validate_spent_nullifiers([&Nullifier([0; 32])])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The note of the spend 0 of the transfer is not tracked by the \
+             shielded context"
+        );
+
+        // The note has meanwhile been spent by another tx
+        shielded_ctx.spents.insert(pos);
+        let err = shielded_ctx.validate_spent_nullifiers([&nf]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The note at position {pos} spent by the transfer has already \
+                 been spent"
+            )
+        );
+    }
+
+    /// Test that the notes are iterated in the order of their positions,
+    /// whatever the order in which they were inserted
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_notes_in_order() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        sync_masp_txs(&mut shielded_ctx, &[5, 6, 7]).await;
+
+        let in_order = |ctx: &ShieldedContext<FsShieldedUtils>| {
+            ctx.notes_in_order()
+                .map(|(pos, note)| (pos, note.asset_type, note.value))
+                .collect::<Vec<_>>()
+        };
+        let notes = in_order(&shielded_ctx);
+        assert_eq!(notes.len(), 3);
+        assert!(notes.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // The same notes inserted in the reverse order
+        let mut reversed_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let mut positions: Vec<_> =
+            shielded_ctx.note_map.keys().copied().collect();
+        positions.sort_unstable_by(|a, b| b.cmp(a));
+        for pos in positions {
+            reversed_ctx
+                .note_map
+                .insert(pos, shielded_ctx.note_map[&pos]);
+        }
+        assert_eq!(in_order(&reversed_ctx), notes);
+    }
+
+    /// Test tracking the notes spent by a speculative tx until it's settled
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_speculative_entries() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        sync_masp_txs(&mut shielded_ctx, &[5]).await;
+        let (nf, pos) = shielded_ctx
+            .nf_map
+            .iter()
+            .next()
+            .map(|(nf, pos)| (*nf, *pos))
+            .expect("Test failed");
+        let pending = IndexedTx {
+            height: 5.into(),
+            index: TxIndex(1),
+        };
+
+        // Spends of confirmed txs are not recorded
+        assert_eq!(shielded_ctx.spend_note(&pending, &nf), Some(pos));
+        assert!(shielded_ctx.speculative_entries().is_empty());
+        shielded_ctx.spents.clear();
+
+        // Spend the note in a speculative tx
+        shielded_ctx.sync_status = ContextSyncStatus::Speculative;
+        assert_eq!(shielded_ctx.spend_note(&pending, &nf), Some(pos));
+        assert_eq!(
+            shielded_ctx.spend_note(&pending, &Nullifier([0; 32])),
+            None
+        );
+        let delta = SpeculativeDelta {
+            spent_notes: BTreeSet::from([pos]),
+            nullifiers: vec![nf],
+        };
+        assert_eq!(
+            shielded_ctx.speculative_entries(),
+            vec![(pending.clone(), delta.clone())]
+        );
+        assert!(shielded_ctx.spents.contains(&pos));
+
+        // The tx is rejected, so the note can be spent again
+        assert_eq!(
+            shielded_ctx.reconcile_speculative(&pending, false),
+            Some(delta.clone())
+        );
+        assert!(shielded_ctx.speculative_entries().is_empty());
+        assert!(!shielded_ctx.spents.contains(&pos));
+        assert_eq!(shielded_ctx.reconcile_speculative(&pending, false), None);
+
+        // The tx is confirmed, so the note stays spent
+        shielded_ctx.spend_note(&pending, &nf);
+        assert_eq!(
+            shielded_ctx.reconcile_speculative(&pending, true),
+            Some(delta)
+        );
+        assert!(shielded_ctx.speculative_entries().is_empty());
+        assert!(shielded_ctx.spents.contains(&pos));
+    }
+
+    /// Test that a context saved in the unversioned layout is still loaded
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_load_unversioned_context() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        sync_masp_txs(&mut shielded_ctx, &[5]).await;
+        assert!(!shielded_ctx.note_map.is_empty());
+
+        // Overwrite the saved context with its unversioned layout
+        let unversioned = ShieldedContextV0 {
+            tree: shielded_ctx.tree.clone(),
+            vk_heights: shielded_ctx.vk_heights.clone(),
+            pos_map: shielded_ctx.pos_map.clone(),
+            nf_map: shielded_ctx.nf_map.clone(),
+            note_map: shielded_ctx.note_map.clone(),
+            memo_map: shielded_ctx.memo_map.clone(),
+            div_map: shielded_ctx.div_map.clone(),
+            witness_map: shielded_ctx.witness_map.clone(),
+            spents: shielded_ctx.spents.clone(),
+            asset_types: shielded_ctx.asset_types.clone(),
+            vk_map: shielded_ctx.vk_map.clone(),
+            tx_note_map: shielded_ctx.tx_note_map.clone(),
+            unscanned: Default::default(),
+            sync_status: ContextSyncStatus::Confirmed,
+        };
+        std::fs::write(
+            temp_dir.path().join("shielded.dat"),
+            unversioned.serialize_to_vec(),
+        )
+        .expect("Test failed");
+
+        let mut loaded = FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        loaded.load_confirmed().await.expect("Test failed");
+        assert_eq!(loaded.note_map.len(), shielded_ctx.note_map.len());
+        assert_eq!(loaded.nf_map, shielded_ctx.nf_map);
+        assert_eq!(loaded.tx_note_map, shielded_ctx.tx_note_map);
+        assert_eq!(loaded.sync_mode, SyncMode::Full);
+        assert!(loaded.speculative_txs.is_empty());
+        assert!(loaded.note_values.is_empty());
+
+        // It's saved back in the versioned layout
+        loaded.save().await.expect("Test failed");
+        let bytes = std::fs::read(temp_dir.path().join("shielded.dat"))
+            .expect("Test failed");
+        assert_eq!(bytes[..8], *b"NAMSHCTX");
+        let reloaded =
+            ShieldedContext::<FsShieldedUtils>::deserialize_versioned(&bytes)
+                .expect("Test failed");
+        assert_eq!(reloaded.nf_map, shielded_ctx.nf_map);
+
+        // An unknown version is refused
+        assert!(
+            ShieldedContext::<FsShieldedUtils>::deserialize_versioned(
+                b"NAMSHCTX\x02"
+            )
+            .is_err()
+        );
+    }
+
+    /// Test that the estimated sizes of the maps grow with the synced notes
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_estimated_size() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let empty = shielded_ctx.estimated_size();
+        sync_masp_txs(&mut shielded_ctx, &[5, 6]).await;
+
+        let size = shielded_ctx.estimated_size();
+        assert!(size.note_map > empty.note_map);
+        assert!(size.nf_map > empty.nf_map);
+        assert!(size.pos_map > empty.pos_map);
+        assert!(size.div_map > empty.div_map);
+        assert!(size.memo_map > empty.memo_map);
+        assert!(size.vk_map > empty.vk_map);
+        assert!(size.witness_map > empty.witness_map);
+        // No notes are kept in the balance-only sync mode and the fetched
+        // txs have all been scanned
+        assert_eq!(size.note_values, empty.note_values);
+        assert_eq!(size.unscanned, empty.unscanned);
+        assert_eq!(
+            size.total(),
+            size.note_map
+                + size.nf_map
+                + size.pos_map
+                + size.div_map
+                + size.memo_map
+                + size.vk_map
+                + size.witness_map
+                + size.note_values
+                + size.unscanned
+        );
+    }
+
+    /// Test that pre-checking the outputs with a compact decryption yields
+    /// the same notes as a plain trial decryption
+    #[test]
+    fn test_note_decryption_prechecked() {
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+        let other_vk =
+            to_viewing_key(&MaspExtendedSpendingKey::master(&[1; 32])).vk;
+        let tx = arbitrary_masp_tx();
+        let outputs =
+            &tx.sapling_bundle().expect("Test failed").shielded_outputs;
+        let mut decrypted = 0;
+        for vk in [vk, other_vk] {
+            let ivk = PreparedIncomingViewingKey::new(&vk.ivk());
+            for so in outputs {
+                let expected = try_sapling_note_decryption::<
+                    _,
+                    AuthorizedOutputDescription,
+                >(&NETWORK, 1.into(), &ivk, so)
+                .map(|(note, pa, memo)| (note.cmu(), pa, memo));
+                let prechecked = try_note_decryption_prechecked(&ivk, so)
+                    .map(|(note, pa, memo)| (note.cmu(), pa, memo));
+                decrypted += usize::from(prechecked.is_some());
+                assert_eq!(prechecked, expected);
+            }
+        }
+        // Only the note sent to the first key is decrypted
+        assert_eq!(decrypted, 1);
+    }
+
+    /// Test that compacting the context keeps the balance and the unspent
+    /// notes spendable
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_compact() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let vk = aa_viewing_key();
+        sync_masp_txs(&mut shielded_ctx, &[5, 6]).await;
+
+        let note_pos = |height: u64| {
+            shielded_ctx.tx_note_map[&IndexedTx {
+                height: height.into(),
+                index: TxIndex(0),
+            }]
+        };
+        let (spent, unspent) = (note_pos(5), note_pos(6));
+        let unspent_nf = *shielded_ctx
+            .nf_map
+            .iter()
+            .find(|(_, pos)| **pos == unspent)
+            .expect("Test failed")
+            .0;
+        // Spend the first note
+        shielded_ctx.spents.insert(spent);
+        let balance = shielded_ctx
+            .compute_shielded_balance(&vk)
+            .await
+            .expect("Test failed");
+
+        // A speculative context is left as is
+        shielded_ctx.sync_status = ContextSyncStatus::Speculative;
+        assert_eq!(shielded_ctx.compact(), 0);
+        shielded_ctx.sync_status = ContextSyncStatus::Confirmed;
+
+        assert_eq!(shielded_ctx.compact(), 1);
+        assert!(!shielded_ctx.witness_map.contains_key(&spent));
+        assert_eq!(
+            shielded_ctx.witness_map[&unspent].root(),
+            shielded_ctx.tree.root()
+        );
+        shielded_ctx
+            .validate_spent_nullifiers([&unspent_nf])
+            .expect("Test failed");
+        assert_eq!(
+            shielded_ctx
+                .compute_shielded_balance(&vk)
+                .await
+                .expect("Test failed"),
+            balance
+        );
+        // Compacting again is a no-op
+        assert_eq!(shielded_ctx.compact(), 0);
+    }
+
+    /// Test that the balances computed in the balance-only sync mode match
+    /// those of the full mode, without the data to spend the notes
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_balance_only_sync() {
+        let vk = aa_viewing_key();
+        let mut contexts = vec![];
+        for sync_mode in [SyncMode::Full, SyncMode::BalanceOnly] {
+            let temp_dir = tempdir().unwrap();
+            let mut shielded_ctx =
+                FsShieldedUtils::new(temp_dir.path().to_path_buf());
+            shielded_ctx.sync_mode = sync_mode;
+            sync_masp_txs(&mut shielded_ctx, &[5, 6]).await;
+            // Spend the first note
+            let spent = shielded_ctx.tx_note_map[&IndexedTx {
+                height: 5.into(),
+                index: TxIndex(0),
+            }];
+            shielded_ctx.spents.insert(spent);
+            contexts.push((temp_dir, shielded_ctx));
+        }
+        let [(_, mut full_ctx), (_, mut balance_ctx)] =
+            <[_; 2]>::try_from(contexts).ok().expect("Test failed");
+
+        assert_eq!(balance_ctx.sync_mode, SyncMode::BalanceOnly);
+        assert!(balance_ctx.note_map.is_empty());
+        assert!(balance_ctx.div_map.is_empty());
+        assert!(balance_ctx.witness_map.is_empty());
+        assert_eq!(balance_ctx.note_values.len(), full_ctx.note_map.len());
+        assert_eq!(balance_ctx.nf_map, full_ctx.nf_map);
+        let balance = full_ctx
+            .compute_shielded_balance(&vk)
+            .await
+            .expect("Test failed");
+        assert!(balance.is_some());
+        assert_eq!(
+            balance_ctx
+                .compute_shielded_balance(&vk)
+                .await
+                .expect("Test failed"),
+            balance
+        );
+
+        // The notes cannot be spent in the balance-only mode
+        let nf = *balance_ctx
+            .nf_map
+            .iter()
+            .find(|(_, pos)| !balance_ctx.spents.contains(*pos))
+            .expect("Test failed")
+            .0;
+        assert!(full_ctx.validate_spent_nullifiers([&nf]).is_ok());
+        assert!(balance_ctx.validate_spent_nullifiers([&nf]).is_err());
+
+        // Switching a full context to the balance-only mode keeps its balance
+        full_ctx.set_balance_only();
+        assert!(full_ctx.note_map.is_empty());
+        assert!(full_ctx.witness_map.is_empty());
+        assert_eq!(
+            full_ctx
+                .compute_shielded_balance(&vk)
+                .await
+                .expect("Test failed"),
+            balance
+        );
+    }
+
+    /// Test that `fetch` keeps the balance-only mode of a context, both for a
+    /// new wallet and for a wallet previously synced in the full mode
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_fetch_balance_only() {
+        let new_dir = tempdir().unwrap();
+        let synced_dir = tempdir().unwrap();
+        let mut full_ctx =
+            FsShieldedUtils::new(synced_dir.path().to_path_buf());
+        sync_masp_txs(&mut full_ctx, &[1]).await;
+        assert!(!full_ctx.note_map.is_empty());
+
+        for (dir, height) in [(&new_dir, 1), (&synced_dir, 2)] {
+            let mut shielded_ctx =
+                FsShieldedUtils::new(dir.path().to_path_buf());
+            shielded_ctx.sync_mode = SyncMode::BalanceOnly;
+            let (client, masp_tx_sender) = test_client(height.into());
+            let io = StdIo;
+            let progress = DefaultTracker::new(&io);
+            send_masp_txs(&masp_tx_sender, &[height]);
+            shielded_ctx
+                .fetch(
+                    TestingMaspClient::new(&client),
+                    &progress,
+                    Some(height.into()),
+                    Some(height.into()),
+                    RetryStrategy::Times(1),
+                    &[],
+                    &[aa_viewing_key()],
+                )
+                .await
+                .expect("Test failed");
+            shielded_ctx.load_confirmed().await.expect("Test failed");
+
+            assert_eq!(shielded_ctx.sync_mode, SyncMode::BalanceOnly);
+            assert!(shielded_ctx.note_map.is_empty());
+            assert!(shielded_ctx.witness_map.is_empty());
+            assert!(!shielded_ctx.note_values.is_empty());
+        }
+    }
+
+    /// Test that upon each retry, we either resume from the
+    /// latest height that had been previously stored in the
+    /// `tx_note_map`, or from the minimum height stored in
+    /// `vk_heights`.
+    #[test]
+    fn test_min_height_to_sync_from() {
+        let temp_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+
+        // pretend we start with a tx observed at height 4 whose
+        // notes cannot be decrypted with `vk`
+        shielded_ctx.tx_note_map.insert(
+            IndexedTx {
+                height: 4.into(),
+                index: TxIndex(0),
+            },
+            0,
+        );
+
+        // the min height here should be 1, since
+        // this vk hasn't decrypted any note yet
+        shielded_ctx.vk_heights.insert(vk, None);
+
+        let height = shielded_ctx.min_height_to_sync_from().unwrap();
         assert_eq!(height, BlockHeight(1));
 
         // let's bump the vk height past 4
@@ -4037,6 +6683,161 @@ mod test_shielded_sync {
         assert_eq!(keys, expected);
     }
 
+    /// Test that duplicate insertions into the unscanned cache are
+    /// reported and that its statistics track partially scanned heights
+    #[test]
+    fn test_unscanned_stats() {
+        let unscanned = Unscanned::default();
+        assert_eq!(unscanned.stats(), UnscannedStats::default());
+
+        let masp_tx = arbitrary_masp_tx();
+        let ix = |height: u64, index: u32| IndexedTx {
+            height: height.into(),
+            index: TxIndex(index),
+        };
+        assert!(!unscanned.insert((ix(1, 1), vec![masp_tx.clone()])));
+        assert!(!unscanned.insert((ix(1, 2), vec![masp_tx.clone()])));
+        assert!(!unscanned.insert((ix(3, 0), vec![masp_tx.clone()])));
+        assert!(unscanned.insert((ix(3, 0), vec![masp_tx.clone()])));
+        assert_eq!(
+            unscanned.stats(),
+            UnscannedStats {
+                txs: 3,
+                height_span: Some((1.into(), 3.into())),
+                full_heights: 2,
+                partial_heights: 0,
+            }
+        );
+
+        unscanned.scanned(&ix(1, 1));
+        assert_eq!(
+            unscanned.stats(),
+            UnscannedStats {
+                txs: 2,
+                height_span: Some((1.into(), 3.into())),
+                full_heights: 1,
+                partial_heights: 1,
+            }
+        );
+
+        unscanned.scanned(&ix(1, 2));
+        assert_eq!(
+            unscanned.stats(),
+            UnscannedStats {
+                txs: 1,
+                height_span: Some((3.into(), 3.into())),
+                full_heights: 1,
+                partial_heights: 0,
+            }
+        );
+    }
+
+    /// Test that the unscanned cache spilled to disk yields the same
+    /// entries, with the heights read back one at a time
+    #[test]
+    fn test_unscanned_disk_spill() {
+        let temp_dir = tempdir().unwrap();
+        let spilled_files =
+            || std::fs::read_dir(temp_dir.path()).unwrap().count();
+        let unscanned = Unscanned::default();
+        let masp_tx = arbitrary_masp_tx();
+        let ix = |height: u64, index: u32| IndexedTx {
+            height: height.into(),
+            index: TxIndex(index),
+        };
+        for ix in [ix(1, 0), ix(2, 0), ix(2, 1), ix(3, 0), ix(4, 0)] {
+            assert!(!unscanned.insert((ix, vec![masp_tx.clone()])));
+        }
+
+        // Only the first height is kept in memory
+        unscanned.enable_disk_spill(temp_dir.path(), 1);
+        assert_eq!(spilled_files(), 3);
+        assert_eq!(unscanned.txs.lock().unwrap().len(), 1);
+        assert!(unscanned.contains_height(3));
+        assert_eq!(unscanned.latest_height(), BlockHeight(4));
+
+        // A tx of a spilled height is added to its file
+        assert!(!unscanned.insert((ix(3, 1), vec![masp_tx.clone()])));
+        assert!(unscanned.insert((ix(3, 1), vec![masp_tx.clone()])));
+        assert_eq!(spilled_files(), 3);
+        assert_eq!(
+            unscanned.stats(),
+            UnscannedStats {
+                txs: 6,
+                height_span: Some((1.into(), 4.into())),
+                full_heights: 4,
+                partial_heights: 0,
+            }
+        );
+        let expected =
+            vec![ix(1, 0), ix(2, 0), ix(2, 1), ix(3, 0), ix(3, 1), ix(4, 0)];
+
+        // The spilled txs are serialized with the others
+        let deserialized =
+            Unscanned::try_from_slice(&unscanned.serialize_to_vec()).unwrap();
+        let keys = deserialized
+            .into_iter()
+            .map(|(ix, _)| ix)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        // The entries are popped in order, reading back the spilled
+        // heights as needed
+        let mut keys = vec![];
+        while let Some((ix, _)) = unscanned.pop_first() {
+            assert!(unscanned.txs.lock().unwrap().len() <= 2);
+            keys.push(ix);
+        }
+        assert_eq!(keys, expected);
+        assert!(unscanned.is_empty());
+        assert_eq!(spilled_files(), 0);
+
+        // A spilled height that cannot be read back stops the cache short
+        // of it and is reported
+        for ix in [ix(1, 0), ix(2, 0)] {
+            assert!(!unscanned.insert((ix, vec![masp_tx.clone()])));
+        }
+        std::fs::write(temp_dir.path().join("unscanned-2.borsh"), [0xff])
+            .unwrap();
+        assert_eq!(unscanned.pop_first().map(|(ix, _)| ix), Some(ix(1, 0)));
+        assert!(unscanned.pop_first().is_none());
+        assert!(unscanned.take_spill_error().is_some());
+        assert!(unscanned.take_spill_error().is_none());
+        assert!(unscanned.contains_height(2));
+    }
+
+    /// Test that the cache of fetched txs keeps spilling to disk once the
+    /// context is reloaded
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_unscanned_spill_reload() {
+        let temp_dir = tempdir().unwrap();
+        let spill_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let spill = UnscannedSpill {
+            dir: spill_dir.path().to_path_buf(),
+            max_heights_in_memory: 1,
+        };
+        shielded_ctx.set_unscanned_spill(Some(spill.clone()));
+        let masp_tx = arbitrary_masp_tx();
+        for height in [1, 2, 3] {
+            shielded_ctx.unscanned.insert((
+                IndexedTx {
+                    height: height.into(),
+                    index: TxIndex(0),
+                },
+                vec![masp_tx.clone()],
+            ));
+        }
+        shielded_ctx.save().await.expect("Test failed");
+
+        shielded_ctx.load_confirmed().await.expect("Test failed");
+        assert_eq!(shielded_ctx.unscanned_spill, Some(spill));
+        assert_eq!(shielded_ctx.unscanned.len(), 3);
+        assert_eq!(shielded_ctx.unscanned.txs.lock().unwrap().len(), 1);
+        assert_eq!(std::fs::read_dir(spill_dir.path()).unwrap().count(), 2);
+    }
+
     /// Test that if fetching gets interrupted,
     /// we persist the fetched notes in a cache
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -4089,4 +6890,94 @@ mod test_shielded_sync {
         assert_eq!(entry, expected);
         assert!(shielded_ctx.unscanned.is_empty());
     }
+
+    /// Test that if scanning gets killed, the saved context and
+    /// cache of unscanned txs are consistent with each other and
+    /// the sync can be resumed from them
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_scanning_checkpoint() {
+        let temp_dir = tempdir().unwrap();
+        let killed_dir = tempdir().unwrap();
+        let mut shielded_ctx =
+            FsShieldedUtils::new(temp_dir.path().to_path_buf());
+        let (client, masp_tx_sender) = test_client(3.into());
+        let io = StdIo;
+        let progress = TestKilledScanTracker::new(
+            &io,
+            temp_dir.path().to_path_buf(),
+            killed_dir.path().to_path_buf(),
+        );
+        let vk = ExtendedFullViewingKey::from(
+            ExtendedViewingKey::from_str(AA_VIEWING_KEY).expect("Test failed"),
+        )
+        .fvk
+        .vk;
+        let masp_tx = arbitrary_masp_tx();
+        let indexed_txs = [1, 2, 3].map(|height| IndexedTx {
+            height: BlockHeight(height),
+            index: TxIndex(0),
+        });
+        for indexed_tx in &indexed_txs {
+            masp_tx_sender
+                .send(Some((indexed_tx.clone(), vec![masp_tx.clone()])))
+                .expect("Test failed");
+        }
+        shielded_ctx
+            .fetch(
+                TestingMaspClient::new(&client),
+                &progress,
+                None,
+                None,
+                RetryStrategy::Times(1),
+                &[],
+                &[vk],
+            )
+            .await
+            .expect("Test failed");
+
+        // the context saved when the process got killed must contain
+        // the first height only and the rest must be left in the cache
+        let mut killed_ctx =
+            FsShieldedUtils::new(killed_dir.path().to_path_buf());
+        killed_ctx.load_confirmed().await.expect("Test failed");
+        let scanned =
+            killed_ctx.tx_note_map.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(scanned, indexed_txs[..1]);
+        assert_eq!(killed_ctx.vk_heights[&vk], Some(indexed_txs[0].clone()));
+        assert_eq!(killed_ctx.note_map.len(), 1);
+        let unscanned = killed_ctx
+            .unscanned
+            .txs
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(unscanned, indexed_txs[1..]);
+
+        // resume the sync from the killed context
+        for indexed_tx in &indexed_txs {
+            masp_tx_sender
+                .send(Some((indexed_tx.clone(), vec![masp_tx.clone()])))
+                .expect("Test failed");
+        }
+        killed_ctx
+            .fetch(
+                TestingMaspClient::new(&client),
+                &DefaultTracker::new(&io),
+                None,
+                None,
+                RetryStrategy::Times(1),
+                &[],
+                &[vk],
+            )
+            .await
+            .expect("Test failed");
+        killed_ctx.load_confirmed().await.expect("Test failed");
+        let scanned =
+            killed_ctx.tx_note_map.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(scanned, indexed_txs);
+        assert_eq!(killed_ctx.note_map.len(), 3);
+        assert!(killed_ctx.unscanned.is_empty());
+    }
 }
@@ -1,5 +1,6 @@
 use std::collections::BTreeMap;
 use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 use masp_primitives::merkle_tree::{CommitmentTree, IncrementalWitness};
@@ -129,7 +130,10 @@ impl MaspClient for TestingMaspClient<'_> {
         // N.B. this assumes one masp tx per block
         let mut fetch_iter = progress.fetch(from..=to);
 
-        while fetch_iter.peek().is_some() {
+        while let Some(&height) = fetch_iter.peek() {
+            if tx_sender.pause_at(height) {
+                break;
+            }
             let next_tx = self
                 .client
                 .next_masp_txs
@@ -253,3 +257,167 @@ impl<'io, IO: Io> ProgressTracker<IO> for TestUnscannedTracker<'io, IO> {
         locked.length - locked.index
     }
 }
+
+/// An iterator that takes a copy of the files of a saved
+/// shielded context once the scanning moves past the first
+/// block height, as if the process had been killed then.
+struct KillAfterFirstHeightIterator<I> {
+    inner: I,
+    first_height: Option<BlockHeight>,
+    context_dir: PathBuf,
+    killed_dir: PathBuf,
+    killed: bool,
+}
+
+impl<I> Iterator for KillAfterFirstHeightIterator<I>
+where
+    I: Iterator<Item = IndexedNoteEntry>,
+{
+    type Item = IndexedNoteEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.inner.next();
+        let next_height = next.as_ref().map(|(ix, _)| ix.height);
+        let first_height = *self.first_height.get_or_insert_with(|| {
+            next_height.expect("Test failed: nothing to scan")
+        });
+        if !self.killed && next_height.map_or(true, |h| h > first_height) {
+            for file in std::fs::read_dir(&self.context_dir).unwrap() {
+                let path = file.unwrap().path();
+                std::fs::copy(
+                    &path,
+                    self.killed_dir.join(path.file_name().unwrap()),
+                )
+                .unwrap();
+            }
+            self.killed = true;
+        }
+        next
+    }
+}
+
+/// A progress tracker that scans all the fetched blocks,
+/// but also simulates the process being killed after
+/// the first block height has been scanned. At that point,
+/// the saved shielded context is copied to another directory
+/// for the purposes of testing the scanning checkpoints.
+pub(super) struct TestKilledScanTracker<'io, IO> {
+    io: &'io IO,
+    progress: Arc<Mutex<IterProgress>>,
+    context_dir: PathBuf,
+    killed_dir: PathBuf,
+}
+
+impl<'io, IO: Io> TestKilledScanTracker<'io, IO> {
+    pub fn new(io: &'io IO, context_dir: PathBuf, killed_dir: PathBuf) -> Self {
+        Self {
+            io,
+            progress: Arc::new(Mutex::new(Default::default())),
+            context_dir,
+            killed_dir,
+        }
+    }
+}
+
+impl<'io, IO: Io> ProgressTracker<IO> for TestKilledScanTracker<'io, IO> {
+    fn io(&self) -> &IO {
+        self.io
+    }
+
+    fn fetch<I>(&self, items: I) -> impl PeekableIter<u64>
+    where
+        I: Iterator<Item = u64>,
+    {
+        {
+            let mut locked = self.progress.lock().unwrap();
+            locked.length = items.size_hint().0;
+        }
+        crate::masp::utils::DefaultFetchIterator {
+            inner: items,
+            progress: self.progress.clone(),
+            peeked: None,
+        }
+    }
+
+    fn scan<I>(&self, items: I) -> impl Iterator<Item = IndexedNoteEntry> + Send
+    where
+        I: Iterator<Item = IndexedNoteEntry> + Send,
+    {
+        KillAfterFirstHeightIterator {
+            inner: items,
+            first_height: None,
+            context_dir: self.context_dir.clone(),
+            killed_dir: self.killed_dir.clone(),
+            killed: false,
+        }
+    }
+
+    fn left_to_fetch(&self) -> usize {
+        let locked = self.progress.lock().unwrap();
+        locked.length - locked.index
+    }
+}
+
+/// A progress tracker that keeps track of the number of blocks
+/// fetched by each call to the MASP client, i.e. between the
+/// pauses of the fetching, for the purposes of testing that the
+/// fetched blocks are handed over to the scanning in batches.
+pub(super) struct TestFetchBatchesTracker<'io, IO> {
+    io: &'io IO,
+    batches: Arc<Mutex<Vec<Arc<Mutex<IterProgress>>>>>,
+}
+
+impl<'io, IO: Io> TestFetchBatchesTracker<'io, IO> {
+    pub fn new(io: &'io IO) -> Self {
+        Self {
+            io,
+            batches: Default::default(),
+        }
+    }
+
+    /// The number of blocks fetched in each batch
+    pub fn batches(&self) -> Vec<usize> {
+        self.batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|progress| progress.lock().unwrap().index)
+            .collect()
+    }
+}
+
+impl<'io, IO: Io> ProgressTracker<IO> for TestFetchBatchesTracker<'io, IO> {
+    fn io(&self) -> &IO {
+        self.io
+    }
+
+    fn fetch<I>(&self, items: I) -> impl PeekableIter<u64>
+    where
+        I: Iterator<Item = u64>,
+    {
+        let progress = Arc::new(Mutex::new(IterProgress {
+            index: 0,
+            length: items.size_hint().0,
+        }));
+        self.batches.lock().unwrap().push(progress.clone());
+        crate::masp::utils::DefaultFetchIterator {
+            inner: items,
+            progress,
+            peeked: None,
+        }
+    }
+
+    fn scan<I>(&self, items: I) -> impl Iterator<Item = IndexedNoteEntry> + Send
+    where
+        I: Iterator<Item = IndexedNoteEntry> + Send,
+    {
+        items
+    }
+
+    fn left_to_fetch(&self) -> usize {
+        self.batches.lock().unwrap().last().map_or(0, |progress| {
+            let locked = progress.lock().unwrap();
+            locked.length - locked.index
+        })
+    }
+}
@@ -15,7 +15,8 @@ use crate::error::{Error, QueryError};
 use crate::io::Io;
 use crate::masp::{
     extract_masp_tx, extract_masp_tx_from_ibc_message,
-    get_indexed_masp_events_at_height, IndexedNoteEntry, Unscanned,
+    get_indexed_masp_events_at_height, FetchWatermarks, IndexedNoteEntry,
+    Unscanned,
 };
 use crate::queries::Client;
 
@@ -170,6 +171,9 @@ impl<C: Client + Sync> MaspClient for LedgerMaspClient<'_, C> {
             let Some(height) = fetch_iter.peek().copied() else {
                 break;
             };
+            if tx_sender.pause_at(height) {
+                break;
+            }
             _ = fetch_iter.next();
 
             if shutdown_signal.received() {
@@ -415,6 +419,10 @@ impl MaspClient for IndexerMaspClient {
         let mut fetch_iter = progress.fetch(from..=to);
 
         loop {
+            if tx_sender.pause_at(from) {
+                // NB: the remaining heights are fetched when resuming
+                return Ok(());
+            }
             let from_height = from;
             let off = (to - from).min(MAX_RANGE_THRES);
             let to_height = from + off;
@@ -471,6 +479,18 @@ impl MaspClient for IndexerMaspClient {
                 block_height,
             } in payload.txs
             {
+                let curr_height = Some(block_height);
+                if curr_height > last_height {
+                    // Pause before the first tx of a height, such that the
+                    // cache holds either all the txs of a height or none
+                    if tx_sender.pause_at(block_height) {
+                        // NB: the remaining heights are fetched when resuming
+                        return Ok(());
+                    }
+                    last_height = curr_height;
+                    _ = fetch_iter.next();
+                }
+
                 let mut extracted_masp_txs = Vec::with_capacity(batch.len());
 
                 for TransactionSlot { bytes } in batch {
@@ -494,12 +514,6 @@ impl MaspClient for IndexerMaspClient {
                     },
                     extracted_masp_txs,
                 ));
-
-                let curr_height = Some(block_height);
-                if curr_height > last_height {
-                    last_height = curr_height;
-                    _ = fetch_iter.next();
-                }
             }
 
             if from >= to {
@@ -693,23 +707,32 @@ impl MaspClient for IndexerMaspClient {
 /// Furthermore, has an actual channel for keeping track if
 /// 1. The process in possession of the channel is still alive
 /// 2. Quickly updating the latest block height scanned.
+///
+/// If the cache has watermarks, the fetching must pause once the cache
+/// grows past the high-water mark, see [`FetchQueueSender::pause_at`].
 #[derive(Clone)]
 pub struct FetchQueueSender {
     cache: Unscanned,
     last_fetched: flume::Sender<BlockHeight>,
+    watermarks: Option<FetchWatermarks>,
+    paused_at: Arc<Mutex<Option<BlockHeight>>>,
 }
 
 /// A channel-like struct for "receiving" new fetched
 /// blocks for the scanning algorithm.
 ///
 /// This is implemented as an iterator for the scanning
-/// algorithm. This receiver pulls from the cache until
-/// it is empty. It then waits until new entries appear
-/// in the cache or the sender hangs up.
+/// algorithm. This receiver reads from the cache until
+/// it has yielded all of its entries. It then waits until
+/// new entries appear in the cache or the sender hangs up.
+/// The entries are left in the cache, such that they are
+/// only removed once they have been scanned.
 #[derive(Clone)]
 pub(super) struct FetchQueueReceiver {
     cache: Unscanned,
     last_fetched: flume::Receiver<BlockHeight>,
+    last_yielded: Option<IndexedTx>,
+    paused_at: Arc<Mutex<Option<BlockHeight>>>,
 }
 
 impl FetchQueueReceiver {
@@ -717,23 +740,37 @@ impl FetchQueueReceiver {
     fn sender_alive(&self) -> bool {
         self.last_fetched.sender_count() > 0
     }
+
+    /// The block height at which the fetching paused, if it did. The
+    /// fetching must resume from this height.
+    pub(super) fn paused_at(&self) -> Option<BlockHeight> {
+        *self.paused_at.lock().unwrap()
+    }
 }
 
 impl Iterator for FetchQueueReceiver {
     type Item = IndexedNoteEntry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(entry) = self.cache.pop_first() {
-            Some(entry)
+        let entry = if let Some(entry) =
+            self.cache.next_after(self.last_yielded.as_ref())
+        {
+            entry
         } else {
-            while self.sender_alive() {
-                if let Some(entry) = self.cache.pop_first() {
-                    return Some(entry);
+            loop {
+                if !self.sender_alive() {
+                    return None;
+                }
+                if let Some(entry) =
+                    self.cache.next_after(self.last_yielded.as_ref())
+                {
+                    break entry;
                 }
                 core::hint::spin_loop();
             }
-            None
-        }
+        };
+        self.last_yielded = Some(entry.0.clone());
+        Some(entry)
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -748,29 +785,60 @@ impl FetchQueueSender {
         self.cache.contains_height(height)
     }
 
+    /// Check if the fetching must pause before the given block height,
+    /// because the cache holds at least as many transactions as its
+    /// high-water mark. If so, the height is recorded for the fetching
+    /// to resume from it once the cache has been scanned down to its
+    /// low-water mark.
+    pub(super) fn pause_at(&self, height: u64) -> bool {
+        let Some(watermarks) = self.watermarks else {
+            return false;
+        };
+        if self.cache.len() < watermarks.high {
+            return false;
+        }
+        *self.paused_at.lock().unwrap() = Some(BlockHeight(height));
+        true
+    }
+
     /// Send a new value of the channel
     pub(super) fn send(&mut self, data: IndexedNoteEntry) {
-        self.last_fetched.send(data.0.height).unwrap();
-        self.cache.insert(data);
+        let index = data.0.clone();
+        self.last_fetched.send(index.height).unwrap();
+        if self.cache.insert(data) {
+            tracing::warn!(
+                "The masp transaction at {index:?} was fetched more than once"
+            );
+        }
     }
 }
 
 /// A convenience for creating a channel for fetching blocks.
 pub mod fetch_channel {
 
-    use super::{FetchQueueReceiver, FetchQueueSender, Unscanned};
+    use std::sync::{Arc, Mutex};
+
+    use super::{
+        FetchQueueReceiver, FetchQueueSender, FetchWatermarks, Unscanned,
+    };
     pub(in super::super) fn new(
         cache: Unscanned,
+        watermarks: Option<FetchWatermarks>,
     ) -> (FetchQueueSender, FetchQueueReceiver) {
         let (fetch_send, fetch_recv) = flume::unbounded();
+        let paused_at = Arc::new(Mutex::new(None));
         (
             FetchQueueSender {
                 cache: cache.clone(),
                 last_fetched: fetch_send,
+                watermarks,
+                paused_at: paused_at.clone(),
             },
             FetchQueueReceiver {
                 cache: cache.clone(),
                 last_fetched: fetch_recv,
+                last_yielded: None,
+                paused_at,
             },
         )
     }
@@ -919,7 +987,9 @@ impl<'io, IO: Io> ProgressTracker<IO> for DefaultTracker<'io, IO> {
 
     fn left_to_fetch(&self) -> usize {
         let locked = self.progress.lock().unwrap();
-        locked.length - locked.index
+        // NB: the blocks fetched before resuming a paused fetching are
+        // also counted
+        locked.length.saturating_sub(locked.index)
     }
 }
 
@@ -213,7 +213,8 @@ pub fn dump_tx<IO: Io>(io: &IO, args: &args::Tx, tx: Tx) {
 }
 
 /// Prepare a transaction for signing and submission by adding a wrapper header
-/// to it.
+/// to it. Unless forced, the transaction is rejected if it couldn't be
+/// submitted.
 pub async fn prepare_tx(
     args: &args::Tx,
     tx: &mut Tx,
@@ -221,9 +222,17 @@ pub async fn prepare_tx(
     fee_payer: common::PublicKey,
 ) -> Result<()> {
     if !args.dry_run {
-        signing::wrap_tx(tx, args, fee_amount, fee_payer).await
-    } else {
-        Ok(())
+        signing::wrap_tx(tx, args, fee_amount, fee_payer).await?;
+    }
+    match tx.validate_build() {
+        Err(issues) if !args.force => {
+            let issues: Vec<_> =
+                issues.iter().map(ToString::to_string).collect();
+            Err(Error::from(TxSubmitError::InvalidBuiltTx(
+                issues.join(", "),
+            )))
+        }
+        _ => Ok(()),
     }
 }
 
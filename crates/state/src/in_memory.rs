@@ -123,6 +123,8 @@ pub struct BlockStorage<H: StorageHasher> {
     pub epoch: Epoch,
     /// Results of applying transactions
     pub results: BlockResults,
+    /// Total gas used by the transactions applied in the block
+    pub gas: u64,
     /// Predecessor block epochs
     pub pred_epochs: Epochs,
 }
@@ -143,6 +145,7 @@ where
             epoch: Epoch::default(),
             pred_epochs: Epochs::default(),
             results: BlockResults::default(),
+            gas: 0,
         };
         InMemory::<H> {
             chain_id,
@@ -201,9 +204,11 @@ where
         Ok(())
     }
 
-    /// Store in memory a total gas of a transaction with the given hash.
+    /// Store in memory a total gas of a transaction with the given hash and
+    /// add it to the gas of the current block.
     pub fn add_tx_gas(&mut self, tx_hash: Hash, gas: u64) {
         self.commit_only_data.tx_gas.insert(tx_hash, gas);
+        self.block.gas = self.block.gas.saturating_add(gas);
     }
 
     /// Get the chain ID as a raw string
@@ -650,6 +650,7 @@ pub mod testing {
                 epoch: Epoch::default(),
                 pred_epochs: Epochs::default(),
                 results: BlockResults::default(),
+                gas: 0,
             };
             Self {
                 chain_id,
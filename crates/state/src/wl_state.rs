@@ -275,17 +275,12 @@ where
             .read(&replay_prot_key)
             .expect("Could not read db")
             .unwrap_or_default();
+        let hashes = std::mem::take(&mut self.0.write_log.replay_protection);
+        let keys: Vec<_> =
+            hashes.iter().map(replay_protection::current_key).collect();
+        self.db.write_replay_protection_entries(batch, &keys)?;
         let new_commitment =
-            std::mem::take(&mut self.0.write_log.replay_protection)
-                .iter()
-                .try_fold(commitment, |mut acc, hash| {
-                    self.write_replay_protection_entry(
-                        batch,
-                        &replay_protection::current_key(hash),
-                    )?;
-                    acc = acc.concat(hash);
-                    Ok::<_, Error>(acc)
-                })?;
+            hashes.iter().fold(commitment, |acc, hash| acc.concat(hash));
         self.batch_write_subspace_val(batch, &replay_prot_key, new_commitment)?;
 
         debug_assert!(self.0.write_log.replay_protection.is_empty());
@@ -590,6 +585,7 @@ where
                 .time,
             epoch: self.in_mem.block.epoch,
             results: &self.in_mem.block.results,
+            gas: self.in_mem.block.gas,
             pred_epochs: &self.in_mem.block.pred_epochs,
             next_epoch_min_start_height: self
                 .in_mem
@@ -44,6 +44,18 @@ pub struct ConversionState {
     pub assets: BTreeMap<AssetType, ConversionLeaf>,
 }
 
+impl ConversionState {
+    /// Iterate the latest allowed conversion of each asset type, in the
+    /// ascending order of the asset types
+    pub fn allowed_conversions(
+        &self,
+    ) -> impl Iterator<Item = (&AssetType, &AllowedConversion)> {
+        self.assets
+            .iter()
+            .map(|(asset_type, leaf)| (asset_type, &leaf.conversion))
+    }
+}
+
 /// Able to borrow mutable conversion state.
 pub trait WithConversionState {
     /// Borrow immutable conversion state
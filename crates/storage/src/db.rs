@@ -46,6 +46,56 @@ pub enum Error {
     NumConversionError(#[from] TryFromIntError),
     #[error("Arithmetic {0}")]
     Arith(#[from] arith::Error),
+    #[error("The value of the key {key} failed its integrity check")]
+    CorruptValue { key: String },
+    #[error("The column family {name} is missing from the DB")]
+    MissingColumnFamily { name: String },
+    #[error(
+        "The block at height {height} has only been partially written to the \
+         DB. The DB has to be rolled back to the previous height, e.g. by \
+         restoring it from a snapshot or a backup, before the node can start"
+    )]
+    TornBlockWrite { height: BlockHeight },
+    #[error(
+        "The conversion state of the block at height {height} is corrupt. It \
+         may be recovered from the conversion state preceding the last epoch \
+         change"
+    )]
+    CorruptConversionState { height: BlockHeight },
+    #[error(
+        "Cannot roll back to height {target}, the diffs needed for it have \
+         been pruned and the oldest retained diffs are at height \
+         {oldest_retained}"
+    )]
+    RollbackBeyondRetention {
+        target: BlockHeight,
+        oldest_retained: BlockHeight,
+    },
+    #[error(
+        "Cannot write the block at height {got}, the next block of the DB is \
+         at height {expected}"
+    )]
+    NonContiguousBlock {
+        expected: BlockHeight,
+        got: BlockHeight,
+    },
+    #[error(
+        "The DB at {path} is locked, it is already open in another process. \
+         Another node instance or a stale process may be running with the \
+         same base directory and has to be stopped first"
+    )]
+    DbLocked { path: String },
+    #[error(
+        "The DB's storage is full: {error}. The node should halt, as the DB \
+         rejects all the writes after a failed write to its storage. It has \
+         to be reopened once some space has been freed"
+    )]
+    StorageFull { error: String },
+    #[error(
+        "The DB rejected a write with a transient error: {error}. Nothing \
+         was written and the write may be retried"
+    )]
+    IoTransient { error: String },
 }
 
 /// A result of a function that may fail
@@ -106,6 +156,8 @@ pub struct BlockStateWrite<'a> {
     pub address_gen: &'a EstablishedAddressGen,
     /// Results of applying transactions
     pub results: &'a BlockResults,
+    /// Total gas used by the block's txs
+    pub gas: u64,
     /// The conversion state
     pub conversion_state: &'a ConversionState,
     /// The latest block height on Ethereum processed, if
@@ -118,6 +170,14 @@ pub struct BlockStateWrite<'a> {
 }
 
 /// A database backend.
+///
+/// The write methods that take `&mut self`, e.g. [`DB::write_subspace_val`],
+/// are exclusive. The batch-building methods take `&self`, e.g.
+/// [`DB::batch_write_subspace_val`], and read the prior values of the keys to
+/// persist their diffs, so they are not: when batches are built concurrently,
+/// another batch may change these values before this one is written, leaving
+/// its diffs stale. An implementation may provide a way to build and write a
+/// batch exclusively of the other writers.
 pub trait DB: Debug {
     /// A DB's cache
     type Cache;
@@ -266,6 +326,37 @@ pub trait DB: Debug {
         key: &Key,
     ) -> Result<()>;
 
+    /// Write multiple replay protection entries
+    fn write_replay_protection_entries(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        keys: &[Key],
+    ) -> Result<()> {
+        for key in keys {
+            self.write_replay_protection_entry(batch, key)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a replay protection entry
+    fn delete_replay_protection_entry(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        key: &Key,
+    ) -> Result<()>;
+
+    /// Delete multiple replay protection entries
+    fn delete_replay_protection_entries(
+        &mut self,
+        batch: &mut Self::WriteBatch,
+        keys: &[Key],
+    ) -> Result<()> {
+        for key in keys {
+            self.delete_replay_protection_entry(batch, key)?;
+        }
+        Ok(())
+    }
+
     /// Move the current replay protection bucket to the general one
     fn move_current_replay_protection_entries(
         &mut self,
@@ -212,6 +212,7 @@ impl DB for MockDB {
             update_epoch_blocks_delay,
             address_gen,
             results,
+            gas: _,
             conversion_state,
             ethereum_height,
             eth_events_queue,
@@ -568,6 +569,18 @@ impl DB for MockDB {
         }
     }
 
+    fn delete_replay_protection_entry(
+        &mut self,
+        _batch: &mut Self::WriteBatch,
+        key: &Key,
+    ) -> Result<()> {
+        let key = Key::parse("replay_protection")
+            .map_err(Error::KeyError)?
+            .join(key);
+        self.0.borrow_mut().remove(&key.to_string());
+        Ok(())
+    }
+
     fn move_current_replay_protection_entries(
         &mut self,
         _batch: &mut Self::WriteBatch,
@@ -71,6 +71,20 @@ pub enum DecodeError {
     InvalidJSONDeserialization(String),
 }
 
+/// An issue that would make a built transaction unsubmittable
+#[allow(missing_docs)]
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TxBuildIssue {
+    #[error("The chain ID of the transaction has not been set")]
+    DefaultChainId,
+    #[error("The transaction has neither a code nor a data section")]
+    NoCodeOrData,
+    #[error("The wrapper transaction does not pay for any gas")]
+    NoGasPayment,
+    #[error("The section {0} referenced by the header is missing")]
+    MissingSection(namada_core::hash::Hash),
+}
+
 /// This can be used to sign an arbitrary tx. The signature is produced and
 /// verified on the tx data concatenated with the tx code, however the tx code
 /// itself is not part of this structure.
@@ -1492,6 +1506,41 @@ impl Tx {
         }
     }
 
+    /// Check that the transaction being built can be submitted. All the
+    /// issues found are returned at once.
+    pub fn validate_build(&self) -> std::result::Result<(), Vec<TxBuildIssue>> {
+        let mut issues = vec![];
+        if self.header.chain_id == ChainId::default() {
+            issues.push(TxBuildIssue::DefaultChainId);
+        }
+        let has_code_or_data = self.sections.iter().any(|section| {
+            matches!(section, Section::Code(_) | Section::Data(_))
+        });
+        if !has_code_or_data {
+            issues.push(TxBuildIssue::NoCodeOrData);
+        }
+        if let TxType::Wrapper(wrapper) = &self.header.tx_type {
+            if u64::from(wrapper.gas_limit) == 0 {
+                issues.push(TxBuildIssue::NoGasPayment);
+            }
+        }
+        // A zero hash stands for a section that is not present, e.g. a memo
+        for cmt in self.commitments() {
+            for hash in [cmt.code_hash, cmt.data_hash, cmt.memo_hash] {
+                if hash != namada_core::hash::Hash::default()
+                    && self.get_section(&hash).is_none()
+                {
+                    issues.push(TxBuildIssue::MissingSection(hash));
+                }
+            }
+        }
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
     /// Filter out all the sections that must not be submitted to the protocol
     /// and return them.
     pub fn protocol_filter(&mut self) -> Vec<Section> {
@@ -1639,6 +1688,28 @@ impl Tx {
         self
     }
 
+    /// Wrap the tx to have its fees paid by the given keypair and sign the
+    /// wrapper with it, such that the wrapper's public key always matches the
+    /// signer. This must be the last step of the build, as the signature
+    /// commits to the sections already added.
+    pub fn wrap(
+        &mut self,
+        fee_token: Address,
+        fee_amount: namada_core::token::DenominatedAmount,
+        gas_limit: GasLimit,
+        gas_payer: common::SecretKey,
+    ) -> &mut Self {
+        self.add_wrapper(
+            Fee {
+                amount_per_gas_unit: fee_amount,
+                token: fee_token,
+            },
+            gas_payer.ref_to(),
+            gas_limit,
+        )
+        .sign_wrapper(gas_payer)
+    }
+
     /// Add signing keys to the tx builder
     pub fn sign_raw(
         &mut self,
@@ -1816,6 +1887,7 @@ mod test {
 
     use borsh::schema::BorshSchema;
     use data_encoding::HEXLOWER;
+    use namada_core::token::{Amount, DenominatedAmount};
 
     use super::*;
 
@@ -1828,6 +1900,87 @@ mod test {
         super::Tx::add_definitions_recursively(&mut definitions);
     }
 
+    /// Build a transaction with some code and data
+    fn tx_with_code_and_data() -> Tx {
+        let mut tx = Tx::new(ChainId("test-chain".to_string()), None);
+        tx.add_code(vec![1, 2, 3], None).add_data(42_u64);
+        tx
+    }
+
+    /// Test that a complete transaction passes the build validation
+    #[test]
+    fn test_validate_build_ok() {
+        let tx = tx_with_code_and_data();
+        assert_eq!(tx.validate_build(), Ok(()));
+    }
+
+    /// Test that all build issues get reported at once
+    #[test]
+    fn test_validate_build_issues() {
+        // default chain id and no sections
+        let tx = Tx::from_type(TxType::Raw);
+        assert_eq!(
+            tx.validate_build(),
+            Err(vec![
+                TxBuildIssue::DefaultChainId,
+                TxBuildIssue::NoCodeOrData
+            ])
+        );
+
+        // wrapper without any gas
+        let mut tx = tx_with_code_and_data();
+        tx.add_wrapper(
+            Fee {
+                amount_per_gas_unit: DenominatedAmount::native(
+                    Amount::from_u64(1),
+                ),
+                token: namada_core::address::testing::nam(),
+            },
+            namada_core::key::testing::keypair_1().ref_to(),
+            GasLimit::from(0),
+        );
+        assert_eq!(tx.validate_build(), Err(vec![TxBuildIssue::NoGasPayment]));
+
+        // a section referenced by the header is missing
+        let mut tx = tx_with_code_and_data();
+        let data_hash = tx.first_commitments().unwrap().data_hash;
+        tx.sections
+            .retain(|section| !matches!(section, Section::Data(_)));
+        assert_eq!(
+            tx.validate_build(),
+            Err(vec![TxBuildIssue::MissingSection(data_hash)])
+        );
+    }
+
+    /// Test that a wrapped tx has the given fee and is signed by its payer
+    #[test]
+    fn test_wrap() {
+        let gas_payer = namada_core::key::testing::keypair_1();
+        let fee_amount = DenominatedAmount::native(Amount::from_u64(10));
+        let fee_token = namada_core::address::testing::nam();
+        let mut tx = tx_with_code_and_data();
+        tx.wrap(
+            fee_token.clone(),
+            fee_amount,
+            GasLimit::from(1_000),
+            gas_payer.clone(),
+        );
+
+        let wrapper = tx.wrapper().expect("The tx should be wrapped");
+        assert_eq!(
+            wrapper.fee,
+            Fee {
+                amount_per_gas_unit: fee_amount,
+                token: fee_token,
+            }
+        );
+        assert_eq!(wrapper.pk, gas_payer.ref_to());
+        assert_eq!(wrapper.fee_payer(), Address::from(&gas_payer.ref_to()));
+        assert_eq!(u64::from(wrapper.gas_limit), 1_000);
+        assert!(tx.validate_tx().unwrap().is_some());
+        assert_eq!(tx.validate_build(), Ok(()));
+    }
+
     /// Tx encoding must not change
     #[test]
     fn test_txs_fixture_decoding() {